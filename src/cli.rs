@@ -2,7 +2,9 @@
 
 use std::path::PathBuf;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
+
+use crate::config::{CassetteConfig, CassetteMode};
 
 /// Sandbox Runtime - OS-level sandboxing tool
 #[derive(Parser, Debug)]
@@ -26,9 +28,50 @@ pub struct Cli {
     #[arg(long = "control-fd")]
     pub control_fd: Option<i32>,
 
+    /// Write askUnknownDomains requests to file descriptor (JSON lines);
+    /// answers are read from --control-fd
+    #[arg(long = "ask-fd")]
+    pub ask_fd: Option<i32>,
+
+    /// Record HTTP traffic through the proxy to a cassette file
+    #[arg(long = "record", value_name = "CASSETTE", conflicts_with = "replay")]
+    pub record: Option<String>,
+
+    /// Serve HTTP traffic from a recorded cassette file, without network access
+    #[arg(long = "replay", value_name = "CASSETTE")]
+    pub replay: Option<String>,
+
     /// Command and arguments to run
     #[arg(trailing_var_arg = true)]
     pub args: Vec<String>,
+
+    /// Internal helper run by srt itself
+    #[command(subcommand)]
+    pub helper: Option<Helper>,
+}
+
+/// Internal subcommands, hidden from help.
+#[derive(Subcommand, Debug)]
+pub enum Helper {
+    /// Run the sandbox side of the network bridges, then the command (Linux)
+    #[command(name = "__bridge", hide = true)]
+    Bridge {
+        /// Bridges as `tcp:PORT:SOCKET`, `udp:PORT:SOCKET`, `dns:SOCKET` or `expose:PORT:SOCKET`
+        bridges: Vec<String>,
+
+        /// Refuse `listen` to the command, without allowLocalBinding
+        #[arg(long)]
+        deny_listen: bool,
+
+        /// tun2socks binary, to capture TCP traffic through a TUN device
+        /// (the proxy URL is read from `SRT_TUN_PROXY`)
+        #[arg(long)]
+        tun2socks: Option<String>,
+
+        /// Command to run once the bridges are listening
+        #[arg(last = true, required = true)]
+        command: Vec<String>,
+    },
 }
 
 impl Cli {
@@ -53,6 +96,19 @@ impl Cli {
         }
     }
 
+    /// Get the cassette selected by --record or --replay, if any.
+    pub fn get_cassette(&self) -> Option<CassetteConfig> {
+        let (path, mode) = match (&self.record, &self.replay) {
+            (Some(path), _) => (path, CassetteMode::Record),
+            (None, Some(path)) => (path, CassetteMode::Replay),
+            (None, None) => return None,
+        };
+        Some(CassetteConfig {
+            path: path.clone(),
+            mode,
+        })
+    }
+
     /// Get the settings file path.
     pub fn get_settings_path(&self) -> Option<PathBuf> {
         self.settings.clone().or_else(crate::config::default_settings_path)
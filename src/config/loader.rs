@@ -120,6 +120,132 @@ mod tests {
 
         let result = parse_config(json);
         assert!(result.is_err());
+
+        let json = r#"{ "network": { "allowedDomains": ["*.github.io"] } }"#;
+        assert!(parse_config(json).is_err());
+        let json = r#"{
+            "network": { "allowedDomains": ["*.github.io"], "allowPublicSuffixWildcards": true }
+        }"#;
+        assert!(parse_config(json).is_ok());
+    }
+
+    #[test]
+    fn test_http_rules_config() {
+        let json = r#"{
+            "network": {
+                "httpRules": [
+                    { "domain": "registry.npmjs.org", "methods": ["GET", "HEAD"] },
+                    { "domain": "api.github.com", "action": "deny", "methods": ["POST"],
+                      "paths": ["/repos/*/issues"] }
+                ]
+            }
+        }"#;
+        let config = parse_config(json).unwrap();
+        let rules = &config.network.http_rules;
+        assert_eq!(rules[0].action, crate::config::HttpRuleAction::Allow);
+        assert_eq!(rules[1].action, crate::config::HttpRuleAction::Deny);
+
+        let bad_path = r#"{ "network": { "httpRules": [
+            { "domain": "example.com", "paths": ["repos/*"] } ] } }"#;
+        assert!(parse_config(bad_path).is_err());
+        let bad_method = r#"{ "network": { "httpRules": [
+            { "domain": "example.com", "methods": ["GET /"] } ] } }"#;
+        assert!(parse_config(bad_method).is_err());
+    }
+
+    #[test]
+    fn test_credentials_config() {
+        let json = r#"{
+            "network": {
+                "credentials": [
+                    { "domain": "api.github.com:443", "header": "Authorization",
+                      "prefix": "Bearer ", "env": "GITHUB_TOKEN" }
+                ]
+            }
+        }"#;
+        let config = parse_config(json).unwrap();
+        assert_eq!(config.network.credentials[0].prefix.as_deref(), Some("Bearer "));
+
+        let both = r#"{ "network": { "credentials": [
+            { "domain": "example.com", "header": "X-Key", "env": "KEY", "file": "~/.key" } ] } }"#;
+        assert!(parse_config(both).is_err());
+        let bad_header = r#"{ "network": { "credentials": [
+            { "domain": "example.com", "header": "X Key", "env": "KEY" } ] } }"#;
+        assert!(parse_config(bad_header).is_err());
+    }
+
+    #[test]
+    fn test_ask_unknown_domains_config() {
+        let json = r#"{ "network": { "askUnknownDomains": { "timeoutSecs": 120 } } }"#;
+        let config = parse_config(json).unwrap();
+        assert_eq!(config.network.ask_unknown_domains.unwrap().timeout_secs, Some(120));
+
+        let zero = r#"{ "network": { "askUnknownDomains": { "timeoutSecs": 0 } } }"#;
+        assert!(parse_config(zero).is_err());
+    }
+
+    #[test]
+    fn test_cassette_config() {
+        let json = r#"{ "network": { "cassette": { "path": "npm.jsonl", "mode": "replay" } } }"#;
+        let config = parse_config(json).unwrap();
+        assert!(config.network.has_restrictions());
+        let cassette = config.network.cassette.unwrap();
+        assert_eq!(cassette.mode, crate::config::CassetteMode::Replay);
+
+        let json = r#"{ "network": { "cassette": { "path": "npm.jsonl" } } }"#;
+        let mode = parse_config(json).unwrap().network.cassette.unwrap().mode;
+        assert_eq!(mode, crate::config::CassetteMode::Record);
+        assert!(parse_config(r#"{ "network": { "cassette": { "path": "" } } }"#).is_err());
+    }
+
+    #[test]
+    fn test_tun_capture_config() {
+        let json = r#"{ "network": { "tunCapture": { "fakeIpRange": "100.100.0.0/16" } } }"#;
+        let config = parse_config(json).unwrap();
+        assert!(config.network.tun_capture.unwrap().tun2socks_path.is_none());
+
+        for range in ["fd00::/64", "10.0.0.0/30", "not-a-range"] {
+            let json = format!(
+                r#"{{ "network": {{ "tunCapture": {{ "fakeIpRange": "{}" }} }} }}"#,
+                range
+            );
+            assert!(parse_config(&json).is_err(), "{}", range);
+        }
+    }
+
+    #[test]
+    fn test_expose_ports_config() {
+        let json = r#"{
+            "network": { "allowLocalBinding": true, "exposePorts": ["3000", "8080:3000"] }
+        }"#;
+        let config = parse_config(json).unwrap();
+        assert_eq!(config.network.expose_ports, ["3000", "8080:3000"]);
+
+        let json = r#"{ "network": { "exposePorts": ["3000"] } }"#;
+        assert!(parse_config(json).is_err());
+        let json = r#"{
+            "network": { "allowLocalBinding": true, "exposePorts": ["3000", "3000:80"] }
+        }"#;
+        assert!(parse_config(json).is_err());
+    }
+
+    #[test]
+    fn test_proxy_env_config() {
+        let json = r#"{
+            "network": { "proxyEnv": { "presets": ["java", "git"], "noProxy": [".corp"] } }
+        }"#;
+        let config = parse_config(json).unwrap();
+        let proxy_env = config.network.proxy_env.unwrap();
+        assert_eq!(
+            proxy_env.presets,
+            Some(vec![crate::config::ProxyEnvPreset::Java, crate::config::ProxyEnvPreset::Git])
+        );
+        assert_eq!(proxy_env.no_proxy, [".corp"]);
+
+        let json = r#"{ "network": { "proxyEnv": { "presets": ["ruby"] } } }"#;
+        assert!(parse_config(json).is_err());
+        let json = r#"{ "network": { "proxyEnv": { "noProxy": ["a.com,b.com"] } } }"#;
+        assert!(parse_config(json).is_err());
     }
 
     #[test]
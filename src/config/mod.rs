@@ -1,10 +1,15 @@
 //! Configuration module.
 
 pub mod loader;
+pub mod public_suffix;
 pub mod schema;
 
 pub use loader::{default_settings_path, load_config, load_config_from_string, load_default_config, parse_config};
 pub use schema::{
-    matches_domain_pattern, FilesystemConfig, MitmProxyConfig, NetworkConfig, RipgrepConfig,
-    SandboxRuntimeConfig, SeccompConfig, DANGEROUS_DIRECTORIES, DANGEROUS_FILES,
+    matches_domain_pattern, matches_domain_port_pattern, normalize_domain, split_domain_pattern,
+    AskConfig, CassetteConfig, CassetteMode, CredentialConfig, DomainRateLimitConfig, ExposedPort,
+    FilesystemConfig, HttpRuleAction, HttpRuleConfig, MitmProxyConfig, NetworkConfig, PortRange,
+    ProxyEnvConfig, ProxyEnvPreset, RateLimitConfig, RateLimitsConfig, RipgrepConfig,
+    SandboxRuntimeConfig, SeccompConfig, SocksUdpConfig, TlsInterceptConfig, TlsSniConfig,
+    TunCaptureConfig, UpstreamProxyConfig, DANGEROUS_DIRECTORIES, DANGEROUS_FILES,
 };
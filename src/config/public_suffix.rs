@@ -0,0 +1,122 @@
+//! Public suffix checks for wildcard domain patterns.
+//!
+//! Embeds a copy of the Public Suffix List (https://publicsuffix.org/list/),
+//! including its private section (`github.io`, `s3.amazonaws.com`, ...), so
+//! that `*.co.uk` or `*.github.io` can't allow every tenant below a suffix.
+//! Refresh `public_suffix_list.dat` from that URL when updating.
+
+use std::collections::HashSet;
+
+use once_cell::sync::Lazy;
+
+/// Parsed rules, with every name in its lowercase ASCII form.
+struct PublicSuffixList {
+    /// Plain rules ("co.uk").
+    rules: HashSet<String>,
+    /// Bases of wildcard rules ("ck" for "*.ck").
+    wildcards: HashSet<String>,
+    /// Exception rules without the "!" ("www.ck").
+    exceptions: HashSet<String>,
+    /// Names that have a public suffix below them ("amazonaws.com").
+    parents: HashSet<String>,
+}
+
+static LIST: Lazy<PublicSuffixList> =
+    Lazy::new(|| PublicSuffixList::parse(include_str!("public_suffix_list.dat")));
+
+impl PublicSuffixList {
+    fn parse(data: &str) -> Self {
+        let mut list = Self {
+            rules: HashSet::new(),
+            wildcards: HashSet::new(),
+            exceptions: HashSet::new(),
+            parents: HashSet::new(),
+        };
+
+        for line in data.lines() {
+            let Some(rule) = line.split_whitespace().next() else {
+                continue;
+            };
+            if rule.starts_with("//") {
+                continue;
+            }
+            let (name, is_wildcard, is_exception) = if let Some(name) = rule.strip_prefix('!') {
+                (name, false, true)
+            } else if let Some(name) = rule.strip_prefix("*.") {
+                (name, true, false)
+            } else {
+                (rule, false, false)
+            };
+            let Ok(name) = idna::domain_to_ascii(name) else {
+                continue;
+            };
+
+            if !is_exception {
+                // Every name above a suffix covers it, as does a wildcard's base
+                let mut ancestor = if is_wildcard {
+                    Some(name.as_str())
+                } else {
+                    name.split_once('.').map(|(_, parent)| parent)
+                };
+                while let Some(name) = ancestor {
+                    list.parents.insert(name.to_string());
+                    ancestor = name.split_once('.').map(|(_, parent)| parent);
+                }
+            }
+
+            match (is_wildcard, is_exception) {
+                (true, _) => list.wildcards.insert(name),
+                (_, true) => list.exceptions.insert(name),
+                _ => list.rules.insert(name),
+            };
+        }
+
+        list
+    }
+}
+
+/// Check if a lowercase ASCII domain is a public suffix. As in the PSL
+/// algorithm, unlisted top-level names count as suffixes too.
+pub fn is_public_suffix(domain: &str) -> bool {
+    let list = &*LIST;
+    if list.exceptions.contains(domain) {
+        return false;
+    }
+    match domain.split_once('.') {
+        Some((_, parent)) => list.rules.contains(domain) || list.wildcards.contains(parent),
+        None => true,
+    }
+}
+
+/// Check if a lowercase ASCII domain has public suffixes below it, e.g.
+/// "amazonaws.com" for "s3.amazonaws.com".
+pub fn has_public_suffix_below(domain: &str) -> bool {
+    LIST.parents.contains(domain)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_public_suffixes() {
+        assert!(is_public_suffix("com"));
+        assert!(is_public_suffix("co.uk"));
+        assert!(is_public_suffix("github.io"));
+        assert!(is_public_suffix("corp"));
+        assert!(!is_public_suffix("example.com"));
+        assert!(!is_public_suffix("amazonaws.com"));
+
+        // Wildcard and exception rules ("*.ck", "!www.ck")
+        assert!(is_public_suffix("anything.ck"));
+        assert!(!is_public_suffix("www.ck"));
+
+        // Unicode rules are stored in their ASCII form ("公司.cn")
+        assert!(is_public_suffix("xn--55qx5d.cn"));
+
+        assert!(has_public_suffix_below("amazonaws.com"));
+        assert!(has_public_suffix_below("compute.amazonaws.com"));
+        assert!(!has_public_suffix_below("github.com"));
+        assert!(!has_public_suffix_below("www.ck"));
+    }
+}
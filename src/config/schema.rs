@@ -2,7 +2,9 @@
 
 use serde::{Deserialize, Serialize};
 
+use crate::config::public_suffix::{has_public_suffix_below, is_public_suffix};
 use crate::error::{ConfigError, SandboxError};
+use crate::utils::IpCidr;
 
 /// MITM proxy configuration for routing specific domains through a man-in-the-middle proxy.
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -14,6 +16,238 @@ pub struct MitmProxyConfig {
     pub domains: Vec<String>,
 }
 
+/// Upstream proxy that the sandbox proxies chain outbound connections through.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct UpstreamProxyConfig {
+    /// Proxy URL: "http://host:port" or "socks5://host:port", with optional
+    /// percent-encoded "user:password@" credentials for basic auth.
+    pub url: String,
+    /// Hosts reached directly instead of through the upstream (NO_PROXY syntax:
+    /// "example.com" also matches subdomains, CIDRs match IP literals, "*" matches all).
+    #[serde(default)]
+    pub no_proxy: Vec<String>,
+}
+
+/// SOCKS5 UDP relay configuration.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct SocksUdpConfig {
+    /// Enable UDP ASSOCIATE (default: true).
+    #[serde(default)]
+    pub enabled: Option<bool>,
+    /// Drop a client's relay state after this many seconds without traffic (default: 30).
+    #[serde(default)]
+    pub idle_timeout_secs: Option<u64>,
+    /// Close an association after this many seconds (default: 3600).
+    #[serde(default)]
+    pub max_lifetime_secs: Option<u64>,
+    /// Maximum number of concurrent associations (default: 32).
+    #[serde(default)]
+    pub max_associations: Option<usize>,
+    /// Maximum number of distinct destinations per client address (default: 64).
+    #[serde(default)]
+    pub max_destinations: Option<usize>,
+}
+
+/// TLS SNI verification for CONNECT tunnels.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct TlsSniConfig {
+    /// Require a ClientHello whose SNI agrees with the CONNECT host (default: false).
+    #[serde(default)]
+    pub enabled: Option<bool>,
+    /// Ports tunnelled without inspection, for non-TLS protocols (e.g., "22").
+    #[serde(default)]
+    pub non_tls_ports: Vec<String>,
+}
+
+/// Built-in TLS interception for selected domains.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct TlsInterceptConfig {
+    /// Domains whose HTTPS traffic is decrypted and inspected by the HTTP proxy.
+    pub domains: Vec<String>,
+    /// Directory holding a persistent CA ("ca.pem" and "ca-key.pem"), created if
+    /// missing. Without it, a CA is generated for the session only.
+    #[serde(default)]
+    pub ca_dir: Option<String>,
+}
+
+/// Whether an HTTP rule permits or refuses the requests it matches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum HttpRuleAction {
+    /// Only matching requests are allowed on the domain.
+    #[default]
+    Allow,
+    /// Matching requests are refused.
+    Deny,
+}
+
+/// Method and URL-path rule for HTTP requests to a domain.
+/// Applies to plain HTTP and to HTTPS decrypted by `tlsIntercept`.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct HttpRuleConfig {
+    /// Domain pattern, as in `allowedDomains` (e.g., "registry.npmjs.org").
+    pub domain: String,
+    /// "allow" (default) or "deny".
+    #[serde(default)]
+    pub action: HttpRuleAction,
+    /// Methods the rule applies to (e.g., "GET"). Empty means any method.
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// URL path patterns, where "*" matches any characters (e.g., "/repos/*/issues").
+    /// Empty means any path.
+    #[serde(default)]
+    pub paths: Vec<String>,
+}
+
+/// Header added to HTTP requests to a domain, with a value kept outside the sandbox.
+/// Exactly one of `env` and `file` must be set.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct CredentialConfig {
+    /// Domain pattern, as in `allowedDomains`, optionally with a port
+    /// (e.g., "registry.npmjs.org:443").
+    pub domain: String,
+    /// Header name (e.g., "Authorization").
+    pub header: String,
+    /// Text placed before the secret (e.g., "Bearer ").
+    #[serde(default)]
+    pub prefix: Option<String>,
+    /// Host environment variable holding the secret.
+    #[serde(default)]
+    pub env: Option<String>,
+    /// Host file holding the secret; a trailing newline is ignored.
+    #[serde(default)]
+    pub file: Option<String>,
+    /// Also add the header to plain HTTP requests, where anyone on the path
+    /// can read it (default: false, only intercepted HTTPS).
+    #[serde(default)]
+    pub allow_plain_http: Option<bool>,
+}
+
+/// Interactive decisions for destinations that match no allow rule.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct AskConfig {
+    /// Seconds to wait for an answer before denying the connection (default: 30).
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+}
+
+/// Whether a cassette is written or served from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CassetteMode {
+    /// Forward requests and write each interaction to the cassette.
+    #[default]
+    Record,
+    /// Answer requests from the cassette without touching the network.
+    Replay,
+}
+
+/// Recording and offline replay of HTTP traffic through the HTTP proxy.
+/// Covers plain HTTP and HTTPS decrypted by `tlsIntercept`.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct CassetteConfig {
+    /// Cassette file (JSON lines). Recording replaces its contents.
+    pub path: String,
+    /// "record" (default) or "replay".
+    #[serde(default)]
+    pub mode: CassetteMode,
+}
+
+/// Transparent capture of TCP traffic that ignores the proxy environment
+/// variables (Linux only). A TUN device in the sandbox's network namespace
+/// carries it to the SOCKS5 proxy, and the DNS forwarder answers with fake
+/// IPs that the proxy maps back to hostnames.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct TunCaptureConfig {
+    /// tun2socks binary run inside the sandbox (default: "tun2socks" on PATH).
+    #[serde(default)]
+    pub tun2socks_path: Option<String>,
+    /// IPv4 range fake IPs are handed out from (default: "198.18.0.0/15").
+    #[serde(default)]
+    pub fake_ip_range: Option<String>,
+}
+
+/// Tool whose proxy settings are set in a sandboxed command's environment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyEnvPreset {
+    /// `JAVA_TOOL_OPTIONS` proxy properties, for every JVM.
+    Java,
+    /// The same properties in `GRADLE_OPTS`, for the Gradle launcher.
+    Gradle,
+    /// The same properties in `MAVEN_OPTS`, honored by Maven 3.9+.
+    Maven,
+    /// `npm_config_proxy`, `npm_config_https_proxy` and `npm_config_noproxy`.
+    Npm,
+    /// `PIP_PROXY`.
+    Pip,
+    /// `CARGO_HTTP_PROXY`.
+    Cargo,
+    /// `GIT_SSH_COMMAND` tunnelling SSH through the HTTP proxy with bash, without `nc`.
+    Git,
+}
+
+/// Proxy environment variables set for sandboxed commands, besides
+/// `http_proxy`, `https_proxy` and `ALL_PROXY`.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ProxyEnvConfig {
+    /// Tools to configure (default: npm, pip, cargo and git).
+    #[serde(default)]
+    pub presets: Option<Vec<ProxyEnvPreset>>,
+    /// Hosts reached without the proxies, in addition to localhost and the
+    /// loopback addresses (NO_PROXY syntax, e.g., "internal.example.com").
+    #[serde(default)]
+    pub no_proxy: Vec<String>,
+}
+
+/// Bandwidth and connection limits for one scope (global or a domain pattern).
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct RateLimitConfig {
+    /// Maximum throughput in bytes per second, both directions combined.
+    #[serde(default)]
+    pub bytes_per_sec: Option<u64>,
+    /// Maximum number of open connections and tunnels.
+    #[serde(default)]
+    pub max_concurrent: Option<usize>,
+    /// Maximum number of new connections per minute.
+    #[serde(default)]
+    pub connections_per_minute: Option<u32>,
+}
+
+/// Limits for destinations matching a domain pattern.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct DomainRateLimitConfig {
+    /// Domain pattern, as in `allowedDomains` (e.g., "*.npmjs.org").
+    pub domain: String,
+    /// Limits shared by all connections matching the pattern.
+    #[serde(flatten)]
+    pub limits: RateLimitConfig,
+}
+
+/// Proxy rate limits.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct RateLimitsConfig {
+    /// Limits shared by all proxied connections.
+    #[serde(default)]
+    pub global: Option<RateLimitConfig>,
+    /// Per-domain limits. The first matching entry applies.
+    #[serde(default)]
+    pub domains: Vec<DomainRateLimitConfig>,
+}
+
 /// Network restriction configuration.
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 #[serde(rename_all = "camelCase")]
@@ -52,6 +286,123 @@ pub struct NetworkConfig {
     /// MITM proxy configuration.
     #[serde(default)]
     pub mitm_proxy: Option<MitmProxyConfig>,
+
+    /// IP networks allowed for IP-literal destinations (e.g., "10.0.0.0/8", "2001:db8::/32").
+    #[serde(default)]
+    pub allowed_cidrs: Vec<String>,
+
+    /// IP networks denied for IP-literal destinations. Takes precedence over allow rules.
+    #[serde(default)]
+    pub denied_cidrs: Vec<String>,
+
+    /// Refuse connections to loopback, private and link-local addresses, including
+    /// allowed hostnames that resolve to them (default: false).
+    #[serde(default)]
+    pub block_private_addresses: Option<bool>,
+
+    /// Non-public IP networks still reachable when `block_private_addresses` is set
+    /// (e.g., "10.20.0.0/16" for an internal registry).
+    #[serde(default)]
+    pub allowed_private_ranges: Vec<String>,
+
+    /// Ports allowed for any destination (e.g., "80", "443", "8000-8999").
+    /// Empty means all ports are allowed.
+    #[serde(default)]
+    pub allowed_ports: Vec<String>,
+
+    /// Close open tunnels that a config update no longer allows (default: false).
+    /// When false, updates only apply to new connections.
+    #[serde(default)]
+    pub close_revoked_connections: Option<bool>,
+
+    /// SOCKS5 UDP ASSOCIATE relay settings.
+    #[serde(default)]
+    pub socks_udp: Option<SocksUdpConfig>,
+
+    /// Upstream proxy for outbound connections. Domain rules are applied locally first.
+    #[serde(default)]
+    pub upstream_proxy: Option<UpstreamProxyConfig>,
+
+    /// File to append one JSON line per proxied connection to.
+    #[serde(default)]
+    pub audit_log_path: Option<String>,
+
+    /// Bandwidth, concurrency and connection-rate limits.
+    #[serde(default)]
+    pub rate_limits: Option<RateLimitsConfig>,
+
+    /// TLS SNI verification for CONNECT tunnels, against domain fronting.
+    #[serde(default)]
+    pub tls_sni: Option<TlsSniConfig>,
+
+    /// Built-in TLS interception. `mitmProxy` takes precedence for domains in both.
+    #[serde(default)]
+    pub tls_intercept: Option<TlsInterceptConfig>,
+
+    /// Method and URL-path rules for HTTP requests. A request is refused if a
+    /// matching "deny" rule applies, or if its domain has "allow" rules and none match.
+    #[serde(default)]
+    pub http_rules: Vec<HttpRuleConfig>,
+
+    /// Headers injected into HTTP requests, replacing any the client sent.
+    /// Applies to plain HTTP and to HTTPS decrypted by `tlsIntercept`.
+    #[serde(default)]
+    pub credentials: Vec<CredentialConfig>,
+
+    /// Ask a controller instead of denying destinations that match no allow rule.
+    /// The connection is held until it answers or the timeout expires.
+    #[serde(default)]
+    pub ask_unknown_domains: Option<AskConfig>,
+
+    /// Accept wildcard patterns whose base is a public suffix or covers one,
+    /// such as "*.github.io" or "*.amazonaws.com" (default: false).
+    #[serde(default)]
+    pub allow_public_suffix_wildcards: Option<bool>,
+
+    /// Record HTTP traffic to a cassette, or replay it offline.
+    #[serde(default)]
+    pub cassette: Option<CassetteConfig>,
+
+    /// Capture TCP traffic that bypasses the proxies (Linux only).
+    #[serde(default)]
+    pub tun_capture: Option<TunCaptureConfig>,
+
+    /// Ports listening in the sandbox to make reachable on the host's
+    /// 127.0.0.1, as "3000" or "host:sandbox" ("8080:3000").
+    /// Requires `allowLocalBinding`.
+    #[serde(default)]
+    pub expose_ports: Vec<String>,
+
+    /// Proxy variables for common tools in sandboxed commands' environment.
+    #[serde(default)]
+    pub proxy_env: Option<ProxyEnvConfig>,
+}
+
+impl NetworkConfig {
+    /// Check if any domain, IP, port or HTTP rules, or another setting only
+    /// the proxies enforce (limits, TLS checks, asking, auditing, a cassette),
+    /// are configured. Without them, network access is unrestricted.
+    pub fn has_restrictions(&self) -> bool {
+        !self.allowed_domains.is_empty()
+            || !self.denied_domains.is_empty()
+            || !self.allowed_cidrs.is_empty()
+            || !self.denied_cidrs.is_empty()
+            || !self.allowed_ports.is_empty()
+            || self.block_private_addresses.unwrap_or(false)
+            || !self.http_rules.is_empty()
+            || self.rate_limits.is_some()
+            || self.tls_sni.as_ref().and_then(|sni| sni.enabled).unwrap_or(false)
+            || self.tls_intercept.is_some()
+            || self.ask_unknown_domains.is_some()
+            || self.audit_log_path.is_some()
+            || self.cassette.is_some()
+    }
+
+    /// Host environment variables holding `credentials` secrets, which
+    /// sandboxed commands must not inherit.
+    pub fn credential_env_vars(&self) -> Vec<&str> {
+        self.credentials.iter().filter_map(|c| c.env.as_deref()).collect()
+    }
 }
 
 /// Filesystem restriction configuration.
@@ -170,22 +521,229 @@ pub const DANGEROUS_DIRECTORIES: &[&str] = &[
 ];
 
 impl SandboxRuntimeConfig {
+    /// The filesystem config to enforce: `denyRead` also covers the files
+    /// holding `credentials` secrets.
+    pub fn sandbox_filesystem(&self) -> FilesystemConfig {
+        let mut filesystem = self.filesystem.clone();
+        for file in self.network.credentials.iter().filter_map(|c| c.file.as_ref()) {
+            if !filesystem.deny_read.contains(file) {
+                filesystem.deny_read.push(file.clone());
+            }
+        }
+        filesystem
+    }
+
     /// Validate the configuration.
     pub fn validate(&self) -> Result<(), SandboxError> {
+        // Broad wildcards are only a risk where they grant access; deny rules
+        // and limits may cover a whole suffix
+        let allow_public_suffix = self.network.allow_public_suffix_wildcards.unwrap_or(false);
+        let validate_domain =
+            |pattern: &str| validate_domain_pattern(pattern, allow_public_suffix);
+        let validate_restricting_domain = |pattern: &str| validate_domain_pattern(pattern, true);
+
         // Validate allowed domains
         for domain in &self.network.allowed_domains {
-            validate_domain_pattern(domain)?;
+            validate_domain(domain)?;
         }
 
         // Validate denied domains
         for domain in &self.network.denied_domains {
-            validate_domain_pattern(domain)?;
+            validate_restricting_domain(domain)?;
         }
 
         // Validate MITM proxy domains
         if let Some(ref mitm) = self.network.mitm_proxy {
             for domain in &mitm.domains {
-                validate_domain_pattern(domain)?;
+                validate_domain(domain)?;
+            }
+        }
+
+        // Validate TLS interception domains
+        if let Some(ref intercept) = self.network.tls_intercept {
+            for domain in &intercept.domains {
+                validate_domain(domain)?;
+            }
+        }
+
+        // Validate HTTP rules
+        for rule in &self.network.http_rules {
+            match rule.action {
+                HttpRuleAction::Allow => validate_domain(&rule.domain)?,
+                HttpRuleAction::Deny => validate_restricting_domain(&rule.domain)?,
+            }
+            if let Some(method) = rule
+                .methods
+                .iter()
+                .find(|m| m.is_empty() || !m.chars().all(|c| c.is_ascii_alphabetic()))
+            {
+                return Err(ConfigError::ValidationError(format!(
+                    "invalid method '{}' in httpRules",
+                    method
+                ))
+                .into());
+            }
+            if let Some(path) = rule.paths.iter().find(|p| !p.starts_with('/')) {
+                return Err(ConfigError::ValidationError(format!(
+                    "invalid path pattern '{}' in httpRules (must start with '/')",
+                    path
+                ))
+                .into());
+            }
+        }
+
+        // Validate credentials
+        for credential in &self.network.credentials {
+            validate_domain(&credential.domain)?;
+            if hyper::header::HeaderName::from_bytes(credential.header.as_bytes()).is_err() {
+                return Err(ConfigError::ValidationError(format!(
+                    "invalid header name '{}' in credentials",
+                    credential.header
+                ))
+                .into());
+            }
+            if credential.env.is_some() == credential.file.is_some() {
+                return Err(ConfigError::ValidationError(format!(
+                    "credentials for '{}' must set exactly one of 'env' and 'file'",
+                    credential.domain
+                ))
+                .into());
+            }
+        }
+
+        // Validate ask timeout
+        if let Some(ref ask) = self.network.ask_unknown_domains {
+            if ask.timeout_secs == Some(0) {
+                return Err(ConfigError::ValidationError(
+                    "askUnknownDomains.timeoutSecs must be greater than 0".to_string(),
+                )
+                .into());
+            }
+        }
+
+        // Validate cassette
+        if let Some(ref cassette) = self.network.cassette {
+            if cassette.path.trim().is_empty() {
+                return Err(ConfigError::ValidationError(
+                    "cassette.path cannot be empty".to_string(),
+                )
+                .into());
+            }
+        }
+
+        // Validate the fake IP range: IPv4, with room for a useful pool
+        if let Some(range) = self
+            .network
+            .tun_capture
+            .as_ref()
+            .and_then(|tun| tun.fake_ip_range.as_deref())
+        {
+            let valid = IpCidr::parse(range)
+                .is_some_and(|cidr| cidr.addr().is_ipv4() && cidr.prefix_len() <= 24);
+            if !valid {
+                return Err(ConfigError::ValidationError(format!(
+                    "invalid tunCapture.fakeIpRange '{}' (must be an IPv4 CIDR of /24 or larger)",
+                    range
+                ))
+                .into());
+            }
+        }
+
+        // Validate exposed ports
+        let mut host_ports = std::collections::HashSet::new();
+        for spec in &self.network.expose_ports {
+            let Some(port) = ExposedPort::parse(spec) else {
+                return Err(ConfigError::ValidationError(format!(
+                    "invalid port '{}' in exposePorts (expected \"3000\" or \"8080:3000\")",
+                    spec
+                ))
+                .into());
+            };
+            if !host_ports.insert(port.host_port) {
+                return Err(ConfigError::ValidationError(format!(
+                    "host port {} is exposed more than once in exposePorts",
+                    port.host_port
+                ))
+                .into());
+            }
+        }
+        if !host_ports.is_empty() && !self.network.allow_local_binding.unwrap_or(false) {
+            return Err(ConfigError::ValidationError(
+                "exposePorts requires allowLocalBinding".to_string(),
+            )
+            .into());
+        }
+
+        // Validate proxy exclusions, which are joined into comma, pipe and space separated lists
+        if let Some(ref proxy_env) = self.network.proxy_env {
+            let is_separator = |c: char| c == ',' || c == '|' || c.is_whitespace();
+            if let Some(host) =
+                proxy_env.no_proxy.iter().find(|host| host.is_empty() || host.contains(is_separator))
+            {
+                return Err(ConfigError::ValidationError(format!(
+                    "invalid host '{}' in proxyEnv.noProxy",
+                    host
+                ))
+                .into());
+            }
+        }
+
+        // Validate CIDR rules
+        for (field, cidrs) in [
+            ("allowedCidrs", &self.network.allowed_cidrs),
+            ("deniedCidrs", &self.network.denied_cidrs),
+            ("allowedPrivateRanges", &self.network.allowed_private_ranges),
+        ] {
+            for cidr in cidrs {
+                if IpCidr::parse(cidr).is_none() {
+                    return Err(ConfigError::ValidationError(format!(
+                        "invalid CIDR '{}' in {}",
+                        cidr, field
+                    ))
+                    .into());
+                }
+            }
+        }
+
+        // Validate upstream proxy
+        if let Some(ref upstream) = self.network.upstream_proxy {
+            crate::proxy::UpstreamProxy::from_config(upstream)?;
+        }
+
+        // Validate rate limits
+        if let Some(ref limits) = self.network.rate_limits {
+            for domain in &limits.domains {
+                validate_restricting_domain(&domain.domain)?;
+            }
+            let scopes = limits.global.iter().chain(limits.domains.iter().map(|d| &d.limits));
+            for scope in scopes {
+                if scope.bytes_per_sec == Some(0)
+                    || scope.max_concurrent == Some(0)
+                    || scope.connections_per_minute == Some(0)
+                {
+                    return Err(ConfigError::ValidationError(
+                        "rate limits must be greater than zero".to_string(),
+                    )
+                    .into());
+                }
+            }
+        }
+
+        // Validate allowed ports
+        let non_tls_ports = self.network.tls_sni.iter().flat_map(|sni| &sni.non_tls_ports);
+        let port_specs = self
+            .network
+            .allowed_ports
+            .iter()
+            .map(|spec| ("allowedPorts", spec))
+            .chain(non_tls_ports.map(|spec| ("tlsSni.nonTlsPorts", spec)));
+        for (field, spec) in port_specs {
+            if PortRange::parse(spec).is_none() {
+                return Err(ConfigError::ValidationError(format!(
+                    "invalid port or port range '{}' in {}",
+                    spec, field
+                ))
+                .into());
             }
         }
 
@@ -193,10 +751,75 @@ impl SandboxRuntimeConfig {
     }
 }
 
+/// An inclusive range of TCP ports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PortRange {
+    /// Parse a single port ("443") or an inclusive range ("8080-8090").
+    pub fn parse(spec: &str) -> Option<Self> {
+        let (start, end) = match spec.split_once('-') {
+            Some((start, end)) => (start.parse().ok()?, end.parse().ok()?),
+            None => {
+                let port = spec.parse().ok()?;
+                (port, port)
+            }
+        };
+
+        if start == 0 || start > end {
+            return None;
+        }
+
+        Some(Self { start, end })
+    }
+
+    /// Check if a port is within the range.
+    pub fn contains(&self, port: u16) -> bool {
+        (self.start..=self.end).contains(&port)
+    }
+}
+
+/// A port listening in the sandbox, exposed on a host port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExposedPort {
+    pub host_port: u16,
+    pub sandbox_port: u16,
+}
+
+impl ExposedPort {
+    /// Parse "3000" (the same port on both sides) or "8080:3000" (host:sandbox).
+    pub fn parse(spec: &str) -> Option<Self> {
+        let (host, sandbox) = spec.split_once(':').unwrap_or((spec, spec));
+        let (host_port, sandbox_port) = (host.parse().ok()?, sandbox.parse().ok()?);
+        if host_port == 0 || sandbox_port == 0 {
+            return None;
+        }
+
+        Some(Self { host_port, sandbox_port })
+    }
+}
+
+/// Split a domain pattern into its host part and optional port suffix.
+/// "github.com:443" -> ("github.com", Some("443")).
+pub fn split_domain_pattern(pattern: &str) -> (&str, Option<&str>) {
+    match pattern.rsplit_once(':') {
+        Some((host, port)) => (host, Some(port)),
+        None => (pattern, None),
+    }
+}
+
 /// Validate a domain pattern.
-fn validate_domain_pattern(pattern: &str) -> Result<(), SandboxError> {
+fn validate_domain_pattern(
+    pattern: &str,
+    allow_public_suffix: bool,
+) -> Result<(), SandboxError> {
+    let (host, port) = split_domain_pattern(pattern);
+
     // Check for empty pattern
-    if pattern.is_empty() {
+    if host.is_empty() {
         return Err(ConfigError::InvalidDomainPattern {
             pattern: pattern.to_string(),
             reason: "domain pattern cannot be empty".to_string(),
@@ -205,7 +828,7 @@ fn validate_domain_pattern(pattern: &str) -> Result<(), SandboxError> {
     }
 
     // Check for just wildcard
-    if pattern == "*" {
+    if host == "*" {
         return Err(ConfigError::InvalidDomainPattern {
             pattern: pattern.to_string(),
             reason: "wildcard-only patterns are not allowed".to_string(),
@@ -213,36 +836,47 @@ fn validate_domain_pattern(pattern: &str) -> Result<(), SandboxError> {
         .into());
     }
 
-    // Check for too broad patterns like *.com
-    if pattern.starts_with("*.") {
-        let suffix = &pattern[2..];
-        // Check if suffix is a TLD or too short
-        if !suffix.contains('.') && suffix.len() <= 4 {
+    // Compare internationalized names in their ASCII form
+    let (wildcard, name) = match host.strip_prefix("*.") {
+        Some(base) => (true, base),
+        None => (false, host),
+    };
+    let name = idna::domain_to_ascii(name).map_err(|_| ConfigError::InvalidDomainPattern {
+        pattern: pattern.to_string(),
+        reason: "invalid internationalized domain name".to_string(),
+    })?;
+
+    // Check for too broad patterns like *.com, *.co.uk or *.github.io
+    if wildcard && !allow_public_suffix {
+        let reason = if is_public_suffix(&name) {
+            Some(format!("pattern is too broad ('{}' is a public suffix)", name))
+        } else if has_public_suffix_below(&name) {
+            Some(format!("pattern is too broad (covers public suffixes under '{}')", name))
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
             return Err(ConfigError::InvalidDomainPattern {
                 pattern: pattern.to_string(),
-                reason: "pattern is too broad (matches entire TLD)".to_string(),
+                reason: format!("{}; set allowPublicSuffixWildcards to accept it", reason),
             }
             .into());
         }
     }
 
-    // Check for port numbers
-    if pattern.contains(':') {
-        return Err(ConfigError::InvalidDomainPattern {
-            pattern: pattern.to_string(),
-            reason: "domain patterns cannot include port numbers".to_string(),
+    // Check the optional port suffix
+    if let Some(port) = port {
+        if PortRange::parse(port).is_none() {
+            return Err(ConfigError::InvalidDomainPattern {
+                pattern: pattern.to_string(),
+                reason: format!("invalid port or port range '{}'", port),
+            }
+            .into());
         }
-        .into());
     }
 
     // Check for invalid characters
-    let check_part = if pattern.starts_with("*.") {
-        &pattern[2..]
-    } else {
-        pattern
-    };
-
-    for ch in check_part.chars() {
+    for ch in name.chars() {
         if !ch.is_ascii_alphanumeric() && ch != '.' && ch != '-' && ch != '_' {
             return Err(ConfigError::InvalidDomainPattern {
                 pattern: pattern.to_string(),
@@ -255,21 +889,43 @@ fn validate_domain_pattern(pattern: &str) -> Result<(), SandboxError> {
     Ok(())
 }
 
+/// Normalize a domain name for comparison: lowercase, with Unicode labels in
+/// their ASCII (punycode) form, so "bücher.de" and "xn--bcher-kva.de" agree.
+/// Names that aren't valid IDNs are only lowercased.
+pub fn normalize_domain(domain: &str) -> String {
+    if domain.is_ascii() {
+        return domain.to_ascii_lowercase();
+    }
+    idna::domain_to_ascii(domain).unwrap_or_else(|_| domain.to_lowercase())
+}
+
 /// Check if a hostname matches a domain pattern.
+/// Unicode and ASCII forms of a name match each other.
 pub fn matches_domain_pattern(hostname: &str, pattern: &str) -> bool {
-    let hostname_lower = hostname.to_lowercase();
-    let pattern_lower = pattern.to_lowercase();
+    let hostname = normalize_domain(hostname);
 
-    if pattern_lower.starts_with("*.") {
+    if let Some(base_domain) = pattern.strip_prefix("*.") {
         // Wildcard pattern: *.example.com matches api.example.com but NOT example.com
-        let base_domain = &pattern_lower[2..];
-        hostname_lower.ends_with(&format!(".{}", base_domain))
+        hostname.ends_with(&format!(".{}", normalize_domain(base_domain)))
     } else {
         // Exact match
-        hostname_lower == pattern_lower
+        hostname == normalize_domain(pattern)
     }
 }
 
+/// Check if a hostname and port match a domain pattern.
+/// Patterns may carry a port suffix ("github.com:443", "*.corp:8080-8090");
+/// without one, any port matches.
+pub fn matches_domain_port_pattern(hostname: &str, port: u16, pattern: &str) -> bool {
+    let (host_pattern, port_spec) = split_domain_pattern(pattern);
+    if let Some(spec) = port_spec {
+        if !PortRange::parse(spec).is_some_and(|range| range.contains(port)) {
+            return false;
+        }
+    }
+    matches_domain_pattern(hostname, host_pattern)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -288,20 +944,186 @@ mod tests {
 
         // Case insensitivity
         assert!(matches_domain_pattern("API.EXAMPLE.COM", "*.example.com"));
+
+        // Unicode and punycode forms
+        assert!(matches_domain_pattern("bücher.de", "xn--bcher-kva.de"));
+        assert!(matches_domain_pattern("XN--BCHER-KVA.de", "Bücher.de"));
+        assert!(matches_domain_pattern("shop.xn--bcher-kva.de", "*.bücher.de"));
+        assert!(!matches_domain_pattern("bucher.de", "bücher.de"));
+    }
+
+    #[test]
+    fn test_network_has_restrictions() {
+        assert!(!NetworkConfig::default().has_restrictions());
+        assert!(!NetworkConfig {
+            tls_sni: Some(TlsSniConfig::default()),
+            ..Default::default()
+        }
+        .has_restrictions());
+
+        let restricted = [
+            NetworkConfig {
+                block_private_addresses: Some(true),
+                ..Default::default()
+            },
+            NetworkConfig {
+                http_rules: vec![HttpRuleConfig::default()],
+                ..Default::default()
+            },
+            NetworkConfig {
+                rate_limits: Some(RateLimitsConfig::default()),
+                ..Default::default()
+            },
+            NetworkConfig {
+                tls_sni: Some(TlsSniConfig {
+                    enabled: Some(true),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            NetworkConfig {
+                tls_intercept: Some(TlsInterceptConfig::default()),
+                ..Default::default()
+            },
+            NetworkConfig {
+                ask_unknown_domains: Some(AskConfig::default()),
+                ..Default::default()
+            },
+            NetworkConfig {
+                audit_log_path: Some("/tmp/audit.jsonl".to_string()),
+                ..Default::default()
+            },
+        ];
+        for config in restricted {
+            assert!(config.has_restrictions(), "{:?}", config);
+        }
     }
 
     #[test]
     fn test_domain_pattern_validation() {
         // Valid patterns
-        assert!(validate_domain_pattern("example.com").is_ok());
-        assert!(validate_domain_pattern("*.example.com").is_ok());
-        assert!(validate_domain_pattern("localhost").is_ok());
-        assert!(validate_domain_pattern("api.github.com").is_ok());
+        assert!(validate_domain_pattern("example.com", false).is_ok());
+        assert!(validate_domain_pattern("*.example.com", false).is_ok());
+        assert!(validate_domain_pattern("localhost", false).is_ok());
+        assert!(validate_domain_pattern("api.github.com", false).is_ok());
 
         // Invalid patterns
-        assert!(validate_domain_pattern("").is_err());
-        assert!(validate_domain_pattern("*").is_err());
-        assert!(validate_domain_pattern("*.com").is_err());
-        assert!(validate_domain_pattern("example.com:8080").is_err());
+        assert!(validate_domain_pattern("", false).is_err());
+        assert!(validate_domain_pattern("*", false).is_err());
+        assert!(validate_domain_pattern("*.com", false).is_err());
+        assert!(validate_domain_pattern("*.com:443", false).is_err());
+        assert!(validate_domain_pattern("*:443", false).is_err());
+        assert!(validate_domain_pattern(":443", false).is_err());
+        assert!(validate_domain_pattern("example.com:", false).is_err());
+        assert!(validate_domain_pattern("example.com:0", false).is_err());
+        assert!(validate_domain_pattern("example.com:https", false).is_err());
+        assert!(validate_domain_pattern("example.com:9000-8000", false).is_err());
+        assert!(validate_domain_pattern("example.com:70000", false).is_err());
+
+        // Port-qualified patterns
+        assert!(validate_domain_pattern("example.com:8080", false).is_ok());
+        assert!(validate_domain_pattern("*.internal.corp:8080-8090", false).is_ok());
+    }
+
+    #[test]
+    fn test_public_suffix_wildcards() {
+        for pattern in ["*.co.uk", "*.github.io", "*.amazonaws.com", "*.corp", "*.公司.cn"] {
+            assert!(validate_domain_pattern(pattern, false).is_err(), "{}", pattern);
+            assert!(validate_domain_pattern(pattern, true).is_ok(), "{}", pattern);
+        }
+        assert!(validate_domain_pattern("*.example.co.uk", false).is_ok());
+        assert!(validate_domain_pattern("*.myorg.github.io:443", false).is_ok());
+        assert!(validate_domain_pattern("github.io", false).is_ok());
+
+        // Internationalized names are checked in their ASCII form
+        assert!(validate_domain_pattern("*.bücher.de", false).is_ok());
+        assert!(validate_domain_pattern("my_host.example.com", false).is_ok());
+        assert!(validate_domain_pattern("bad host.example.com", false).is_err());
+    }
+
+    #[test]
+    fn test_public_suffix_wildcards_only_limit_allow_lists() {
+        let mut config = SandboxRuntimeConfig::default();
+        config.network.denied_domains = vec!["*.githubusercontent.com".to_string()];
+        config.network.http_rules = vec![HttpRuleConfig {
+            domain: "*.cloudfront.net".to_string(),
+            action: HttpRuleAction::Deny,
+            ..Default::default()
+        }];
+        config.network.rate_limits = Some(RateLimitsConfig {
+            domains: vec![DomainRateLimitConfig {
+                domain: "*.amazonaws.com".to_string(),
+                limits: RateLimitConfig {
+                    max_concurrent: Some(4),
+                    ..Default::default()
+                },
+            }],
+            ..Default::default()
+        });
+        assert!(config.validate().is_ok());
+
+        config.network.allowed_domains = vec!["*.githubusercontent.com".to_string()];
+        assert!(config.validate().is_err());
+        config.network.allow_public_suffix_wildcards = Some(true);
+        assert!(config.validate().is_ok());
+
+        config.network.allow_public_suffix_wildcards = None;
+        config.network.allowed_domains.clear();
+        config.network.http_rules[0].action = HttpRuleAction::Allow;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_domain_port_pattern_matching() {
+        assert!(matches_domain_port_pattern("github.com", 443, "github.com:443"));
+        assert!(!matches_domain_port_pattern("github.com", 22, "github.com:443"));
+        assert!(matches_domain_port_pattern("github.com", 22, "github.com"));
+
+        assert!(matches_domain_port_pattern("api.internal.corp", 8085, "*.internal.corp:8080-8090"));
+        assert!(!matches_domain_port_pattern("api.internal.corp", 8091, "*.internal.corp:8080-8090"));
+        assert!(!matches_domain_port_pattern("internal.corp", 8085, "*.internal.corp:8080-8090"));
+    }
+
+    #[test]
+    fn test_port_range_parse() {
+        assert_eq!(PortRange::parse("443"), Some(PortRange { start: 443, end: 443 }));
+        assert_eq!(PortRange::parse("8000-8999"), Some(PortRange { start: 8000, end: 8999 }));
+        assert_eq!(PortRange::parse("0"), None);
+        assert_eq!(PortRange::parse("443-80"), None);
+        assert_eq!(PortRange::parse("-443"), None);
+        assert_eq!(PortRange::parse(" 443"), None);
+    }
+
+    #[test]
+    fn test_exposed_port_parse() {
+        let port = |host_port, sandbox_port| Some(ExposedPort { host_port, sandbox_port });
+        assert_eq!(ExposedPort::parse("3000"), port(3000, 3000));
+        assert_eq!(ExposedPort::parse("8080:3000"), port(8080, 3000));
+        assert_eq!(ExposedPort::parse("0"), None);
+        assert_eq!(ExposedPort::parse("8080:"), None);
+        assert_eq!(ExposedPort::parse("3000-3001"), None);
+    }
+
+    #[test]
+    fn test_credential_secrets_kept_from_sandbox() {
+        let credential = |env: Option<&str>, file: Option<&str>| CredentialConfig {
+            domain: "api.github.com".to_string(),
+            header: "Authorization".to_string(),
+            env: env.map(str::to_string),
+            file: file.map(str::to_string),
+            ..Default::default()
+        };
+        let mut config = SandboxRuntimeConfig::default();
+        config.filesystem.deny_read = vec!["~/.ssh".to_string()];
+        config.network.credentials = vec![
+            credential(Some("GITHUB_TOKEN"), None),
+            credential(None, Some("~/.config/npm-token")),
+        ];
+
+        assert_eq!(config.network.credential_env_vars(), ["GITHUB_TOKEN"]);
+        assert_eq!(
+            config.sandbox_filesystem().deny_read,
+            ["~/.ssh", "~/.config/npm-token"]
+        );
     }
 }
@@ -1,15 +1,17 @@
 //! CLI entry point for the sandbox runtime (srt).
 
+use std::io::Write;
 use std::os::unix::io::FromRawFd;
 use std::process::ExitCode;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::sync::oneshot;
 
-use sandbox_runtime::cli::Cli;
+use sandbox_runtime::cli::{Cli, Helper};
 use sandbox_runtime::config::{load_config, load_config_from_string, load_default_config};
 use sandbox_runtime::manager::SandboxManager;
+use sandbox_runtime::proxy::AskResponse;
 use sandbox_runtime::utils::init_debug_logging;
 
 #[tokio::main]
@@ -19,8 +21,19 @@ async fn main() -> ExitCode {
     // Initialize logging
     init_debug_logging(cli.debug);
 
+    // Run as the bridge helper inside the sandbox
+    if let Some(Helper::Bridge {
+        bridges,
+        deny_listen,
+        tun2socks,
+        command,
+    }) = &cli.helper
+    {
+        return run_bridge_helper(bridges, tun2socks.clone(), *deny_listen, command).await;
+    }
+
     // Load configuration
-    let config = match cli.get_settings_path() {
+    let mut config = match cli.get_settings_path() {
         Some(path) if path.exists() => match load_config(&path) {
             Ok(config) => config,
             Err(e) => {
@@ -37,6 +50,11 @@ async fn main() -> ExitCode {
         },
     };
 
+    // --record and --replay override the settings file
+    if let Some(cassette) = cli.get_cassette() {
+        config.network.cassette = Some(cassette);
+    }
+
     // Get command to execute
     let (command, _shell_mode) = match cli.get_command() {
         Some(cmd) => cmd,
@@ -46,6 +64,14 @@ async fn main() -> ExitCode {
         }
     };
 
+    // Secrets the proxy injects are read now and kept from the command
+    let credential_env_vars: Vec<String> = config
+        .network
+        .credential_env_vars()
+        .into_iter()
+        .map(str::to_string)
+        .collect();
+
     // Initialize sandbox manager
     let manager = Arc::new(SandboxManager::new());
     if let Err(e) = manager.initialize(config).await {
@@ -53,6 +79,48 @@ async fn main() -> ExitCode {
         return ExitCode::from(1);
     }
 
+    // The command trusts the interception CA through its environment; say where it is
+    if let Some(path) = manager.get_ca_cert_path() {
+        eprintln!("TLS interception CA certificate: {}", path.display());
+    }
+
+    // Send ask requests to the ask fd if specified
+    if let Some(fd) = cli.ask_fd {
+        if fd < 0 {
+            eprintln!("Invalid ask fd: {} (must be non-negative)", fd);
+            manager.reset().await;
+            return ExitCode::from(1);
+        }
+        if cli.control_fd.is_none() {
+            tracing::warn!("--ask-fd without --control-fd: ask requests can't be answered");
+        }
+
+        // A bidirectional control fd may be used for both; the reader owns the original
+        let fd = if cli.control_fd == Some(fd) {
+            match nix::unistd::dup(fd) {
+                Ok(fd) => fd,
+                Err(e) => {
+                    eprintln!("Failed to duplicate ask fd {}: {}", fd, e);
+                    manager.reset().await;
+                    return ExitCode::from(1);
+                }
+            }
+        } else {
+            fd
+        };
+        // Safety: as for the control fd below, the parent passes an open, writable fd.
+        let file = Mutex::new(unsafe { std::fs::File::from_raw_fd(fd) });
+        manager.get_ask_broker().subscribe(Box::new(move |request| {
+            let Ok(line) = serde_json::to_string(request) else {
+                return;
+            };
+            let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+            if let Err(e) = writeln!(file, "{}", line).and_then(|_| file.flush()) {
+                tracing::warn!("Failed to write ask request: {}", e);
+            }
+        }));
+    }
+
     // Set up control fd for dynamic config updates if specified
     // Shutdown channel for graceful termination of the control fd reader task
     let control_fd_shutdown: Option<oneshot::Sender<()>> = if let Some(fd) = cli.control_fd {
@@ -87,7 +155,14 @@ async fn main() -> ExitCode {
                     result = lines.next_line() => {
                         match result {
                             Ok(Some(line)) => {
-                                if let Some(new_config) = load_config_from_string(&line) {
+                                if let Ok(response) = serde_json::from_str::<AskResponse>(&line) {
+                                    if !manager_clone.get_ask_broker().respond(&response) {
+                                        tracing::debug!(
+                                            "Ignoring answer to unknown or expired ask request {}",
+                                            response.id
+                                        );
+                                    }
+                                } else if let Some(new_config) = load_config_from_string(&line) {
                                     tracing::debug!("Config updated from control fd: {:?}", new_config);
                                     if let Err(e) = manager_clone.update_config(new_config) {
                                         tracing::warn!("Failed to apply config update: {}", e);
@@ -129,11 +204,15 @@ async fn main() -> ExitCode {
     tracing::debug!("Wrapped command: {}", wrapped_command);
 
     // Execute the wrapped command
-    let status = tokio::process::Command::new("sh")
-        .arg("-c")
-        .arg(&wrapped_command)
-        .status()
-        .await;
+    // The proxy variables carry the session credentials, so they go in the
+    // environment rather than on the command line
+    let mut child = tokio::process::Command::new("sh");
+    child.arg("-c").arg(&wrapped_command);
+    child.envs(manager.get_sandbox_env(None));
+    for name in &credential_env_vars {
+        child.env_remove(name);
+    }
+    let status = child.status().await;
 
     // Cleanup: signal control fd reader to stop and reset sandbox manager
     if let Some(shutdown_tx) = control_fd_shutdown {
@@ -157,3 +236,47 @@ async fn main() -> ExitCode {
         }
     }
 }
+
+/// Run the sandbox side of the network bridges and TUN capture (with the
+/// tun2socks binary), then the command.
+#[cfg(target_os = "linux")]
+async fn run_bridge_helper(
+    bridges: &[String],
+    tun2socks: Option<String>,
+    deny_listen: bool,
+    command: &[String],
+) -> ExitCode {
+    use sandbox_runtime::sandbox::linux::{run_bridge_helper, BridgeSpec, TunSpec};
+
+    let mut specs = Vec::with_capacity(bridges.len());
+    for bridge in bridges {
+        match BridgeSpec::parse(bridge) {
+            Some(spec) => specs.push(spec),
+            None => {
+                eprintln!("Invalid bridge: {}", bridge);
+                return ExitCode::from(1);
+            }
+        }
+    }
+
+    let tun = tun2socks.map(|tun2socks| TunSpec { tun2socks });
+    match run_bridge_helper(&specs, tun.as_ref(), deny_listen, command).await {
+        Ok(code) => ExitCode::from(code as u8),
+        Err(e) => {
+            eprintln!("Failed to start sandbox bridges: {}", e);
+            ExitCode::from(1)
+        }
+    }
+}
+
+/// The bridge helper only exists on Linux.
+#[cfg(not(target_os = "linux"))]
+async fn run_bridge_helper(
+    _bridges: &[String],
+    _tun2socks: Option<String>,
+    _deny_listen: bool,
+    _command: &[String],
+) -> ExitCode {
+    eprintln!("The bridge helper is only available on Linux");
+    ExitCode::from(1)
+}
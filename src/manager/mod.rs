@@ -4,12 +4,14 @@ pub mod filesystem;
 pub mod network;
 pub mod state;
 
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use parking_lot::RwLock;
 
 use crate::config::SandboxRuntimeConfig;
-use crate::error::SandboxError;
+use crate::error::{ConfigError, SandboxError};
+use crate::proxy::{AskBroker, AuditLog, FakeIpPool, NetworkPolicy, ProxyAuth};
 use crate::utils::{current_platform, check_ripgrep, Platform};
 use crate::violation::SandboxViolationStore;
 
@@ -71,12 +73,161 @@ impl SandboxManager {
         let platform = current_platform()
             .ok_or_else(|| SandboxError::UnsupportedPlatform("Unsupported platform".to_string()))?;
 
+        // TUN capture (Linux only) maps fake IPs from the DNS forwarder back to names
+        let fake_ips = match config.network.tun_capture {
+            Some(ref tun) if platform == Platform::Linux => {
+                #[cfg(target_os = "linux")]
+                crate::sandbox::linux::check_tun_capture(tun)?;
+                Some(Arc::new(FakeIpPool::from_config(tun)?))
+            }
+            Some(_) => {
+                tracing::warn!("tunCapture is only supported on Linux and will be ignored");
+                None
+            }
+            None => None,
+        };
+
+        // Without a network namespace, sandboxed servers listen on the host's ports directly
+        if platform != Platform::Linux {
+            let remapped = config.network.expose_ports.iter().find(|spec| {
+                crate::config::ExposedPort::parse(spec)
+                    .is_some_and(|port| port.host_port != port.sandbox_port)
+            });
+            if let Some(spec) = remapped {
+                return Err(ConfigError::ValidationError(format!(
+                    "exposePorts can't remap ports on {} ('{}')",
+                    platform.name(),
+                    spec
+                ))
+                .into());
+            }
+        }
+
         // Initialize proxies
-        let (http_proxy, socks_proxy) =
-            network::initialize_proxies(&config.network).await?;
+        let audit_log = self.get_audit_log();
+        let ask_broker = self.get_ask_broker();
+        ask_broker.configure(config.network.ask_unknown_domains.as_ref());
+        let policy = self.state.read().network_policy.clone();
+        let violations = self.get_violation_store();
+        let proxy_auth = Arc::new(ProxyAuth::generate(Some(violations.clone())));
+        let (http_proxy, socks_proxy) = network::initialize_proxies(
+            &config.network,
+            &audit_log,
+            &ask_broker,
+            policy.clone(),
+            violations.clone(),
+            fake_ips.clone(),
+            Some(proxy_auth.clone()),
+        )
+        .await?;
+        let dns_forwarder =
+            network::initialize_dns(http_proxy.filter(), policy, violations, fake_ips).await?;
+        if platform == Platform::MacOS && config.network.has_restrictions() {
+            tracing::warn!(
+                "DNS filtering is not supported on macOS: name servers on ports 53 and 853 stay \
+                 reachable, only queries sent to 127.0.0.1:{} are filtered",
+                dns_forwarder.port()
+            );
+        }
 
         let http_port = http_proxy.port();
         let socks_port = socks_proxy.port();
+        let dns_port = dns_forwarder.port();
+        #[cfg(target_os = "linux")]
+        let socks_udp_port = socks_proxy.udp_port();
+
+        // Initialize platform-specific infrastructure (before taking the state lock,
+        // since bridge setup awaits)
+        #[cfg(target_os = "linux")]
+        let (
+            http_socket_path,
+            socks_socket_path,
+            socks_udp_socket_path,
+            dns,
+            expose_dir,
+            runtime_dir,
+            bridges,
+        ) = {
+            use crate::config::ExposedPort;
+            use crate::sandbox::linux::{
+                expose_socket_path, reap_stale_sessions, write_resolv_conf, HostBridge, RuntimeDir,
+            };
+            use crate::sandbox::linux::bridge::{DNS_BRIDGE_TIMEOUT_SECS, UDP_BRIDGE_TIMEOUT_SECS};
+            use std::time::Duration;
+
+            // Clean up after sessions that didn't get to reset
+            let reaped = reap_stale_sessions();
+            if reaped > 0 {
+                tracing::info!("Removed {} stale sandbox runtime entries", reaped);
+            }
+
+            // Keep the bridge sockets in a private directory for this session
+            let runtime_dir = RuntimeDir::create()?;
+
+            // Create Unix socket bridges for proxies
+            let http_socket_path = runtime_dir.socket_path("http");
+            let socks_socket_path = runtime_dir.socket_path("socks");
+
+            let http_bridge =
+                HostBridge::unix_to_tcp(http_socket_path.clone(), "127.0.0.1", http_port)?;
+            let socks_bridge =
+                HostBridge::unix_to_tcp(socks_socket_path.clone(), "127.0.0.1", socks_port)?;
+            let mut bridges = vec![http_bridge, socks_bridge];
+
+            // UDP relay bridge, when UDP ASSOCIATE is enabled
+            let socks_udp_socket_path = match socks_udp_port {
+                Some(port) => {
+                    let path = runtime_dir.socket_path("socks-udp");
+                    let timeout = Duration::from_secs(UDP_BRIDGE_TIMEOUT_SECS);
+                    let bridge = HostBridge::unix_to_udp(path.clone(), "127.0.0.1", port, timeout)?;
+                    bridges.push(bridge);
+                    Some(path.display().to_string())
+                }
+                None => None,
+            };
+
+            // DNS forwarder bridge, answering on port 53 inside the sandbox
+            let dns_socket_path = runtime_dir.socket_path("dns");
+            bridges.push(HostBridge::unix_to_udp(
+                dns_socket_path.clone(),
+                "127.0.0.1",
+                dns_port,
+                Duration::from_secs(DNS_BRIDGE_TIMEOUT_SECS),
+            )?);
+            let resolv_conf_path = runtime_dir.path().join("resolv.conf");
+            write_resolv_conf(&resolv_conf_path)?;
+            let dns = (dns_socket_path, resolv_conf_path);
+
+            // Reverse bridges from host ports to ports listening in the sandbox
+            let exposed: Vec<_> =
+                config.network.expose_ports.iter().filter_map(|s| ExposedPort::parse(s)).collect();
+            let expose_dir = if exposed.is_empty() {
+                None
+            } else {
+                // The sandbox creates these sockets, so it gets this directory writable
+                let dir = runtime_dir.create_subdir("expose")?;
+                for port in &exposed {
+                    let socket_path = expose_socket_path(&dir, port.host_port);
+                    bridges.push(HostBridge::tcp_to_unix(port.host_port, socket_path).await?);
+                    tracing::info!(
+                        "Exposing sandbox port {} on 127.0.0.1:{}",
+                        port.sandbox_port,
+                        port.host_port
+                    );
+                }
+                Some(dir.display().to_string())
+            };
+
+            (
+                http_socket_path,
+                socks_socket_path,
+                socks_udp_socket_path,
+                dns,
+                expose_dir,
+                runtime_dir,
+                bridges,
+            )
+        };
 
         // Update state
         let mut state = self.state.write();
@@ -84,26 +235,20 @@ impl SandboxManager {
         state.socks_proxy = Some(socks_proxy);
         state.http_proxy_port = Some(http_port);
         state.socks_proxy_port = Some(socks_port);
+        state.proxy_auth = Some(proxy_auth);
+        state.dns_forwarder = Some(dns_forwarder);
+        state.dns_port = Some(dns_port);
 
-        // Initialize platform-specific infrastructure
         #[cfg(target_os = "linux")]
         {
-            use crate::sandbox::linux::{generate_socket_path, SocatBridge};
-
-            // Create Unix socket bridges for proxies
-            let http_socket_path = generate_socket_path("srt-http");
-            let socks_socket_path = generate_socket_path("srt-socks");
-
-            let http_bridge =
-                SocatBridge::unix_to_tcp(http_socket_path.clone(), "localhost", http_port).await?;
-            let socks_bridge =
-                SocatBridge::unix_to_tcp(socks_socket_path.clone(), "localhost", socks_port)
-                    .await?;
-
             state.http_socket_path = Some(http_socket_path.display().to_string());
             state.socks_socket_path = Some(socks_socket_path.display().to_string());
-            state.bridges.push(http_bridge);
-            state.bridges.push(socks_bridge);
+            state.socks_udp_socket_path = socks_udp_socket_path;
+            state.dns_socket_path = Some(dns.0.display().to_string());
+            state.resolv_conf_path = Some(dns.1.display().to_string());
+            state.expose_dir = expose_dir;
+            state.bridges.extend(bridges);
+            state.runtime_dir = Some(runtime_dir);
         }
 
         state.config = Some(config);
@@ -111,10 +256,11 @@ impl SandboxManager {
         state.network_ready = true;
 
         tracing::info!(
-            "Sandbox manager initialized for {} (HTTP proxy: {}, SOCKS proxy: {})",
+            "Sandbox manager initialized for {} (HTTP proxy: {}, SOCKS proxy: {}, DNS: {})",
             platform.name(),
             http_port,
-            socks_port
+            socks_port,
+            dns_port
         );
 
         Ok(())
@@ -133,7 +279,16 @@ impl SandboxManager {
     /// Update the configuration.
     pub fn update_config(&self, config: SandboxRuntimeConfig) -> Result<(), SandboxError> {
         config.validate()?;
-        self.state.write().config = Some(config);
+
+        let mut state = self.state.write();
+
+        // Push the new network policy to the running proxies (they share one filter)
+        if let Some(ref proxy) = state.http_proxy {
+            network::update_filter(proxy.filter(), &config.network);
+        }
+        state.ask_broker.configure(config.network.ask_unknown_domains.as_ref());
+
+        state.config = Some(config);
         Ok(())
     }
 
@@ -147,6 +302,51 @@ impl SandboxManager {
         self.state.read().socks_proxy_port
     }
 
+    /// Get the UDP port of the DNS forwarder, the sandbox's only resolver.
+    pub fn get_dns_port(&self) -> Option<u16> {
+        self.state.read().dns_port
+    }
+
+    /// Get the session credentials both proxies require. Commands wrapped by
+    /// the manager get them in their proxy environment variables.
+    pub fn get_proxy_auth(&self) -> Option<Arc<ProxyAuth>> {
+        self.state.read().proxy_auth.clone()
+    }
+
+    /// Get the environment variables a command wrapped by
+    /// [`Self::wrap_with_sandbox`] must run with, e.g. with `Command::envs`:
+    /// the proxy variables with the session credentials, which are kept out
+    /// of the wrapped command line. Pass the same `custom_config`. Empty
+    /// before initialization.
+    pub fn get_sandbox_env(
+        &self,
+        custom_config: Option<&SandboxRuntimeConfig>,
+    ) -> Vec<(String, String)> {
+        let state = self.state.read();
+        let Some(config) = custom_config.or(state.config.as_ref()) else {
+            return Vec::new();
+        };
+        if !state.initialized {
+            return Vec::new();
+        }
+        let ca = state.http_proxy.as_ref().and_then(|proxy| proxy.ca_trust());
+        crate::sandbox::sandbox_env(
+            config,
+            state.http_proxy_port.unwrap_or(3128),
+            state.socks_proxy_port.unwrap_or(1080),
+            state.proxy_auth.as_deref(),
+            ca.as_ref(),
+        )
+    }
+
+    /// Get the path of the TLS interception CA certificate, to be trusted
+    /// inside the sandbox. None unless `tlsIntercept` is configured.
+    pub fn get_ca_cert_path(&self) -> Option<PathBuf> {
+        let state = self.state.read();
+        let proxy = state.http_proxy.as_ref()?;
+        proxy.ca_cert_path().map(Path::to_path_buf)
+    }
+
     /// Get the HTTP socket path (Linux only).
     #[cfg(target_os = "linux")]
     pub fn get_http_socket_path(&self) -> Option<String> {
@@ -224,7 +424,27 @@ impl SandboxManager {
         self.state.read().violation_store.clone()
     }
 
-    /// Wrap a command with sandbox restrictions.
+    /// Get the connection audit log. Listeners survive resets and re-initialization.
+    pub fn get_audit_log(&self) -> AuditLog {
+        self.state.read().audit_log.clone()
+    }
+
+    /// Get the broker for `askUnknownDomains` requests. Subscribe to receive
+    /// requests and answer them with [`AskBroker::respond`]; listeners survive
+    /// resets and re-initialization.
+    pub fn get_ask_broker(&self) -> AskBroker {
+        self.state.read().ask_broker.clone()
+    }
+
+    /// Decide proxied connections with a custom policy instead of the domain
+    /// rules in `NetworkConfig`, whose other network settings still apply.
+    /// Takes effect at the next [`SandboxManager::initialize`].
+    pub fn set_network_policy(&self, policy: Option<Arc<dyn NetworkPolicy>>) {
+        self.state.write().network_policy = policy;
+    }
+
+    /// Wrap a command with sandbox restrictions. Run it with the variables
+    /// from [`Self::get_sandbox_env`] in its environment.
     pub async fn wrap_with_sandbox(
         &self,
         command: &str,
@@ -232,7 +452,8 @@ impl SandboxManager {
         custom_config: Option<SandboxRuntimeConfig>,
     ) -> Result<String, SandboxError> {
         // Extract needed values from state while holding the lock
-        let (config, http_port, socks_port) = {
+        #[cfg_attr(not(target_os = "macos"), allow(unused_variables))]
+        let (config, http_port, socks_port, dns_port, ca) = {
             let state = self.state.read();
 
             if !state.initialized {
@@ -245,7 +466,13 @@ impl SandboxManager {
                 .or_else(|| state.config.clone())
                 .ok_or_else(|| SandboxError::ExecutionFailed("No configuration available".to_string()))?;
 
-            (config, state.http_proxy_port, state.socks_proxy_port)
+            (
+                config,
+                state.http_proxy_port,
+                state.socks_proxy_port,
+                state.dns_port,
+                state.http_proxy.as_ref().and_then(|proxy| proxy.ca_trust()),
+            )
         };
 
         let _platform = current_platform()
@@ -259,6 +486,8 @@ impl SandboxManager {
                 &config,
                 http_port,
                 socks_port,
+                dns_port,
+                ca.as_ref(),
                 shell,
                 true, // enable log monitor
             )?;
@@ -267,9 +496,23 @@ impl SandboxManager {
 
         #[cfg(target_os = "linux")]
         {
-            let (http_socket, socks_socket) = {
+            let (
+                http_socket,
+                socks_socket,
+                socks_udp_socket,
+                dns_socket,
+                resolv_conf,
+                expose_dir,
+            ) = {
                 let state = self.state.read();
-                (state.http_socket_path.clone(), state.socks_socket_path.clone())
+                (
+                    state.http_socket_path.clone(),
+                    state.socks_socket_path.clone(),
+                    state.socks_udp_socket_path.clone(),
+                    state.dns_socket_path.clone(),
+                    state.resolv_conf_path.clone(),
+                    state.expose_dir.clone(),
+                )
             };
 
             let cwd = std::env::current_dir()?;
@@ -279,6 +522,11 @@ impl SandboxManager {
                 &cwd,
                 http_socket.as_deref(),
                 socks_socket.as_deref(),
+                socks_udp_socket.as_deref(),
+                dns_socket.as_deref(),
+                resolv_conf.as_deref(),
+                expose_dir.as_deref(),
+                ca.as_ref(),
                 http_port.unwrap_or(3128),
                 socks_port.unwrap_or(1080),
                 shell,
@@ -325,36 +573,58 @@ impl SandboxManager {
             crate::sandbox::macos::cleanup_temp_profiles();
         }
 
-        let mut state = self.state.write();
-        // We need to release the lock before calling async reset
-        // So we'll just do the cleanup inline
+        // Take the bridges out, so they can be stopped without holding the lock
+        #[cfg(target_os = "linux")]
+        let (bridges, runtime_dir);
+        {
+            let mut state = self.state.write();
 
-        // Stop proxies
-        if let Some(ref mut proxy) = state.http_proxy {
-            proxy.stop();
-        }
-        if let Some(ref mut proxy) = state.socks_proxy {
-            proxy.stop();
+            // Stop proxies
+            if let Some(ref mut proxy) = state.http_proxy {
+                proxy.stop();
+            }
+            if let Some(ref mut proxy) = state.socks_proxy {
+                proxy.stop();
+            }
+            if let Some(ref mut forwarder) = state.dns_forwarder {
+                forwarder.stop();
+            }
+
+            #[cfg(target_os = "linux")]
+            {
+                bridges = std::mem::take(&mut state.bridges);
+                runtime_dir = state.runtime_dir.take();
+                state.http_socket_path = None;
+                state.socks_socket_path = None;
+                state.socks_udp_socket_path = None;
+                state.dns_socket_path = None;
+                state.resolv_conf_path = None;
+                state.expose_dir = None;
+            }
+
+            // Clear state
+            state.http_proxy = None;
+            state.socks_proxy = None;
+            state.http_proxy_port = None;
+            state.socks_proxy_port = None;
+            state.proxy_auth = None;
+            state.dns_forwarder = None;
+            state.dns_port = None;
+            let _ = state.audit_log.set_file(None);
+            state.config = None;
+            state.initialized = false;
+            state.network_ready = false;
         }
 
-        // Stop bridges (Linux)
+        // Stop bridges (Linux), then remove the directory holding their sockets
         #[cfg(target_os = "linux")]
         {
-            // Note: We can't call async stop here, so we rely on Drop
-            state.bridges.clear();
-            state.http_socket_path = None;
-            state.socks_socket_path = None;
+            for mut bridge in bridges {
+                bridge.stop().await;
+            }
+            drop(runtime_dir);
         }
 
-        // Clear state
-        state.http_proxy = None;
-        state.socks_proxy = None;
-        state.http_proxy_port = None;
-        state.socks_proxy_port = None;
-        state.config = None;
-        state.initialized = false;
-        state.network_ready = false;
-
         tracing::info!("Sandbox manager reset");
     }
 }
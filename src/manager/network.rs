@@ -1,25 +1,116 @@
 //! Network initialization and management.
 
-use crate::config::NetworkConfig;
+use std::sync::Arc;
+
+use crate::config::{CassetteMode, NetworkConfig};
 use crate::error::SandboxError;
-use crate::proxy::{DomainFilter, HttpProxy, Socks5Proxy};
+use crate::proxy::{
+    AskBroker, AuditLog, Cassette, CredentialInjector, DnsForwarder, DomainFilter, FakeIpPool,
+    FilteredPolicy, HttpProxy, NetworkPolicy, ProxyAuth, RateLimiter, SharedFilter, Socks5Proxy,
+    TlsInterceptor, UdpRelayLimits, UpstreamProxy,
+};
+use crate::violation::SandboxViolationStore;
 
 /// Initialize network proxies.
+/// Both proxies share one filter so that config updates reach them together,
+/// report every connection to `audit_log`, send ask requests to `ask_broker`,
+/// and share one set of rate limits whose refusals are recorded in `violations`.
+/// A custom `policy` decides connections in place of the filter, and the
+/// SOCKS5 proxy maps addresses from `fake_ips` back to names. With `auth`,
+/// both proxies require the session credentials.
 pub async fn initialize_proxies(
     config: &NetworkConfig,
+    audit_log: &AuditLog,
+    ask_broker: &AskBroker,
+    policy: Option<Arc<dyn NetworkPolicy>>,
+    violations: Arc<SandboxViolationStore>,
+    fake_ips: Option<Arc<FakeIpPool>>,
+    auth: Option<Arc<ProxyAuth>>,
 ) -> Result<(HttpProxy, Socks5Proxy), SandboxError> {
     // Create domain filter from config
-    let filter = DomainFilter::from_config(config);
+    let filter = SharedFilter::new(DomainFilter::from_config(config));
 
     // Get MITM socket path if configured
     let mitm_socket_path = config.mitm_proxy.as_ref().map(|m| m.socket_path.clone());
 
+    // Upstream proxy to chain through, if any
+    let upstream = config
+        .upstream_proxy
+        .as_ref()
+        .map(UpstreamProxy::from_config)
+        .transpose()?;
+
+    // Audit file sink, if configured
+    audit_log.set_file(config.audit_log_path.as_deref().map(std::path::Path::new))?;
+
+    // Rate limits shared by both proxies
+    let limiter_violations = Some(violations.clone());
+    let rate_limiter =
+        RateLimiter::from_config(config.rate_limits.as_ref(), limiter_violations).map(Arc::new);
+
+    // Built-in TLS interception, with its local CA
+    let interceptor = config
+        .tls_intercept
+        .as_ref()
+        .map(TlsInterceptor::from_config)
+        .transpose()?
+        .map(Arc::new);
+
+    // Cassette to record to or replay from; replay misses are violations
+    let cassette = config
+        .cassette
+        .as_ref()
+        .map(|cassette| Cassette::from_config(cassette, Some(violations)))
+        .transpose()?
+        .map(Arc::new);
+
+    // Credential headers, with secrets read on the host. A replay sends
+    // nothing anywhere, so it doesn't need them
+    let replaying = cassette.as_ref().is_some_and(|c| c.mode() == CassetteMode::Replay);
+    let credentials = if replaying {
+        None
+    } else {
+        CredentialInjector::from_config(&config.credentials)?.map(Arc::new)
+    };
+
     // Create HTTP proxy
-    let mut http_proxy = HttpProxy::new(filter.clone(), mitm_socket_path).await?;
+    let filtered = |filter: &SharedFilter| {
+        policy.clone().map(|policy| FilteredPolicy { policy, filter: filter.clone() })
+    };
+    let mut http_proxy = match filtered(&filter) {
+        Some(policy) => HttpProxy::new(policy, mitm_socket_path).await?,
+        None => HttpProxy::new(filter.clone(), mitm_socket_path).await?,
+    };
+    http_proxy.set_upstream_proxy(upstream.clone());
+    http_proxy.set_audit_log(Some(audit_log.clone()));
+    http_proxy.set_rate_limiter(rate_limiter.clone());
+    http_proxy.set_tls_interceptor(interceptor);
+    http_proxy.set_credentials(credentials);
+    http_proxy.set_ask_broker(Some(ask_broker.clone()));
+    http_proxy.set_cassette(cassette.clone());
+    http_proxy.set_auth(auth.clone());
     http_proxy.start()?;
 
     // Create SOCKS5 proxy
-    let mut socks_proxy = Socks5Proxy::new(filter).await?;
+    let mut socks_proxy = match filtered(&filter) {
+        Some(policy) => Socks5Proxy::new(policy).await?,
+        None => Socks5Proxy::new(filter).await?,
+    };
+    let udp_limits = UdpRelayLimits::from_config(config.socks_udp.as_ref());
+    if upstream.is_some() && udp_limits.is_some() {
+        tracing::warn!(
+            "network.upstreamProxy only carries TCP; SOCKS5 UDP is relayed directly from this \
+             host. Set network.socksUdp.enabled to false to keep UDP off the local network"
+        );
+    }
+    socks_proxy.set_udp_limits(udp_limits);
+    socks_proxy.set_upstream_proxy(upstream);
+    socks_proxy.set_audit_log(Some(audit_log.clone()));
+    socks_proxy.set_rate_limiter(rate_limiter);
+    socks_proxy.set_ask_broker(Some(ask_broker.clone()));
+    socks_proxy.set_cassette(cassette);
+    socks_proxy.set_fake_ips(fake_ips);
+    socks_proxy.set_auth(auth);
     socks_proxy.start()?;
 
     tracing::debug!(
@@ -31,40 +122,32 @@ pub async fn initialize_proxies(
     Ok((http_proxy, socks_proxy))
 }
 
-/// Generate proxy environment variables for sandboxed commands.
-#[allow(dead_code)]
-pub fn generate_proxy_env_vars(
-    http_port: u16,
-    socks_port: u16,
-    http_socket_path: Option<&str>,
-    _socks_socket_path: Option<&str>,
-) -> Vec<(String, String)> {
-    let http_proxy = if let Some(_socket) = http_socket_path {
-        // On Linux, use localhost inside the sandbox (socat bridges to socket)
-        format!("http://localhost:{}", http_port)
-    } else {
-        format!("http://localhost:{}", http_port)
+/// Start the DNS forwarder for sandboxed commands. It shares the proxies'
+/// filter and custom policy, so config updates reach it too; refused lookups
+/// are recorded in `violations`. With `fake_ips`, names are answered with
+/// fake IPs.
+pub async fn initialize_dns(
+    filter: &SharedFilter,
+    policy: Option<Arc<dyn NetworkPolicy>>,
+    violations: Arc<SandboxViolationStore>,
+    fake_ips: Option<Arc<FakeIpPool>>,
+) -> Result<DnsForwarder, SandboxError> {
+    let mut forwarder = match policy {
+        Some(policy) => {
+            DnsForwarder::new(FilteredPolicy { policy, filter: filter.clone() }).await?
+        }
+        None => DnsForwarder::new(filter.clone()).await?,
     };
+    forwarder.set_violations(Some(violations));
+    forwarder.set_fake_ips(fake_ips);
+    forwarder.start()?;
+    Ok(forwarder)
+}
 
-    let socks_proxy = format!("socks5://localhost:{}", socks_port);
-
-    let mut env = vec![
-        ("http_proxy".to_string(), http_proxy.clone()),
-        ("HTTP_PROXY".to_string(), http_proxy.clone()),
-        ("https_proxy".to_string(), http_proxy.clone()),
-        ("HTTPS_PROXY".to_string(), http_proxy),
-        ("ALL_PROXY".to_string(), socks_proxy.clone()),
-        ("all_proxy".to_string(), socks_proxy.clone()),
-    ];
-
-    // Git SSH command for SOCKS proxy
-    env.push((
-        "GIT_SSH_COMMAND".to_string(),
-        format!(
-            "ssh -o ProxyCommand='nc -X 5 -x localhost:{} %h %p'",
-            socks_port
-        ),
-    ));
-
-    env
+/// Apply an updated network config to the running proxies.
+pub fn update_filter(filter: &SharedFilter, config: &NetworkConfig) {
+    filter.update(
+        DomainFilter::from_config(config),
+        config.close_revoked_connections.unwrap_or(false),
+    );
 }
@@ -4,7 +4,9 @@ use std::sync::Arc;
 
 
 use crate::config::SandboxRuntimeConfig;
-use crate::proxy::{HttpProxy, Socks5Proxy};
+use crate::proxy::{
+    AskBroker, AuditLog, DnsForwarder, HttpProxy, NetworkPolicy, ProxyAuth, Socks5Proxy,
+};
 use crate::violation::SandboxViolationStore;
 
 /// Internal state for the sandbox manager.
@@ -24,6 +26,15 @@ pub struct ManagerState {
     /// SOCKS5 proxy port.
     pub socks_proxy_port: Option<u16>,
 
+    /// Session credentials required by both proxies.
+    pub proxy_auth: Option<Arc<ProxyAuth>>,
+
+    /// DNS forwarder for sandboxed commands.
+    pub dns_forwarder: Option<DnsForwarder>,
+
+    /// DNS forwarder port.
+    pub dns_port: Option<u16>,
+
     /// Unix socket path for HTTP proxy (Linux only).
     #[cfg(target_os = "linux")]
     pub http_socket_path: Option<String>,
@@ -32,9 +43,29 @@ pub struct ManagerState {
     #[cfg(target_os = "linux")]
     pub socks_socket_path: Option<String>,
 
-    /// Socat bridge processes (Linux only).
+    /// Unix socket path for the SOCKS5 UDP relay (Linux only).
+    #[cfg(target_os = "linux")]
+    pub socks_udp_socket_path: Option<String>,
+
+    /// Unix socket path for the DNS forwarder (Linux only).
+    #[cfg(target_os = "linux")]
+    pub dns_socket_path: Option<String>,
+
+    /// Resolver config mounted into the sandbox (Linux only).
+    #[cfg(target_os = "linux")]
+    pub resolv_conf_path: Option<String>,
+
+    /// Directory of sockets for exposed ports (Linux only).
+    #[cfg(target_os = "linux")]
+    pub expose_dir: Option<String>,
+
+    /// Private directory holding this session's sockets, removed on drop (Linux only).
     #[cfg(target_os = "linux")]
-    pub bridges: Vec<crate::sandbox::linux::SocatBridge>,
+    pub runtime_dir: Option<crate::sandbox::linux::RuntimeDir>,
+
+    /// Host sides of the Unix socket bridges (Linux only).
+    #[cfg(target_os = "linux")]
+    pub bridges: Vec<crate::sandbox::linux::HostBridge>,
 
     /// Whether the manager has been initialized.
     pub initialized: bool,
@@ -44,6 +75,15 @@ pub struct ManagerState {
 
     /// Violation store.
     pub violation_store: Arc<SandboxViolationStore>,
+
+    /// Connection audit log shared by both proxies.
+    pub audit_log: AuditLog,
+
+    /// Broker for `askUnknownDomains` requests shared by both proxies.
+    pub ask_broker: AskBroker,
+
+    /// Custom policy deciding proxied connections instead of the config's domain rules.
+    pub network_policy: Option<Arc<dyn NetworkPolicy>>,
 }
 
 impl Default for ManagerState {
@@ -54,15 +94,31 @@ impl Default for ManagerState {
             socks_proxy: None,
             http_proxy_port: None,
             socks_proxy_port: None,
+            proxy_auth: None,
+            dns_forwarder: None,
+            dns_port: None,
             #[cfg(target_os = "linux")]
             http_socket_path: None,
             #[cfg(target_os = "linux")]
             socks_socket_path: None,
             #[cfg(target_os = "linux")]
+            socks_udp_socket_path: None,
+            #[cfg(target_os = "linux")]
+            dns_socket_path: None,
+            #[cfg(target_os = "linux")]
+            resolv_conf_path: None,
+            #[cfg(target_os = "linux")]
+            expose_dir: None,
+            #[cfg(target_os = "linux")]
+            runtime_dir: None,
+            #[cfg(target_os = "linux")]
             bridges: Vec::new(),
             initialized: false,
             network_ready: false,
             violation_store: Arc::new(SandboxViolationStore::new()),
+            audit_log: AuditLog::new(),
+            ask_broker: AskBroker::new(),
+            network_policy: None,
         }
     }
 }
@@ -82,6 +138,9 @@ impl ManagerState {
         if let Some(ref mut proxy) = self.socks_proxy {
             proxy.stop();
         }
+        if let Some(ref mut forwarder) = self.dns_forwarder {
+            forwarder.stop();
+        }
 
         // Stop bridges (Linux)
         #[cfg(target_os = "linux")]
@@ -92,6 +151,12 @@ impl ManagerState {
             self.bridges.clear();
             self.http_socket_path = None;
             self.socks_socket_path = None;
+            self.socks_udp_socket_path = None;
+            self.dns_socket_path = None;
+            self.resolv_conf_path = None;
+            self.expose_dir = None;
+            // The bridges are stopped, so their directory can go
+            self.runtime_dir = None;
         }
 
         // Clear state
@@ -99,6 +164,10 @@ impl ManagerState {
         self.socks_proxy = None;
         self.http_proxy_port = None;
         self.socks_proxy_port = None;
+        self.proxy_auth = None;
+        self.dns_forwarder = None;
+        self.dns_port = None;
+        let _ = self.audit_log.set_file(None);
         self.config = None;
         self.initialized = false;
         self.network_ready = false;
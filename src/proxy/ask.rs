@@ -0,0 +1,322 @@
+//! Interactive decisions for destinations that match no allow rule.
+//!
+//! With `askUnknownDomains`, the filter answers [`FilterDecision::Ask`] instead
+//! of denying. The proxy then holds the connection while an [`AskRequest`] is
+//! passed to listeners (the CLI writes it to `--ask-fd`), and waits for an
+//! [`AskResponse`] or the timeout. Concurrent connections to the same
+//! destination share one request.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+
+use crate::config::{normalize_domain, AskConfig};
+use crate::proxy::audit::{AuditProtocol, ConnectionAudit};
+use crate::proxy::filter::{FilterDecision, FilterRule};
+
+/// Default time to wait for an answer.
+const DEFAULT_ASK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A controller's answer to an ask request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AskAnswer {
+    /// Allow the connections waiting on this request.
+    AllowOnce,
+    /// Allow them, and the host on any port for the rest of the session.
+    AllowAlways,
+    /// Deny the connections waiting on this request.
+    Deny,
+}
+
+impl fmt::Display for AskAnswer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AllowOnce => write!(f, "allow-once"),
+            Self::AllowAlways => write!(f, "allow-always"),
+            Self::Deny => write!(f, "deny"),
+        }
+    }
+}
+
+/// A held connection waiting for a decision, serialized as
+/// `{"type":"askRequest","id":1,"host":"example.com","port":443,"protocol":"CONNECT"}`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename = "askRequest", rename_all = "camelCase")]
+pub struct AskRequest {
+    /// Id to answer with.
+    pub id: u64,
+    /// Destination host as requested by the client.
+    pub host: String,
+    /// Destination port.
+    pub port: u16,
+    /// Proxy protocol of the first waiting connection.
+    pub protocol: AuditProtocol,
+}
+
+/// An answer to an [`AskRequest`], parsed from
+/// `{"type":"askResponse","id":1,"answer":"allow-once"}`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename = "askResponse", rename_all = "camelCase")]
+pub struct AskResponse {
+    /// Id of the request being answered.
+    pub id: u64,
+    /// The decision.
+    pub answer: AskAnswer,
+}
+
+/// Type for ask listeners.
+pub type AskListener = Box<dyn Fn(&AskRequest) + Send + Sync>;
+
+/// An unanswered request and the connections waiting on it.
+struct PendingAsk {
+    host: String,
+    port: u16,
+    waiters: Vec<oneshot::Sender<AskAnswer>>,
+}
+
+struct AskBrokerInner {
+    listeners: RwLock<Vec<(usize, Arc<AskListener>)>>,
+    next_listener_id: AtomicUsize,
+    next_request_id: AtomicU64,
+    pending: Mutex<HashMap<u64, PendingAsk>>,
+    always_allowed: Mutex<HashSet<String>>,
+    timeout_ms: AtomicU64,
+}
+
+/// Passes ask requests to listeners and routes their answers back to the
+/// waiting connections. Shared by both proxies.
+#[derive(Clone)]
+pub struct AskBroker {
+    inner: Arc<AskBrokerInner>,
+}
+
+impl Default for AskBroker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AskBroker {
+    /// Create a broker with no listeners and the default timeout.
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(AskBrokerInner {
+                listeners: RwLock::new(Vec::new()),
+                next_listener_id: AtomicUsize::new(0),
+                next_request_id: AtomicU64::new(1),
+                pending: Mutex::new(HashMap::new()),
+                always_allowed: Mutex::new(HashSet::new()),
+                timeout_ms: AtomicU64::new(DEFAULT_ASK_TIMEOUT.as_millis() as u64),
+            }),
+        }
+    }
+
+    /// Apply `askUnknownDomains` settings.
+    pub fn configure(&self, config: Option<&AskConfig>) {
+        let timeout = config
+            .and_then(|c| c.timeout_secs)
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_ASK_TIMEOUT);
+        self.set_timeout(timeout);
+    }
+
+    /// Set how long a connection waits for an answer before it is denied.
+    pub fn set_timeout(&self, timeout: Duration) {
+        self.inner.timeout_ms.store(timeout.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Subscribe to new requests. Returns an id for [`AskBroker::unsubscribe`].
+    pub fn subscribe(&self, listener: AskListener) -> usize {
+        let id = self.inner.next_listener_id.fetch_add(1, Ordering::Relaxed);
+        self.inner.listeners.write().push((id, Arc::new(listener)));
+        id
+    }
+
+    /// Remove a listener.
+    pub fn unsubscribe(&self, id: usize) {
+        self.inner.listeners.write().retain(|(listener_id, _)| *listener_id != id);
+    }
+
+    /// Answer a pending request. Returns false if the id is unknown or the
+    /// request already timed out.
+    pub fn respond(&self, response: &AskResponse) -> bool {
+        let Some(pending) = self.inner.pending.lock().remove(&response.id) else {
+            return false;
+        };
+        if response.answer == AskAnswer::AllowAlways {
+            self.inner.always_allowed.lock().insert(pending.host.clone());
+        }
+        for waiter in pending.waiters {
+            let _ = waiter.send(response.answer);
+        }
+        true
+    }
+
+    /// Ask about a destination and wait for the answer. Returns `None` if no
+    /// listener is subscribed or the request timed out.
+    pub async fn ask(&self, host: &str, port: u16, protocol: AuditProtocol) -> Option<AskAnswer> {
+        let host = normalize_domain(host);
+        if self.inner.always_allowed.lock().contains(&host) {
+            return Some(AskAnswer::AllowAlways);
+        }
+
+        // Clone the listeners so one may subscribe or unsubscribe from a callback
+        let listeners: Vec<_> = self
+            .inner
+            .listeners
+            .read()
+            .iter()
+            .map(|(_, listener)| listener.clone())
+            .collect();
+        if listeners.is_empty() {
+            return None;
+        }
+
+        // Join a pending request for the same destination, or start one
+        let (tx, rx) = oneshot::channel();
+        let request = {
+            let mut pending = self.inner.pending.lock();
+            match pending.values_mut().find(|p| p.host == host && p.port == port) {
+                Some(existing) => {
+                    existing.waiters.push(tx);
+                    None
+                }
+                None => {
+                    let id = self.inner.next_request_id.fetch_add(1, Ordering::Relaxed);
+                    let waiters = vec![tx];
+                    pending.insert(id, PendingAsk { host: host.clone(), port, waiters });
+                    Some(AskRequest { id, host, port, protocol })
+                }
+            }
+        };
+        if let Some(request) = &request {
+            for listener in listeners {
+                listener(request);
+            }
+        }
+
+        let timeout = Duration::from_millis(self.inner.timeout_ms.load(Ordering::Relaxed));
+        match tokio::time::timeout(timeout, rx).await {
+            Ok(Ok(answer)) => Some(answer),
+            // Joined connections give up with the request that started it
+            _ => {
+                if let Some(request) = request {
+                    self.inner.pending.lock().remove(&request.id);
+                }
+                None
+            }
+        }
+    }
+}
+
+/// Settle a [`FilterDecision::Ask`] and record the outcome in `audit`.
+/// Returns `Allow` or `Deny`; without a broker the connection is denied.
+pub async fn resolve_ask(
+    broker: Option<&AskBroker>,
+    host: &str,
+    port: u16,
+    protocol: AuditProtocol,
+    audit: &ConnectionAudit,
+) -> FilterDecision {
+    let answer = match broker {
+        Some(broker) => broker.ask(host, port, protocol).await,
+        None => None,
+    };
+    let (decision, rule) = match answer {
+        Some(AskAnswer::Deny) => (FilterDecision::Deny, FilterRule::Asked(AskAnswer::Deny)),
+        Some(answer) => (FilterDecision::Allow, FilterRule::Asked(answer)),
+        None => (FilterDecision::Deny, FilterRule::AskUnanswered),
+    };
+    tracing::debug!("Ask for {}:{} settled by {}", host, port, rule);
+    audit.set_verdict(decision, rule);
+    decision
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Subscribe a listener that forwards requests to a channel.
+    fn listen(broker: &AskBroker) -> tokio::sync::mpsc::UnboundedReceiver<AskRequest> {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        broker.subscribe(Box::new(move |request| {
+            let _ = tx.send(request.clone());
+        }));
+        rx
+    }
+
+    #[test]
+    fn test_ask_wire_format() {
+        let request = AskRequest {
+            id: 7,
+            host: "example.com".to_string(),
+            port: 443,
+            protocol: AuditProtocol::Connect,
+        };
+        assert_eq!(
+            serde_json::to_string(&request).unwrap(),
+            r#"{"type":"askRequest","id":7,"host":"example.com","port":443,"protocol":"CONNECT"}"#
+        );
+
+        let response: AskResponse =
+            serde_json::from_str(r#"{"type":"askResponse","id":7,"answer":"allow-always"}"#)
+                .unwrap();
+        assert_eq!(response.id, 7);
+        assert_eq!(response.answer, AskAnswer::AllowAlways);
+        // Config lines on the control fd are not mistaken for answers
+        assert!(serde_json::from_str::<AskResponse>(r#"{"network":{}}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_ask_shares_pending_requests() {
+        let broker = AskBroker::new();
+        let mut requests = listen(&broker);
+
+        let first = tokio::spawn({
+            let broker = broker.clone();
+            async move { broker.ask("Example.com", 443, AuditProtocol::Connect).await }
+        });
+        let request = requests.recv().await.unwrap();
+        assert_eq!(request.host, "example.com");
+        let second = tokio::spawn({
+            let broker = broker.clone();
+            async move { broker.ask("example.com", 443, AuditProtocol::Socks5).await }
+        });
+        tokio::task::yield_now().await;
+        assert!(requests.try_recv().is_err());
+
+        // Wait until the second connection has joined before answering
+        while broker.inner.pending.lock()[&request.id].waiters.len() < 2 {
+            tokio::task::yield_now().await;
+        }
+        let response = AskResponse { id: request.id, answer: AskAnswer::AllowAlways };
+        assert!(broker.respond(&response));
+        assert!(!broker.respond(&response));
+        assert_eq!(first.await.unwrap(), Some(AskAnswer::AllowAlways));
+        assert_eq!(second.await.unwrap(), Some(AskAnswer::AllowAlways));
+
+        // "allow-always" covers later connections on any port without asking
+        let answer = broker.ask("example.com", 8443, AuditProtocol::Http).await;
+        assert_eq!(answer, Some(AskAnswer::AllowAlways));
+        assert!(requests.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_ask_unanswered() {
+        let broker = AskBroker::new();
+        assert_eq!(broker.ask("example.com", 443, AuditProtocol::Connect).await, None);
+
+        let mut requests = listen(&broker);
+        broker.set_timeout(Duration::from_millis(20));
+        assert_eq!(broker.ask("example.com", 443, AuditProtocol::Connect).await, None);
+        let request = requests.recv().await.unwrap();
+        assert!(!broker.respond(&AskResponse { id: request.id, answer: AskAnswer::AllowOnce }));
+    }
+}
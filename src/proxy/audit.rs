@@ -0,0 +1,442 @@
+//! Structured audit log of proxied connections.
+//!
+//! Every connection handled by the proxies produces one [`AuditRecord`] when
+//! it ends. Records are appended to an optional JSONL file and passed to
+//! in-process listeners.
+
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use parking_lot::{Mutex, RwLock};
+use pin_project_lite::pin_project;
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::error::SandboxError;
+use crate::proxy::filter::{FilterDecision, FilterRule};
+
+/// Protocol of an audited connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum AuditProtocol {
+    /// Plain HTTP request forwarded by the HTTP proxy.
+    #[serde(rename = "HTTP")]
+    Http,
+    /// HTTP CONNECT tunnel.
+    #[serde(rename = "CONNECT")]
+    Connect,
+    /// SOCKS5 CONNECT.
+    #[serde(rename = "SOCKS5")]
+    Socks5,
+    /// SOCKS5 UDP datagram. Only seen by policies; datagrams are not audited.
+    #[serde(rename = "UDP")]
+    Udp,
+    /// DNS query from the sandbox, decided by name; the port is 0. Only seen
+    /// by policies; queries are not audited.
+    #[serde(rename = "DNS")]
+    Dns,
+}
+
+/// Why an audited connection ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseReason {
+    /// Refused by the filter.
+    Denied,
+    /// Refused by a concurrency or connection-rate limit.
+    RateLimited,
+    /// The target (or upstream proxy) could not be reached.
+    ConnectFailed,
+    /// Closed normally by either side.
+    Closed,
+    /// Closed because a policy update revoked the destination.
+    Revoked,
+    /// Ended by an I/O or protocol error.
+    Error,
+    /// Dropped before an outcome was recorded, e.g. on proxy shutdown.
+    Aborted,
+}
+
+/// One audited connection.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditRecord {
+    /// When the connection was accepted (RFC 3339, UTC).
+    pub timestamp: String,
+    /// Proxy protocol.
+    pub protocol: AuditProtocol,
+    /// Destination host as requested by the client.
+    pub host: String,
+    /// Destination port.
+    pub port: u16,
+    /// Filter decision.
+    pub decision: FilterDecision,
+    /// The rule that produced the decision (e.g. "allowedDomains:github.com").
+    pub rule: String,
+    /// Bytes sent from the client to the destination.
+    pub bytes_up: u64,
+    /// Bytes sent from the destination to the client.
+    pub bytes_down: u64,
+    /// Time from accept to close, in milliseconds.
+    pub duration_ms: u64,
+    /// Why the connection ended.
+    pub close_reason: CloseReason,
+    /// Error detail for `connect_failed`, `rate_limited` and `error`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Type for audit listeners.
+pub type AuditListener = Box<dyn Fn(&AuditRecord) + Send + Sync>;
+
+#[derive(Default)]
+struct AuditLogInner {
+    file: Mutex<Option<File>>,
+    listeners: RwLock<Vec<(usize, Arc<AuditListener>)>>,
+    next_listener_id: AtomicUsize,
+}
+
+/// Destination for audit records: an optional JSONL file plus listeners.
+#[derive(Clone, Default)]
+pub struct AuditLog {
+    inner: Arc<AuditLogInner>,
+}
+
+impl AuditLog {
+    /// Create an audit log with no file sink.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create an audit log that appends JSON lines to the given file.
+    pub fn with_file(path: impl AsRef<Path>) -> Result<Self, SandboxError> {
+        let log = Self::new();
+        log.set_file(Some(path.as_ref()))?;
+        Ok(log)
+    }
+
+    /// Replace the file sink. `None` stops writing to a file; listeners are kept.
+    pub fn set_file(&self, path: Option<&Path>) -> Result<(), SandboxError> {
+        let file = match path {
+            Some(path) => Some(
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)?,
+            ),
+            None => None,
+        };
+        *self.inner.file.lock() = file;
+        Ok(())
+    }
+
+    /// Subscribe to new records. Returns an id for [`AuditLog::unsubscribe`].
+    pub fn subscribe(&self, listener: AuditListener) -> usize {
+        let id = self.inner.next_listener_id.fetch_add(1, Ordering::Relaxed);
+        self.inner.listeners.write().push((id, Arc::new(listener)));
+        id
+    }
+
+    /// Remove a listener.
+    pub fn unsubscribe(&self, id: usize) {
+        self.inner.listeners.write().retain(|(listener_id, _)| *listener_id != id);
+    }
+
+    /// Write a record to the file sink and notify listeners.
+    pub fn record(&self, record: &AuditRecord) {
+        if let Some(file) = self.inner.file.lock().as_mut() {
+            match serde_json::to_string(record) {
+                Ok(line) => {
+                    if let Err(e) = writeln!(file, "{}", line) {
+                        tracing::warn!("Failed to write audit record: {}", e);
+                    }
+                }
+                Err(e) => tracing::warn!("Failed to serialize audit record: {}", e),
+            }
+        }
+
+        // Clone the listeners so one may subscribe or unsubscribe from a callback
+        let listeners: Vec<_> = self
+            .inner
+            .listeners
+            .read()
+            .iter()
+            .map(|(_, listener)| listener.clone())
+            .collect();
+        for listener in listeners {
+            listener(record);
+        }
+    }
+}
+
+/// Outcome and counters of a connection that is still open.
+struct PendingRecord {
+    log: AuditLog,
+    timestamp: SystemTime,
+    started: Instant,
+    protocol: AuditProtocol,
+    host: String,
+    port: u16,
+    verdict: Mutex<(FilterDecision, FilterRule)>,
+    outcome: Mutex<Option<(CloseReason, Option<String>)>>,
+    bytes_up: AtomicU64,
+    bytes_down: AtomicU64,
+}
+
+impl Drop for PendingRecord {
+    fn drop(&mut self) {
+        let (decision, rule) = self.verdict.get_mut().clone();
+        let (close_reason, error) = self
+            .outcome
+            .get_mut()
+            .take()
+            .unwrap_or((CloseReason::Aborted, None));
+
+        self.log.record(&AuditRecord {
+            timestamp: format_rfc3339(self.timestamp),
+            protocol: self.protocol,
+            host: std::mem::take(&mut self.host),
+            port: self.port,
+            decision,
+            rule: rule.to_string(),
+            bytes_up: *self.bytes_up.get_mut(),
+            bytes_down: *self.bytes_down.get_mut(),
+            duration_ms: self.started.elapsed().as_millis() as u64,
+            close_reason,
+            error,
+        });
+    }
+}
+
+/// Handle to the audit record of one connection.
+///
+/// Clones share the record, which is emitted when the last clone is dropped.
+/// A handle created without an audit log does nothing.
+#[derive(Clone)]
+pub struct ConnectionAudit {
+    pending: Option<Arc<PendingRecord>>,
+}
+
+impl ConnectionAudit {
+    /// Start auditing a connection with the filter's verdict.
+    pub fn start(
+        log: Option<&AuditLog>,
+        protocol: AuditProtocol,
+        host: &str,
+        port: u16,
+        decision: FilterDecision,
+        rule: FilterRule,
+    ) -> Self {
+        let pending = log.map(|log| {
+            Arc::new(PendingRecord {
+                log: log.clone(),
+                timestamp: SystemTime::now(),
+                started: Instant::now(),
+                protocol,
+                host: host.to_string(),
+                port,
+                verdict: Mutex::new((decision, rule)),
+                outcome: Mutex::new(None),
+                bytes_up: AtomicU64::new(0),
+                bytes_down: AtomicU64::new(0),
+            })
+        });
+        Self { pending }
+    }
+
+    /// Replace the verdict, e.g. when the resolved address turns out to be blocked.
+    pub fn set_verdict(&self, decision: FilterDecision, rule: FilterRule) {
+        if let Some(pending) = &self.pending {
+            *pending.verdict.lock() = (decision, rule);
+        }
+    }
+
+    /// Record why the connection ended. The first outcome recorded wins.
+    pub fn close(&self, reason: CloseReason, error: Option<String>) {
+        if let Some(pending) = &self.pending {
+            pending.outcome.lock().get_or_insert((reason, error));
+        }
+    }
+
+    /// Record a failed outbound connection. Blocked addresses count as denials.
+    pub fn connect_failed(&self, error: &std::io::Error) {
+        if error.kind() == std::io::ErrorKind::PermissionDenied {
+            self.set_verdict(FilterDecision::Deny, FilterRule::BlockedAddress);
+            self.close(CloseReason::Denied, Some(error.to_string()));
+        } else {
+            self.close(CloseReason::ConnectFailed, Some(error.to_string()));
+        }
+    }
+
+    /// Count bytes sent from the client to the destination.
+    pub fn add_up(&self, bytes: usize) {
+        if let Some(pending) = &self.pending {
+            pending.bytes_up.fetch_add(bytes as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Count bytes sent from the destination to the client.
+    pub fn add_down(&self, bytes: usize) {
+        if let Some(pending) = &self.pending {
+            pending.bytes_down.fetch_add(bytes as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+pin_project! {
+    /// Destination-side stream wrapper that counts bytes for a [`ConnectionAudit`].
+    /// Reads count as downstream, writes as upstream.
+    pub struct CountingIo<T> {
+        #[pin]
+        inner: T,
+        audit: ConnectionAudit,
+    }
+}
+
+impl<T> CountingIo<T> {
+    /// Wrap a destination-side stream.
+    pub fn new(inner: T, audit: ConnectionAudit) -> Self {
+        Self { inner, audit }
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for CountingIo<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.project();
+        let before = buf.filled().len();
+        let result = this.inner.poll_read(cx, buf);
+        this.audit.add_down(buf.filled().len() - before);
+        result
+    }
+}
+
+impl<T: AsyncWrite> AsyncWrite for CountingIo<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.project();
+        let result = this.inner.poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            this.audit.add_up(n);
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+}
+
+/// Format a time as RFC 3339 in UTC with millisecond precision.
+fn format_rfc3339(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = (secs / 86_400, secs % 86_400);
+
+    // Civil date from days since the epoch (Howard Hinnant's algorithm)
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_format_rfc3339() {
+        assert_eq!(format_rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        let time = UNIX_EPOCH + Duration::from_millis(1_709_251_199_250);
+        assert_eq!(format_rfc3339(time), "2024-02-29T23:59:59.250Z");
+    }
+
+    #[test]
+    fn test_connection_audit_emits_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let log = AuditLog::with_file(&path).unwrap();
+
+        let records = Arc::new(Mutex::new(Vec::new()));
+        let sink = records.clone();
+        let id = log.subscribe(Box::new(move |record| sink.lock().push(record.clone())));
+
+        let audit = ConnectionAudit::start(
+            Some(&log),
+            AuditProtocol::Connect,
+            "github.com",
+            443,
+            FilterDecision::Allow,
+            FilterRule::AllowedDomain("github.com".to_string()),
+        );
+        let clone = audit.clone();
+        audit.add_up(10);
+        clone.add_down(20);
+        clone.close(CloseReason::Closed, None);
+        audit.close(CloseReason::Error, Some("ignored".to_string()));
+
+        drop(audit);
+        assert!(records.lock().is_empty());
+        drop(clone);
+
+        let records = records.lock().clone();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].rule, "allowedDomains:github.com");
+        assert_eq!((records[0].bytes_up, records[0].bytes_down), (10, 20));
+        assert_eq!(records[0].close_reason, CloseReason::Closed);
+        assert!(records[0].error.is_none());
+
+        let line = std::fs::read_to_string(&path).unwrap();
+        let json: serde_json::Value = serde_json::from_str(line.trim()).unwrap();
+        assert_eq!(json["protocol"], "CONNECT");
+        assert_eq!(json["decision"], "allow");
+        assert_eq!(json["bytesDown"], 20);
+        assert_eq!(json["closeReason"], "closed");
+        assert!(json.get("error").is_none());
+
+        // Unsubscribed listeners and handles without a log see nothing
+        log.unsubscribe(id);
+        ConnectionAudit::start(
+            None,
+            AuditProtocol::Socks5,
+            "example.com",
+            80,
+            FilterDecision::Deny,
+            FilterRule::NotAllowed,
+        )
+        .close(CloseReason::Denied, None);
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 1);
+    }
+}
@@ -0,0 +1,564 @@
+//! Recording and offline replay of HTTP traffic.
+//!
+//! In record mode the HTTP proxy forwards requests as usual, streaming both
+//! bodies while copying them, and appends each completed exchange to the
+//! cassette as a JSON line. In replay mode it answers every request from the
+//! cassette without connecting anywhere, and requests that weren't recorded
+//! are refused with a violation. Only plain HTTP and HTTPS decrypted by
+//! `tlsIntercept` go through a cassette: while recording, other tunnels and
+//! SOCKS5 connections pass unrecorded with a warning, and when replaying they
+//! are refused.
+//!
+//! Requests match on method, URL and body. Request headers are not recorded,
+//! so injected credentials stay out of cassettes. A request recorded several
+//! times is answered in recorded order, repeating the last response.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::Write;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use base64::Engine;
+use bytes::{Bytes, BytesMut};
+use http_body_util::{combinators::BoxBody, BodyExt, Full};
+use hyper::body::{Body, Frame, SizeHint};
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use hyper::{Request, Response, StatusCode};
+use parking_lot::Mutex;
+use pin_project_lite::pin_project;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{CassetteConfig, CassetteMode};
+use crate::error::SandboxError;
+use crate::proxy::audit::CloseReason;
+use crate::utils::{expand_home, format_host_port};
+use crate::violation::{SandboxViolationEvent, SandboxViolationStore};
+
+const BASE64: base64::engine::GeneralPurpose = base64::engine::general_purpose::STANDARD;
+
+/// Headers that describe a connection rather than the message; never recorded.
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// A recorded request, as matched against requests being replayed.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecordedRequest {
+    /// Request method.
+    pub method: String,
+    /// Absolute URL, always with a port (e.g., "https://example.com:443/a?b=c").
+    pub url: String,
+    /// Base64-encoded body.
+    #[serde(default)]
+    pub body: String,
+}
+
+/// A recorded response.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecordedResponse {
+    /// Status code.
+    pub status: u16,
+    /// Header names and values in order.
+    #[serde(default)]
+    pub headers: Vec<(String, String)>,
+    /// Base64-encoded body.
+    #[serde(default)]
+    pub body: String,
+}
+
+/// One line of a cassette file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Interaction {
+    /// The request.
+    pub request: RecordedRequest,
+    /// The response it got.
+    pub response: RecordedResponse,
+}
+
+/// A decoded response waiting to be replayed.
+struct ReplayResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+/// Responses recorded for one request, and the next one to serve.
+#[derive(Default)]
+struct ReplayQueue {
+    responses: Vec<Arc<ReplayResponse>>,
+    next: usize,
+}
+
+/// A cassette opened for recording or replay. Shared by every connection.
+pub struct Cassette {
+    mode: CassetteMode,
+    path: String,
+    file: Mutex<Option<File>>,
+    replays: Mutex<HashMap<RecordedRequest, ReplayQueue>>,
+    unrecorded: Mutex<HashSet<String>>,
+    violations: Option<Arc<SandboxViolationStore>>,
+}
+
+impl Cassette {
+    /// Open a cassette: truncate it for recording, or load it for replay.
+    /// Replay misses and refused connections are recorded in `violations`.
+    pub fn from_config(
+        config: &CassetteConfig,
+        violations: Option<Arc<SandboxViolationStore>>,
+    ) -> Result<Self, SandboxError> {
+        let path = expand_home(&config.path);
+        let invalid =
+            |reason: String| SandboxError::Proxy(format!("cassette '{}': {}", path, reason));
+
+        let mut file = None;
+        let mut replays: HashMap<RecordedRequest, ReplayQueue> = HashMap::new();
+        match config.mode {
+            CassetteMode::Record => {
+                let created = File::create(&path).map_err(|e| invalid(e.to_string()))?;
+                file = Some(created);
+            }
+            CassetteMode::Replay => {
+                let content = std::fs::read_to_string(&path).map_err(|e| invalid(e.to_string()))?;
+                for (index, line) in content.lines().enumerate() {
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    let at_line = |e: String| invalid(format!("line {}: {}", index + 1, e));
+                    let interaction: Interaction =
+                        serde_json::from_str(line).map_err(|e| at_line(e.to_string()))?;
+                    let response = ReplayResponse::decode(&interaction.response).map_err(at_line)?;
+                    let queue = replays.entry(interaction.request).or_default();
+                    queue.responses.push(Arc::new(response));
+                }
+            }
+        }
+
+        Ok(Self {
+            mode: config.mode,
+            path,
+            file: Mutex::new(file),
+            replays: Mutex::new(replays),
+            unrecorded: Mutex::new(HashSet::new()),
+            violations,
+        })
+    }
+
+    /// Whether the cassette is being recorded or replayed.
+    pub fn mode(&self) -> CassetteMode {
+        self.mode
+    }
+
+    /// Refuse a connection that can't be replayed and record a violation.
+    /// Returns the reason.
+    pub fn refuse(&self, what: &str, host: &str, port: u16) -> String {
+        let reason = format!(
+            "{} to {} can't be replayed from cassette {}",
+            what,
+            format_host_port(host, port),
+            self.path
+        );
+        self.record_violation(format!("Network connection refused: {}", reason));
+        reason
+    }
+
+    /// Warn, once per destination, that a connection passes through
+    /// unrecorded while recording, so a replay will refuse it.
+    pub fn pass_unrecorded(&self, what: &str, host: &str, port: u16) {
+        let destination = format_host_port(host, port);
+        if self.unrecorded.lock().insert(destination.clone()) {
+            tracing::warn!(
+                "{} to {} is not recorded in cassette {}; replaying it will be refused",
+                what,
+                destination,
+                self.path
+            );
+        }
+    }
+
+    /// Take the next recorded response for a request.
+    fn replay(&self, request: &RecordedRequest) -> Option<Arc<ReplayResponse>> {
+        let mut replays = self.replays.lock();
+        let queue = replays.get_mut(request)?;
+        let response = queue.responses.get(queue.next).or(queue.responses.last())?.clone();
+        queue.next += 1;
+        Some(response)
+    }
+
+    /// Append an interaction to the cassette file.
+    fn write(&self, interaction: &Interaction) {
+        let Ok(line) = serde_json::to_string(interaction) else {
+            return;
+        };
+        if let Some(file) = self.file.lock().as_mut() {
+            if let Err(e) = writeln!(file, "{}", line).and_then(|_| file.flush()) {
+                tracing::warn!("Failed to write cassette {}: {}", self.path, e);
+            }
+        }
+    }
+
+    fn record_violation(&self, line: String) {
+        tracing::warn!("{}", line);
+        if let Some(violations) = &self.violations {
+            violations.add_violation(SandboxViolationEvent::new(line));
+        }
+    }
+}
+
+impl ReplayResponse {
+    fn decode(response: &RecordedResponse) -> Result<Self, String> {
+        let status = StatusCode::from_u16(response.status)
+            .map_err(|_| format!("invalid status {}", response.status))?;
+        let mut headers = HeaderMap::new();
+        for (name, value) in &response.headers {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| format!("invalid header name '{}'", name))?;
+            let value = HeaderValue::from_str(value)
+                .map_err(|_| format!("invalid value for header '{}'", name))?;
+            headers.append(name, value);
+        }
+        let body = BASE64
+            .decode(&response.body)
+            .map_err(|e| format!("invalid response body: {}", e))?;
+        Ok(Self {
+            status,
+            headers,
+            body: body.into(),
+        })
+    }
+
+    fn to_response(&self) -> Response<BoxBody<Bytes, hyper::Error>> {
+        let mut response = Response::new(full_body(self.body.clone()));
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers.clone();
+        response
+    }
+}
+
+/// A request on its way through a cassette.
+pub enum CassetteStep {
+    /// Answered without forwarding, with how the exchange ended.
+    Answered {
+        response: Response<BoxBody<Bytes, hyper::Error>>,
+        reason: CloseReason,
+        detail: Option<String>,
+    },
+    /// To be forwarded; when recording, pass the response to [`Recording::finish`].
+    Forward(Request<BoxBody<Bytes, hyper::Error>>, Option<Recording>),
+}
+
+/// A request being recorded, waiting for its response.
+pub struct Recording {
+    cassette: Arc<Cassette>,
+    method: String,
+    url: String,
+    /// The request body, once it has been sent in full.
+    request_body: Arc<Mutex<Option<Bytes>>>,
+}
+
+impl Recording {
+    /// Return the response with its body copied as it streams; the
+    /// interaction is written once both bodies have ended. Exchanges whose
+    /// bodies fail or are abandoned are not recorded, and upgrades (e.g.
+    /// WebSocket) are passed through unrecorded.
+    pub fn finish(
+        self,
+        response: Response<BoxBody<Bytes, hyper::Error>>,
+    ) -> Response<BoxBody<Bytes, hyper::Error>> {
+        if response.status() == StatusCode::SWITCHING_PROTOCOLS {
+            return response;
+        }
+
+        let (parts, body) = response.into_parts();
+        let headers = parts
+            .headers
+            .iter()
+            .filter(|(name, _)| !HOP_BY_HOP_HEADERS.contains(&name.as_str()))
+            .map(|(name, value)| {
+                (name.to_string(), String::from_utf8_lossy(value.as_bytes()).into_owned())
+            })
+            .collect();
+        let status = parts.status.as_u16();
+        let body = TeeBody::new(body, move |body| {
+            let Some(request_body) = self.request_body.lock().take() else {
+                tracing::debug!("Request to {} was not sent in full; not recorded", self.url);
+                return;
+            };
+            self.cassette.write(&Interaction {
+                request: RecordedRequest {
+                    method: self.method,
+                    url: self.url,
+                    body: BASE64.encode(&request_body),
+                },
+                response: RecordedResponse {
+                    status,
+                    headers,
+                    body: BASE64.encode(&body),
+                },
+            });
+        });
+
+        Response::from_parts(parts, body.boxed())
+    }
+}
+
+pin_project! {
+    /// A body passed on unchanged while its data is copied. `done` gets the
+    /// copy once the body has ended, and isn't called if it fails or is
+    /// dropped before the end.
+    struct TeeBody<B> {
+        #[pin]
+        inner: B,
+        copy: BytesMut,
+        done: Option<Box<dyn FnOnce(Bytes) + Send + Sync>>,
+    }
+}
+
+impl<B: Body<Data = Bytes>> TeeBody<B> {
+    fn new(inner: B, done: impl FnOnce(Bytes) + Send + Sync + 'static) -> Self {
+        // An empty body may never be polled
+        let done: Option<Box<dyn FnOnce(Bytes) + Send + Sync>> = if inner.is_end_stream() {
+            done(Bytes::new());
+            None
+        } else {
+            Some(Box::new(done))
+        };
+        Self {
+            inner,
+            copy: BytesMut::new(),
+            done,
+        }
+    }
+}
+
+impl<B: Body<Data = Bytes>> Body for TeeBody<B> {
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, B::Error>>> {
+        let mut this = self.project();
+        let result = futures::ready!(this.inner.as_mut().poll_frame(cx));
+        match &result {
+            Some(Err(_)) => {
+                this.done.take();
+            }
+            Some(Ok(frame)) => {
+                if let Some(data) = frame.data_ref() {
+                    this.copy.extend_from_slice(data);
+                }
+            }
+            None => {}
+        }
+        // The end may be signalled along with the last frame
+        if result.is_none() || this.inner.is_end_stream() {
+            if let Some(done) = this.done.take() {
+                done(std::mem::take(this.copy).freeze());
+            }
+        }
+        Poll::Ready(result)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Pass a request for `url` through `cassette`: answer it when replaying, or
+/// copy its body as it is sent when recording. Without a cassette it is
+/// forwarded as is.
+pub async fn through_cassette(
+    cassette: Option<&Arc<Cassette>>,
+    req: Request<hyper::body::Incoming>,
+    url: String,
+) -> CassetteStep {
+    let Some(cassette) = cassette else {
+        return CassetteStep::Forward(req.map(|body| body.boxed()), None);
+    };
+
+    let (parts, body) = req.into_parts();
+    if cassette.mode == CassetteMode::Record {
+        let request_body = Arc::new(Mutex::new(None));
+        let sent = request_body.clone();
+        let body = TeeBody::new(body, move |body| *sent.lock() = Some(body));
+        let recording = Recording {
+            cassette: cassette.clone(),
+            method: parts.method.to_string(),
+            url,
+            request_body,
+        };
+        return CassetteStep::Forward(Request::from_parts(parts, body.boxed()), Some(recording));
+    }
+
+    // Replayed requests match on their whole body
+    let body = match body.collect().await {
+        Ok(collected) => collected.to_bytes(),
+        Err(e) => {
+            return CassetteStep::Answered {
+                response: plain_response(StatusCode::BAD_REQUEST, "Invalid request body".into()),
+                reason: CloseReason::Error,
+                detail: Some(e.to_string()),
+            };
+        }
+    };
+    let request = RecordedRequest {
+        method: parts.method.to_string(),
+        url,
+        body: BASE64.encode(&body),
+    };
+
+    match cassette.replay(&request) {
+        Some(response) => CassetteStep::Answered {
+            response: response.to_response(),
+            reason: CloseReason::Closed,
+            detail: None,
+        },
+        None => {
+            let reason = format!(
+                "{} {} is not in cassette {}",
+                request.method, request.url, cassette.path
+            );
+            cassette.record_violation(format!("Network request refused: {}", reason));
+            let body = format!("Blocked by sandbox replay: {}", reason);
+            CassetteStep::Answered {
+                response: plain_response(StatusCode::BAD_GATEWAY, body),
+                reason: CloseReason::Denied,
+                detail: Some(reason),
+            }
+        }
+    }
+}
+
+fn plain_response(status: StatusCode, body: String) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let mut response = Response::new(full_body(Bytes::from(body)));
+    *response.status_mut() = status;
+    response
+}
+
+fn full_body(body: Bytes) -> BoxBody<Bytes, hyper::Error> {
+    Full::new(body).map_err(|never| match never {}).boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn interaction(url: &str, body: &str) -> Interaction {
+        Interaction {
+            request: RecordedRequest {
+                method: "GET".to_string(),
+                url: url.to_string(),
+                body: String::new(),
+            },
+            response: RecordedResponse {
+                status: 200,
+                headers: vec![("content-type".to_string(), "text/plain".to_string())],
+                body: BASE64.encode(body),
+            },
+        }
+    }
+
+    #[test]
+    fn test_cassette_replay_order() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        for (url, body) in [
+            ("http://example.com:80/a", "first"),
+            ("http://example.com:80/b", "other"),
+            ("http://example.com:80/a", "second"),
+        ] {
+            writeln!(file, "{}", serde_json::to_string(&interaction(url, body)).unwrap()).unwrap();
+        }
+        let config = CassetteConfig {
+            path: file.path().to_str().unwrap().to_string(),
+            mode: CassetteMode::Replay,
+        };
+        let violations = Arc::new(SandboxViolationStore::new());
+        let cassette = Cassette::from_config(&config, Some(violations.clone())).unwrap();
+
+        let request = interaction("http://example.com:80/a", "").request;
+        let bodies: Vec<_> =
+            (0..3).map(|_| cassette.replay(&request).unwrap().body.clone()).collect();
+        assert_eq!(bodies, ["first", "second", "second"]);
+        let response = cassette.replay(&request).unwrap().to_response();
+        assert_eq!(response.headers()["content-type"], "text/plain");
+
+        let missing = interaction("http://example.com:80/c", "").request;
+        assert!(cassette.replay(&missing).is_none());
+        assert!(cassette.refuse("CONNECT tunnel", "example.com", 443).contains("example.com:443"));
+        assert_eq!(violations.get_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_tee_body_streams() {
+        type Sent = Result<Frame<Bytes>, std::io::Error>;
+        let tee = || {
+            let (tx, rx) = futures::channel::mpsc::unbounded::<Sent>();
+            let copy = Arc::new(Mutex::new(None));
+            let done = copy.clone();
+            let body = TeeBody::new(http_body_util::StreamBody::new(rx), move |body| {
+                *done.lock() = Some(body)
+            });
+            (tx, body, copy)
+        };
+
+        // Frames are passed on as they arrive, and the copy is complete at the end
+        let (tx, mut body, copy) = tee();
+        tx.unbounded_send(Ok(Frame::data(Bytes::from("one ")))).unwrap();
+        let frame = body.frame().await.unwrap().unwrap();
+        assert_eq!(frame.into_data().unwrap(), "one ");
+        assert!(copy.lock().is_none());
+        tx.unbounded_send(Ok(Frame::data(Bytes::from("two")))).unwrap();
+        drop(tx);
+        assert_eq!(body.collect().await.unwrap().to_bytes(), "two");
+        assert_eq!(copy.lock().as_deref(), Some(&b"one two"[..]));
+
+        // A failed body isn't passed on
+        let (tx, body, copy) = tee();
+        tx.unbounded_send(Ok(Frame::data(Bytes::from("one")))).unwrap();
+        tx.unbounded_send(Err(std::io::ErrorKind::BrokenPipe.into())).unwrap();
+        assert!(body.collect().await.is_err());
+        assert!(copy.lock().is_none());
+    }
+
+    #[test]
+    fn test_cassette_invalid_file() {
+        let replay = |content: &str| {
+            let mut file = tempfile::NamedTempFile::new().unwrap();
+            write!(file, "{}", content).unwrap();
+            let config = CassetteConfig {
+                path: file.path().to_str().unwrap().to_string(),
+                mode: CassetteMode::Replay,
+            };
+            Cassette::from_config(&config, None).map(|_| ())
+        };
+
+        assert!(replay("\n").is_ok());
+        assert!(replay("not json\n").is_err());
+        let bad_body = r#"{"request":{"method":"GET","url":"http://a:80/"},
+            "response":{"status":200,"body":"!!"}}"#.replace('\n', "");
+        assert!(replay(&bad_body).is_err());
+
+        let missing = CassetteConfig {
+            path: "/nonexistent/cassette.jsonl".to_string(),
+            mode: CassetteMode::Replay,
+        };
+        assert!(Cassette::from_config(&missing, None).is_err());
+    }
+}
@@ -0,0 +1,122 @@
+//! Outbound connections from the proxy servers.
+
+use std::io;
+use std::net::SocketAddr;
+
+use tokio::net::{lookup_host, TcpStream};
+
+use crate::proxy::filter::DomainFilter;
+use crate::proxy::upstream::UpstreamProxy;
+use crate::utils::{format_host_port, parse_ip_host};
+
+/// Resolve a target to the addresses the filter allows.
+///
+/// Returns `PermissionDenied` if every resolved address is blocked.
+pub async fn resolve_target(
+    filter: &DomainFilter,
+    host: &str,
+    port: u16,
+) -> io::Result<Vec<SocketAddr>> {
+    let addrs: Vec<SocketAddr> = match parse_ip_host(host) {
+        Some(ip) => vec![SocketAddr::new(ip, port)],
+        None => lookup_host(format_host_port(host, port)).await?.collect(),
+    };
+
+    if addrs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} did not resolve to any address", host),
+        ));
+    }
+
+    let vetted: Vec<SocketAddr> = addrs
+        .into_iter()
+        .filter(|addr| filter.is_address_allowed(addr.ip()))
+        .collect();
+
+    if vetted.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} resolves only to blocked addresses", host),
+        ));
+    }
+
+    Ok(vetted)
+}
+
+/// Pick the host to ask an upstream proxy for.
+///
+/// Without an address policy names are passed on for the upstream to resolve.
+/// Otherwise the target is resolved and vetted locally, and the upstream gets
+/// the vetted address, so it can't resolve the name to a blocked one.
+pub async fn upstream_target(filter: &DomainFilter, host: &str, port: u16) -> io::Result<String> {
+    if !filter.has_address_policy() {
+        return Ok(host.to_string());
+    }
+    let addrs = resolve_target(filter, host, port).await?;
+    Ok(addrs[0].ip().to_string())
+}
+
+/// Resolve a target and connect to the first address the filter allows.
+///
+/// The name is resolved once and the vetted address is connected to directly,
+/// so a rebinding DNS server can't swap in a different address afterwards.
+/// With an upstream proxy, the tunnel is opened through it unless the host is
+/// bypassed; see `upstream_target` for how the address policy still applies.
+/// Returns `PermissionDenied` if every resolved address is blocked.
+pub async fn connect_target(
+    filter: &DomainFilter,
+    upstream: Option<&UpstreamProxy>,
+    host: &str,
+    port: u16,
+) -> io::Result<TcpStream> {
+    if let Some(upstream) = upstream.filter(|upstream| !upstream.bypasses(host)) {
+        let target = upstream_target(filter, host, port).await?;
+        return upstream.connect(&target, port).await;
+    }
+
+    let mut last_error = None;
+    for addr in resolve_target(filter, host, port).await? {
+        match TcpStream::connect(addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => {
+                tracing::debug!("Failed to connect to {} ({}): {}", host, addr, e);
+                last_error = Some(e);
+            }
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| io::Error::other("no address to connect to")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::NetworkConfig;
+
+    #[tokio::test]
+    async fn test_connect_target_private_address_policy() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        // Allowed by default
+        let filter = DomainFilter::allow_all();
+        assert!(connect_target(&filter, None, "127.0.0.1", port).await.is_ok());
+
+        // Blocked for IP literals and for names that resolve to loopback
+        let mut config = NetworkConfig {
+            block_private_addresses: Some(true),
+            ..Default::default()
+        };
+        let filter = DomainFilter::from_config(&config);
+        for host in ["127.0.0.1", "localhost"] {
+            let err = connect_target(&filter, None, host, port).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::PermissionDenied, "{}", host);
+        }
+
+        // Explicit opt-in for the range
+        config.allowed_private_ranges = vec!["127.0.0.0/8".to_string()];
+        let filter = DomainFilter::from_config(&config);
+        assert!(connect_target(&filter, None, "127.0.0.1", port).await.is_ok());
+    }
+}
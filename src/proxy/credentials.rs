@@ -0,0 +1,183 @@
+//! Credential headers injected into proxied HTTP requests.
+//!
+//! Secrets are read on the host when the proxy starts, so the sandboxed
+//! process can use an API without ever seeing its token. Whatever value the
+//! client sends for an injected header is dropped. Only intercepted HTTPS
+//! requests get a secret, unless an entry opts into plain HTTP.
+
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+
+use crate::config::{matches_domain_port_pattern, CredentialConfig};
+use crate::error::SandboxError;
+use crate::utils::expand_home;
+
+/// One entry of `credentials`, with its secret resolved.
+struct Credential {
+    domain: String,
+    header: HeaderName,
+    value: HeaderValue,
+    plain_http: bool,
+}
+
+/// Resolved `credentials`. Deliberately not `Debug`, so secrets can't end up in logs.
+pub struct CredentialInjector {
+    credentials: Vec<Credential>,
+}
+
+impl CredentialInjector {
+    /// Resolve every secret. Returns `None` if no credentials are configured.
+    pub fn from_config(configs: &[CredentialConfig]) -> Result<Option<Self>, SandboxError> {
+        if configs.is_empty() {
+            return Ok(None);
+        }
+        let credentials = configs
+            .iter()
+            .map(Credential::from_config)
+            .collect::<Result<_, _>>()?;
+        Ok(Some(Self { credentials }))
+    }
+
+    /// Replace the credential headers for requests to `host:port`, sent over
+    /// TLS or as plain HTTP. When several entries set the same header, the
+    /// first one wins.
+    pub fn apply(&self, host: &str, port: u16, tls: bool, headers: &mut HeaderMap) {
+        let mut injected: Vec<&HeaderName> = Vec::new();
+        for credential in &self.credentials {
+            if injected.contains(&&credential.header)
+                || !(tls || credential.plain_http)
+                || !matches_domain_port_pattern(host, port, &credential.domain)
+            {
+                continue;
+            }
+            // `insert` drops every value the client sent
+            headers.insert(credential.header.clone(), credential.value.clone());
+            injected.push(&credential.header);
+            tracing::debug!("Injected {} header for {}", credential.header, host);
+        }
+    }
+}
+
+impl Credential {
+    fn from_config(config: &CredentialConfig) -> Result<Self, SandboxError> {
+        let invalid = |reason: String| {
+            SandboxError::Proxy(format!("credentials for '{}': {}", config.domain, reason))
+        };
+
+        let header = HeaderName::from_bytes(config.header.as_bytes())
+            .map_err(|_| invalid(format!("invalid header name '{}'", config.header)))?;
+        let secret = match (&config.env, &config.file) {
+            (Some(name), None) => std::env::var(name)
+                .map_err(|_| invalid(format!("environment variable '{}' is not set", name)))?,
+            (None, Some(path)) => std::fs::read_to_string(expand_home(path))
+                .map_err(|e| invalid(format!("failed to read '{}': {}", path, e)))?,
+            _ => return Err(invalid("set exactly one of 'env' and 'file'".to_string())),
+        };
+        let secret = secret.trim_end_matches(['\r', '\n']);
+        if secret.is_empty() {
+            return Err(invalid("the secret is empty".to_string()));
+        }
+
+        let prefix = config.prefix.as_deref().unwrap_or_default();
+        let mut value = HeaderValue::try_from(format!("{}{}", prefix, secret))
+            .map_err(|_| invalid("the value is not a valid header value".to_string()))?;
+        value.set_sensitive(true);
+
+        Ok(Self {
+            domain: config.domain.clone(),
+            header,
+            value,
+            plain_http: config.allow_plain_http.unwrap_or(false),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn file_credential(
+        domain: &str,
+        header: &str,
+        prefix: Option<&str>,
+        file: &str,
+    ) -> CredentialConfig {
+        CredentialConfig {
+            domain: domain.to_string(),
+            header: header.to_string(),
+            prefix: prefix.map(str::to_string),
+            env: None,
+            file: Some(file.to_string()),
+            allow_plain_http: None,
+        }
+    }
+
+    #[test]
+    fn test_credentials_replace_client_headers() {
+        let mut secret = tempfile::NamedTempFile::new().unwrap();
+        writeln!(secret, "s3cret").unwrap();
+        let path = secret.path().to_str().unwrap();
+        let injector = CredentialInjector::from_config(&[
+            file_credential("api.github.com:443", "Authorization", Some("Bearer "), path),
+            file_credential("*.github.com", "authorization", None, path),
+            file_credential("*.github.com", "X-Api-Key", None, path),
+        ])
+        .unwrap()
+        .unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.append("authorization", HeaderValue::from_static("Bearer forged"));
+        headers.append("authorization", HeaderValue::from_static("Basic Zm9yZ2Vk"));
+        headers.insert("accept", HeaderValue::from_static("*/*"));
+        injector.apply("api.github.com", 443, true, &mut headers);
+
+        let auth: Vec<_> = headers.get_all("authorization").iter().collect();
+        assert_eq!(auth, ["Bearer s3cret"]);
+        assert!(auth[0].is_sensitive());
+        assert_eq!(headers["x-api-key"], "s3cret");
+        assert_eq!(headers["accept"], "*/*");
+
+        // The port-qualified entry doesn't apply to other ports
+        let mut headers = HeaderMap::new();
+        injector.apply("api.github.com", 8443, true, &mut headers);
+        assert_eq!(headers["authorization"], "s3cret");
+
+        // Plain HTTP needs an explicit opt-in
+        let mut headers = HeaderMap::new();
+        injector.apply("api.github.com", 80, false, &mut headers);
+        assert!(headers.is_empty());
+        let mut plain = file_credential("*.github.com", "X-Api-Key", None, path);
+        plain.allow_plain_http = Some(true);
+        let injector = CredentialInjector::from_config(&[plain]).unwrap().unwrap();
+        injector.apply("api.github.com", 80, false, &mut headers);
+        assert_eq!(headers["x-api-key"], "s3cret");
+
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", HeaderValue::from_static("mine"));
+        injector.apply("example.com", 443, true, &mut headers);
+        assert_eq!(headers["authorization"], "mine");
+        assert!(!headers.contains_key("x-api-key"));
+    }
+
+    #[test]
+    fn test_credentials_missing_secret() {
+        assert!(CredentialInjector::from_config(&[]).unwrap().is_none());
+
+        let missing = file_credential("example.com", "Authorization", None, "/nonexistent/token");
+        assert!(CredentialInjector::from_config(&[missing]).is_err());
+
+        let unset = CredentialConfig {
+            domain: "example.com".to_string(),
+            header: "Authorization".to_string(),
+            env: Some("SRT_TEST_CREDENTIAL_NOT_SET".to_string()),
+            ..Default::default()
+        };
+        assert!(CredentialInjector::from_config(&[unset]).is_err());
+
+        let empty = tempfile::NamedTempFile::new().unwrap();
+        let path = empty.path().to_str().unwrap();
+        let empty = file_credential("example.com", "Authorization", None, path);
+        assert!(CredentialInjector::from_config(&[empty]).is_err());
+    }
+}
@@ -0,0 +1,460 @@
+//! DNS forwarder for sandboxed commands.
+//!
+//! The sandbox's only resolver. A and AAAA queries for names the policy
+//! allows are resolved on the host and answered with the addresses the
+//! address policy allows; every other name gets NXDOMAIN, so DNS can't carry
+//! data to arbitrary name servers. Other record types get an empty answer.
+//! With TUN capture, A queries are answered with fake IPs instead.
+//!
+//! Unlike the proxies, the forwarder doesn't authenticate its clients: any
+//! local process reaching its loopback port can resolve the allowed names.
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::task::JoinHandle;
+
+use crate::error::SandboxError;
+use crate::proxy::audit::AuditProtocol;
+use crate::proxy::fake_ip::FakeIpPool;
+use crate::proxy::filter::{FilterDecision, SharedFilter};
+use crate::proxy::policy::{ConnectionRequest, NetworkPolicy};
+use crate::violation::{SandboxViolationEvent, SandboxViolationStore};
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+const RCODE_FORMERR: u8 = 1;
+const RCODE_SERVFAIL: u8 = 2;
+const RCODE_NXDOMAIN: u8 = 3;
+const RCODE_NOTIMP: u8 = 4;
+
+const HEADER_LEN: usize = 12;
+
+/// Largest query accepted; plain DNS over UDP is limited to 512 bytes.
+const MAX_QUERY_SIZE: usize = 512;
+
+/// Answers per response, keeping it under 512 bytes without EDNS.
+const MAX_ANSWERS: usize = 8;
+
+/// TTL of answers, short so that filter updates take effect.
+const ANSWER_TTL_SECS: u32 = 30;
+
+/// Time allowed for a host lookup before answering SERVFAIL.
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// State shared by every query of a DNS forwarder.
+#[derive(Clone)]
+struct ForwarderContext {
+    policy: Arc<dyn NetworkPolicy>,
+    filter: SharedFilter,
+    violations: Option<Arc<SandboxViolationStore>>,
+    fake_ips: Option<Arc<FakeIpPool>>,
+}
+
+/// DNS forwarder listening on a loopback UDP port.
+pub struct DnsForwarder {
+    socket: Option<UdpSocket>,
+    port: u16,
+    policy: Arc<dyn NetworkPolicy>,
+    filter: SharedFilter,
+    violations: Option<Arc<SandboxViolationStore>>,
+    fake_ips: Option<Arc<FakeIpPool>>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl DnsForwarder {
+    /// Create a forwarder that answers for the names `policy` allows. It is
+    /// asked with [`AuditProtocol::Dns`] and port 0.
+    pub async fn new(policy: impl NetworkPolicy) -> Result<Self, SandboxError> {
+        let socket = UdpSocket::bind("127.0.0.1:0").await?;
+        let port = socket.local_addr()?.port();
+
+        tracing::debug!("DNS forwarder listening on port {}", port);
+
+        let (policy, filter) = policy.into_shared();
+        Ok(Self {
+            socket: Some(socket),
+            port,
+            policy,
+            filter,
+            violations: None,
+            fake_ips: None,
+            task: None,
+        })
+    }
+
+    /// Get the UDP port the forwarder is listening on.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Record refused lookups as violations.
+    /// Must be called before [`DnsForwarder::start`].
+    pub fn set_violations(&mut self, violations: Option<Arc<SandboxViolationStore>>) {
+        self.violations = violations;
+    }
+
+    /// Answer A queries with addresses from `fake_ips`, for TUN capture.
+    /// AAAA queries get an empty answer, keeping clients on IPv4.
+    /// Must be called before [`DnsForwarder::start`].
+    pub fn set_fake_ips(&mut self, fake_ips: Option<Arc<FakeIpPool>>) {
+        self.fake_ips = fake_ips;
+    }
+
+    /// Start answering queries.
+    pub fn start(&mut self) -> Result<(), SandboxError> {
+        let socket = self
+            .socket
+            .take()
+            .ok_or_else(|| SandboxError::Proxy("DNS forwarder already started".to_string()))?;
+        let socket = Arc::new(socket);
+        let context = ForwarderContext {
+            policy: self.policy.clone(),
+            filter: self.filter.clone(),
+            violations: self.violations.clone(),
+            fake_ips: self.fake_ips.clone(),
+        };
+
+        self.task = Some(tokio::spawn(async move {
+            let mut buf = vec![0u8; MAX_QUERY_SIZE];
+            loop {
+                let (len, client) = match socket.recv_from(&mut buf).await {
+                    Ok(received) => received,
+                    Err(e) => {
+                        tracing::debug!("DNS forwarder receive error: {}", e);
+                        continue;
+                    }
+                };
+                let query = buf[..len].to_vec();
+                let socket = socket.clone();
+                let context = context.clone();
+                tokio::spawn(async move {
+                    if let Some(response) = answer(&query, client, &context).await {
+                        if let Err(e) = socket.send_to(&response, client).await {
+                            tracing::debug!("DNS forwarder send error to {}: {}", client, e);
+                        }
+                    }
+                });
+            }
+        }));
+
+        Ok(())
+    }
+
+    /// Stop answering queries.
+    pub fn stop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+    }
+}
+
+impl Drop for DnsForwarder {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// A parsed single-question query.
+#[derive(Debug, PartialEq, Eq)]
+struct Question {
+    name: String,
+    qtype: u16,
+    qclass: u16,
+    /// End of the question section in the query.
+    end: usize,
+}
+
+/// Parse the question of a standard query. Compression pointers are not
+/// expected in queries and are rejected.
+fn parse_question(query: &[u8]) -> Option<Question> {
+    let mut labels = Vec::new();
+    let mut pos = HEADER_LEN;
+    loop {
+        let len = *query.get(pos)? as usize;
+        pos += 1;
+        if len == 0 {
+            break;
+        }
+        if len > 63 {
+            return None;
+        }
+        let label = query.get(pos..pos + len)?;
+        if !label.iter().all(|b| b.is_ascii_alphanumeric() || *b == b'-' || *b == b'_') {
+            return None;
+        }
+        labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+        pos += len;
+    }
+
+    let fixed = query.get(pos..pos + 4)?;
+    Some(Question {
+        name: labels.join("."),
+        qtype: u16::from_be_bytes([fixed[0], fixed[1]]),
+        qclass: u16::from_be_bytes([fixed[2], fixed[3]]),
+        end: pos + 4,
+    })
+}
+
+/// Answer a query, or None if it isn't worth a response.
+async fn answer(
+    query: &[u8],
+    client: SocketAddr,
+    context: &ForwarderContext,
+) -> Option<Vec<u8>> {
+    if query.len() < HEADER_LEN || query[2] & 0x80 != 0 {
+        // Too short to answer, or a response
+        return None;
+    }
+
+    let opcode = (query[2] >> 3) & 0x0f;
+    let qdcount = u16::from_be_bytes([query[4], query[5]]);
+    if opcode != 0 {
+        return Some(response(query, None, RCODE_NOTIMP, &[]));
+    }
+    let question = match parse_question(query) {
+        Some(question) if qdcount == 1 && !question.name.is_empty() => question,
+        _ => return Some(response(query, None, RCODE_FORMERR, &[])),
+    };
+
+    let request = ConnectionRequest::new(AuditProtocol::Dns, &question.name, 0, client);
+    if context.policy.decide(&request).await.0 != FilterDecision::Allow {
+        let line = format!("DNS lookup refused: {} is not allowed", question.name);
+        tracing::debug!("{}", line);
+        if let Some(violations) = &context.violations {
+            violations.add_violation(SandboxViolationEvent::new(line));
+        }
+        return Some(response(query, Some(&question), RCODE_NXDOMAIN, &[]));
+    }
+
+    if question.qclass != CLASS_IN || !matches!(question.qtype, TYPE_A | TYPE_AAAA) {
+        return Some(response(query, Some(&question), 0, &[]));
+    }
+
+    // The SOCKS5 proxy maps fake IPs back to the name
+    if let Some(fake_ips) = &context.fake_ips {
+        let ips = match question.qtype {
+            TYPE_A => vec![IpAddr::V4(fake_ips.assign(&question.name))],
+            _ => vec![],
+        };
+        return Some(response(query, Some(&question), 0, &ips));
+    }
+
+    let lookup = tokio::time::timeout(LOOKUP_TIMEOUT, lookup_host((question.name.as_str(), 0)));
+    let addrs: Vec<SocketAddr> = match lookup.await {
+        Ok(Ok(addrs)) => addrs.collect(),
+        Ok(Err(e)) => {
+            tracing::debug!("DNS lookup of {} failed: {}", question.name, e);
+            return Some(response(query, Some(&question), RCODE_SERVFAIL, &[]));
+        }
+        Err(_) => {
+            tracing::debug!("DNS lookup of {} timed out", question.name);
+            return Some(response(query, Some(&question), RCODE_SERVFAIL, &[]));
+        }
+    };
+
+    // Vet the addresses as the proxies would before connecting
+    let filter = context.filter.current();
+    let mut ips: Vec<IpAddr> = Vec::new();
+    for ip in addrs.into_iter().map(|addr| addr.ip()) {
+        let wanted = match ip {
+            IpAddr::V4(_) => question.qtype == TYPE_A,
+            IpAddr::V6(_) => question.qtype == TYPE_AAAA,
+        };
+        if wanted && filter.is_address_allowed(ip) && !ips.contains(&ip) {
+            ips.push(ip);
+        }
+    }
+    ips.truncate(MAX_ANSWERS);
+
+    Some(response(query, Some(&question), 0, &ips))
+}
+
+/// Build a response to `query`, echoing its question and answering it with
+/// `ips`.
+fn response(query: &[u8], question: Option<&Question>, rcode: u8, ips: &[IpAddr]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(MAX_QUERY_SIZE);
+    out.extend_from_slice(&query[..2]);
+    // QR, the query's opcode and RD; RA
+    out.push(0x80 | (query[2] & 0x79));
+    out.push(0x80 | rcode);
+    out.extend_from_slice(&u16::from(question.is_some()).to_be_bytes());
+    out.extend_from_slice(&(ips.len() as u16).to_be_bytes());
+    out.extend_from_slice(&[0, 0, 0, 0]);
+
+    let Some(question) = question else {
+        return out;
+    };
+    out.extend_from_slice(&query[HEADER_LEN..question.end]);
+    for ip in ips {
+        // Name as a pointer to the question
+        out.extend_from_slice(&[0xc0, HEADER_LEN as u8]);
+        let (rtype, rdata) = match ip {
+            IpAddr::V4(ip) => (TYPE_A, ip.octets().to_vec()),
+            IpAddr::V6(ip) => (TYPE_AAAA, ip.octets().to_vec()),
+        };
+        out.extend_from_slice(&rtype.to_be_bytes());
+        out.extend_from_slice(&CLASS_IN.to_be_bytes());
+        out.extend_from_slice(&ANSWER_TTL_SECS.to_be_bytes());
+        out.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        out.extend_from_slice(&rdata);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::NetworkConfig;
+    use crate::proxy::filter::DomainFilter;
+    use crate::utils::IpCidr;
+
+    fn query(id: u16, name: &str, qtype: u16) -> Vec<u8> {
+        let mut query = id.to_be_bytes().to_vec();
+        query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+        for label in name.split('.') {
+            query.push(label.len() as u8);
+            query.extend_from_slice(label.as_bytes());
+        }
+        query.push(0);
+        query.extend_from_slice(&qtype.to_be_bytes());
+        query.extend_from_slice(&CLASS_IN.to_be_bytes());
+        query
+    }
+
+    /// Send a query to the forwarder and return the rcode and answered IPv4 addresses.
+    async fn resolve(port: u16, query: &[u8]) -> (u8, Vec<IpAddr>) {
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(query, ("127.0.0.1", port)).await.unwrap();
+        let mut buf = [0u8; 512];
+        let len = tokio::time::timeout(Duration::from_secs(5), client.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let response = &buf[..len];
+
+        assert_eq!(response[..2], query[..2]);
+        assert_eq!(response[2] & 0x80, 0x80);
+        let ancount = u16::from_be_bytes([response[6], response[7]]) as usize;
+        let question_end = parse_question(response).map_or(HEADER_LEN, |q| q.end);
+        let ips = response[question_end..]
+            .chunks(16)
+            .take(ancount)
+            .map(|answer| IpAddr::from([answer[12], answer[13], answer[14], answer[15]]))
+            .collect();
+        (response[3] & 0x0f, ips)
+    }
+
+    #[test]
+    fn test_parse_question() {
+        let question = parse_question(&query(1, "API.Example.com", TYPE_AAAA)).unwrap();
+        assert_eq!(question.name, "api.example.com");
+        assert_eq!(question.qtype, TYPE_AAAA);
+
+        // Compression pointers, bad characters and truncation
+        let mut pointer = query(1, "example.com", TYPE_A);
+        pointer[HEADER_LEN] = 0xc0;
+        assert!(parse_question(&pointer).is_none());
+        assert!(parse_question(&query(1, "ex ample.com", TYPE_A)).is_none());
+        let full = query(1, "example.com", TYPE_A);
+        assert!(parse_question(&full[..full.len() - 1]).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_dns_forwarder() {
+        let config = NetworkConfig {
+            allowed_domains: vec!["localhost".to_string()],
+            ..Default::default()
+        };
+        let filter = SharedFilter::new(DomainFilter::from_config(&config));
+        let violations = Arc::new(SandboxViolationStore::new());
+        let mut forwarder = DnsForwarder::new(filter.clone()).await.unwrap();
+        forwarder.set_violations(Some(violations.clone()));
+        forwarder.start().unwrap();
+        let port = forwarder.port();
+
+        let (rcode, ips) = resolve(port, &query(7, "localhost", TYPE_A)).await;
+        assert_eq!(rcode, 0);
+        assert_eq!(ips, vec![IpAddr::from([127, 0, 0, 1])]);
+
+        // Other names and types
+        let (rcode, ips) = resolve(port, &query(8, "evil.example.com", TYPE_A)).await;
+        assert_eq!((rcode, ips.len()), (RCODE_NXDOMAIN, 0));
+        assert_eq!(violations.get_count(), 1);
+        let (rcode, ips) = resolve(port, &query(9, "localhost", 16)).await;
+        assert_eq!((rcode, ips.len()), (0, 0));
+        let (rcode, _) = resolve(port, &query(10, "ex ample.com", TYPE_A)).await;
+        assert_eq!(rcode, RCODE_FORMERR);
+
+        // Blocked addresses are left out of answers
+        let config = NetworkConfig {
+            block_private_addresses: Some(true),
+            ..config
+        };
+        filter.update(DomainFilter::from_config(&config), false);
+        let (rcode, ips) = resolve(port, &query(11, "localhost", TYPE_A)).await;
+        assert_eq!((rcode, ips.len()), (0, 0));
+
+        forwarder.stop();
+    }
+
+    #[tokio::test]
+    async fn test_dns_forwarder_fake_ips() {
+        let config = NetworkConfig {
+            allowed_domains: vec!["*.example.com".to_string()],
+            ..Default::default()
+        };
+        let filter = SharedFilter::new(DomainFilter::from_config(&config));
+        let fake_ips = Arc::new(FakeIpPool::new(IpCidr::parse("10.99.0.0/24").unwrap()).unwrap());
+        let mut forwarder = DnsForwarder::new(filter).await.unwrap();
+        forwarder.set_fake_ips(Some(fake_ips.clone()));
+        forwarder.start().unwrap();
+        let port = forwarder.port();
+
+        // Allowed names get a fake IP without a lookup on the host
+        let (rcode, ips) = resolve(port, &query(1, "api.example.com", TYPE_A)).await;
+        assert_eq!((rcode, ips), (0, vec![IpAddr::from([10, 99, 0, 1])]));
+        assert_eq!(fake_ips.translate("10.99.0.1").unwrap(), "api.example.com");
+        let (rcode, ips) = resolve(port, &query(2, "api.example.com", TYPE_AAAA)).await;
+        assert_eq!((rcode, ips.len()), (0, 0));
+        let (rcode, _) = resolve(port, &query(3, "evil.com", TYPE_A)).await;
+        assert_eq!(rcode, RCODE_NXDOMAIN);
+        assert!(fake_ips.translate("10.99.0.2").is_err());
+    }
+
+    /// Allows only names in its list.
+    struct NamePolicy(Vec<&'static str>);
+
+    impl NetworkPolicy for NamePolicy {
+        fn decide<'a>(
+            &'a self,
+            request: &'a ConnectionRequest,
+        ) -> futures::future::BoxFuture<'a, (FilterDecision, crate::proxy::FilterRule)> {
+            let decision = match self.0.contains(&request.host.as_str()) {
+                true => FilterDecision::Allow,
+                false => FilterDecision::Deny,
+            };
+            assert_eq!((request.protocol, request.port), (AuditProtocol::Dns, 0));
+            let rule = crate::proxy::FilterRule::Policy("names".to_string());
+            Box::pin(std::future::ready((decision, rule)))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dns_forwarder_custom_policy() {
+        let mut forwarder = DnsForwarder::new(NamePolicy(vec!["localhost"])).await.unwrap();
+        forwarder.start().unwrap();
+        let port = forwarder.port();
+
+        let (rcode, ips) = resolve(port, &query(1, "localhost", TYPE_A)).await;
+        assert_eq!((rcode, ips), (0, vec![IpAddr::from([127, 0, 0, 1])]));
+
+        // The policy decides even though its filter allows everything
+        let (rcode, ips) = resolve(port, &query(2, "example.com", TYPE_A)).await;
+        assert_eq!((rcode, ips.len()), (RCODE_NXDOMAIN, 0));
+    }
+}
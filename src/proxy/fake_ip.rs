@@ -0,0 +1,123 @@
+//! Fake IP addresses for names resolved in the sandbox.
+//!
+//! With TUN capture, connections reach the SOCKS5 proxy by address only. The
+//! DNS forwarder answers each allowed name with an address from a reserved
+//! range, and the proxy maps it back so the filter still decides by name.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+
+use parking_lot::Mutex;
+
+use crate::config::TunCaptureConfig;
+use crate::error::{ConfigError, SandboxError};
+use crate::utils::{parse_ip_host, IpCidr};
+
+/// Default range, reserved for benchmarking (RFC 2544) and never routed.
+const DEFAULT_RANGE: &str = "198.18.0.0/15";
+
+#[derive(Default)]
+struct Assignments {
+    by_name: HashMap<String, Ipv4Addr>,
+    by_ip: HashMap<Ipv4Addr, String>,
+    /// Offset of the next address to hand out.
+    next: u32,
+}
+
+/// Pool of fake IPv4 addresses, each standing for one name.
+pub struct FakeIpPool {
+    range: IpCidr,
+    base: u32,
+    size: u32,
+    assignments: Mutex<Assignments>,
+}
+
+impl FakeIpPool {
+    /// Create a pool over an IPv4 range. The network and broadcast addresses
+    /// are not handed out.
+    pub fn new(range: IpCidr) -> Option<Self> {
+        let IpAddr::V4(base) = range.addr() else {
+            return None;
+        };
+        let size = 1u32.checked_shl(32 - range.prefix_len() as u32)?.checked_sub(2)?;
+        if size == 0 {
+            return None;
+        }
+
+        Some(Self {
+            range,
+            base: u32::from(base) + 1,
+            size,
+            assignments: Mutex::new(Assignments::default()),
+        })
+    }
+
+    /// Create a pool from `tunCapture.fakeIpRange`.
+    pub fn from_config(config: &TunCaptureConfig) -> Result<Self, SandboxError> {
+        let range = config.fake_ip_range.as_deref().unwrap_or(DEFAULT_RANGE);
+        IpCidr::parse(range).and_then(Self::new).ok_or_else(|| {
+            ConfigError::ValidationError(format!("invalid fake IP range '{}'", range)).into()
+        })
+    }
+
+    /// Get the address standing for `name`, assigning one if needed. Once the
+    /// range is used up, the oldest assignments are reused.
+    pub fn assign(&self, name: &str) -> Ipv4Addr {
+        let mut assignments = self.assignments.lock();
+        if let Some(ip) = assignments.by_name.get(name) {
+            return *ip;
+        }
+
+        let ip = Ipv4Addr::from(self.base + assignments.next);
+        assignments.next = (assignments.next + 1) % self.size;
+        if let Some(previous) = assignments.by_ip.insert(ip, name.to_string()) {
+            assignments.by_name.remove(&previous);
+        }
+        assignments.by_name.insert(name.to_string(), ip);
+        ip
+    }
+
+    /// Map a fake IP back to its name. Other hosts are returned unchanged;
+    /// fake IPs that stand for no name are an error.
+    pub fn translate(&self, host: &str) -> io::Result<String> {
+        let ip = match parse_ip_host(host) {
+            Some(IpAddr::V4(ip)) if self.range.contains(IpAddr::V4(ip)) => ip,
+            _ => return Ok(host.to_string()),
+        };
+
+        self.assignments.lock().by_ip.get(&ip).cloned().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} is a fake IP that was not handed out", ip),
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fake_ip_pool() {
+        let pool = FakeIpPool::new(IpCidr::parse("10.99.0.0/30").unwrap()).unwrap();
+
+        let github = pool.assign("github.com");
+        assert_eq!(github, Ipv4Addr::new(10, 99, 0, 1));
+        assert_eq!(pool.assign("github.com"), github);
+        assert_eq!(pool.translate("10.99.0.1").unwrap(), "github.com");
+        assert_eq!(pool.translate("example.com").unwrap(), "example.com");
+        assert_eq!(pool.translate("1.2.3.4").unwrap(), "1.2.3.4");
+        assert!(pool.translate("10.99.0.2").is_err());
+
+        // The range holds two names; the oldest is reused
+        assert_eq!(pool.assign("npmjs.org"), Ipv4Addr::new(10, 99, 0, 2));
+        assert_eq!(pool.assign("crates.io"), github);
+        assert_eq!(pool.translate("10.99.0.1").unwrap(), "crates.io");
+        assert_ne!(pool.assign("github.com"), github);
+
+        assert!(FakeIpPool::new(IpCidr::parse("10.99.0.0/31").unwrap()).is_none());
+        assert!(FakeIpPool::new(IpCidr::parse("fd00::/64").unwrap()).is_none());
+    }
+}
@@ -1,9 +1,23 @@
 //! Domain filtering logic for proxy servers.
 
-use crate::config::{matches_domain_pattern, NetworkConfig};
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use serde::Serialize;
+use tokio::sync::watch;
+
+use crate::config::{
+    matches_domain_pattern, matches_domain_port_pattern, split_domain_pattern, NetworkConfig,
+    PortRange,
+};
+use crate::proxy::ask::AskAnswer;
+use crate::proxy::http_rules::{HttpRuleDenial, HttpRules};
+use crate::proxy::policy::{ConnectionRequest, NetworkPolicy};
+use crate::utils::{is_private_address, parse_ip_host, IpCidr};
 
 /// Filter decision for a domain.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum FilterDecision {
     /// Allow the connection.
     Allow,
@@ -11,6 +25,73 @@ pub enum FilterDecision {
     Deny,
     /// Route through MITM proxy.
     Mitm,
+    /// Decrypt and inspect with the built-in TLS interceptor.
+    Intercept,
+    /// Hold the connection and ask a controller (see [`crate::proxy::AskBroker`]).
+    Ask,
+}
+
+/// The rule that produced a filter decision.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilterRule {
+    /// Matched a `deniedDomains` pattern.
+    DeniedDomain(String),
+    /// Matched a `deniedCidrs` network.
+    DeniedCidr(IpCidr),
+    /// The port is not in `allowedPorts`.
+    PortNotAllowed,
+    /// Matched a `mitmProxy.domains` pattern.
+    MitmDomain(String),
+    /// Matched a `tlsIntercept.domains` pattern.
+    InterceptDomain(String),
+    /// Matched an `allowedDomains` pattern.
+    AllowedDomain(String),
+    /// Matched an `allowedCidrs` network.
+    AllowedCidr(IpCidr),
+    /// An allow list is configured and nothing in it matched.
+    NotAllowed,
+    /// No allow list is configured.
+    Default,
+    /// The resolved address is blocked by `deniedCidrs` or `blockPrivateAddresses`.
+    BlockedAddress,
+    /// The tunnel's TLS SNI is missing or disagrees with the CONNECT host.
+    TlsSni,
+    /// The request matched the `httpRules` deny rule at this index.
+    HttpRule(usize),
+    /// The domain has `httpRules` allow rules and none matched the request.
+    HttpRuleNotAllowed,
+    /// The domain has `httpRules`, which can't be enforced on this connection.
+    HttpRulesUninspected,
+    /// Decided by the controller's answer to an ask request.
+    Asked(AskAnswer),
+    /// An ask request timed out or nobody was listening.
+    AskUnanswered,
+    /// Decided by a custom [`crate::proxy::NetworkPolicy`], with its explanation.
+    Policy(String),
+}
+
+impl std::fmt::Display for FilterRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::DeniedDomain(pattern) => write!(f, "deniedDomains:{}", pattern),
+            Self::DeniedCidr(cidr) => write!(f, "deniedCidrs:{}", cidr),
+            Self::PortNotAllowed => write!(f, "allowedPorts"),
+            Self::MitmDomain(pattern) => write!(f, "mitmProxy.domains:{}", pattern),
+            Self::InterceptDomain(pattern) => write!(f, "tlsIntercept.domains:{}", pattern),
+            Self::AllowedDomain(pattern) => write!(f, "allowedDomains:{}", pattern),
+            Self::AllowedCidr(cidr) => write!(f, "allowedCidrs:{}", cidr),
+            Self::NotAllowed => write!(f, "notAllowed"),
+            Self::Default => write!(f, "default"),
+            Self::BlockedAddress => write!(f, "blockedAddress"),
+            Self::TlsSni => write!(f, "tlsSni"),
+            Self::HttpRule(index) => write!(f, "httpRules[{}]", index),
+            Self::HttpRuleNotAllowed => write!(f, "httpRules:notAllowed"),
+            Self::HttpRulesUninspected => write!(f, "httpRules:uninspected"),
+            Self::Asked(answer) => write!(f, "ask:{}", answer),
+            Self::AskUnanswered => write!(f, "ask:unanswered"),
+            Self::Policy(reason) => write!(f, "policy:{}", reason),
+        }
+    }
 }
 
 /// Domain filter for proxy connections.
@@ -19,6 +100,16 @@ pub struct DomainFilter {
     allowed_domains: Vec<String>,
     denied_domains: Vec<String>,
     mitm_domains: Vec<String>,
+    intercept_domains: Vec<String>,
+    allowed_ports: Vec<PortRange>,
+    allowed_cidrs: Vec<IpCidr>,
+    denied_cidrs: Vec<IpCidr>,
+    block_private_addresses: bool,
+    allowed_private_ranges: Vec<IpCidr>,
+    verify_tls_sni: bool,
+    non_tls_ports: Vec<PortRange>,
+    http_rules: HttpRules,
+    ask_unknown: bool,
 }
 
 impl DomainFilter {
@@ -30,10 +121,33 @@ impl DomainFilter {
             .map(|m| m.domains.clone())
             .unwrap_or_default();
 
+        // Invalid entries are rejected by config validation
+        let tls_sni = config.tls_sni.as_ref();
+        let parse_ports = |specs: &[String]| -> Vec<PortRange> {
+            specs.iter().filter_map(|spec| PortRange::parse(spec)).collect()
+        };
+        let parse_cidrs = |cidrs: &[String]| -> Vec<IpCidr> {
+            cidrs.iter().filter_map(|cidr| IpCidr::parse(cidr)).collect()
+        };
+
         Self {
             allowed_domains: config.allowed_domains.clone(),
             denied_domains: config.denied_domains.clone(),
             mitm_domains,
+            intercept_domains: config
+                .tls_intercept
+                .as_ref()
+                .map(|i| i.domains.clone())
+                .unwrap_or_default(),
+            allowed_ports: parse_ports(&config.allowed_ports),
+            allowed_cidrs: parse_cidrs(&config.allowed_cidrs),
+            denied_cidrs: parse_cidrs(&config.denied_cidrs),
+            block_private_addresses: config.block_private_addresses.unwrap_or(false),
+            allowed_private_ranges: parse_cidrs(&config.allowed_private_ranges),
+            verify_tls_sni: tls_sni.and_then(|sni| sni.enabled).unwrap_or(false),
+            non_tls_ports: tls_sni.map(|sni| parse_ports(&sni.non_tls_ports)).unwrap_or_default(),
+            http_rules: HttpRules::from_config(&config.http_rules),
+            ask_unknown: config.ask_unknown_domains.is_some(),
         }
     }
 
@@ -43,49 +157,169 @@ impl DomainFilter {
             allowed_domains: vec![],
             denied_domains: vec![],
             mitm_domains: vec![],
+            intercept_domains: vec![],
+            allowed_ports: vec![],
+            allowed_cidrs: vec![],
+            denied_cidrs: vec![],
+            block_private_addresses: false,
+            allowed_private_ranges: vec![],
+            verify_tls_sni: false,
+            non_tls_ports: vec![],
+            http_rules: HttpRules::default(),
+            ask_unknown: false,
         }
     }
 
-    /// Check if a domain should be allowed, denied, or routed through MITM.
-    pub fn check(&self, hostname: &str, _port: u16) -> FilterDecision {
-        // Check denied list first (highest priority)
+    /// Check if a domain should be allowed, denied, routed through MITM, intercepted,
+    /// or asked about.
+    /// Patterns may be port-qualified ("github.com:443", "*.corp:8080-8090").
+    /// IP literals (with or without IPv6 brackets) are also checked against CIDR rules.
+    pub fn check(&self, hostname: &str, port: u16) -> FilterDecision {
+        self.evaluate(hostname, port).0
+    }
+
+    /// Like [`DomainFilter::check`], but also returns the rule that decided.
+    pub fn evaluate(&self, hostname: &str, port: u16) -> (FilterDecision, FilterRule) {
+        // Match IP literals in canonical form, so "[::1]" and "0:0::1" agree
+        let ip = parse_ip_host(hostname);
+        let canonical = ip.map(|ip| ip.to_string());
+        let hostname = canonical.as_deref().unwrap_or(hostname);
+
+        // Check denied lists first (highest priority)
         for pattern in &self.denied_domains {
-            if matches_domain_pattern(hostname, pattern) {
-                return FilterDecision::Deny;
+            if matches_domain_port_pattern(hostname, port, pattern) {
+                return (FilterDecision::Deny, FilterRule::DeniedDomain(pattern.clone()));
+            }
+        }
+        if let Some(ip) = ip {
+            if let Some(cidr) = self.denied_cidrs.iter().find(|cidr| cidr.contains(ip)) {
+                return (FilterDecision::Deny, FilterRule::DeniedCidr(*cidr));
             }
         }
 
+        // Check global port restriction
+        if !self.allowed_ports.is_empty()
+            && !self.allowed_ports.iter().any(|range| range.contains(port))
+        {
+            return (FilterDecision::Deny, FilterRule::PortNotAllowed);
+        }
+
         // Check MITM list
         for pattern in &self.mitm_domains {
-            if matches_domain_pattern(hostname, pattern) {
-                return FilterDecision::Mitm;
+            if matches_domain_port_pattern(hostname, port, pattern) {
+                return (FilterDecision::Mitm, FilterRule::MitmDomain(pattern.clone()));
+            }
+        }
+
+        // Check TLS interception list
+        for pattern in &self.intercept_domains {
+            if matches_domain_port_pattern(hostname, port, pattern) {
+                return (FilterDecision::Intercept, FilterRule::InterceptDomain(pattern.clone()));
             }
         }
 
         // If we have an allow list, check against it
-        if !self.allowed_domains.is_empty() {
+        if !self.allowed_domains.is_empty() || !self.allowed_cidrs.is_empty() {
             for pattern in &self.allowed_domains {
-                if matches_domain_pattern(hostname, pattern) {
-                    return FilterDecision::Allow;
+                if matches_domain_port_pattern(hostname, port, pattern) {
+                    return (FilterDecision::Allow, FilterRule::AllowedDomain(pattern.clone()));
+                }
+            }
+            if let Some(ip) = ip {
+                if let Some(cidr) = self.allowed_cidrs.iter().find(|cidr| cidr.contains(ip)) {
+                    return (FilterDecision::Allow, FilterRule::AllowedCidr(*cidr));
                 }
             }
-            // Not in allow list = denied
-            return FilterDecision::Deny;
+            // Not in allow list = denied, unless a controller is asked
+            if self.ask_unknown {
+                return (FilterDecision::Ask, FilterRule::NotAllowed);
+            }
+            return (FilterDecision::Deny, FilterRule::NotAllowed);
         }
 
         // No allow list = allow all (except denied)
-        FilterDecision::Allow
+        (FilterDecision::Allow, FilterRule::Default)
+    }
+
+    /// Check whether CONNECT tunnels to this port must start with a TLS ClientHello.
+    pub fn requires_tls_sni(&self, port: u16) -> bool {
+        self.verify_tls_sni && !self.non_tls_ports.iter().any(|range| range.contains(port))
+    }
+
+    /// Check whether `httpRules` apply to a destination. Connections to it
+    /// must be readable by the proxy (plain HTTP or intercepted HTTPS).
+    pub fn has_http_rules(&self, hostname: &str, port: u16) -> bool {
+        self.http_rules.applies_to(hostname, port)
+    }
+
+    /// Check an HTTP request's method and path against `httpRules`.
+    pub fn check_request(
+        &self,
+        hostname: &str,
+        port: u16,
+        method: &str,
+        path: &str,
+    ) -> Result<(), HttpRuleDenial> {
+        self.http_rules.check(hostname, port, method, path)
+    }
+
+    /// Check if a resolved destination address may be connected to.
+    /// Applies denied CIDRs and the private-address policy, so an allowed
+    /// hostname can't be pointed at internal services via DNS.
+    pub fn is_address_allowed(&self, ip: IpAddr) -> bool {
+        if self.denied_cidrs.iter().any(|cidr| cidr.contains(ip)) {
+            return false;
+        }
+
+        if self.block_private_addresses && is_private_address(ip) {
+            return self
+                .allowed_private_ranges
+                .iter()
+                .any(|cidr| cidr.contains(ip));
+        }
+
+        true
+    }
+
+    /// Whether any destination address can be refused by `is_address_allowed`.
+    pub fn has_address_policy(&self) -> bool {
+        self.block_private_addresses || !self.denied_cidrs.is_empty()
     }
 
     /// Check if a domain is allowed.
     pub fn is_allowed(&self, hostname: &str, port: u16) -> bool {
-        matches!(self.check(hostname, port), FilterDecision::Allow | FilterDecision::Mitm)
+        !matches!(self.check(hostname, port), FilterDecision::Deny)
+    }
+
+    /// Check if a name may be resolved for the sandbox, i.e. some port of it
+    /// can be connected to. Port-qualified denials leave the name resolvable.
+    /// Names that would be asked about are not resolved.
+    pub fn allows_name(&self, hostname: &str) -> bool {
+        let matches = |pattern: &String| {
+            matches_domain_pattern(hostname, split_domain_pattern(pattern).0)
+        };
+        let denied = self
+            .denied_domains
+            .iter()
+            .any(|pattern| split_domain_pattern(pattern).1.is_none() && matches(pattern));
+        if denied {
+            return false;
+        }
+
+        if self.allowed_domains.is_empty() && self.allowed_cidrs.is_empty() {
+            return true;
+        }
+        self.allowed_domains
+            .iter()
+            .chain(&self.mitm_domains)
+            .chain(&self.intercept_domains)
+            .any(matches)
     }
 
-    /// Check if a domain should be routed through MITM.
+    /// Check if a domain should be routed through MITM (on any port).
     pub fn should_mitm(&self, hostname: &str) -> bool {
         for pattern in &self.mitm_domains {
-            if matches_domain_pattern(hostname, pattern) {
+            if matches_domain_pattern(hostname, split_domain_pattern(pattern).0) {
                 return true;
             }
         }
@@ -93,6 +327,122 @@ impl DomainFilter {
     }
 }
 
+/// A filter snapshot published to the proxies.
+#[derive(Debug, Clone)]
+struct FilterUpdate {
+    filter: Arc<DomainFilter>,
+    close_revoked: bool,
+}
+
+/// Hot-swappable domain filter shared by the proxy servers.
+///
+/// New connections always see the most recently published filter. Open
+/// tunnels hold a [`FilterWatch`] and are torn down when an update that asks
+/// for it no longer allows their destination.
+#[derive(Debug, Clone)]
+pub struct SharedFilter {
+    tx: Arc<watch::Sender<FilterUpdate>>,
+}
+
+impl SharedFilter {
+    /// Create a shared filter with an initial policy.
+    pub fn new(filter: DomainFilter) -> Self {
+        let (tx, _rx) = watch::channel(FilterUpdate {
+            filter: Arc::new(filter),
+            close_revoked: false,
+        });
+        Self { tx: Arc::new(tx) }
+    }
+
+    /// Get the current filter.
+    pub fn current(&self) -> Arc<DomainFilter> {
+        self.tx.borrow().filter.clone()
+    }
+
+    /// Replace the filter. New connections use it immediately.
+    /// If `close_revoked` is true, open tunnels whose destination is no
+    /// longer allowed are closed.
+    pub fn update(&self, filter: DomainFilter, close_revoked: bool) {
+        self.tx.send_replace(FilterUpdate {
+            filter: Arc::new(filter),
+            close_revoked,
+        });
+    }
+
+    /// Subscribe to filter updates.
+    /// The returned watch starts at the current filter.
+    pub fn subscribe(&self) -> FilterWatch {
+        let mut rx = self.tx.subscribe();
+        let filter = rx.borrow_and_update().filter.clone();
+        FilterWatch {
+            rx,
+            filter,
+            policy: None,
+        }
+    }
+}
+
+impl From<DomainFilter> for SharedFilter {
+    fn from(filter: DomainFilter) -> Self {
+        Self::new(filter)
+    }
+}
+
+/// Per-connection view of a [`SharedFilter`].
+pub struct FilterWatch {
+    rx: watch::Receiver<FilterUpdate>,
+    filter: Arc<DomainFilter>,
+    /// Asked again instead of the filter when an update closes revoked connections.
+    policy: Option<(Arc<dyn NetworkPolicy>, ConnectionRequest)>,
+}
+
+impl std::fmt::Debug for FilterWatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FilterWatch")
+            .field("filter", &self.filter)
+            .field("request", &self.policy.as_ref().map(|(_, request)| request))
+            .finish_non_exhaustive()
+    }
+}
+
+impl FilterWatch {
+    /// Get the filter this connection was admitted under.
+    pub fn filter(&self) -> &DomainFilter {
+        &self.filter
+    }
+
+    /// Decide revocations by asking `policy` about `request` again, so that
+    /// custom policies can close open connections on an update too.
+    pub fn decided_by(mut self, policy: Arc<dyn NetworkPolicy>, request: ConnectionRequest) -> Self {
+        self.policy = Some((policy, request));
+        self
+    }
+
+    /// Wait until a filter update revokes access to `hostname:port`, or
+    /// denies the request given to [`FilterWatch::decided_by`]. Destinations
+    /// the update leaves to be asked about count as revoked, since nothing
+    /// allows them anymore. Never resolves if the shared filter is dropped.
+    pub async fn revoked(&mut self, hostname: &str, port: u16) {
+        loop {
+            if self.rx.changed().await.is_err() {
+                std::future::pending::<()>().await;
+            }
+
+            let update = self.rx.borrow_and_update().clone();
+            if !update.close_revoked {
+                continue;
+            }
+            let decision = match &self.policy {
+                Some((policy, request)) => policy.decide(request).await.0,
+                None => update.filter.check(hostname, port),
+            };
+            if matches!(decision, FilterDecision::Deny | FilterDecision::Ask) {
+                return;
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -110,6 +460,7 @@ mod tests {
             allowed_domains: vec!["github.com".to_string(), "*.npmjs.org".to_string()],
             denied_domains: vec![],
             mitm_domains: vec![],
+            ..DomainFilter::allow_all()
         };
 
         assert_eq!(filter.check("github.com", 443), FilterDecision::Allow);
@@ -123,10 +474,13 @@ mod tests {
             allowed_domains: vec!["*.example.com".to_string()],
             denied_domains: vec!["evil.example.com".to_string()],
             mitm_domains: vec![],
+            ..DomainFilter::allow_all()
         };
 
         assert_eq!(filter.check("api.example.com", 443), FilterDecision::Allow);
         assert_eq!(filter.check("evil.example.com", 443), FilterDecision::Deny);
+        assert!(filter.allows_name("api.example.com"));
+        assert!(!filter.allows_name("evil.example.com"));
     }
 
     #[test]
@@ -135,9 +489,256 @@ mod tests {
             allowed_domains: vec!["*.example.com".to_string()],
             denied_domains: vec![],
             mitm_domains: vec!["api.example.com".to_string()],
+            ..DomainFilter::allow_all()
         };
 
         assert_eq!(filter.check("api.example.com", 443), FilterDecision::Mitm);
         assert_eq!(filter.check("other.example.com", 443), FilterDecision::Allow);
     }
+
+    #[test]
+    fn test_domain_filter_with_intercept() {
+        let filter = DomainFilter {
+            denied_domains: vec!["blocked.example.com".to_string()],
+            mitm_domains: vec!["api.example.com".to_string()],
+            intercept_domains: vec!["*.example.com".to_string()],
+            ..DomainFilter::allow_all()
+        };
+
+        assert_eq!(
+            filter.evaluate("www.example.com", 443),
+            (
+                FilterDecision::Intercept,
+                FilterRule::InterceptDomain("*.example.com".to_string())
+            )
+        );
+        // Denied domains and the MITM proxy take precedence
+        assert_eq!(filter.check("blocked.example.com", 443), FilterDecision::Deny);
+        assert_eq!(filter.check("api.example.com", 443), FilterDecision::Mitm);
+        assert!(filter.is_allowed("www.example.com", 443));
+    }
+
+    #[test]
+    fn test_domain_filter_with_ask() {
+        let filter = DomainFilter {
+            allowed_domains: vec!["github.com".to_string()],
+            denied_domains: vec!["evil.com".to_string()],
+            allowed_ports: vec![PortRange::parse("443").unwrap()],
+            ask_unknown: true,
+            ..DomainFilter::allow_all()
+        };
+
+        assert_eq!(filter.check("github.com", 443), FilterDecision::Allow);
+        assert_eq!(
+            filter.evaluate("example.com", 443),
+            (FilterDecision::Ask, FilterRule::NotAllowed)
+        );
+        // Explicit denials are never asked about
+        assert_eq!(filter.check("evil.com", 443), FilterDecision::Deny);
+        assert_eq!(filter.check("example.com", 22), FilterDecision::Deny);
+        assert_eq!(FilterRule::Asked(AskAnswer::AllowOnce).to_string(), "ask:allow-once");
+        assert!(!filter.allows_name("example.com"));
+    }
+
+    #[test]
+    fn test_domain_filter_with_ports() {
+        let filter = DomainFilter {
+            allowed_domains: vec![
+                "github.com:443".to_string(),
+                "*.internal.corp:8080-8090".to_string(),
+                "example.com".to_string(),
+            ],
+            denied_domains: vec!["example.com:25".to_string()],
+            mitm_domains: vec![],
+            ..DomainFilter::allow_all()
+        };
+
+        assert_eq!(filter.check("github.com", 443), FilterDecision::Allow);
+        assert_eq!(filter.check("github.com", 22), FilterDecision::Deny);
+        assert_eq!(filter.check("api.internal.corp", 8088), FilterDecision::Allow);
+        assert_eq!(filter.check("api.internal.corp", 9000), FilterDecision::Deny);
+        assert_eq!(filter.check("example.com", 8443), FilterDecision::Allow);
+        assert_eq!(filter.check("example.com", 25), FilterDecision::Deny);
+
+        // Names resolve if any port is allowed
+        assert!(filter.allows_name("github.com"));
+        assert!(filter.allows_name("API.internal.corp"));
+        assert!(filter.allows_name("example.com"));
+        assert!(!filter.allows_name("evil.com"));
+    }
+
+    #[test]
+    fn test_domain_filter_with_allowed_ports() {
+        let config = NetworkConfig {
+            allowed_domains: vec!["*.example.com".to_string()],
+            allowed_ports: vec!["80".to_string(), "443".to_string()],
+            mitm_proxy: Some(crate::config::MitmProxyConfig {
+                socket_path: "/tmp/mitm.sock".to_string(),
+                domains: vec!["api.example.com".to_string()],
+            }),
+            ..Default::default()
+        };
+        let filter = DomainFilter::from_config(&config);
+
+        assert_eq!(filter.check("www.example.com", 80), FilterDecision::Allow);
+        assert_eq!(filter.check("www.example.com", 443), FilterDecision::Allow);
+        assert_eq!(filter.check("www.example.com", 6379), FilterDecision::Deny);
+        assert_eq!(filter.check("api.example.com", 443), FilterDecision::Mitm);
+        assert_eq!(filter.check("api.example.com", 22), FilterDecision::Deny);
+    }
+
+    #[test]
+    fn test_domain_filter_with_cidrs() {
+        let config = NetworkConfig {
+            allowed_domains: vec!["github.com".to_string()],
+            allowed_cidrs: vec!["10.0.0.0/8".to_string(), "2001:db8::/32".to_string()],
+            denied_cidrs: vec!["10.0.0.0/24".to_string()],
+            ..Default::default()
+        };
+        let filter = DomainFilter::from_config(&config);
+
+        assert_eq!(filter.check("10.1.2.3", 443), FilterDecision::Allow);
+        assert_eq!(filter.check("10.0.0.5", 443), FilterDecision::Deny);
+        assert_eq!(filter.check("::ffff:10.1.2.3", 443), FilterDecision::Allow);
+        assert_eq!(filter.check("[2001:db8::1]", 443), FilterDecision::Allow);
+        assert_eq!(filter.check("2001:0db8:0000::0001", 443), FilterDecision::Allow);
+        assert_eq!(filter.check("192.168.1.1", 443), FilterDecision::Deny);
+        assert_eq!(filter.check("github.com", 443), FilterDecision::Allow);
+        assert_eq!(filter.check("example.com", 443), FilterDecision::Deny);
+    }
+
+    #[test]
+    fn test_domain_filter_ip_literal_patterns() {
+        // Exact IP patterns still work, and IPv4-mapped forms can't sidestep them
+        let filter = DomainFilter {
+            denied_domains: vec!["1.2.3.4".to_string()],
+            ..DomainFilter::allow_all()
+        };
+
+        assert_eq!(filter.check("1.2.3.4", 443), FilterDecision::Deny);
+        assert_eq!(filter.check("::ffff:1.2.3.4", 443), FilterDecision::Deny);
+        assert_eq!(filter.check("1.2.3.5", 443), FilterDecision::Allow);
+    }
+
+    #[test]
+    fn test_domain_filter_address_policy() {
+        let config = NetworkConfig {
+            denied_cidrs: vec!["203.0.113.0/24".to_string()],
+            block_private_addresses: Some(true),
+            allowed_private_ranges: vec!["10.20.0.0/16".to_string()],
+            ..Default::default()
+        };
+        let filter = DomainFilter::from_config(&config);
+
+        assert!(filter.is_address_allowed("140.82.112.3".parse().unwrap()));
+        assert!(!filter.is_address_allowed("203.0.113.7".parse().unwrap()));
+        assert!(!filter.is_address_allowed("127.0.0.1".parse().unwrap()));
+        assert!(!filter.is_address_allowed("169.254.169.254".parse().unwrap()));
+        assert!(!filter.is_address_allowed("::ffff:10.0.0.1".parse().unwrap()));
+        assert!(filter.is_address_allowed("10.20.3.4".parse().unwrap()));
+
+        // Private addresses are reachable unless blocking is enabled
+        assert!(DomainFilter::allow_all().is_address_allowed("127.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_domain_filter_evaluate_rule() {
+        let config = NetworkConfig {
+            allowed_domains: vec!["*.example.com".to_string()],
+            denied_domains: vec!["evil.example.com".to_string()],
+            allowed_cidrs: vec!["10.0.0.0/8".to_string()],
+            allowed_ports: vec!["443".to_string()],
+            ..Default::default()
+        };
+        let filter = DomainFilter::from_config(&config);
+
+        let rule = |host, port| filter.evaluate(host, port).1.to_string();
+        assert_eq!(rule("evil.example.com", 443), "deniedDomains:evil.example.com");
+        assert_eq!(rule("api.example.com", 22), "allowedPorts");
+        assert_eq!(rule("api.example.com", 443), "allowedDomains:*.example.com");
+        assert_eq!(rule("10.1.2.3", 443), "allowedCidrs:10.0.0.0/8");
+        assert_eq!(rule("other.org", 443), "notAllowed");
+        assert_eq!(DomainFilter::allow_all().evaluate("other.org", 443).1, FilterRule::Default);
+    }
+
+    #[test]
+    fn test_domain_filter_requires_tls_sni() {
+        let config = NetworkConfig {
+            tls_sni: Some(crate::config::TlsSniConfig {
+                enabled: Some(true),
+                non_tls_ports: vec!["22".to_string()],
+            }),
+            ..Default::default()
+        };
+        let filter = DomainFilter::from_config(&config);
+
+        assert!(filter.requires_tls_sni(443));
+        assert!(!filter.requires_tls_sni(22));
+        assert!(!DomainFilter::allow_all().requires_tls_sni(443));
+
+        // Verification is opt-in
+        let filter = DomainFilter::from_config(&NetworkConfig::default());
+        assert!(!filter.requires_tls_sni(443));
+    }
+
+    #[test]
+    fn test_shared_filter_update() {
+        let shared = SharedFilter::new(DomainFilter::allow_all());
+        assert!(shared.current().is_allowed("evil.com", 443));
+
+        let config = NetworkConfig {
+            allowed_domains: vec!["github.com".to_string()],
+            ..Default::default()
+        };
+        shared.update(DomainFilter::from_config(&config), false);
+
+        assert!(!shared.current().is_allowed("evil.com", 443));
+        assert!(shared.current().is_allowed("github.com", 443));
+    }
+
+    #[tokio::test]
+    async fn test_filter_watch_revoked() {
+        let shared = SharedFilter::new(DomainFilter::allow_all());
+        let mut watch = shared.subscribe();
+        let timeout = std::time::Duration::from_millis(50);
+
+        let config = NetworkConfig {
+            denied_domains: vec!["evil.com".to_string()],
+            ..Default::default()
+        };
+
+        // Updates that don't close revoked connections leave tunnels open
+        shared.update(DomainFilter::from_config(&config), false);
+        assert!(tokio::time::timeout(timeout, watch.revoked("evil.com", 443)).await.is_err());
+
+        // Closing revoked connections only affects denied destinations
+        let mut other = shared.subscribe();
+        shared.update(DomainFilter::from_config(&config), true);
+        assert!(tokio::time::timeout(timeout, watch.revoked("evil.com", 443)).await.is_ok());
+        assert!(tokio::time::timeout(timeout, other.revoked("github.com", 443)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_filter_watch_revoked_ask() {
+        let config = NetworkConfig {
+            allowed_domains: vec!["github.com".to_string(), "example.com".to_string()],
+            ask_unknown_domains: Some(crate::config::AskConfig::default()),
+            ..Default::default()
+        };
+        let shared = SharedFilter::new(DomainFilter::from_config(&config));
+        let mut removed = shared.subscribe();
+        let mut kept = shared.subscribe();
+        let timeout = std::time::Duration::from_millis(50);
+
+        // Removing a domain leaves it to be asked about, which revokes it
+        let config = NetworkConfig {
+            allowed_domains: vec!["github.com".to_string()],
+            ..config
+        };
+        let filter = DomainFilter::from_config(&config);
+        assert_eq!(filter.check("example.com", 443), FilterDecision::Ask);
+        shared.update(filter, true);
+        assert!(tokio::time::timeout(timeout, removed.revoked("example.com", 443)).await.is_ok());
+        assert!(tokio::time::timeout(timeout, kept.revoked("github.com", 443)).await.is_err());
+    }
 }
@@ -0,0 +1,366 @@
+//! Method and URL-path rules for HTTP requests.
+//!
+//! Rules are matched against requests the proxy can read: plain HTTP and
+//! HTTPS decrypted by the built-in interceptor. Paths are normalized first
+//! (percent-decoding, dot segments, repeated and trailing slashes), so
+//! encodings of the same path can't slip past a rule. Patterns are normalized
+//! the same way. Encoded separators ("%2F", "%5C") are left as they are, since
+//! servers disagree on them, and requests with them are refused wherever a
+//! deny rule could apply.
+
+use std::fmt;
+
+use crate::config::{matches_domain_port_pattern, HttpRuleAction, HttpRuleConfig};
+use crate::proxy::filter::FilterRule;
+
+/// A request refused by the HTTP rules.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpRuleDenial {
+    /// The rule that refused the request, for the audit log.
+    pub rule: FilterRule,
+    /// Explanation returned to the client.
+    pub reason: String,
+}
+
+/// One entry of `httpRules`.
+#[derive(Debug, Clone)]
+struct HttpRule {
+    index: usize,
+    config: HttpRuleConfig,
+}
+
+impl HttpRule {
+    fn applies_to(&self, host: &str, port: u16) -> bool {
+        matches_domain_port_pattern(host, port, &self.config.domain)
+    }
+
+    fn matches_method(&self, method: &str) -> bool {
+        let methods = &self.config.methods;
+        methods.is_empty() || methods.iter().any(|m| m.eq_ignore_ascii_case(method))
+    }
+
+    fn matches(&self, method: &str, path: &str) -> bool {
+        let paths = &self.config.paths;
+        self.matches_method(method)
+            && (paths.is_empty() || paths.iter().any(|p| matches_path_pattern(path, p)))
+    }
+}
+
+impl fmt::Display for HttpRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = match self.config.action {
+            HttpRuleAction::Allow => "allow",
+            HttpRuleAction::Deny => "deny",
+        };
+        let methods = match self.config.methods.is_empty() {
+            true => "*".to_string(),
+            false => self.config.methods.join(","),
+        };
+        let paths = match self.config.paths.is_empty() {
+            true => "/*".to_string(),
+            false => self.config.paths.join(","),
+        };
+        write!(f, "{} {} {} on {}", action, methods, paths, self.config.domain)
+    }
+}
+
+/// Compiled `httpRules`.
+#[derive(Debug, Clone, Default)]
+pub struct HttpRules {
+    rules: Vec<HttpRule>,
+}
+
+impl HttpRules {
+    /// Build the rules from config, keeping their order for error messages.
+    pub fn from_config(configs: &[HttpRuleConfig]) -> Self {
+        Self {
+            rules: configs
+                .iter()
+                .enumerate()
+                .map(|(index, config)| {
+                    // Patterns are normalized like the paths they are matched against
+                    let mut config = config.clone();
+                    for path in &mut config.paths {
+                        *path = normalize_path(path);
+                    }
+                    HttpRule { index, config }
+                })
+                .collect(),
+        }
+    }
+
+    /// Check whether any rule applies to the destination.
+    pub fn applies_to(&self, host: &str, port: u16) -> bool {
+        self.rules.iter().any(|rule| rule.applies_to(host, port))
+    }
+
+    /// Check a request. A matching deny rule refuses it; otherwise, if the
+    /// domain has allow rules, one of them must match.
+    pub fn check(
+        &self,
+        host: &str,
+        port: u16,
+        method: &str,
+        path: &str,
+    ) -> Result<(), HttpRuleDenial> {
+        let path = normalize_path(path);
+        let rules: Vec<&HttpRule> = self.rules.iter().filter(|r| r.applies_to(host, port)).collect();
+
+        // A deny rule can't tell where segments of such a path end
+        let upper = path.to_ascii_uppercase();
+        if ENCODED_SEPARATORS.iter().any(|s| upper.contains(s)) {
+            let deny = rules
+                .iter()
+                .find(|r| r.config.action == HttpRuleAction::Deny && r.matches_method(method));
+            if let Some(rule) = deny {
+                return Err(HttpRuleDenial {
+                    rule: FilterRule::HttpRule(rule.index),
+                    reason: format!(
+                        "{} {} has an encoded path separator, refused by httpRules[{}] ({})",
+                        method, path, rule.index, rule
+                    ),
+                });
+            }
+        }
+
+        let deny = rules
+            .iter()
+            .find(|r| r.config.action == HttpRuleAction::Deny && r.matches(method, &path));
+        if let Some(rule) = deny {
+            return Err(HttpRuleDenial {
+                rule: FilterRule::HttpRule(rule.index),
+                reason: format!(
+                    "{} {} is blocked by httpRules[{}] ({})",
+                    method, path, rule.index, rule
+                ),
+            });
+        }
+
+        let allow: Vec<&&HttpRule> = rules
+            .iter()
+            .filter(|r| r.config.action == HttpRuleAction::Allow)
+            .collect();
+        if allow.is_empty() || allow.iter().any(|r| r.matches(method, &path)) {
+            return Ok(());
+        }
+        let allowed: Vec<String> = allow.iter().map(|r| r.to_string()).collect();
+        Err(HttpRuleDenial {
+            rule: FilterRule::HttpRuleNotAllowed,
+            reason: format!(
+                "{} {} on {} matches no allow rule in httpRules ({})",
+                method,
+                path,
+                host,
+                allowed.join("; ")
+            ),
+        })
+    }
+}
+
+/// Percent-escapes of separators, kept encoded by [`normalize_path`].
+const ENCODED_SEPARATORS: [&str; 2] = ["%2F", "%5C"];
+
+/// Normalize a URL path the way servers interpret it: decode percent-escapes
+/// (except those of "/" and "\", which servers may or may not take as
+/// separators), drop "." and empty segments, including a trailing one, and
+/// resolve "..".
+fn normalize_path(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| bytes.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(b'/') => {
+                decoded.extend_from_slice(b"%2F");
+                i += 3;
+            }
+            Some(b'\\') => {
+                decoded.extend_from_slice(b"%5C");
+                i += 3;
+            }
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    let decoded = String::from_utf8_lossy(&decoded);
+
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    format!("/{}", segments.join("/"))
+}
+
+/// Match a path against a pattern where "*" matches any characters, including "/".
+fn matches_path_pattern(path: &str, pattern: &str) -> bool {
+    let (path, pattern) = (path.as_bytes(), pattern.as_bytes());
+    let (mut p, mut t) = (0, 0);
+    // Position of the last "*" in the pattern, and the path position it matched up to
+    let mut star: Option<(usize, usize)> = None;
+
+    while t < path.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, t));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == path[t] {
+            p += 1;
+            t += 1;
+        } else if let Some((star_p, star_t)) = star {
+            // Let the last "*" absorb one more character
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(domain: &str, action: HttpRuleAction, methods: &[&str], paths: &[&str]) -> HttpRuleConfig {
+        HttpRuleConfig {
+            domain: domain.to_string(),
+            action,
+            methods: methods.iter().map(|m| m.to_string()).collect(),
+            paths: paths.iter().map(|p| p.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!(normalize_path("/"), "/");
+        assert_eq!(normalize_path(""), "/");
+        assert_eq!(normalize_path("/repos/a/b/issues"), "/repos/a/b/issues");
+        assert_eq!(normalize_path("//repos/./a/b/../b/issues"), "/repos/a/b/issues");
+        assert_eq!(normalize_path("/repos/a/b/%69ssues"), "/repos/a/b/issues");
+        assert_eq!(normalize_path("/a%2fb/"), "/a%2Fb");
+        assert_eq!(normalize_path("/a%5cb"), "/a%5Cb");
+        assert_eq!(normalize_path("/repos/a/b/issues/"), "/repos/a/b/issues");
+        assert_eq!(normalize_path("/repos/a//b/issues//"), "/repos/a/b/issues");
+        assert_eq!(normalize_path("/../../etc"), "/etc");
+        assert_eq!(normalize_path("/bad%zz"), "/bad%zz");
+    }
+
+    #[test]
+    fn test_matches_path_pattern() {
+        assert!(matches_path_pattern("/repos/a/b/issues", "/repos/*/issues"));
+        assert!(!matches_path_pattern("/repos/a/b/issues/1", "/repos/*/issues"));
+        assert!(matches_path_pattern("/repos/a/b/issues/1", "/repos/*/issues*"));
+        assert!(matches_path_pattern("/anything", "/*"));
+        assert!(matches_path_pattern("/", "/*"));
+        assert!(!matches_path_pattern("/Repos/a", "/repos/*"));
+        assert!(matches_path_pattern("/a/x/b/y/c", "/a/*/b/*/c"));
+    }
+
+    #[test]
+    fn test_http_rules_allow_list() {
+        let rules = HttpRules::from_config(&[rule(
+            "registry.npmjs.org",
+            HttpRuleAction::Allow,
+            &["GET", "HEAD"],
+            &[],
+        )]);
+
+        assert!(rules.check("registry.npmjs.org", 443, "GET", "/react").is_ok());
+        assert!(rules.check("registry.npmjs.org", 443, "head", "/react").is_ok());
+        let denial = rules.check("registry.npmjs.org", 443, "PUT", "/react").unwrap_err();
+        assert_eq!(denial.rule, FilterRule::HttpRuleNotAllowed);
+        assert!(denial.reason.contains("allow GET,HEAD /* on registry.npmjs.org"));
+
+        // Domains without rules are unaffected
+        assert!(rules.check("example.com", 443, "DELETE", "/").is_ok());
+        assert!(rules.applies_to("registry.npmjs.org", 443));
+        assert!(!rules.applies_to("example.com", 443));
+    }
+
+    #[test]
+    fn test_http_rules_deny() {
+        let rules = HttpRules::from_config(&[
+            rule("*.github.com", HttpRuleAction::Allow, &[], &["/repos/*"]),
+            rule("api.github.com", HttpRuleAction::Deny, &["POST"], &["/repos/*/issues"]),
+        ]);
+
+        assert!(rules.check("api.github.com", 443, "POST", "/repos/a/b/pulls").is_ok());
+        let denial = rules.check("api.github.com", 443, "POST", "/repos/a/b/issues").unwrap_err();
+        assert_eq!(denial.rule, FilterRule::HttpRule(1));
+        assert_eq!(
+            denial.reason,
+            "POST /repos/a/b/issues is blocked by httpRules[1] \
+             (deny POST /repos/*/issues on api.github.com)"
+        );
+
+        // Encoded and dotted paths are matched after normalization
+        assert!(rules.check("api.github.com", 443, "POST", "/repos/a/b/%69ssues").is_err());
+        assert!(rules.check("api.github.com", 443, "POST", "/repos/a/b/x/../issues").is_err());
+
+        // Trailing slashes and empty segments don't get past a rule
+        assert!(rules.check("api.github.com", 443, "POST", "/repos/a/b/issues/").is_err());
+        assert!(rules.check("api.github.com", 443, "POST", "/repos/a//b//issues").is_err());
+
+        // Deny rules take precedence; allow rules still restrict other paths
+        assert!(rules.check("api.github.com", 443, "GET", "/repos/a/b/issues").is_ok());
+        assert!(rules.check("api.github.com", 443, "GET", "/user").is_err());
+    }
+
+    #[test]
+    fn test_http_rules_patterns_normalized() {
+        let rules = HttpRules::from_config(&[rule(
+            "api.github.com",
+            HttpRuleAction::Deny,
+            &[],
+            &["/repos/*/issues/"],
+        )]);
+        assert!(rules.check("api.github.com", 443, "POST", "/repos/a/b/issues").is_err());
+        assert!(rules.check("api.github.com", 443, "POST", "/repos/a/b/issues/").is_err());
+
+        let rules = HttpRules::from_config(&[rule(
+            "api.github.com",
+            HttpRuleAction::Deny,
+            &[],
+            &["//repos/./*/%69ssues"],
+        )]);
+        assert!(rules.check("api.github.com", 443, "POST", "/repos/a/b/issues").is_err());
+        assert!(rules.check("api.github.com", 443, "POST", "/repos/a/b/pulls").is_ok());
+    }
+
+    #[test]
+    fn test_http_rules_encoded_separators() {
+        let rules = HttpRules::from_config(&[
+            rule("*.github.com", HttpRuleAction::Allow, &[], &["/repos/*"]),
+            rule("api.github.com", HttpRuleAction::Deny, &["POST"], &["/repos/*/issues"]),
+        ]);
+
+        // Where a deny rule could apply, encoded separators are refused
+        let check = |method: &str, path: &str| rules.check("api.github.com", 443, method, path);
+        let denial = check("POST", "/repos/a/b%2Fissues").unwrap_err();
+        assert_eq!(denial.rule, FilterRule::HttpRule(1));
+        assert!(denial.reason.contains("encoded path separator"));
+        assert!(check("POST", "/repos/a/b%2fissues").is_err());
+        assert!(check("POST", "/repos/a/b%5Cissues").is_err());
+        assert!(check("POST", "/repos/a/b%252fissues").is_err());
+
+        // Elsewhere they are matched as they are
+        assert!(check("GET", "/repos/a/b%2Fissues").is_ok());
+        assert!(rules.check("www.github.com", 443, "POST", "/repos/a%2Fb").is_ok());
+    }
+}
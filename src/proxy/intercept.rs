@@ -0,0 +1,449 @@
+//! Built-in TLS interception for selected domains.
+//!
+//! The HTTP proxy terminates CONNECT tunnels to `tlsIntercept.domains` itself:
+//! it presents a leaf certificate minted on the fly by a local CA, reads the
+//! requests in plain text, and forwards them over a fresh TLS connection to
+//! the real server. The CA certificate must be trusted inside the sandbox.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use parking_lot::Mutex;
+use rcgen::{
+    BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, Issuer, KeyPair,
+    KeyUsagePurpose,
+};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::{client, server, TlsAcceptor, TlsConnector};
+
+use crate::config::TlsInterceptConfig;
+use crate::error::SandboxError;
+
+/// File names of a persistent CA inside `tlsIntercept.caDir`.
+const CA_CERT_FILE: &str = "ca.pem";
+const CA_KEY_FILE: &str = "ca-key.pem";
+
+/// Common name of CAs generated by the sandbox.
+const CA_COMMON_NAME: &str = "Sandbox Runtime Interception CA";
+
+/// Validity of generated CAs and leaf certificates.
+const CA_VALIDITY: Duration = Duration::from_secs(10 * 365 * 24 * 60 * 60);
+const LEAF_VALIDITY: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+/// Backdating of leaf certificates, to tolerate clock skew.
+const LEAF_BACKDATE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// System CA bundles, the first one found is extended with the CA.
+const SYSTEM_CA_BUNDLES: &[&str] = &[
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/etc/ssl/ca-bundle.pem",
+    "/etc/ssl/cert.pem",
+];
+
+/// Leaf certificates kept before the cache is cleared.
+const MAX_CACHED_LEAVES: usize = 1024;
+
+/// A local certificate authority that mints leaf certificates for intercepted hosts.
+pub struct CertificateAuthority {
+    issuer: Issuer<'static, KeyPair>,
+    cert_der: CertificateDer<'static>,
+    cert_path: PathBuf,
+    /// System roots plus the CA, for tools that take a single bundle.
+    bundle_path: PathBuf,
+    /// Remove `cert_path` on drop (the CA only lives as long as the session).
+    ephemeral: bool,
+    /// Key shared by every leaf certificate.
+    leaf_key: KeyPair,
+    leaves: Mutex<HashMap<String, Arc<ServerConfig>>>,
+}
+
+impl CertificateAuthority {
+    /// Load the CA in `dir`, or create one there if the directory has none.
+    /// The key is written with mode 0600.
+    pub fn load_or_create(dir: &Path) -> Result<Self, SandboxError> {
+        let cert_path = dir.join(CA_CERT_FILE);
+        let key_path = dir.join(CA_KEY_FILE);
+
+        match (cert_path.exists(), key_path.exists()) {
+            (true, true) => {
+                let cert_pem = fs::read_to_string(&cert_path)?;
+                let key = KeyPair::from_pem(&fs::read_to_string(&key_path)?)
+                    .map_err(|e| ca_error(&key_path, e))?;
+                let issuer =
+                    Issuer::from_ca_cert_pem(&cert_pem, key).map_err(|e| ca_error(&cert_path, e))?;
+                let cert_der = pem_to_der(&cert_pem)
+                    .ok_or_else(|| ca_error(&cert_path, "not a PEM certificate"))?;
+                Self::new(issuer, cert_der, cert_path, false)
+            }
+            (false, false) => {
+                create_private_dir(dir)?;
+                let (issuer, cert_pem, key_pem) = generate_ca()?;
+                fs::write(&cert_path, &cert_pem)?;
+                write_private_file(&key_path, &key_pem)?;
+                tracing::debug!("Generated TLS interception CA in {}", dir.display());
+                let cert_der = pem_to_der(&cert_pem).expect("generated certificate is valid PEM");
+                Self::new(issuer, cert_der, cert_path, false)
+            }
+            _ => Err(SandboxError::Proxy(format!(
+                "{} must contain both {} and {}",
+                dir.display(),
+                CA_CERT_FILE,
+                CA_KEY_FILE
+            ))),
+        }
+    }
+
+    /// Create a CA for this session only. The key never touches the disk;
+    /// the certificate is written to the temp directory and removed on drop.
+    pub fn ephemeral() -> Result<Self, SandboxError> {
+        use rand::Rng;
+
+        let (issuer, cert_pem, _) = generate_ca()?;
+        let suffix: u32 = rand::thread_rng().gen();
+        let cert_path = std::env::temp_dir().join(format!(
+            "srt-ca-{}-{:08x}.pem",
+            std::process::id(),
+            suffix
+        ));
+        fs::write(&cert_path, &cert_pem)?;
+        let cert_der = pem_to_der(&cert_pem).expect("generated certificate is valid PEM");
+        Self::new(issuer, cert_der, cert_path, true)
+    }
+
+    fn new(
+        issuer: Issuer<'static, KeyPair>,
+        cert_der: CertificateDer<'static>,
+        cert_path: PathBuf,
+        ephemeral: bool,
+    ) -> Result<Self, SandboxError> {
+        let bundle_path = write_bundle(&cert_path)?;
+        Ok(Self {
+            issuer,
+            cert_der,
+            cert_path,
+            bundle_path,
+            ephemeral,
+            leaf_key: KeyPair::generate().map_err(tls_error)?,
+            leaves: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Path of the CA certificate (PEM), to be trusted inside the sandbox.
+    pub fn cert_path(&self) -> &Path {
+        &self.cert_path
+    }
+
+    /// Path of the system roots plus the CA certificate (PEM), for tools
+    /// whose CA setting replaces the system roots. Removed on drop.
+    pub fn bundle_path(&self) -> &Path {
+        &self.bundle_path
+    }
+
+    /// The CA certificate (DER).
+    pub fn cert_der(&self) -> &CertificateDer<'static> {
+        &self.cert_der
+    }
+
+    /// TLS server config presenting a leaf certificate for `host`.
+    pub fn server_config(&self, host: &str) -> Result<Arc<ServerConfig>, SandboxError> {
+        let host = host.trim_start_matches('[').trim_end_matches(']').to_ascii_lowercase();
+        if let Some(config) = self.leaves.lock().get(&host) {
+            return Ok(config.clone());
+        }
+
+        let leaf = self.mint_leaf(&host)?;
+        let key = PrivateKeyDer::Pkcs8(self.leaf_key.serialize_der().into());
+        let mut config = ServerConfig::builder_with_provider(crypto_provider())
+            .with_safe_default_protocol_versions()
+            .map_err(tls_error)?
+            .with_no_client_auth()
+            .with_single_cert(vec![leaf, self.cert_der.clone()], key)
+            .map_err(tls_error)?;
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        let config = Arc::new(config);
+
+        let mut leaves = self.leaves.lock();
+        if leaves.len() >= MAX_CACHED_LEAVES {
+            leaves.clear();
+        }
+        leaves.insert(host, config.clone());
+        Ok(config)
+    }
+
+    /// Sign a leaf certificate for `host` (a DNS name or an IP literal).
+    fn mint_leaf(&self, host: &str) -> Result<CertificateDer<'static>, SandboxError> {
+        let mut params = CertificateParams::new(vec![host.to_string()]).map_err(tls_error)?;
+        params.distinguished_name.push(DnType::CommonName, host);
+        let now = SystemTime::now();
+        params.not_before = (now - LEAF_BACKDATE).into();
+        params.not_after = (now + LEAF_VALIDITY).into();
+        params.key_usages = vec![
+            KeyUsagePurpose::DigitalSignature,
+            KeyUsagePurpose::KeyEncipherment,
+        ];
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        params.use_authority_key_identifier_extension = true;
+
+        let cert = params.signed_by(&self.leaf_key, &self.issuer).map_err(tls_error)?;
+        Ok(cert.der().clone())
+    }
+}
+
+impl Drop for CertificateAuthority {
+    fn drop(&mut self) {
+        if self.ephemeral {
+            let _ = fs::remove_file(&self.cert_path);
+        }
+        let _ = fs::remove_file(&self.bundle_path);
+    }
+}
+
+/// Terminates intercepted tunnels and opens the matching server connections.
+pub struct TlsInterceptor {
+    ca: CertificateAuthority,
+    client_config: Arc<ClientConfig>,
+}
+
+impl TlsInterceptor {
+    /// Build an interceptor from config. Servers are verified against the
+    /// bundled Mozilla root store.
+    pub fn from_config(config: &TlsInterceptConfig) -> Result<Self, SandboxError> {
+        let ca = match config.ca_dir.as_deref() {
+            Some(dir) => CertificateAuthority::load_or_create(Path::new(dir))?,
+            None => CertificateAuthority::ephemeral()?,
+        };
+        let roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        Self::new(ca, roots)
+    }
+
+    /// Build an interceptor that verifies servers against `roots`.
+    pub fn new(ca: CertificateAuthority, roots: RootCertStore) -> Result<Self, SandboxError> {
+        let mut client_config = ClientConfig::builder_with_provider(crypto_provider())
+            .with_safe_default_protocol_versions()
+            .map_err(tls_error)?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        client_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        Ok(Self {
+            ca,
+            client_config: Arc::new(client_config),
+        })
+    }
+
+    /// The certificate authority that signs leaf certificates.
+    pub fn ca(&self) -> &CertificateAuthority {
+        &self.ca
+    }
+
+    /// Path of the CA certificate (PEM), to be trusted inside the sandbox.
+    pub fn ca_cert_path(&self) -> &Path {
+        self.ca.cert_path()
+    }
+
+    /// Path of the system roots plus the CA certificate (PEM).
+    pub fn ca_bundle_path(&self) -> &Path {
+        self.ca.bundle_path()
+    }
+
+    /// Complete the client's TLS handshake, presenting a certificate for `host`.
+    pub async fn accept<IO>(&self, stream: IO, host: &str) -> io::Result<server::TlsStream<IO>>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin,
+    {
+        let config = self.ca.server_config(host).map_err(io::Error::other)?;
+        TlsAcceptor::from(config).accept(stream).await
+    }
+
+    /// Open a verified TLS connection to `host` over `stream`.
+    pub async fn connect<IO>(&self, stream: IO, host: &str) -> io::Result<client::TlsStream<IO>>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin,
+    {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let name = ServerName::try_from(host.to_string())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        TlsConnector::from(self.client_config.clone())
+            .connect(name, stream)
+            .await
+    }
+}
+
+/// Write the first system CA bundle found plus the certificate at `cert_path`
+/// to a session file in the temp directory.
+fn write_bundle(cert_path: &Path) -> Result<PathBuf, SandboxError> {
+    use rand::Rng;
+
+    let mut bundle = SYSTEM_CA_BUNDLES
+        .iter()
+        .find_map(|path| fs::read_to_string(path).ok())
+        .unwrap_or_default();
+    if !bundle.is_empty() && !bundle.ends_with('\n') {
+        bundle.push('\n');
+    }
+    bundle.push_str(&fs::read_to_string(cert_path)?);
+
+    let suffix: u32 = rand::thread_rng().gen();
+    let bundle_path = std::env::temp_dir().join(format!(
+        "srt-ca-bundle-{}-{:08x}.pem",
+        std::process::id(),
+        suffix
+    ));
+    fs::write(&bundle_path, bundle)?;
+    Ok(bundle_path)
+}
+
+/// Generate a CA certificate and key. Returns the issuer and both as PEM.
+fn generate_ca() -> Result<(Issuer<'static, KeyPair>, String, String), SandboxError> {
+    let mut params = CertificateParams::default();
+    params.distinguished_name = rcgen::DistinguishedName::new();
+    params.distinguished_name.push(DnType::CommonName, CA_COMMON_NAME);
+    let now = SystemTime::now();
+    params.not_before = (now - LEAF_BACKDATE).into();
+    params.not_after = (now + CA_VALIDITY).into();
+    params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+    params.key_usages = vec![
+        KeyUsagePurpose::KeyCertSign,
+        KeyUsagePurpose::CrlSign,
+        KeyUsagePurpose::DigitalSignature,
+    ];
+
+    let key = KeyPair::generate().map_err(tls_error)?;
+    let cert = params.self_signed(&key).map_err(tls_error)?;
+    let key_pem = key.serialize_pem();
+    Ok((Issuer::new(params, key), cert.pem(), key_pem))
+}
+
+fn crypto_provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// Decode the first certificate in a PEM document.
+fn pem_to_der(pem: &str) -> Option<CertificateDer<'static>> {
+    use base64::Engine;
+
+    let body = pem
+        .split("-----BEGIN CERTIFICATE-----")
+        .nth(1)?
+        .split("-----END CERTIFICATE-----")
+        .next()?;
+    let body: String = body.chars().filter(|c| !c.is_whitespace()).collect();
+    let der = base64::engine::general_purpose::STANDARD.decode(body).ok()?;
+    Some(CertificateDer::from(der))
+}
+
+fn create_private_dir(dir: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        fs::DirBuilder::new().recursive(true).mode(0o700).create(dir)
+    }
+    #[cfg(not(unix))]
+    fs::create_dir_all(dir)
+}
+
+fn write_private_file(path: &Path, contents: &str) -> io::Result<()> {
+    use std::io::Write;
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(contents.as_bytes())
+}
+
+fn ca_error(path: &Path, e: impl std::fmt::Display) -> SandboxError {
+    SandboxError::Proxy(format!("Invalid TLS interception CA {}: {}", path.display(), e))
+}
+
+fn tls_error(e: impl std::fmt::Display) -> SandboxError {
+    SandboxError::Proxy(format!("TLS interception: {}", e))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// An interceptor whose servers are verified against its own CA,
+    /// so tests can stand up servers with [`CertificateAuthority::server_config`].
+    pub(crate) fn test_interceptor() -> TlsInterceptor {
+        let ca = CertificateAuthority::ephemeral().unwrap();
+        let mut roots = RootCertStore::empty();
+        roots.add(ca.cert_der().clone()).unwrap();
+        TlsInterceptor::new(ca, roots).unwrap()
+    }
+
+    #[test]
+    fn test_ephemeral_ca_removed_on_drop() {
+        let ca = CertificateAuthority::ephemeral().unwrap();
+        let path = ca.cert_path().to_path_buf();
+        let pem = fs::read_to_string(&path).unwrap();
+        assert!(pem.starts_with("-----BEGIN CERTIFICATE-----"));
+        assert_eq!(pem_to_der(&pem).as_ref(), Some(ca.cert_der()));
+
+        // The bundle ends with the CA, after any system roots
+        let bundle = ca.bundle_path().to_path_buf();
+        assert!(fs::read_to_string(&bundle).unwrap().ends_with(&pem));
+
+        drop(ca);
+        assert!(!path.exists());
+        assert!(!bundle.exists());
+    }
+
+    #[test]
+    fn test_ca_dir_is_reused() {
+        let dir = tempfile::tempdir().unwrap();
+        let ca_dir = dir.path().join("ca");
+        let first = CertificateAuthority::load_or_create(&ca_dir).unwrap();
+        let cert = first.cert_der().clone();
+        drop(first);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(ca_dir.join(CA_KEY_FILE)).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        // The persistent CA survives and is loaded again
+        let second = CertificateAuthority::load_or_create(&ca_dir).unwrap();
+        assert_eq!(second.cert_der(), &cert);
+        assert!(second.server_config("example.com").is_ok());
+
+        // A directory with only one of the files is rejected
+        fs::remove_file(ca_dir.join(CA_KEY_FILE)).unwrap();
+        assert!(CertificateAuthority::load_or_create(&ca_dir).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_leaf_certificate_is_trusted() {
+        let interceptor = Arc::new(test_interceptor());
+        let (client, server) = tokio::io::duplex(16 * 1024);
+
+        let acceptor = interceptor.clone();
+        let accepted = tokio::spawn(async move {
+            acceptor.accept(server, "api.example.com").await.map(|_| ())
+        });
+        // The client verifies the minted certificate against the CA
+        let connected = interceptor.connect(client, "api.example.com").await;
+        assert!(connected.is_ok());
+        accepted.await.unwrap().unwrap();
+
+        // Leaf configs are cached per host
+        let ca = interceptor.ca();
+        let first = ca.server_config("api.example.com").unwrap();
+        assert!(Arc::ptr_eq(&first, &ca.server_config("API.example.com").unwrap()));
+        assert!(ca.server_config("127.0.0.1").is_ok());
+    }
+}
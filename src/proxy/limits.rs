@@ -0,0 +1,496 @@
+//! Bandwidth, concurrency and connection-rate limits for the proxies.
+//!
+//! Limits are grouped into scopes: one global scope and one per configured
+//! domain pattern. A connection counts against the global scope and the first
+//! matching domain scope. Concurrency and connection-rate limits refuse new
+//! connections; bandwidth is shaped with a token bucket shared by every
+//! connection in a scope.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use pin_project_lite::pin_project;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Instant, Sleep};
+
+use crate::config::{matches_domain_port_pattern, RateLimitConfig, RateLimitsConfig};
+use crate::utils::format_host_port;
+use crate::violation::{SandboxViolationEvent, SandboxViolationStore};
+
+/// Window for `connectionsPerMinute`.
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Which limit refused a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitKind {
+    /// Too many open connections in the scope.
+    MaxConcurrent,
+    /// Too many new connections in the last minute.
+    ConnectionsPerMinute,
+}
+
+/// A new connection was refused by a rate limit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RateLimitExceeded {
+    /// "global" or the domain pattern of the scope.
+    pub scope: String,
+    /// The limit that was hit.
+    pub kind: RateLimitKind,
+    /// The configured value of the limit.
+    pub limit: u64,
+}
+
+impl fmt::Display for RateLimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            RateLimitKind::MaxConcurrent => write!(
+                f,
+                "rate limit exceeded ({}): more than {} concurrent connections",
+                self.scope, self.limit
+            ),
+            RateLimitKind::ConnectionsPerMinute => write!(
+                f,
+                "rate limit exceeded ({}): more than {} new connections per minute",
+                self.scope, self.limit
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RateLimitExceeded {}
+
+/// Token bucket holding up to one second of traffic.
+/// Charges may overdraw it; callers wait until it is back above zero.
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            rate: bytes_per_sec as f64,
+            tokens: bytes_per_sec as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.updated = now;
+    }
+
+    /// Time until the bucket is no longer overdrawn.
+    fn delay(&mut self) -> Option<Duration> {
+        self.refill();
+        (self.tokens < 0.0).then(|| Duration::from_secs_f64(-self.tokens / self.rate))
+    }
+
+    fn charge(&mut self, bytes: usize) {
+        self.refill();
+        self.tokens -= bytes as f64;
+    }
+}
+
+#[derive(Default)]
+struct ScopeCounters {
+    active: usize,
+    recent: VecDeque<Instant>,
+}
+
+/// One set of limits and the connections counted against it.
+struct Scope {
+    name: String,
+    limits: RateLimitConfig,
+    counters: Mutex<ScopeCounters>,
+    bucket: Option<Mutex<TokenBucket>>,
+}
+
+impl Scope {
+    fn new(name: String, limits: RateLimitConfig) -> Arc<Self> {
+        Arc::new(Self {
+            name,
+            bucket: limits.bytes_per_sec.map(|rate| Mutex::new(TokenBucket::new(rate))),
+            limits,
+            counters: Mutex::new(ScopeCounters::default()),
+        })
+    }
+
+    /// Check whether one more connection fits, pruning the rate window.
+    fn check(&self, counters: &mut ScopeCounters, now: Instant) -> Result<(), RateLimitExceeded> {
+        while counters
+            .recent
+            .front()
+            .is_some_and(|started| now.duration_since(*started) >= RATE_WINDOW)
+        {
+            counters.recent.pop_front();
+        }
+
+        if let Some(max) = self.limits.max_concurrent {
+            if counters.active >= max {
+                return Err(self.exceeded(RateLimitKind::MaxConcurrent, max as u64));
+            }
+        }
+        if let Some(max) = self.limits.connections_per_minute {
+            if counters.recent.len() >= max as usize {
+                return Err(self.exceeded(RateLimitKind::ConnectionsPerMinute, max as u64));
+            }
+        }
+        Ok(())
+    }
+
+    fn exceeded(&self, kind: RateLimitKind, limit: u64) -> RateLimitExceeded {
+        RateLimitExceeded {
+            scope: self.name.clone(),
+            kind,
+            limit,
+        }
+    }
+}
+
+/// Rate limits shared by the HTTP and SOCKS5 proxies.
+pub struct RateLimiter {
+    global: Option<Arc<Scope>>,
+    domains: Vec<(String, Arc<Scope>)>,
+    violations: Option<Arc<SandboxViolationStore>>,
+}
+
+impl RateLimiter {
+    /// Build a limiter from config. Returns None if no limits are configured.
+    /// Refusals and throttled connections are recorded in `violations`.
+    pub fn from_config(
+        config: Option<&RateLimitsConfig>,
+        violations: Option<Arc<SandboxViolationStore>>,
+    ) -> Option<Self> {
+        let config = config?;
+        if config.global.is_none() && config.domains.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            global: config
+                .global
+                .clone()
+                .map(|limits| Scope::new("global".to_string(), limits)),
+            domains: config
+                .domains
+                .iter()
+                .map(|d| (d.domain.clone(), Scope::new(d.domain.clone(), d.limits.clone())))
+                .collect(),
+            violations,
+        })
+    }
+
+    /// Count a new connection against the matching scopes.
+    fn acquire(&self, host: &str, port: u16) -> Result<RateLimitPermit, RateLimitExceeded> {
+        let domain = self
+            .domains
+            .iter()
+            .find(|(pattern, _)| matches_domain_port_pattern(host, port, pattern))
+            .map(|(_, scope)| scope);
+        let scopes: Vec<Arc<Scope>> = self.global.iter().chain(domain).cloned().collect();
+
+        // Lock every scope (global first) so the check and the count are atomic
+        let now = Instant::now();
+        let mut counters: Vec<_> = scopes.iter().map(|scope| scope.counters.lock()).collect();
+        let checked = scopes
+            .iter()
+            .zip(counters.iter_mut())
+            .try_for_each(|(scope, counters)| scope.check(counters, now));
+        if checked.is_ok() {
+            for counters in counters.iter_mut() {
+                counters.active += 1;
+                counters.recent.push_back(now);
+            }
+        }
+        drop(counters);
+
+        if let Err(e) = checked {
+            self.record_violation(format!(
+                "Network connection to {} refused: {}",
+                format_host_port(host, port),
+                e
+            ));
+            return Err(e);
+        }
+
+        Ok(RateLimitPermit {
+            scopes,
+            target: format_host_port(host, port),
+            violations: self.violations.clone(),
+            throttled: AtomicBool::new(false),
+        })
+    }
+
+    fn record_violation(&self, line: String) {
+        tracing::debug!("{}", line);
+        if let Some(violations) = &self.violations {
+            violations.add_violation(SandboxViolationEvent::new(line));
+        }
+    }
+}
+
+/// A connection counted against its rate-limit scopes.
+/// Dropping the permit frees its concurrency slots.
+pub struct RateLimitPermit {
+    scopes: Vec<Arc<Scope>>,
+    target: String,
+    violations: Option<Arc<SandboxViolationStore>>,
+    throttled: AtomicBool,
+}
+
+impl RateLimitPermit {
+    /// Admit a new connection. Without a limiter, the permit does nothing.
+    pub fn acquire(
+        limiter: Option<&RateLimiter>,
+        host: &str,
+        port: u16,
+    ) -> Result<Self, RateLimitExceeded> {
+        match limiter {
+            Some(limiter) => limiter.acquire(host, port),
+            None => Ok(Self {
+                scopes: Vec::new(),
+                target: String::new(),
+                violations: None,
+                throttled: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    /// Time to wait before moving more bytes, if any scope's bandwidth is used up.
+    /// The first wait of a connection is recorded as a violation.
+    fn delay(&self) -> Option<Duration> {
+        let (scope, delay) = self
+            .scopes
+            .iter()
+            .filter_map(|scope| Some((scope, scope.bucket.as_ref()?.lock().delay()?)))
+            .max_by_key(|(_, delay)| *delay)?;
+
+        if !self.throttled.swap(true, Ordering::Relaxed) {
+            let line = format!(
+                "Network connection to {} throttled: bandwidth limit ({}) of {} bytes/sec reached",
+                self.target,
+                scope.name,
+                scope.limits.bytes_per_sec.unwrap_or_default()
+            );
+            tracing::debug!("{}", line);
+            if let Some(violations) = &self.violations {
+                violations.add_violation(SandboxViolationEvent::new(line));
+            }
+        }
+        Some(delay)
+    }
+
+    /// Count transferred bytes against every scope's bandwidth.
+    fn charge(&self, bytes: usize) {
+        if bytes == 0 {
+            return;
+        }
+        for bucket in self.scopes.iter().filter_map(|scope| scope.bucket.as_ref()) {
+            bucket.lock().charge(bytes);
+        }
+    }
+}
+
+impl Drop for RateLimitPermit {
+    fn drop(&mut self) {
+        for scope in &self.scopes {
+            let mut counters = scope.counters.lock();
+            counters.active = counters.active.saturating_sub(1);
+        }
+    }
+}
+
+pin_project! {
+    /// Stream wrapper that holds a [`RateLimitPermit`] and shapes traffic to
+    /// its bandwidth limits. Reads and writes share the budget.
+    pub struct ThrottledIo<T> {
+        #[pin]
+        inner: T,
+        permit: RateLimitPermit,
+        read_delay: Option<Pin<Box<Sleep>>>,
+        write_delay: Option<Pin<Box<Sleep>>>,
+    }
+}
+
+impl<T> ThrottledIo<T> {
+    /// Wrap a destination-side stream.
+    pub fn new(inner: T, permit: RateLimitPermit) -> Self {
+        Self {
+            inner,
+            permit,
+            read_delay: None,
+            write_delay: None,
+        }
+    }
+}
+
+/// Wait until the permit's buckets have budget again.
+fn poll_budget(
+    delay: &mut Option<Pin<Box<Sleep>>>,
+    permit: &RateLimitPermit,
+    cx: &mut Context<'_>,
+) -> Poll<()> {
+    loop {
+        if let Some(sleep) = delay.as_mut() {
+            ready!(sleep.as_mut().poll(cx));
+            *delay = None;
+        }
+        match permit.delay() {
+            Some(wait) => *delay = Some(Box::pin(tokio::time::sleep(wait))),
+            None => return Poll::Ready(()),
+        }
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for ThrottledIo<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.project();
+        ready!(poll_budget(this.read_delay, this.permit, cx));
+        let before = buf.filled().len();
+        let result = ready!(this.inner.poll_read(cx, buf));
+        this.permit.charge(buf.filled().len() - before);
+        Poll::Ready(result)
+    }
+}
+
+impl<T: AsyncWrite> AsyncWrite for ThrottledIo<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.project();
+        ready!(poll_budget(this.write_delay, this.permit, cx));
+        let result = ready!(this.inner.poll_write(cx, buf));
+        if let Ok(n) = result {
+            this.permit.charge(n);
+        }
+        Poll::Ready(result)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::config::DomainRateLimitConfig;
+
+    fn limiter(config: RateLimitsConfig) -> (RateLimiter, Arc<SandboxViolationStore>) {
+        let violations = Arc::new(SandboxViolationStore::new());
+        let limiter = RateLimiter::from_config(Some(&config), Some(violations.clone())).unwrap();
+        (limiter, violations)
+    }
+
+    #[test]
+    fn test_rate_limiter_from_config() {
+        assert!(RateLimiter::from_config(None, None).is_none());
+        assert!(RateLimiter::from_config(Some(&RateLimitsConfig::default()), None).is_none());
+    }
+
+    #[test]
+    fn test_rate_limiter_max_concurrent() {
+        let (limiter, violations) = limiter(RateLimitsConfig {
+            global: Some(RateLimitConfig {
+                max_concurrent: Some(3),
+                ..Default::default()
+            }),
+            domains: vec![DomainRateLimitConfig {
+                domain: "*.example.com".to_string(),
+                limits: RateLimitConfig {
+                    max_concurrent: Some(1),
+                    ..Default::default()
+                },
+            }],
+        });
+
+        let first = limiter.acquire("api.example.com", 443).unwrap();
+        let err = limiter.acquire("cdn.example.com", 443).err().unwrap();
+        assert_eq!(err.scope, "*.example.com");
+        assert_eq!(err.kind, RateLimitKind::MaxConcurrent);
+        assert_eq!(violations.get_count(), 1);
+
+        // Other domains only count against the global scope
+        let _second = limiter.acquire("github.com", 443).unwrap();
+        let _third = limiter.acquire("github.com", 443).unwrap();
+        let err = limiter.acquire("github.com", 443).err().unwrap();
+        assert_eq!(err.scope, "global");
+
+        // Closing a connection frees its slots
+        drop(first);
+        assert!(limiter.acquire("api.example.com", 443).is_ok());
+    }
+
+    #[test]
+    fn test_rate_limiter_connections_per_minute() {
+        let (limiter, _) = limiter(RateLimitsConfig {
+            global: None,
+            domains: vec![DomainRateLimitConfig {
+                domain: "github.com".to_string(),
+                limits: RateLimitConfig {
+                    connections_per_minute: Some(2),
+                    ..Default::default()
+                },
+            }],
+        });
+
+        // Closed connections still count within the window
+        drop(limiter.acquire("github.com", 443).unwrap());
+        drop(limiter.acquire("github.com", 443).unwrap());
+        let err = limiter.acquire("github.com", 443).err().unwrap();
+        assert_eq!(err.kind, RateLimitKind::ConnectionsPerMinute);
+        assert!(limiter.acquire("example.com", 443).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_throttled_io_bandwidth() {
+        let (limiter, violations) = limiter(RateLimitsConfig {
+            global: Some(RateLimitConfig {
+                bytes_per_sec: Some(1000),
+                ..Default::default()
+            }),
+            domains: vec![],
+        });
+        let permit = limiter.acquire("example.com", 443).unwrap();
+
+        let (client, mut server) = tokio::io::duplex(64 * 1024);
+        let mut client = ThrottledIo::new(client, permit);
+        let started = Instant::now();
+        tokio::spawn(async move {
+            // One second of burst, then 100 bytes take 100ms each
+            for len in [1000, 100, 100, 100] {
+                client.write_all(&vec![0u8; len]).await.unwrap();
+            }
+        });
+        let mut received = vec![0u8; 1300];
+        server.read_exact(&mut received).await.unwrap();
+
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(190), "elapsed {:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(1), "elapsed {:?}", elapsed);
+        assert_eq!(violations.get_count(), 1);
+    }
+}
@@ -0,0 +1,145 @@
+//! Client for an external MITM proxy listening on a Unix socket.
+
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use hyper::body::{Body, Frame, Incoming, SizeHint};
+use hyper::client::conn::http1::{self, SendRequest};
+use hyper::{Request, Response};
+use hyper_util::rt::TokioIo;
+use parking_lot::Mutex;
+use pin_project_lite::pin_project;
+use tokio::net::UnixStream;
+
+/// Maximum number of idle keep-alive connections kept open to the MITM proxy.
+const MAX_IDLE_CONNECTIONS: usize = 8;
+
+/// How long a pooled connection may take to become ready before a new one is opened.
+const READY_TIMEOUT: Duration = Duration::from_secs(1);
+
+type MitmSender = SendRequest<BoxBody<Bytes, hyper::Error>>;
+
+/// HTTP/1.1 client for the MITM proxy with a small keep-alive pool.
+#[derive(Clone)]
+pub struct MitmClient {
+    socket_path: Arc<str>,
+    idle: Arc<Mutex<Vec<MitmSender>>>,
+}
+
+impl MitmClient {
+    /// Create a client for the MITM proxy at the given socket path.
+    pub fn new(socket_path: impl Into<String>) -> Self {
+        Self {
+            socket_path: Arc::from(socket_path.into()),
+            idle: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Get the MITM proxy socket path.
+    pub fn socket_path(&self) -> &str {
+        &self.socket_path
+    }
+
+    /// Send a proxy request (absolute-form URI) to the MITM proxy.
+    /// Bodies are streamed in both directions. Connections are reused once
+    /// the response body has been fully read, unless the exchange upgraded.
+    pub async fn send_request(
+        &self,
+        req: Request<BoxBody<Bytes, hyper::Error>>,
+    ) -> Result<Response<MitmBody>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut sender = self.checkout().await?;
+        let resp = sender.send_request(req).await?;
+
+        let checkin = (resp.status() != hyper::StatusCode::SWITCHING_PROTOCOLS)
+            .then(|| (self.clone(), sender));
+        Ok(resp.map(|inner| MitmBody { inner, checkin }))
+    }
+
+    /// Take an idle connection from the pool, or open a new one.
+    async fn checkout(&self) -> Result<MitmSender, Box<dyn std::error::Error + Send + Sync>> {
+        loop {
+            let Some(mut sender) = self.idle.lock().pop() else {
+                break;
+            };
+            // Returned connections finish their exchange right after the body ends
+            if let Ok(Ok(())) = tokio::time::timeout(READY_TIMEOUT, sender.ready()).await {
+                return Ok(sender);
+            }
+        }
+
+        let stream = UnixStream::connect(&*self.socket_path).await?;
+        let (sender, conn) = http1::Builder::new()
+            .preserve_header_case(true)
+            .title_case_headers(true)
+            .handshake(TokioIo::new(stream))
+            .await?;
+
+        tokio::spawn(async move {
+            if let Err(e) = conn.with_upgrades().await {
+                tracing::debug!("MITM connection error: {}", e);
+            }
+        });
+
+        Ok(sender)
+    }
+
+    /// Return a connection whose response body has been read to the pool.
+    fn checkin(&self, sender: MitmSender) {
+        if sender.is_closed() {
+            return;
+        }
+        let mut idle = self.idle.lock();
+        if idle.len() < MAX_IDLE_CONNECTIONS {
+            idle.push(sender);
+        }
+    }
+}
+
+pin_project! {
+    /// Response body from the MITM proxy. Its connection goes back to the pool
+    /// as soon as the last frame is read, before the client sees the end, so a
+    /// follow-up request on the same client connection finds it there.
+    pub struct MitmBody {
+        #[pin]
+        inner: Incoming,
+        checkin: Option<(MitmClient, MitmSender)>,
+    }
+}
+
+impl Body for MitmBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, hyper::Error>>> {
+        let mut this = self.project();
+        let result = futures::ready!(this.inner.as_mut().poll_frame(cx));
+        match &result {
+            // A broken connection is not reused
+            Some(Err(_)) => {
+                this.checkin.take();
+            }
+            _ if result.is_none() || this.inner.is_end_stream() => {
+                if let Some((client, sender)) = this.checkin.take() {
+                    client.checkin(sender);
+                }
+            }
+            _ => {}
+        }
+        Poll::Ready(result)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
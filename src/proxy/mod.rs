@@ -1,9 +1,45 @@
 //! Proxy server implementations.
 
+pub mod ask;
+pub mod audit;
+pub mod auth;
+pub mod cassette;
+pub mod connect;
+pub mod credentials;
+pub mod dns;
+pub mod fake_ip;
 pub mod filter;
 pub mod http;
+pub mod http_rules;
+pub mod intercept;
+pub mod limits;
+pub mod mitm;
+pub mod policy;
+pub mod sni;
 pub mod socks5;
+pub mod udp;
+pub mod upstream;
 
-pub use filter::{DomainFilter, FilterDecision};
+pub use ask::{resolve_ask, AskAnswer, AskBroker, AskListener, AskRequest, AskResponse};
+pub use audit::{
+    AuditListener, AuditLog, AuditProtocol, AuditRecord, CloseReason, ConnectionAudit, CountingIo,
+};
+pub use auth::{proxy_url, ProxyAuth};
+pub use cassette::{through_cassette, Cassette, CassetteStep, Interaction, Recording};
+pub use connect::{connect_target, resolve_target};
+pub use credentials::CredentialInjector;
+pub use dns::DnsForwarder;
+pub use fake_ip::FakeIpPool;
+pub use filter::{DomainFilter, FilterDecision, FilterRule, FilterWatch, SharedFilter};
 pub use http::HttpProxy;
+pub use http_rules::{HttpRuleDenial, HttpRules};
+pub use intercept::{CertificateAuthority, TlsInterceptor};
+pub use limits::{
+    RateLimitExceeded, RateLimitKind, RateLimitPermit, RateLimiter, ThrottledIo,
+};
+pub use mitm::{MitmBody, MitmClient};
+pub use policy::{verify_client_hello, ConnectionRequest, FilteredPolicy, NetworkPolicy};
+pub use sni::ClientHello;
 pub use socks5::Socks5Proxy;
+pub use udp::UdpRelayLimits;
+pub use upstream::{UpstreamProxy, UpstreamScheme};
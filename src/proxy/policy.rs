@@ -0,0 +1,371 @@
+//! Pluggable connection policy for the proxy servers.
+//!
+//! The proxies ask a [`NetworkPolicy`] about every connection (and every
+//! SOCKS5 UDP datagram and DNS query). [`DomainFilter`] and [`SharedFilter`] implement it
+//! from `NetworkConfig`; embedders can implement it with their own async
+//! logic, such as a database-backed allowlist or per-tenant rules.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+
+use crate::config::NetworkConfig;
+use crate::proxy::audit::AuditProtocol;
+use crate::proxy::filter::{DomainFilter, FilterDecision, FilterRule, SharedFilter};
+use crate::proxy::sni::ClientHello;
+use crate::utils::parse_ip_host;
+
+/// A connection awaiting a policy decision.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionRequest {
+    /// Proxy protocol.
+    pub protocol: AuditProtocol,
+    /// Destination host as requested by the client.
+    pub host: String,
+    /// Destination port.
+    pub port: u16,
+    /// Address of the sandboxed client as seen by the proxy.
+    pub client_addr: SocketAddr,
+    /// Request method, for plain HTTP requests.
+    pub method: Option<String>,
+    /// Request path, for plain HTTP requests.
+    pub path: Option<String>,
+}
+
+impl ConnectionRequest {
+    /// Create a request without HTTP details.
+    pub fn new(protocol: AuditProtocol, host: &str, port: u16, client_addr: SocketAddr) -> Self {
+        Self {
+            protocol,
+            host: host.to_string(),
+            port,
+            client_addr,
+            method: None,
+            path: None,
+        }
+    }
+}
+
+/// Decides whether the proxies allow a connection.
+///
+/// Checks that need no decision stay with a [`SharedFilter`]: vetting
+/// resolved addresses, requiring TLS SNI and `httpRules`. Updates to the
+/// filter that close revoked connections ask the policy again about each
+/// open connection. A custom policy gets an unrestricted filter that
+/// only verifies TLS SNI, unless it overrides [`NetworkPolicy::shared_filter`]
+/// or [`NetworkPolicy::into_shared`].
+pub trait NetworkPolicy: Send + Sync + 'static {
+    /// Decide a connection, returning the decision and the rule behind it.
+    /// Custom policies can report their reasoning with [`FilterRule::Policy`].
+    fn decide<'a>(
+        &'a self,
+        request: &'a ConnectionRequest,
+    ) -> BoxFuture<'a, (FilterDecision, FilterRule)>;
+
+    /// The filter for the checks other than decisions, if the policy has one.
+    fn shared_filter(&self) -> Option<SharedFilter> {
+        None
+    }
+
+    /// Split into the decision logic and the filter for the remaining checks.
+    fn into_shared(self) -> (Arc<dyn NetworkPolicy>, SharedFilter)
+    where
+        Self: Sized,
+    {
+        let filter = self.shared_filter().unwrap_or_else(|| {
+            SharedFilter::new(DomainFilter::from_config(&NetworkConfig::default()))
+        });
+        (Arc::new(self), filter)
+    }
+}
+
+/// Decide a request by the config rules. DNS queries are decided by name.
+fn evaluate(filter: &DomainFilter, request: &ConnectionRequest) -> (FilterDecision, FilterRule) {
+    match request.protocol {
+        AuditProtocol::Dns if filter.allows_name(&request.host) => {
+            (FilterDecision::Allow, FilterRule::Default)
+        }
+        AuditProtocol::Dns => (FilterDecision::Deny, FilterRule::NotAllowed),
+        _ => filter.evaluate(&request.host, request.port),
+    }
+}
+
+impl NetworkPolicy for DomainFilter {
+    fn decide<'a>(
+        &'a self,
+        request: &'a ConnectionRequest,
+    ) -> BoxFuture<'a, (FilterDecision, FilterRule)> {
+        Box::pin(std::future::ready(evaluate(self, request)))
+    }
+
+    fn shared_filter(&self) -> Option<SharedFilter> {
+        Some(SharedFilter::new(self.clone()))
+    }
+
+    /// Decisions follow updates to the returned filter.
+    fn into_shared(self) -> (Arc<dyn NetworkPolicy>, SharedFilter) {
+        SharedFilter::new(self).into_shared()
+    }
+}
+
+impl NetworkPolicy for SharedFilter {
+    fn decide<'a>(
+        &'a self,
+        request: &'a ConnectionRequest,
+    ) -> BoxFuture<'a, (FilterDecision, FilterRule)> {
+        let verdict = evaluate(&self.current(), request);
+        Box::pin(std::future::ready(verdict))
+    }
+
+    fn shared_filter(&self) -> Option<SharedFilter> {
+        Some(self.clone())
+    }
+
+    fn into_shared(self) -> (Arc<dyn NetworkPolicy>, SharedFilter) {
+        (Arc::new(self.clone()), self)
+    }
+}
+
+/// Lets embedders keep a handle on their policy after passing it to a proxy.
+impl<P: NetworkPolicy + ?Sized> NetworkPolicy for Arc<P> {
+    fn decide<'a>(
+        &'a self,
+        request: &'a ConnectionRequest,
+    ) -> BoxFuture<'a, (FilterDecision, FilterRule)> {
+        (**self).decide(request)
+    }
+
+    fn shared_filter(&self) -> Option<SharedFilter> {
+        (**self).shared_filter()
+    }
+}
+
+/// A custom policy combined with the filter for the checks other than decisions,
+/// e.g. to keep the address, TLS SNI and `httpRules` settings of a `NetworkConfig`.
+pub struct FilteredPolicy<P> {
+    /// Decides connections.
+    pub policy: P,
+    /// Used for everything else.
+    pub filter: SharedFilter,
+}
+
+impl<P: NetworkPolicy> NetworkPolicy for FilteredPolicy<P> {
+    fn decide<'a>(
+        &'a self,
+        request: &'a ConnectionRequest,
+    ) -> BoxFuture<'a, (FilterDecision, FilterRule)> {
+        self.policy.decide(request)
+    }
+
+    fn shared_filter(&self) -> Option<SharedFilter> {
+        Some(self.filter.clone())
+    }
+
+    fn into_shared(self) -> (Arc<dyn NetworkPolicy>, SharedFilter) {
+        (Arc::new(self.policy), self.filter)
+    }
+}
+
+/// Check a tunnel's ClientHello against the decision for its CONNECT host.
+/// The SNI may name another host only if the policy treats both the same way.
+/// Clients send no SNI for IP literals, so it may be missing for those.
+pub async fn verify_client_hello(
+    policy: &dyn NetworkPolicy,
+    request: &ConnectionRequest,
+    decision: FilterDecision,
+    hello: &ClientHello,
+) -> Result<(), String> {
+    let host = request.host.strip_suffix('.').unwrap_or(&request.host);
+    match hello {
+        ClientHello::Sni(name) if name.eq_ignore_ascii_case(host) => Ok(()),
+        ClientHello::Sni(name) => {
+            let sni_request = ConnectionRequest {
+                host: name.clone(),
+                ..request.clone()
+            };
+            if policy.decide(&sni_request).await.0 == decision {
+                Ok(())
+            } else {
+                Err(format!("TLS SNI '{}' does not match CONNECT host '{}'", name, host))
+            }
+        }
+        ClientHello::NoSni if parse_ip_host(host).is_some() => Ok(()),
+        ClientHello::NoSni => Err(format!("TLS ClientHello for '{}' has no SNI", host)),
+        ClientHello::NotTls => Err(format!("tunnel to '{}' did not start with TLS", host)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(host: &str) -> ConnectionRequest {
+        let client_addr = "127.0.0.1:40000".parse().unwrap();
+        ConnectionRequest::new(AuditProtocol::Connect, host, 443, client_addr)
+    }
+
+    /// Allows hosts on a tenant's list, whatever the port.
+    struct TenantPolicy {
+        hosts: Vec<String>,
+    }
+
+    impl NetworkPolicy for TenantPolicy {
+        fn decide<'a>(
+            &'a self,
+            request: &'a ConnectionRequest,
+        ) -> BoxFuture<'a, (FilterDecision, FilterRule)> {
+            Box::pin(async move {
+                tokio::task::yield_now().await;
+                if self.hosts.contains(&request.host) {
+                    (FilterDecision::Allow, FilterRule::Policy("tenant".to_string()))
+                } else {
+                    (FilterDecision::Deny, FilterRule::Policy("not in tenant".to_string()))
+                }
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_verify_client_hello() {
+        let config = NetworkConfig {
+            allowed_domains: vec!["*.example.com".to_string()],
+            ..Default::default()
+        };
+        let (policy, _filter) = DomainFilter::from_config(&config).into_shared();
+        let sni = |name: &str| ClientHello::Sni(name.to_string());
+        let verify = |host: &'static str, hello: ClientHello| {
+            let policy = policy.clone();
+            async move {
+                let request = request(host);
+                verify_client_hello(&*policy, &request, FilterDecision::Allow, &hello).await
+            }
+        };
+
+        assert!(verify("api.example.com", sni("api.example.com")).await.is_ok());
+        assert!(verify("api.example.com.", sni("API.example.com")).await.is_ok());
+        assert!(verify("api.example.com", sni("cdn.example.com")).await.is_ok());
+        assert!(verify("api.example.com", sni("evil.org")).await.is_err());
+        assert!(verify("api.example.com", ClientHello::NoSni).await.is_err());
+        assert!(verify("10.0.0.1", ClientHello::NoSni).await.is_ok());
+        assert!(verify("api.example.com", ClientHello::NotTls).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_custom_policy() {
+        let tenant = TenantPolicy {
+            hosts: vec!["api.example.com".to_string()],
+        };
+        let (policy, filter) = tenant.into_shared();
+
+        let (decision, rule) = policy.decide(&request("api.example.com")).await;
+        assert_eq!(decision, FilterDecision::Allow);
+        assert_eq!(rule.to_string(), "policy:tenant");
+        assert_eq!(policy.decide(&request("evil.org")).await.0, FilterDecision::Deny);
+
+        // The default filter adds no restrictions of its own
+        assert!(filter.current().is_allowed("evil.org", 443));
+        assert!(!filter.current().requires_tls_sni(443));
+        let hello = ClientHello::Sni("evil.org".to_string());
+        let request = request("api.example.com");
+        let verified = verify_client_hello(&*policy, &request, FilterDecision::Allow, &hello);
+        assert!(verified.await.is_err());
+
+        // Paired with a config filter, the policy still decides
+        let config = NetworkConfig {
+            tls_sni: Some(crate::config::TlsSniConfig {
+                enabled: Some(true),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let filtered = FilteredPolicy {
+            policy: policy.clone(),
+            filter: SharedFilter::new(DomainFilter::from_config(&config)),
+        };
+        let (policy, filter) = filtered.into_shared();
+        assert!(filter.current().requires_tls_sni(443));
+        assert_eq!(policy.decide(&request).await.0, FilterDecision::Allow);
+    }
+
+    #[tokio::test]
+    async fn test_custom_policy_revokes() {
+        let tenant = TenantPolicy {
+            hosts: vec!["api.example.com".to_string()],
+        };
+        let (policy, filter) = tenant.into_shared();
+        let timeout = std::time::Duration::from_millis(50);
+
+        // The unrestricted filter would never revoke; the policy is asked instead
+        let mut allowed = filter.subscribe().decided_by(policy.clone(), request("api.example.com"));
+        let mut denied = filter.subscribe().decided_by(policy, request("evil.org"));
+        filter.update(DomainFilter::allow_all(), false);
+        assert!(tokio::time::timeout(timeout, denied.revoked("evil.org", 443)).await.is_err());
+        filter.update(DomainFilter::allow_all(), true);
+        assert!(tokio::time::timeout(timeout, denied.revoked("evil.org", 443)).await.is_ok());
+        let revoked = allowed.revoked("api.example.com", 443);
+        assert!(tokio::time::timeout(timeout, revoked).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_arc_policy_keeps_filter() {
+        use crate::proxy::{HttpProxy, Socks5Proxy};
+
+        let config = NetworkConfig {
+            allowed_domains: vec!["*.example.com".to_string()],
+            block_private_addresses: Some(true),
+            tls_sni: Some(crate::config::TlsSniConfig {
+                enabled: Some(true),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let private = "10.0.0.1".parse().unwrap();
+
+        // The proxies share the embedder's filter, updates included
+        let shared = Arc::new(SharedFilter::new(DomainFilter::from_config(&config)));
+        let http = HttpProxy::new(shared.clone(), None).await.unwrap();
+        let socks = Socks5Proxy::new(shared.clone()).await.unwrap();
+        for filter in [http.filter(), socks.filter()] {
+            assert!(filter.current().requires_tls_sni(443));
+            assert!(!filter.current().is_address_allowed(private));
+        }
+        shared.update(DomainFilter::allow_all(), false);
+        assert!(!http.filter().current().requires_tls_sni(443));
+        assert!(!socks.filter().current().requires_tls_sni(443));
+
+        let filter = Arc::new(DomainFilter::from_config(&config));
+        let http = HttpProxy::new(filter.clone(), None).await.unwrap();
+        let socks = Socks5Proxy::new(filter).await.unwrap();
+        for filter in [http.filter(), socks.filter()] {
+            assert!(filter.current().requires_tls_sni(443));
+            assert!(!filter.current().is_address_allowed(private));
+        }
+
+        let filtered = Arc::new(FilteredPolicy {
+            policy: TenantPolicy { hosts: vec![] },
+            filter: SharedFilter::new(DomainFilter::from_config(&config)),
+        });
+        let socks = Socks5Proxy::new(filtered).await.unwrap();
+        assert!(socks.filter().current().requires_tls_sni(443));
+    }
+
+    #[tokio::test]
+    async fn test_dns_decided_by_name() {
+        let config = NetworkConfig {
+            allowed_domains: vec!["*.example.com".to_string()],
+            denied_domains: vec!["evil.example.com".to_string(), "*.example.com:80".to_string()],
+            ..Default::default()
+        };
+        let (policy, _filter) = DomainFilter::from_config(&config).into_shared();
+        let dns = |host: &str| {
+            let client_addr = "127.0.0.1:40000".parse().unwrap();
+            ConnectionRequest::new(AuditProtocol::Dns, host, 0, client_addr)
+        };
+
+        // Port-qualified denials leave the name resolvable
+        assert_eq!(policy.decide(&dns("api.example.com")).await.0, FilterDecision::Allow);
+        assert_eq!(policy.decide(&dns("evil.example.com")).await.0, FilterDecision::Deny);
+        assert_eq!(policy.decide(&dns("example.org")).await.0, FilterDecision::Deny);
+    }
+}
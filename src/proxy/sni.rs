@@ -0,0 +1,253 @@
+//! TLS ClientHello inspection for CONNECT tunnels.
+//!
+//! The proxy reads the client's first TLS handshake message before piping the
+//! tunnel, so the server name it asks for (SNI) can be checked against the
+//! CONNECT host. The bytes read are returned so they can be forwarded.
+
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// TLS record type for handshake messages.
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+/// Handshake message type for ClientHello.
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+/// Extension type for server_name (RFC 6066).
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+/// server_name entry type for DNS host names.
+const NAME_TYPE_HOST_NAME: u8 = 0x00;
+
+/// Largest ClientHello we are willing to buffer (records may be fragmented).
+const MAX_CLIENT_HELLO_SIZE: usize = 64 * 1024;
+
+/// How long the client may take to send its ClientHello.
+pub const CLIENT_HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What the client sent first in a tunnel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientHello {
+    /// A ClientHello naming a server (lowercase, without a trailing dot).
+    Sni(String),
+    /// A ClientHello without a server_name extension.
+    NoSni,
+    /// Not a TLS handshake, or a malformed one.
+    NotTls,
+}
+
+/// Read the client's first TLS handshake message.
+/// Returns what was found and every byte read, which must be forwarded.
+pub async fn read_client_hello<R>(reader: &mut R) -> std::io::Result<(ClientHello, Vec<u8>)>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut buf = Vec::with_capacity(1024);
+    loop {
+        if let Some(hello) = parse_client_hello(&buf) {
+            return Ok((hello, buf));
+        }
+        if buf.len() >= MAX_CLIENT_HELLO_SIZE {
+            return Ok((ClientHello::NotTls, buf));
+        }
+
+        let mut chunk = [0u8; 4096];
+        let n = reader.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+/// Parse a ClientHello from the start of a TLS stream.
+/// Returns None if more bytes are needed.
+pub fn parse_client_hello(data: &[u8]) -> Option<ClientHello> {
+    // Reassemble the handshake message from (possibly several) records
+    let mut handshake = Vec::new();
+    let mut rest = data;
+    loop {
+        let header = rest.get(..5)?;
+        if header[0] != CONTENT_TYPE_HANDSHAKE {
+            return Some(ClientHello::NotTls);
+        }
+        let len = u16::from_be_bytes([header[3], header[4]]) as usize;
+        let fragment = rest.get(5..5 + len)?;
+        handshake.extend_from_slice(fragment);
+        rest = &rest[5 + len..];
+
+        if handshake.len() >= 4 {
+            if handshake[0] != HANDSHAKE_CLIENT_HELLO {
+                return Some(ClientHello::NotTls);
+            }
+            let body_len = u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]);
+            let body_len = body_len as usize;
+            if handshake.len() >= 4 + body_len {
+                let hello = parse_client_hello_body(&handshake[4..4 + body_len]);
+                return Some(hello.unwrap_or(ClientHello::NotTls));
+            }
+        }
+    }
+}
+
+/// Parse the body of a ClientHello handshake message.
+/// Returns None if it is malformed.
+fn parse_client_hello_body(body: &[u8]) -> Option<ClientHello> {
+    let mut reader = Reader(body);
+
+    reader.skip(2 + 32)?; // client_version, random
+    reader.skip_vec8()?; // session_id
+    reader.skip_vec16()?; // cipher_suites
+    reader.skip_vec8()?; // compression_methods
+
+    // Extensions are optional in TLS 1.2 and earlier
+    if reader.0.is_empty() {
+        return Some(ClientHello::NoSni);
+    }
+
+    let mut extensions = Reader(reader.vec16()?);
+    while !extensions.0.is_empty() {
+        let ext_type = extensions.u16()?;
+        let data = extensions.vec16()?;
+        if ext_type != EXTENSION_SERVER_NAME {
+            continue;
+        }
+
+        let mut names = Reader(Reader(data).vec16()?);
+        while !names.0.is_empty() {
+            let name_type = names.u8()?;
+            let name = names.vec16()?;
+            if name_type == NAME_TYPE_HOST_NAME {
+                let name = std::str::from_utf8(name).ok()?;
+                let name = name.strip_suffix('.').unwrap_or(name);
+                return Some(ClientHello::Sni(name.to_ascii_lowercase()));
+            }
+        }
+        return Some(ClientHello::NoSni);
+    }
+
+    Some(ClientHello::NoSni)
+}
+
+/// Minimal big-endian reader over TLS wire structures.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn skip(&mut self, len: usize) -> Option<()> {
+        self.take(len).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn vec16(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()? as usize;
+        self.take(len)
+    }
+
+    fn skip_vec8(&mut self) -> Option<()> {
+        let len = self.u8()? as usize;
+        self.skip(len)
+    }
+
+    fn skip_vec16(&mut self) -> Option<()> {
+        self.vec16().map(|_| ())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Build a minimal TLS 1.2-style ClientHello record, optionally with SNI.
+    pub(crate) fn client_hello(sni: Option<&str>) -> Vec<u8> {
+        let mut extensions = Vec::new();
+        if let Some(name) = sni {
+            let mut list = vec![NAME_TYPE_HOST_NAME];
+            list.extend_from_slice(&(name.len() as u16).to_be_bytes());
+            list.extend_from_slice(name.as_bytes());
+            let mut data = (list.len() as u16).to_be_bytes().to_vec();
+            data.extend_from_slice(&list);
+            extensions.extend_from_slice(&EXTENSION_SERVER_NAME.to_be_bytes());
+            extensions.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            extensions.extend_from_slice(&data);
+        }
+        // An unrelated extension (supported_versions) before or after SNI
+        extensions.extend_from_slice(&[0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04]);
+
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0u8; 32]);
+        body.push(0); // session_id
+        body.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]); // cipher_suites
+        body.extend_from_slice(&[0x01, 0x00]); // compression_methods
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+
+        let mut handshake = vec![HANDSHAKE_CLIENT_HELLO];
+        handshake.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(&body);
+
+        let mut record = vec![CONTENT_TYPE_HANDSHAKE, 0x03, 0x01];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    #[test]
+    fn test_parse_client_hello() {
+        let hello = client_hello(Some("API.Example.com."));
+        assert_eq!(
+            parse_client_hello(&hello),
+            Some(ClientHello::Sni("api.example.com".to_string()))
+        );
+        assert_eq!(parse_client_hello(&client_hello(None)), Some(ClientHello::NoSni));
+
+        // Incomplete records need more bytes
+        assert_eq!(parse_client_hello(&hello[..3]), None);
+        assert_eq!(parse_client_hello(&hello[..hello.len() - 1]), None);
+
+        assert_eq!(parse_client_hello(b"GET / HTTP/1.1\r\n"), Some(ClientHello::NotTls));
+        assert_eq!(parse_client_hello(b"SSH-2.0-OpenSSH_9.6\r\n"), Some(ClientHello::NotTls));
+    }
+
+    #[test]
+    fn test_parse_fragmented_client_hello() {
+        // Split the handshake message across two records
+        let hello = client_hello(Some("example.com"));
+        let handshake = &hello[5..];
+        let (first, second) = handshake.split_at(20);
+        let mut data = Vec::new();
+        for fragment in [first, second] {
+            data.extend_from_slice(&[CONTENT_TYPE_HANDSHAKE, 0x03, 0x01]);
+            data.extend_from_slice(&(fragment.len() as u16).to_be_bytes());
+            data.extend_from_slice(fragment);
+        }
+
+        assert_eq!(parse_client_hello(&data[..30]), None);
+        assert_eq!(
+            parse_client_hello(&data),
+            Some(ClientHello::Sni("example.com".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_client_hello_returns_bytes() {
+        let mut data = client_hello(Some("example.com"));
+        data.extend_from_slice(b"trailing");
+        let (hello, read) = read_client_hello(&mut data.as_slice()).await.unwrap();
+        assert_eq!(hello, ClientHello::Sni("example.com".to_string()));
+        assert_eq!(read, data);
+    }
+}
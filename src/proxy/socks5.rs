@@ -1,19 +1,37 @@
 //! SOCKS5 proxy server (RFC 1928).
 
-use std::net::SocketAddr;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::sync::Arc;
 
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
 use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
 
+use crate::config::CassetteMode;
 use crate::error::SandboxError;
-use crate::proxy::filter::{DomainFilter, FilterDecision};
+use crate::proxy::ask::{resolve_ask, AskBroker};
+use crate::proxy::audit::{AuditLog, AuditProtocol, CloseReason, ConnectionAudit, CountingIo};
+use crate::proxy::auth::ProxyAuth;
+use crate::proxy::cassette::Cassette;
+use crate::proxy::connect::connect_target;
+use crate::proxy::fake_ip::FakeIpPool;
+use crate::proxy::filter::{FilterDecision, FilterRule, SharedFilter};
+use crate::proxy::limits::{RateLimitPermit, RateLimiter, ThrottledIo};
+use crate::proxy::policy::{ConnectionRequest, NetworkPolicy};
+use crate::proxy::udp::{UdpRelay, UdpRelayLimits};
+use crate::proxy::upstream::UpstreamProxy;
 
 // SOCKS5 constants
 const SOCKS_VERSION: u8 = 0x05;
 const AUTH_NONE: u8 = 0x00;
+const AUTH_USERNAME_PASSWORD: u8 = 0x02;
+const AUTH_NO_ACCEPTABLE: u8 = 0xFF;
+const USERPASS_VERSION: u8 = 0x01;
+const USERPASS_SUCCESS: u8 = 0x00;
+const USERPASS_FAILURE: u8 = 0x01;
 const CMD_CONNECT: u8 = 0x01;
+const CMD_UDP_ASSOCIATE: u8 = 0x03;
 const ATYP_IPV4: u8 = 0x01;
 const ATYP_DOMAIN: u8 = 0x03;
 const ATYP_IPV6: u8 = 0x04;
@@ -21,27 +39,74 @@ const REP_SUCCESS: u8 = 0x00;
 const REP_GENERAL_FAILURE: u8 = 0x01;
 const REP_CONNECTION_NOT_ALLOWED: u8 = 0x02;
 const REP_HOST_UNREACHABLE: u8 = 0x04;
+const REP_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+
+/// Attempts to find a port that is free for both the TCP listener and the UDP relay.
+const BIND_ATTEMPTS: usize = 8;
+
+/// State shared by every client connection of a SOCKS5 proxy.
+#[derive(Clone)]
+struct ProxyContext {
+    policy: Arc<dyn NetworkPolicy>,
+    filter: SharedFilter,
+    udp_relay: Option<Arc<UdpRelay>>,
+    upstream: Option<Arc<UpstreamProxy>>,
+    audit_log: Option<AuditLog>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    ask: Option<AskBroker>,
+    cassette: Option<Arc<Cassette>>,
+    fake_ips: Option<Arc<FakeIpPool>>,
+    auth: Option<Arc<ProxyAuth>>,
+}
 
 /// SOCKS5 proxy server.
 pub struct Socks5Proxy {
     listener: Option<TcpListener>,
     port: u16,
-    filter: Arc<DomainFilter>,
+    policy: Arc<dyn NetworkPolicy>,
+    filter: SharedFilter,
+    udp_socket: Option<UdpSocket>,
+    udp_limits: Option<UdpRelayLimits>,
+    udp_relay: Option<Arc<UdpRelay>>,
+    udp_task: Option<JoinHandle<()>>,
+    upstream: Option<Arc<UpstreamProxy>>,
+    audit_log: Option<AuditLog>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    ask: Option<AskBroker>,
+    cassette: Option<Arc<Cassette>>,
+    fake_ips: Option<Arc<FakeIpPool>>,
+    auth: Option<Arc<ProxyAuth>>,
     shutdown_tx: Option<oneshot::Sender<()>>,
 }
 
 impl Socks5Proxy {
-    /// Create a new SOCKS5 proxy server.
-    pub async fn new(filter: DomainFilter) -> Result<Self, SandboxError> {
-        let listener = TcpListener::bind("127.0.0.1:0").await?;
+    /// Create a new SOCKS5 proxy server that asks `policy` about each
+    /// connection and UDP datagram.
+    /// The UDP relay is bound to the same port number as the TCP listener and
+    /// is enabled with default limits; see [`Socks5Proxy::set_udp_limits`].
+    pub async fn new(policy: impl NetworkPolicy) -> Result<Self, SandboxError> {
+        let (listener, udp_socket) = bind_listeners().await?;
         let port = listener.local_addr()?.port();
 
         tracing::debug!("SOCKS5 proxy listening on port {}", port);
 
+        let (policy, filter) = policy.into_shared();
         Ok(Self {
             listener: Some(listener),
             port,
-            filter: Arc::new(filter),
+            policy,
+            filter,
+            udp_socket,
+            udp_limits: Some(UdpRelayLimits::default()),
+            udp_relay: None,
+            udp_task: None,
+            upstream: None,
+            audit_log: None,
+            rate_limiter: None,
+            ask: None,
+            cassette: None,
+            fake_ips: None,
+            auth: None,
             shutdown_tx: None,
         })
     }
@@ -51,6 +116,78 @@ impl Socks5Proxy {
         self.port
     }
 
+    /// Get the UDP relay port, if UDP ASSOCIATE is available.
+    /// This is always the same number as the TCP port.
+    pub fn udp_port(&self) -> Option<u16> {
+        let relay_port = match &self.udp_relay {
+            Some(relay) => relay.local_addr().ok().map(|addr| addr.port()),
+            None => self.udp_socket.as_ref().map(|_| self.port),
+        };
+        self.udp_limits.and(relay_port)
+    }
+
+    /// Set the UDP relay limits, or disable UDP ASSOCIATE with None.
+    /// Must be called before [`Socks5Proxy::start`].
+    pub fn set_udp_limits(&mut self, limits: Option<UdpRelayLimits>) {
+        self.udp_limits = limits;
+    }
+
+    /// Get the shared filter used by this proxy for checks other than decisions.
+    pub fn filter(&self) -> &SharedFilter {
+        &self.filter
+    }
+
+    /// Chain outbound TCP connections through an upstream proxy.
+    /// UDP datagrams are always relayed directly.
+    /// Must be called before [`Socks5Proxy::start`].
+    pub fn set_upstream_proxy(&mut self, upstream: Option<UpstreamProxy>) {
+        self.upstream = upstream.map(Arc::new);
+    }
+
+    /// Record every CONNECT request in an audit log.
+    /// UDP datagrams are not audited.
+    /// Must be called before [`Socks5Proxy::start`].
+    pub fn set_audit_log(&mut self, audit_log: Option<AuditLog>) {
+        self.audit_log = audit_log;
+    }
+
+    /// Enforce bandwidth, concurrency and connection-rate limits on CONNECT.
+    /// UDP datagrams are not limited.
+    /// Must be called before [`Socks5Proxy::start`].
+    pub fn set_rate_limiter(&mut self, rate_limiter: Option<Arc<RateLimiter>>) {
+        self.rate_limiter = rate_limiter;
+    }
+
+    /// Hold connections the filter answers with [`FilterDecision::Ask`] and
+    /// ask the broker's listeners. Without a broker they are denied.
+    /// Must be called before [`Socks5Proxy::start`].
+    pub fn set_ask_broker(&mut self, ask: Option<AskBroker>) {
+        self.ask = ask;
+    }
+
+    /// Refuse connections and disable UDP ASSOCIATE while `cassette` is
+    /// replayed, since only the HTTP proxy can answer from it. While it is
+    /// recorded, connections pass unrecorded with a warning.
+    /// Must be called before [`Socks5Proxy::start`].
+    pub fn set_cassette(&mut self, cassette: Option<Arc<Cassette>>) {
+        self.cassette = cassette;
+    }
+
+    /// Map CONNECT requests to fake IPs handed out by the DNS forwarder back
+    /// to their names, so TUN-captured connections are decided by name.
+    /// Must be called before [`Socks5Proxy::start`].
+    pub fn set_fake_ips(&mut self, fake_ips: Option<Arc<FakeIpPool>>) {
+        self.fake_ips = fake_ips;
+    }
+
+    /// Require RFC 1929 username/password authentication with these
+    /// credentials. UDP datagrams are relayed while an authenticated client
+    /// holds an association.
+    /// Must be called before [`Socks5Proxy::start`].
+    pub fn set_auth(&mut self, auth: Option<Arc<ProxyAuth>>) {
+        self.auth = auth;
+    }
+
     /// Start the proxy server.
     pub fn start(&mut self) -> Result<(), SandboxError> {
         let listener = self
@@ -62,15 +199,38 @@ impl Socks5Proxy {
         let (shutdown_tx, mut shutdown_rx) = oneshot::channel();
         self.shutdown_tx = Some(shutdown_tx);
 
+        let replaying = self.cassette.as_ref().is_some_and(|c| c.mode() == CassetteMode::Replay);
+        let udp_socket = self.udp_socket.take().filter(|_| !replaying);
+        if self.cassette.is_some() && udp_socket.is_some() && self.udp_limits.is_some() {
+            tracing::warn!("SOCKS5 UDP is relayed but not recorded in the cassette");
+        }
+        if let (Some(socket), Some(limits)) = (udp_socket, self.udp_limits) {
+            let relay = UdpRelay::new(socket, self.policy.clone(), filter.clone(), limits);
+            self.udp_task = Some(tokio::spawn(relay.clone().run()));
+            self.udp_relay = Some(relay);
+        }
+        let context = ProxyContext {
+            policy: self.policy.clone(),
+            filter,
+            udp_relay: self.udp_relay.clone(),
+            upstream: self.upstream.clone(),
+            audit_log: self.audit_log.clone(),
+            rate_limiter: self.rate_limiter.clone(),
+            ask: self.ask.clone(),
+            cassette: self.cassette.clone(),
+            fake_ips: self.fake_ips.clone(),
+            auth: self.auth.clone(),
+        };
+
         tokio::spawn(async move {
             loop {
                 tokio::select! {
                     accept_result = listener.accept() => {
                         match accept_result {
                             Ok((stream, addr)) => {
-                                let filter = filter.clone();
+                                let context = context.clone();
                                 tokio::spawn(async move {
-                                    if let Err(e) = handle_client(stream, addr, filter).await {
+                                    if let Err(e) = handle_client(stream, addr, context).await {
                                         tracing::debug!("SOCKS5 error from {}: {}", addr, e);
                                     }
                                 });
@@ -96,14 +256,36 @@ impl Socks5Proxy {
         if let Some(tx) = self.shutdown_tx.take() {
             let _ = tx.send(());
         }
+        if let Some(task) = self.udp_task.take() {
+            task.abort();
+        }
+        if let Some(relay) = self.udp_relay.take() {
+            relay.clear();
+        }
     }
 }
 
+/// Bind the TCP listener and a UDP socket on the same loopback port.
+/// Falls back to TCP only if no shared port could be found.
+async fn bind_listeners() -> Result<(TcpListener, Option<UdpSocket>), SandboxError> {
+    for _ in 0..BIND_ATTEMPTS {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        if let Ok(socket) = UdpSocket::bind(("127.0.0.1", port)).await {
+            return Ok((listener, Some(socket)));
+        }
+    }
+
+    tracing::warn!("SOCKS5 UDP relay unavailable: no port free for both TCP and UDP");
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    Ok((listener, None))
+}
+
 /// Handle a SOCKS5 client connection.
 async fn handle_client(
     mut stream: TcpStream,
-    _addr: SocketAddr,
-    filter: Arc<DomainFilter>,
+    addr: SocketAddr,
+    context: ProxyContext,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     // Read version and authentication methods
     let mut header = [0u8; 2];
@@ -117,14 +299,27 @@ async fn handle_client(
     let mut methods = vec![0u8; nmethods];
     stream.read_exact(&mut methods).await?;
 
-    // We only support no authentication
-    if !methods.contains(&AUTH_NONE) {
-        stream.write_all(&[SOCKS_VERSION, 0xFF]).await?;
+    // No authentication, unless the proxy has session credentials
+    let method = if context.auth.is_some() { AUTH_USERNAME_PASSWORD } else { AUTH_NONE };
+    if !methods.contains(&method) {
+        stream.write_all(&[SOCKS_VERSION, AUTH_NO_ACCEPTABLE]).await?;
+        if let Some(auth) = &context.auth {
+            auth.reject("SOCKS5", addr);
+        }
         return Err("No supported authentication method".into());
     }
 
     // Send auth method selection
-    stream.write_all(&[SOCKS_VERSION, AUTH_NONE]).await?;
+    stream.write_all(&[SOCKS_VERSION, method]).await?;
+
+    if let Some(auth) = &context.auth {
+        if !authenticate(&mut stream, auth).await? {
+            stream.write_all(&[USERPASS_VERSION, USERPASS_FAILURE]).await?;
+            auth.reject("SOCKS5", addr);
+            return Err("Authentication failed".into());
+        }
+        stream.write_all(&[USERPASS_VERSION, USERPASS_SUCCESS]).await?;
+    }
 
     // Read connection request
     let mut request = [0u8; 4];
@@ -138,11 +333,6 @@ async fn handle_client(
     // request[2] is reserved
     let atyp = request[3];
 
-    if cmd != CMD_CONNECT {
-        send_reply(&mut stream, REP_GENERAL_FAILURE, "0.0.0.0", 0).await?;
-        return Err("Only CONNECT command is supported".into());
-    }
-
     // Parse destination address
     let (host, port) = match atyp {
         ATYP_IPV4 => {
@@ -151,7 +341,7 @@ async fn handle_client(
             let mut port_buf = [0u8; 2];
             stream.read_exact(&mut port_buf).await?;
             let port = u16::from_be_bytes(port_buf);
-            let host = format!("{}.{}.{}.{}", addr[0], addr[1], addr[2], addr[3]);
+            let host = Ipv4Addr::from(addr).to_string();
             (host, port)
         }
         ATYP_DOMAIN => {
@@ -172,18 +362,8 @@ async fn handle_client(
             let mut port_buf = [0u8; 2];
             stream.read_exact(&mut port_buf).await?;
             let port = u16::from_be_bytes(port_buf);
-            // Format as IPv6 address
-            let host = format!(
-                "{:x}:{:x}:{:x}:{:x}:{:x}:{:x}:{:x}:{:x}",
-                u16::from_be_bytes([addr[0], addr[1]]),
-                u16::from_be_bytes([addr[2], addr[3]]),
-                u16::from_be_bytes([addr[4], addr[5]]),
-                u16::from_be_bytes([addr[6], addr[7]]),
-                u16::from_be_bytes([addr[8], addr[9]]),
-                u16::from_be_bytes([addr[10], addr[11]]),
-                u16::from_be_bytes([addr[12], addr[13]]),
-                u16::from_be_bytes([addr[14], addr[15]])
-            );
+            // Canonical (RFC 5952) form, e.g. "2001:db8::1"
+            let host = Ipv6Addr::from(addr).to_string();
             (host, port)
         }
         _ => {
@@ -192,23 +372,109 @@ async fn handle_client(
         }
     };
 
+    match cmd {
+        CMD_CONNECT => {}
+        CMD_UDP_ASSOCIATE => {
+            return handle_udp_associate(stream, addr, port, context.udp_relay).await
+        }
+        _ => {
+            send_reply(&mut stream, REP_COMMAND_NOT_SUPPORTED, "0.0.0.0", 0).await?;
+            return Err("Only CONNECT and UDP ASSOCIATE commands are supported".into());
+        }
+    }
+
+    // Connections captured from the TUN device arrive with fake IPs
+    let host = match context.fake_ips.as_ref().map(|fake_ips| fake_ips.translate(&host)) {
+        Some(Ok(name)) => name,
+        Some(Err(e)) => {
+            tracing::debug!("SOCKS5 cannot connect to {}:{}: {}", host, port, e);
+            send_reply(&mut stream, REP_HOST_UNREACHABLE, "0.0.0.0", 0).await?;
+            return Ok(());
+        }
+        None => host,
+    };
+
     tracing::debug!("SOCKS5 CONNECT {}:{}", host, port);
 
-    // Check filter
-    let decision = filter.check(&host, port);
+    // Ask the policy, keeping a watch so the connection can be revoked later
+    let request = ConnectionRequest::new(AuditProtocol::Socks5, &host, port, addr);
+    let mut watch = context.filter.subscribe().decided_by(context.policy.clone(), request.clone());
+    let (decision, rule) = context.policy.decide(&request).await;
+    let audit = ConnectionAudit::start(
+        context.audit_log.as_ref(),
+        AuditProtocol::Socks5,
+        &host,
+        port,
+        decision,
+        rule,
+    );
+    let decision = match decision {
+        FilterDecision::Ask => {
+            let ask = context.ask.as_ref();
+            resolve_ask(ask, &host, port, AuditProtocol::Socks5, &audit).await
+        }
+        decision => decision,
+    };
 
     if matches!(decision, FilterDecision::Deny) {
         tracing::debug!("SOCKS5 denied connection to {}:{}", host, port);
+        audit.close(CloseReason::Denied, None);
+        send_reply(&mut stream, REP_CONNECTION_NOT_ALLOWED, "0.0.0.0", 0).await?;
+        return Ok(());
+    }
+
+    // Intercepted domains must be inspected, which only the HTTP proxy can do
+    if matches!(decision, FilterDecision::Intercept) {
+        tracing::debug!("SOCKS5 denied connection to intercepted {}:{}", host, port);
+        let reason = "intercepted domains are only reachable through the HTTP proxy";
+        audit.close(CloseReason::Denied, Some(reason.to_string()));
+        send_reply(&mut stream, REP_CONNECTION_NOT_ALLOWED, "0.0.0.0", 0).await?;
+        return Ok(());
+    }
+
+    // So must domains with HTTP method and path rules
+    if watch.filter().has_http_rules(&host, port) {
+        tracing::debug!("SOCKS5 denied connection to {}:{} (httpRules)", host, port);
+        let reason = "domains with httpRules are only reachable through the HTTP proxy";
+        audit.set_verdict(FilterDecision::Deny, FilterRule::HttpRulesUninspected);
+        audit.close(CloseReason::Denied, Some(reason.to_string()));
         send_reply(&mut stream, REP_CONNECTION_NOT_ALLOWED, "0.0.0.0", 0).await?;
         return Ok(());
     }
 
-    // Connect to target
-    let target = match TcpStream::connect(format!("{}:{}", host, port)).await {
+    // Nothing is reachable while a cassette is replayed, or recorded while it is recorded
+    if let Some(cassette) = context.cassette {
+        if cassette.mode() == CassetteMode::Replay {
+            let reason = cassette.refuse("SOCKS5 connection", &host, port);
+            audit.close(CloseReason::Denied, Some(reason));
+            send_reply(&mut stream, REP_CONNECTION_NOT_ALLOWED, "0.0.0.0", 0).await?;
+            return Ok(());
+        }
+        cassette.pass_unrecorded("SOCKS5 connection", &host, port);
+    }
+
+    let permit = match RateLimitPermit::acquire(context.rate_limiter.as_deref(), &host, port) {
+        Ok(permit) => permit,
+        Err(e) => {
+            audit.close(CloseReason::RateLimited, Some(e.to_string()));
+            send_reply(&mut stream, REP_CONNECTION_NOT_ALLOWED, "0.0.0.0", 0).await?;
+            return Ok(());
+        }
+    };
+
+    // Connect to target (resolved addresses are vetted by the filter)
+    let upstream = context.upstream.as_deref();
+    let target = match connect_target(watch.filter(), upstream, &host, port).await {
         Ok(s) => s,
         Err(e) => {
             tracing::debug!("SOCKS5 failed to connect to {}:{}: {}", host, port, e);
-            send_reply(&mut stream, REP_HOST_UNREACHABLE, "0.0.0.0", 0).await?;
+            audit.connect_failed(&e);
+            let rep = if e.kind() == std::io::ErrorKind::PermissionDenied {
+                REP_CONNECTION_NOT_ALLOWED
+            } else {
+                REP_HOST_UNREACHABLE
+            };
+            send_reply(&mut stream, rep, "0.0.0.0", 0).await?;
             return Ok(());
         }
     };
@@ -219,23 +485,97 @@ async fn handle_client(
         SocketAddr::V4(addr) => (addr.ip().to_string(), addr.port()),
         SocketAddr::V6(addr) => (addr.ip().to_string(), addr.port()),
     };
-    send_reply(&mut stream, REP_SUCCESS, &bind_addr, bind_port).await?;
+    if let Err(e) = send_reply(&mut stream, REP_SUCCESS, &bind_addr, bind_port).await {
+        audit.close(CloseReason::Error, Some(e.to_string()));
+        return Err(e.into());
+    }
 
     // Pipe data
     let (mut client_read, mut client_write) = stream.into_split();
-    let (mut target_read, mut target_write) = target.into_split();
+    let (mut target_read, mut target_write) =
+        tokio::io::split(ThrottledIo::new(CountingIo::new(target, audit.clone()), permit));
 
     let client_to_target = tokio::io::copy(&mut client_read, &mut target_write);
     let target_to_client = tokio::io::copy(&mut target_read, &mut client_write);
 
-    tokio::select! {
-        _ = client_to_target => {}
-        _ = target_to_client => {}
+    let result = tokio::select! {
+        result = client_to_target => result,
+        result = target_to_client => result,
+        _ = watch.revoked(&host, port) => {
+            tracing::debug!("SOCKS5 closing connection to {}:{} (revoked by policy update)", host, port);
+            audit.close(CloseReason::Revoked, None);
+            return Ok(());
+        }
+    };
+    match result {
+        Ok(_) => audit.close(CloseReason::Closed, None),
+        Err(e) => audit.close(CloseReason::Error, Some(e.to_string())),
     }
 
     Ok(())
 }
 
+/// Handle UDP ASSOCIATE: hand out the relay address and keep the association
+/// open until the client closes the control connection or the lifetime expires.
+/// The association is bound to `client_port`, the source port the client
+/// announced, at the control connection's IP; the announced IP is ignored, so
+/// a client can't have replies sent elsewhere. A client announcing port 0 gets
+/// the first datagram source with the control connection's IP.
+async fn handle_udp_associate(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    client_port: u16,
+    udp_relay: Option<Arc<UdpRelay>>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let Some(relay) = udp_relay else {
+        send_reply(&mut stream, REP_COMMAND_NOT_SUPPORTED, "0.0.0.0", 0).await?;
+        return Err("UDP ASSOCIATE is disabled".into());
+    };
+
+    let Some(association) = relay.associate(addr.ip(), client_port) else {
+        send_reply(&mut stream, REP_GENERAL_FAILURE, "0.0.0.0", 0).await?;
+        return Err("UDP association limit reached".into());
+    };
+
+    let relay_addr = relay.local_addr()?;
+    let relay_ip = relay_addr.ip().to_string();
+    send_reply(&mut stream, REP_SUCCESS, &relay_ip, relay_addr.port()).await?;
+    tracing::debug!("SOCKS5 UDP ASSOCIATE opened (relay {})", relay_addr);
+
+    // The control connection carries no further data; wait for it to close
+    let hold = async {
+        let mut buf = [0u8; 64];
+        while let Ok(n) = stream.read(&mut buf).await {
+            if n == 0 {
+                break;
+            }
+        }
+    };
+
+    if tokio::time::timeout(relay.limits().max_lifetime, hold).await.is_err() {
+        tracing::debug!("SOCKS5 UDP association reached its maximum lifetime");
+    }
+
+    drop(association);
+    Ok(())
+}
+
+/// Read an RFC 1929 username/password request and check it.
+async fn authenticate(stream: &mut TcpStream, auth: &ProxyAuth) -> std::io::Result<bool> {
+    let mut version = [0u8; 1];
+    stream.read_exact(&mut version).await?;
+
+    let mut len = [0u8; 1];
+    stream.read_exact(&mut len).await?;
+    let mut username = vec![0u8; len[0] as usize];
+    stream.read_exact(&mut username).await?;
+    stream.read_exact(&mut len).await?;
+    let mut password = vec![0u8; len[0] as usize];
+    stream.read_exact(&mut password).await?;
+
+    Ok(version[0] == USERPASS_VERSION && auth.check_credentials(&username, &password))
+}
+
 /// Send a SOCKS5 reply.
 async fn send_reply(
     stream: &mut TcpStream,
@@ -246,10 +586,10 @@ async fn send_reply(
     let mut reply = vec![SOCKS_VERSION, rep, 0x00]; // VER, REP, RSV
 
     // Parse address
-    if let Ok(ipv4) = addr.parse::<std::net::Ipv4Addr>() {
+    if let Ok(ipv4) = addr.parse::<Ipv4Addr>() {
         reply.push(ATYP_IPV4);
         reply.extend_from_slice(&ipv4.octets());
-    } else if let Ok(ipv6) = addr.parse::<std::net::Ipv6Addr>() {
+    } else if let Ok(ipv6) = addr.parse::<Ipv6Addr>() {
         reply.push(ATYP_IPV6);
         reply.extend_from_slice(&ipv6.octets());
     } else {
@@ -263,3 +603,208 @@ async fn send_reply(
 
     stream.write_all(&reply).await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use crate::config::NetworkConfig;
+    use crate::proxy::filter::DomainFilter;
+    use crate::proxy::udp::encode_udp_header;
+    use crate::utils::IpCidr;
+
+    /// Send a SOCKS5 CONNECT to an IPv4 address and return the reply code.
+    async fn connect(proxy_port: u16, ip: Ipv4Addr, port: u16) -> (TcpStream, u8) {
+        let mut stream = TcpStream::connect(("127.0.0.1", proxy_port)).await.unwrap();
+        stream.write_all(&[SOCKS_VERSION, 1, AUTH_NONE]).await.unwrap();
+        let mut method = [0u8; 2];
+        stream.read_exact(&mut method).await.unwrap();
+
+        let mut request = vec![SOCKS_VERSION, CMD_CONNECT, 0, ATYP_IPV4];
+        request.extend_from_slice(&ip.octets());
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request).await.unwrap();
+        let mut reply = [0u8; 10];
+        stream.read_exact(&mut reply).await.unwrap();
+        (stream, reply[1])
+    }
+
+    /// Open a UDP association announcing datagrams from `client_port`, and
+    /// return the control stream and the relay address.
+    async fn associate(proxy_port: u16, client_port: u16) -> (TcpStream, SocketAddr) {
+        let mut stream = TcpStream::connect(("127.0.0.1", proxy_port)).await.unwrap();
+        stream.write_all(&[SOCKS_VERSION, 1, AUTH_NONE]).await.unwrap();
+        let mut method = [0u8; 2];
+        stream.read_exact(&mut method).await.unwrap();
+
+        let mut request = vec![SOCKS_VERSION, CMD_UDP_ASSOCIATE, 0, ATYP_IPV4, 0, 0, 0, 0];
+        request.extend_from_slice(&client_port.to_be_bytes());
+        stream.write_all(&request).await.unwrap();
+        let mut reply = [0u8; 10];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], REP_SUCCESS);
+
+        let ip = Ipv4Addr::new(reply[4], reply[5], reply[6], reply[7]);
+        (stream, SocketAddr::from((ip, u16::from_be_bytes([reply[8], reply[9]]))))
+    }
+
+    /// Start a UDP echo server on `ip`, counting the datagrams it receives.
+    async fn echo_server(ip: &str) -> (SocketAddr, Arc<AtomicUsize>) {
+        let socket = UdpSocket::bind((ip, 0)).await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let received = Arc::new(AtomicUsize::new(0));
+        let counter = received.clone();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            while let Ok((n, from)) = socket.recv_from(&mut buf).await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let _ = socket.send_to(&buf[..n], from).await;
+            }
+        });
+        (addr, received)
+    }
+
+    /// Send a datagram through the relay and wait briefly for the reply.
+    async fn relay(client: &UdpSocket, relay: SocketAddr, packet: &[u8]) -> bool {
+        client.send_to(packet, relay).await.unwrap();
+        let mut buf = [0u8; 1024];
+        let reply = tokio::time::timeout(Duration::from_millis(300), client.recv(&mut buf));
+        reply.await.is_ok()
+    }
+
+    #[tokio::test]
+    async fn test_udp_drops_denied_datagrams() {
+        let (allowed, _) = echo_server("127.0.0.1").await;
+        let (denied, denied_received) = echo_server("127.0.0.2").await;
+        let config = NetworkConfig {
+            denied_domains: vec!["denied.test".to_string()],
+            denied_cidrs: vec!["127.0.0.2/32".to_string()],
+            ..Default::default()
+        };
+        let mut proxy = Socks5Proxy::new(DomainFilter::from_config(&config)).await.unwrap();
+        proxy.start().unwrap();
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = client.local_addr().unwrap().port();
+        let (_control, relay_addr) = associate(proxy.port(), port).await;
+        assert!(relay(&client, relay_addr, &encode_udp_header(allowed, b"ping")).await);
+
+        // Datagrams to denied addresses and names never reach them
+        assert!(!relay(&client, relay_addr, &encode_udp_header(denied, b"nope")).await);
+        let mut packet = vec![0, 0, 0, ATYP_DOMAIN, 11];
+        packet.extend_from_slice(b"denied.test");
+        packet.extend_from_slice(&denied.port().to_be_bytes());
+        packet.extend_from_slice(b"nope");
+        assert!(!relay(&client, relay_addr, &packet).await);
+        assert_eq!(denied_received.load(Ordering::SeqCst), 0);
+
+        proxy.stop();
+    }
+
+    #[tokio::test]
+    async fn test_udp_rejects_other_sources() {
+        let (echo, received) = echo_server("127.0.0.1").await;
+        let mut proxy = Socks5Proxy::new(DomainFilter::allow_all()).await.unwrap();
+        proxy.start().unwrap();
+
+        // An association announcing its port only takes that port, at the
+        // control connection's IP
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = client.local_addr().unwrap().port();
+        let (_control, relay_addr) = associate(proxy.port(), port).await;
+        let other_port = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        assert!(!relay(&other_port, relay_addr, &encode_udp_header(echo, b"port")).await);
+        let other_ip = UdpSocket::bind(("127.0.0.2", port)).await.unwrap();
+        assert!(!relay(&other_ip, relay_addr, &encode_udp_header(echo, b"ip")).await);
+        assert_eq!(received.load(Ordering::SeqCst), 0);
+        assert!(relay(&client, relay_addr, &encode_udp_header(echo, b"mine")).await);
+
+        // Without an announced port, the first datagram must still come from
+        // the control connection's IP
+        let (_control, relay_addr) = associate(proxy.port(), 0).await;
+        let other_ip = UdpSocket::bind("127.0.0.2:0").await.unwrap();
+        assert!(!relay(&other_ip, relay_addr, &encode_udp_header(echo, b"ip")).await);
+        assert_eq!(received.load(Ordering::SeqCst), 1);
+
+        proxy.stop();
+    }
+
+    #[tokio::test]
+    async fn test_connect_fake_ip() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.write_all(b"hello").await.unwrap();
+        });
+
+        let config = NetworkConfig {
+            allowed_domains: vec!["localhost".to_string()],
+            ..Default::default()
+        };
+        let fake_ips = Arc::new(FakeIpPool::new(IpCidr::parse("10.99.0.0/24").unwrap()).unwrap());
+        let mut proxy = Socks5Proxy::new(DomainFilter::from_config(&config)).await.unwrap();
+        proxy.set_fake_ips(Some(fake_ips.clone()));
+        proxy.start().unwrap();
+
+        // The fake IP is decided and connected to as its name
+        let localhost = fake_ips.assign("localhost");
+        let (mut stream, rep) = connect(proxy.port(), localhost, port).await;
+        assert_eq!(rep, REP_SUCCESS);
+        let mut greeting = [0u8; 5];
+        stream.read_exact(&mut greeting).await.unwrap();
+        assert_eq!(&greeting, b"hello");
+
+        let (_, rep) = connect(proxy.port(), Ipv4Addr::new(10, 99, 0, 200), port).await;
+        assert_eq!(rep, REP_HOST_UNREACHABLE);
+        let (_, rep) = connect(proxy.port(), Ipv4Addr::LOCALHOST, port).await;
+        assert_eq!(rep, REP_CONNECTION_NOT_ALLOWED);
+
+        proxy.stop();
+    }
+
+    #[tokio::test]
+    async fn test_username_password_auth() {
+        let violations = Arc::new(crate::violation::SandboxViolationStore::new());
+        let auth = ProxyAuth::new("srt", "secret", Some(violations.clone()));
+        let mut proxy = Socks5Proxy::new(DomainFilter::from_config(&NetworkConfig::default()))
+            .await
+            .unwrap();
+        proxy.set_auth(Some(Arc::new(auth)));
+        proxy.start().unwrap();
+        let port = proxy.port();
+
+        // Send a greeting and, if the method is accepted, the credentials
+        let login = |password: &'static [u8]| async move {
+            let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+            stream.write_all(&[SOCKS_VERSION, 2, AUTH_NONE, AUTH_USERNAME_PASSWORD]).await.unwrap();
+            let mut method = [0u8; 2];
+            stream.read_exact(&mut method).await.unwrap();
+            assert_eq!(method, [SOCKS_VERSION, AUTH_USERNAME_PASSWORD]);
+
+            let mut request = vec![USERPASS_VERSION, 3];
+            request.extend_from_slice(b"srt");
+            request.push(password.len() as u8);
+            request.extend_from_slice(password);
+            stream.write_all(&request).await.unwrap();
+            let mut status = [0u8; 2];
+            stream.read_exact(&mut status).await.unwrap();
+            status[1]
+        };
+
+        assert_eq!(login(b"secret").await, USERPASS_SUCCESS);
+        assert_eq!(login(b"guess").await, USERPASS_FAILURE);
+
+        // Clients that only offer no authentication are refused
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        stream.write_all(&[SOCKS_VERSION, 1, AUTH_NONE]).await.unwrap();
+        let mut method = [0u8; 2];
+        stream.read_exact(&mut method).await.unwrap();
+        assert_eq!(method, [SOCKS_VERSION, AUTH_NO_ACCEPTABLE]);
+
+        assert_eq!(violations.get_count(), 2);
+        proxy.stop();
+    }
+}
@@ -0,0 +1,741 @@
+//! SOCKS5 UDP relay (RFC 1928 UDP ASSOCIATE).
+//!
+//! The relay listens on a single loopback socket whose port matches the SOCKS5
+//! TCP port, so the same sandbox rule (macOS) or bridge port (Linux) covers
+//! both. Each association is bound to one client source address at the
+//! control connection's IP: the port the client announces, or, if it announces
+//! none, the first datagram from that IP claims it. The Linux bridge keeps the
+//! sandbox's source ports where it can, so announced ports match. Datagrams
+//! from sources no open association is bound to are dropped.
+//!
+//! Each client gets its own outbound socket ("flow") with a worker task that
+//! decides and resolves its datagrams in order, so a slow policy decision or
+//! DNS lookup only delays that client.
+
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::config::SocksUdpConfig;
+use crate::proxy::connect::resolve_target;
+use crate::proxy::audit::AuditProtocol;
+use crate::proxy::filter::{FilterDecision, SharedFilter};
+use crate::proxy::policy::{ConnectionRequest, NetworkPolicy};
+use crate::utils::canonical_ip;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+/// Maximum size of a relayed datagram, including the SOCKS5 header.
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+/// Maximum number of client flows kept at once.
+const MAX_FLOWS: usize = 1024;
+
+/// Datagrams queued for a flow's worker before more are dropped.
+const FLOW_QUEUE: usize = 64;
+
+/// Limits applied to the UDP relay.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UdpRelayLimits {
+    /// Drop a client flow after this long without traffic.
+    pub idle_timeout: Duration,
+    /// Close an association after this long.
+    pub max_lifetime: Duration,
+    /// Maximum number of concurrent associations.
+    pub max_associations: usize,
+    /// Maximum number of distinct destinations per client flow.
+    pub max_destinations: usize,
+}
+
+impl Default for UdpRelayLimits {
+    fn default() -> Self {
+        Self {
+            idle_timeout: Duration::from_secs(30),
+            max_lifetime: Duration::from_secs(3600),
+            max_associations: 32,
+            max_destinations: 64,
+        }
+    }
+}
+
+impl UdpRelayLimits {
+    /// Build limits from config. Returns None if the relay is disabled.
+    pub fn from_config(config: Option<&SocksUdpConfig>) -> Option<Self> {
+        let defaults = Self::default();
+        let Some(config) = config else {
+            return Some(defaults);
+        };
+
+        if config.enabled == Some(false) {
+            return None;
+        }
+
+        Some(Self {
+            idle_timeout: config
+                .idle_timeout_secs
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.idle_timeout),
+            max_lifetime: config
+                .max_lifetime_secs
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.max_lifetime),
+            max_associations: config.max_associations.unwrap_or(defaults.max_associations),
+            max_destinations: config.max_destinations.unwrap_or(defaults.max_destinations),
+        })
+    }
+}
+
+/// Outbound state for one client source address.
+struct Flow {
+    association: u64,
+    queue: mpsc::Sender<Vec<u8>>,
+    destinations: HashSet<SocketAddr>,
+    resolved: HashMap<(String, u16), SocketAddr>,
+    last_active: Instant,
+    reader: JoinHandle<()>,
+    worker: JoinHandle<()>,
+}
+
+impl Drop for Flow {
+    fn drop(&mut self) {
+        self.reader.abort();
+        self.worker.abort();
+    }
+}
+
+/// The client an association belongs to.
+struct AssociationClient {
+    /// Address of the SOCKS5 control connection.
+    ip: IpAddr,
+    /// Source address of the client's datagrams: the announced one, or that
+    /// of the first datagram once one has arrived.
+    bound: Option<SocketAddr>,
+}
+
+#[derive(Default)]
+struct RelayState {
+    next_association: u64,
+    associations: HashMap<u64, AssociationClient>,
+    flows: HashMap<SocketAddr, Flow>,
+}
+
+impl RelayState {
+    /// Find the association a datagram source belongs to. A source no
+    /// association is bound to claims an unbound one from the same IP.
+    fn association_for(&mut self, client: SocketAddr) -> Option<u64> {
+        if let Some((&id, _)) = self
+            .associations
+            .iter()
+            .find(|(_, assoc)| assoc.bound == Some(client))
+        {
+            return Some(id);
+        }
+
+        let (&id, assoc) = self
+            .associations
+            .iter_mut()
+            .filter(|(_, assoc)| assoc.bound.is_none() && assoc.ip == client.ip())
+            .min_by_key(|(&id, _)| id)?;
+        assoc.bound = Some(client);
+        Some(id)
+    }
+}
+
+/// UDP relay shared by all associations of a SOCKS5 proxy.
+pub struct UdpRelay {
+    socket: Arc<UdpSocket>,
+    policy: Arc<dyn NetworkPolicy>,
+    filter: SharedFilter,
+    limits: UdpRelayLimits,
+    state: Mutex<RelayState>,
+}
+
+/// Keeps an association open; dropping it releases the slot and its flow.
+pub struct Association {
+    relay: Arc<UdpRelay>,
+    id: u64,
+}
+
+impl Drop for Association {
+    fn drop(&mut self) {
+        let mut state = self.relay.state.lock();
+        state.associations.remove(&self.id);
+        let id = self.id;
+        state.flows.retain(|_, flow| flow.association != id);
+    }
+}
+
+impl UdpRelay {
+    /// Create a relay on an already bound socket. Every datagram is decided by
+    /// `policy`; `filter` vets resolved addresses.
+    pub fn new(
+        socket: UdpSocket,
+        policy: Arc<dyn NetworkPolicy>,
+        filter: SharedFilter,
+        limits: UdpRelayLimits,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            socket: Arc::new(socket),
+            policy,
+            filter,
+            limits,
+            state: Mutex::new(RelayState::default()),
+        })
+    }
+
+    /// Get the address clients should send datagrams to.
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Get the relay limits.
+    pub fn limits(&self) -> &UdpRelayLimits {
+        &self.limits
+    }
+
+    /// Open an association for a client whose control connection comes from
+    /// `client_ip` and which announced sending from `client_port`, or None if
+    /// the association limit is reached. With port 0, the first datagram from
+    /// `client_ip` binds the association.
+    pub fn associate(self: &Arc<Self>, client_ip: IpAddr, client_port: u16) -> Option<Association> {
+        let mut state = self.state.lock();
+        if state.associations.len() >= self.limits.max_associations {
+            return None;
+        }
+        let id = state.next_association;
+        state.next_association += 1;
+        let ip = canonical_ip(client_ip);
+        state.associations.insert(
+            id,
+            AssociationClient {
+                ip,
+                bound: (client_port != 0).then(|| SocketAddr::new(ip, client_port)),
+            },
+        );
+        Some(Association {
+            relay: self.clone(),
+            id,
+        })
+    }
+
+    /// Drop all client flows.
+    pub fn clear(&self) {
+        self.state.lock().flows.clear();
+    }
+
+    /// Receive and relay client datagrams until the task is aborted.
+    pub async fn run(self: Arc<Self>) {
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        let mut sweep = tokio::time::interval(Duration::from_secs(1));
+
+        loop {
+            tokio::select! {
+                result = self.socket.recv_from(&mut buf) => {
+                    match result {
+                        Ok((len, client)) => self.dispatch(client, &buf[..len]),
+                        Err(e) => tracing::debug!("SOCKS5 UDP receive error: {}", e),
+                    }
+                }
+                _ = sweep.tick() => self.sweep_idle(),
+            }
+        }
+    }
+
+    /// Remove flows that have been idle longer than the limit.
+    fn sweep_idle(&self) {
+        let idle_timeout = self.limits.idle_timeout;
+        self.state
+            .lock()
+            .flows
+            .retain(|_, flow| flow.last_active.elapsed() < idle_timeout);
+    }
+
+    /// Queue a datagram for its client's flow, opening the flow if needed.
+    fn dispatch(self: &Arc<Self>, client: SocketAddr, packet: &[u8]) {
+        let client = SocketAddr::new(canonical_ip(client.ip()), client.port());
+        let mut state = self.state.lock();
+        let Some(association) = state.association_for(client) else {
+            tracing::debug!("SOCKS5 UDP dropping datagram from {} (no association)", client);
+            return;
+        };
+
+        if !state.flows.contains_key(&client) {
+            if state.flows.len() >= MAX_FLOWS {
+                tracing::debug!("SOCKS5 UDP flow limit reached, dropping datagram");
+                return;
+            }
+            match self.open_flow(client, association) {
+                Ok(flow) => {
+                    state.flows.insert(client, flow);
+                }
+                Err(e) => {
+                    tracing::debug!("SOCKS5 UDP failed to open outbound socket: {}", e);
+                    return;
+                }
+            }
+        }
+
+        let flow = state.flows.get_mut(&client).expect("flow was just inserted");
+        flow.last_active = Instant::now();
+        // A full queue drops the datagram, as the network would
+        if flow.queue.try_send(packet.to_vec()).is_err() {
+            tracing::debug!("SOCKS5 UDP queue full for {}, dropping datagram", client);
+        }
+    }
+
+    /// Check and forward a client's datagrams in order.
+    async fn relay_datagrams(
+        self: Arc<Self>,
+        client: SocketAddr,
+        outbound: Arc<UdpSocket>,
+        mut queue: mpsc::Receiver<Vec<u8>>,
+    ) {
+        while let Some(packet) = queue.recv().await {
+            self.handle_datagram(client, &outbound, &packet).await;
+        }
+    }
+
+    /// Check and forward one datagram from a client.
+    async fn handle_datagram(&self, client: SocketAddr, outbound: &UdpSocket, packet: &[u8]) {
+        let Some((host, port, payload)) = parse_udp_header(packet) else {
+            tracing::debug!("SOCKS5 UDP dropping malformed or fragmented datagram from {}", client);
+            return;
+        };
+
+        // MITM, intercepted and rule-restricted domains are only inspected over HTTP,
+        // so UDP to them is refused
+        let request = ConnectionRequest::new(AuditProtocol::Udp, &host, port, client);
+        let filter = self.filter.current();
+        if !matches!(self.policy.decide(&request).await.0, FilterDecision::Allow)
+            || filter.has_http_rules(&host, port)
+        {
+            tracing::debug!("SOCKS5 UDP denied datagram to {}:{}", host, port);
+            return;
+        }
+
+        let cached = {
+            let state = self.state.lock();
+            state
+                .flows
+                .get(&client)
+                .and_then(|flow| flow.resolved.get(&(host.clone(), port)).copied())
+        };
+
+        // Cached addresses are re-vetted in case the policy changed since
+        let target = match cached.filter(|addr| filter.is_address_allowed(addr.ip())) {
+            Some(addr) => addr,
+            None => match resolve_target(&filter, &host, port).await {
+                Ok(addrs) => addrs[0],
+                Err(e) => {
+                    tracing::debug!("SOCKS5 UDP cannot relay to {}:{}: {}", host, port, e);
+                    return;
+                }
+            },
+        };
+
+        {
+            let mut state = self.state.lock();
+            let Some(flow) = state.flows.get_mut(&client) else {
+                return;
+            };
+            if !flow.destinations.contains(&target)
+                && flow.destinations.len() >= self.limits.max_destinations
+            {
+                tracing::debug!(
+                    "SOCKS5 UDP destination limit reached for {}, dropping datagram to {}:{}",
+                    client,
+                    host,
+                    port
+                );
+                return;
+            }
+
+            flow.destinations.insert(target);
+            flow.resolved.insert((host, port), target);
+        }
+
+        if let Err(e) = outbound.send_to(payload, outbound_addr(outbound, target)).await {
+            tracing::debug!("SOCKS5 UDP failed to send to {}: {}", target, e);
+        }
+    }
+
+    /// Bind an outbound socket for a client and start relaying its datagrams
+    /// and replies.
+    fn open_flow(self: &Arc<Self>, client: SocketAddr, association: u64) -> std::io::Result<Flow> {
+        // Prefer a dual-stack socket so one flow can reach IPv4 and IPv6 targets
+        let std_socket = std::net::UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0))
+            .or_else(|_| std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)))?;
+        std_socket.set_nonblocking(true)?;
+        let outbound = Arc::new(UdpSocket::from_std(std_socket)?);
+
+        let reader = tokio::spawn(self.clone().relay_replies(client, outbound.clone()));
+        let (queue, rx) = mpsc::channel(FLOW_QUEUE);
+        let worker = tokio::spawn(self.clone().relay_datagrams(client, outbound, rx));
+
+        Ok(Flow {
+            association,
+            queue,
+            destinations: HashSet::new(),
+            resolved: HashMap::new(),
+            last_active: Instant::now(),
+            reader,
+            worker,
+        })
+    }
+
+    /// Forward replies from known destinations back to the client.
+    async fn relay_replies(self: Arc<Self>, client: SocketAddr, outbound: Arc<UdpSocket>) {
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+
+        loop {
+            let (len, from) = match outbound.recv_from(&mut buf).await {
+                Ok(result) => result,
+                Err(e) => {
+                    tracing::debug!("SOCKS5 UDP outbound receive error: {}", e);
+                    continue;
+                }
+            };
+            let from = SocketAddr::new(canonical_ip(from.ip()), from.port());
+
+            // Only replies from destinations the client sent to are relayed
+            {
+                let mut state = self.state.lock();
+                let Some(flow) = state.flows.get_mut(&client) else {
+                    return;
+                };
+                if !flow.destinations.contains(&from)
+                    || !self.filter.current().is_address_allowed(from.ip())
+                {
+                    tracing::debug!("SOCKS5 UDP dropping unsolicited datagram from {}", from);
+                    continue;
+                }
+                flow.last_active = Instant::now();
+            }
+
+            let reply = encode_udp_header(from, &buf[..len]);
+            if let Err(e) = self.socket.send_to(&reply, client).await {
+                tracing::debug!("SOCKS5 UDP failed to reply to {}: {}", client, e);
+            }
+        }
+    }
+}
+
+/// Map a target address to the outbound socket's address family.
+fn outbound_addr(socket: &UdpSocket, target: SocketAddr) -> SocketAddr {
+    match (socket.local_addr(), target.ip()) {
+        (Ok(SocketAddr::V6(_)), IpAddr::V4(v4)) => {
+            SocketAddr::new(IpAddr::V6(v4.to_ipv6_mapped()), target.port())
+        }
+        _ => target,
+    }
+}
+
+/// Parse the SOCKS5 UDP request header (RSV, FRAG, ATYP, DST.ADDR, DST.PORT).
+/// Returns the destination and payload. Fragmented datagrams are not supported.
+pub fn parse_udp_header(packet: &[u8]) -> Option<(String, u16, &[u8])> {
+    if packet.len() < 4 || packet[0] != 0 || packet[1] != 0 || packet[2] != 0 {
+        return None;
+    }
+
+    let (host, rest) = match packet[3] {
+        ATYP_IPV4 => {
+            let addr: [u8; 4] = packet.get(4..8)?.try_into().ok()?;
+            (Ipv4Addr::from(addr).to_string(), &packet[8..])
+        }
+        ATYP_IPV6 => {
+            let addr: [u8; 16] = packet.get(4..20)?.try_into().ok()?;
+            (Ipv6Addr::from(addr).to_string(), &packet[20..])
+        }
+        ATYP_DOMAIN => {
+            let len = *packet.get(4)? as usize;
+            let domain = packet.get(5..5 + len)?;
+            (String::from_utf8_lossy(domain).to_string(), &packet[5 + len..])
+        }
+        _ => return None,
+    };
+
+    let port = u16::from_be_bytes(rest.get(..2)?.try_into().ok()?);
+    Some((host, port, &rest[2..]))
+}
+
+/// Prefix a payload with the SOCKS5 UDP header for the given source address.
+pub fn encode_udp_header(from: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(payload.len() + 22);
+    packet.extend_from_slice(&[0, 0, 0]); // RSV, FRAG
+
+    match canonical_ip(from.ip()) {
+        IpAddr::V4(v4) => {
+            packet.push(ATYP_IPV4);
+            packet.extend_from_slice(&v4.octets());
+        }
+        IpAddr::V6(v6) => {
+            packet.push(ATYP_IPV6);
+            packet.extend_from_slice(&v6.octets());
+        }
+    }
+
+    packet.extend_from_slice(&from.port().to_be_bytes());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::NetworkConfig;
+    use crate::proxy::{DomainFilter, Socks5Proxy};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    #[test]
+    fn test_udp_header_roundtrip() {
+        let addr: SocketAddr = "1.2.3.4:53".parse().unwrap();
+        let packet = encode_udp_header(addr, b"hello");
+        assert_eq!(
+            parse_udp_header(&packet),
+            Some(("1.2.3.4".to_string(), 53, &b"hello"[..]))
+        );
+
+        let addr: SocketAddr = "[2001:db8::1]:443".parse().unwrap();
+        let packet = encode_udp_header(addr, b"");
+        assert_eq!(
+            parse_udp_header(&packet),
+            Some(("2001:db8::1".to_string(), 443, &b""[..]))
+        );
+
+        let mut packet = vec![0, 0, 0, ATYP_DOMAIN, 11];
+        packet.extend_from_slice(b"example.com");
+        packet.extend_from_slice(&53u16.to_be_bytes());
+        packet.extend_from_slice(b"q");
+        assert_eq!(
+            parse_udp_header(&packet),
+            Some(("example.com".to_string(), 53, &b"q"[..]))
+        );
+
+        // Fragments and truncated headers are rejected
+        assert!(parse_udp_header(&[0, 0, 1, ATYP_IPV4, 1, 2, 3, 4, 0, 53]).is_none());
+        assert!(parse_udp_header(&[0, 0, 0, ATYP_IPV4, 1, 2]).is_none());
+        assert!(parse_udp_header(&[0, 0, 0, ATYP_DOMAIN, 20, b'a']).is_none());
+    }
+
+    #[test]
+    fn test_limits_from_config() {
+        assert_eq!(UdpRelayLimits::from_config(None), Some(UdpRelayLimits::default()));
+
+        let config = SocksUdpConfig {
+            enabled: Some(false),
+            ..Default::default()
+        };
+        assert_eq!(UdpRelayLimits::from_config(Some(&config)), None);
+
+        let config = SocksUdpConfig {
+            max_destinations: Some(2),
+            ..Default::default()
+        };
+        let limits = UdpRelayLimits::from_config(Some(&config)).unwrap();
+        assert_eq!(limits.max_destinations, 2);
+        assert_eq!(limits.idle_timeout, UdpRelayLimits::default().idle_timeout);
+    }
+
+    /// Open a UDP association and return the control stream and relay address.
+    async fn associate(proxy_port: u16) -> (TcpStream, SocketAddr) {
+        associate_from(proxy_port, 0).await
+    }
+
+    /// Open a UDP association announcing datagrams from `client_port`.
+    async fn associate_from(proxy_port: u16, client_port: u16) -> (TcpStream, SocketAddr) {
+        let mut stream = TcpStream::connect(("127.0.0.1", proxy_port)).await.unwrap();
+        stream.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut method = [0u8; 2];
+        stream.read_exact(&mut method).await.unwrap();
+
+        let mut request = vec![0x05, 0x03, 0x00, ATYP_IPV4, 0, 0, 0, 0];
+        request.extend_from_slice(&client_port.to_be_bytes());
+        stream.write_all(&request).await.unwrap();
+        let mut reply = [0u8; 10];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], 0x00, "UDP ASSOCIATE should succeed");
+
+        let ip = Ipv4Addr::new(reply[4], reply[5], reply[6], reply[7]);
+        let port = u16::from_be_bytes([reply[8], reply[9]]);
+        (stream, SocketAddr::new(IpAddr::V4(ip), port))
+    }
+
+    async fn echo_server() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+                let _ = socket.send_to(&buf[..len], from).await;
+            }
+        });
+        addr
+    }
+
+    async fn recv_reply(client: &UdpSocket) -> Option<Vec<u8>> {
+        let mut buf = [0u8; 1024];
+        let recv = tokio::time::timeout(Duration::from_millis(300), client.recv(&mut buf));
+        recv.await.ok().map(|r| buf[..r.unwrap()].to_vec())
+    }
+
+    #[tokio::test]
+    async fn test_udp_associate_relays_allowed_datagrams() {
+        let echo = echo_server().await;
+        let config = NetworkConfig {
+            denied_cidrs: vec!["127.0.0.2/32".to_string()],
+            ..Default::default()
+        };
+        let mut proxy = Socks5Proxy::new(DomainFilter::from_config(&config)).await.unwrap();
+        proxy.start().unwrap();
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let relay_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), proxy.port());
+
+        // Nothing is relayed without an association
+        client
+            .send_to(&encode_udp_header(echo, b"early"), relay_addr)
+            .await
+            .unwrap();
+        assert!(recv_reply(&client).await.is_none());
+
+        let (control, relay) = associate(proxy.port()).await;
+        assert_eq!(relay, relay_addr);
+
+        client.send_to(&encode_udp_header(echo, b"ping"), relay).await.unwrap();
+        let reply = recv_reply(&client).await.expect("echo reply");
+        assert_eq!(
+            parse_udp_header(&reply),
+            Some(("127.0.0.1".to_string(), echo.port(), &b"ping"[..]))
+        );
+
+        // Denied destinations are dropped
+        let denied: SocketAddr = SocketAddr::new("127.0.0.2".parse().unwrap(), echo.port());
+        client.send_to(&encode_udp_header(denied, b"nope"), relay).await.unwrap();
+        assert!(recv_reply(&client).await.is_none());
+
+        // Closing the control connection ends the association
+        drop(control);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        client.send_to(&encode_udp_header(echo, b"late"), relay).await.unwrap();
+        assert!(recv_reply(&client).await.is_none());
+
+        proxy.stop();
+    }
+
+    #[tokio::test]
+    async fn test_udp_association_bound_to_client() {
+        let echo = echo_server().await;
+        let mut proxy = Socks5Proxy::new(DomainFilter::allow_all()).await.unwrap();
+        proxy.start().unwrap();
+
+        let (_control, relay) = associate(proxy.port()).await;
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let other = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        // The first datagram binds the association to its source
+        client.send_to(&encode_udp_header(echo, b"mine"), relay).await.unwrap();
+        assert!(recv_reply(&client).await.is_some());
+
+        // Other local sockets can't use it
+        other.send_to(&encode_udp_header(echo, b"theirs"), relay).await.unwrap();
+        assert!(recv_reply(&other).await.is_none());
+
+        // Until they open an association of their own
+        let (_other_control, _) = associate(proxy.port()).await;
+        other.send_to(&encode_udp_header(echo, b"theirs"), relay).await.unwrap();
+        assert!(recv_reply(&other).await.is_some());
+
+        proxy.stop();
+    }
+
+    #[tokio::test]
+    async fn test_udp_association_bound_to_announced_port() {
+        let echo = echo_server().await;
+        let mut proxy = Socks5Proxy::new(DomainFilter::allow_all()).await.unwrap();
+        proxy.start().unwrap();
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let other = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = client.local_addr().unwrap().port();
+        let (_control, relay) = associate_from(proxy.port(), port).await;
+
+        // A local socket sending first can't claim the announced association
+        other.send_to(&encode_udp_header(echo, b"theirs"), relay).await.unwrap();
+        assert!(recv_reply(&other).await.is_none());
+
+        client.send_to(&encode_udp_header(echo, b"mine"), relay).await.unwrap();
+        assert!(recv_reply(&client).await.is_some());
+
+        proxy.stop();
+    }
+
+    #[tokio::test]
+    async fn test_udp_slow_decision_only_delays_its_client() {
+        /// Holds datagrams to "slow.test" without deciding.
+        struct SlowPolicy;
+
+        impl NetworkPolicy for SlowPolicy {
+            fn decide<'a>(
+                &'a self,
+                request: &'a ConnectionRequest,
+            ) -> futures::future::BoxFuture<'a, (FilterDecision, crate::proxy::FilterRule)> {
+                Box::pin(async move {
+                    if request.host == "slow.test" {
+                        std::future::pending::<()>().await;
+                    }
+                    (FilterDecision::Allow, crate::proxy::FilterRule::Default)
+                })
+            }
+        }
+
+        let echo = echo_server().await;
+        let mut proxy = Socks5Proxy::new(SlowPolicy).await.unwrap();
+        proxy.start().unwrap();
+
+        let (_slow_control, relay) = associate(proxy.port()).await;
+        let slow = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut packet = vec![0, 0, 0, ATYP_DOMAIN, 9];
+        packet.extend_from_slice(b"slow.test");
+        packet.extend_from_slice(&53u16.to_be_bytes());
+        slow.send_to(&packet, relay).await.unwrap();
+
+        let (_control, _) = associate(proxy.port()).await;
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(&encode_udp_header(echo, b"fast"), relay).await.unwrap();
+        assert!(recv_reply(&client).await.is_some());
+
+        proxy.stop();
+    }
+
+    #[tokio::test]
+    async fn test_udp_destination_limit() {
+        let first = echo_server().await;
+        let second = echo_server().await;
+        let mut proxy = Socks5Proxy::new(DomainFilter::allow_all()).await.unwrap();
+        proxy.set_udp_limits(Some(UdpRelayLimits {
+            max_destinations: 1,
+            ..Default::default()
+        }));
+        proxy.start().unwrap();
+
+        let (_control, relay) = associate(proxy.port()).await;
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        client.send_to(&encode_udp_header(first, b"a"), relay).await.unwrap();
+        assert!(recv_reply(&client).await.is_some());
+
+        client.send_to(&encode_udp_header(second, b"b"), relay).await.unwrap();
+        assert!(recv_reply(&client).await.is_none());
+
+        proxy.stop();
+    }
+}
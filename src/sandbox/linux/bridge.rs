@@ -1,60 +1,80 @@
-//! Socat Unix socket bridges for Linux network sandboxing.
-
-use std::path::PathBuf;
-use std::process::Stdio;
-
-use tokio::process::{Child, Command};
-
-use crate::error::SandboxError;
-
-/// A socat bridge between a Unix socket and a TCP port.
-pub struct SocatBridge {
-    child: Option<Child>,
-    socket_path: PathBuf,
+//! The sandbox side of the Unix socket bridges.
+//!
+//! The host side is [`HostBridge`](super::host_bridge::HostBridge), run
+//! in-process. Inside the sandbox the bridges are run by the `srt` bridge
+//! helper ([`super::helper`]), or by socat when the helper isn't available.
+
+use std::path::{Path, PathBuf};
+
+/// Idle timeout for UDP bridges, which have no end-of-stream.
+pub(crate) const UDP_BRIDGE_TIMEOUT_SECS: u64 = 30;
+
+/// Idle timeout for DNS bridges, one connection per query.
+pub(crate) const DNS_BRIDGE_TIMEOUT_SECS: u64 = 5;
+
+/// Port the DNS bridge listens on inside the sandbox.
+pub(crate) const SANDBOX_DNS_PORT: u16 = 53;
+
+/// A bridge inside the sandbox.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BridgeSpec {
+    /// Listen on a TCP port and connect to a host socket, for the proxies.
+    Tcp { port: u16, socket: String },
+    /// Receive on a UDP port and relay each peer through a host socket.
+    Udp { port: u16, socket: String },
+    /// Answer on the DNS port through the DNS forwarder's socket.
+    Dns { socket: String },
+    /// Listen on a socket for the host's exposed-port bridge and connect to
+    /// a TCP port in the sandbox.
+    Expose { port: u16, socket: String },
 }
 
-impl SocatBridge {
-    /// Create a bridge from a Unix socket to a TCP port.
-    /// The Unix socket will be created and listen for connections.
-    /// Each connection will be forwarded to the TCP port.
-    pub async fn unix_to_tcp(
-        socket_path: PathBuf,
-        tcp_host: &str,
-        tcp_port: u16,
-    ) -> Result<Self, SandboxError> {
-        // Remove existing socket if present
-        if socket_path.exists() {
-            std::fs::remove_file(&socket_path)?;
+impl BridgeSpec {
+    /// Parse a bridge from its helper argument, as made by [`BridgeSpec::to_arg`].
+    pub fn parse(arg: &str) -> Option<Self> {
+        let (kind, rest) = arg.split_once(':')?;
+        if kind == "dns" {
+            return Some(BridgeSpec::Dns {
+                socket: rest.to_string(),
+            });
+        }
+        let (port, socket) = rest.split_once(':')?;
+        let port = port.parse().ok()?;
+        let socket = socket.to_string();
+        match kind {
+            "tcp" => Some(BridgeSpec::Tcp { port, socket }),
+            "udp" => Some(BridgeSpec::Udp { port, socket }),
+            "expose" => Some(BridgeSpec::Expose { port, socket }),
+            _ => None,
+        }
+    }
+
+    /// The argument passing this bridge to the helper: `tcp:PORT:SOCKET`,
+    /// `udp:PORT:SOCKET`, `dns:SOCKET` or `expose:PORT:SOCKET`.
+    pub fn to_arg(&self) -> String {
+        match self {
+            BridgeSpec::Tcp { port, socket } => format!("tcp:{}:{}", port, socket),
+            BridgeSpec::Udp { port, socket } => format!("udp:{}:{}", port, socket),
+            BridgeSpec::Dns { socket } => format!("dns:{}", socket),
+            BridgeSpec::Expose { port, socket } => format!("expose:{}:{}", port, socket),
         }
+    }
 
-        let child = Command::new("socat")
-            .args([
-                &format!("UNIX-LISTEN:{},fork", socket_path.display()),
-                &format!("TCP:{}:{}", tcp_host, tcp_port),
-            ])
-            .stdin(Stdio::null())
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .spawn()
-            .map_err(|e| {
-                if e.kind() == std::io::ErrorKind::NotFound {
-                    SandboxError::MissingDependency(
-                        "socat not found. Please install socat.".to_string(),
-                    )
-                } else {
-                    SandboxError::Io(e)
-                }
-            })?;
-
-        // Wait a bit for the socket to be created
-        tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
-
-        Ok(Self {
-            child: Some(child),
-            socket_path,
-        })
+    /// The socat command running this bridge.
+    pub fn socat_command(&self) -> String {
+        match self {
+            BridgeSpec::Tcp { port, socket } => SocatBridge::tcp_to_unix_command(*port, socket),
+            BridgeSpec::Udp { port, socket } => SocatBridge::udp_to_unix_command(*port, socket),
+            BridgeSpec::Dns { socket } => SocatBridge::dns_to_unix_command(socket),
+            BridgeSpec::Expose { port, socket } => SocatBridge::unix_to_tcp_command(socket, *port),
+        }
     }
+}
+
+/// Builders for the socat commands run inside the sandbox.
+pub struct SocatBridge;
 
+impl SocatBridge {
     /// Create a bridge from a TCP port to a Unix socket.
     /// This is used inside the sandbox to connect to the host proxies.
     pub fn tcp_to_unix_command(tcp_port: u16, socket_path: &str) -> String {
@@ -64,33 +84,34 @@ impl SocatBridge {
         )
     }
 
-    /// Get the socket path.
-    pub fn socket_path(&self) -> &PathBuf {
-        &self.socket_path
+    /// Create a bridge from a UDP port to a Unix socket.
+    /// socat forks one child per datagram peer; the child relays replies back
+    /// to that peer until it has been idle for the bridge timeout. It neither
+    /// sends the peer's port first nor frames datagrams, so it can't reach
+    /// [`HostBridge::unix_to_udp`](super::host_bridge::HostBridge::unix_to_udp).
+    pub fn udp_to_unix_command(udp_port: u16, socket_path: &str) -> String {
+        format!(
+            "socat -T {} UDP4-RECVFROM:{},fork,reuseaddr UNIX-CONNECT:{}",
+            UDP_BRIDGE_TIMEOUT_SECS, udp_port, socket_path
+        )
     }
 
-    /// Stop the bridge.
-    pub async fn stop(&mut self) {
-        if let Some(ref mut child) = self.child {
-            let _ = child.kill().await;
-        }
-
-        // Clean up socket
-        if self.socket_path.exists() {
-            let _ = std::fs::remove_file(&self.socket_path);
-        }
+    /// Create a bridge from the sandbox's DNS port to a Unix socket.
+    /// Binding port 53 needs CAP_NET_BIND_SERVICE in the sandbox's namespace.
+    /// Like [`Self::udp_to_unix_command`], it can't reach the host bridge.
+    pub fn dns_to_unix_command(socket_path: &str) -> String {
+        format!(
+            "socat -T {} UDP4-RECVFROM:{},bind=127.0.0.1,fork,reuseaddr UNIX-CONNECT:{}",
+            DNS_BRIDGE_TIMEOUT_SECS, SANDBOX_DNS_PORT, socket_path
+        )
     }
-}
-
-impl Drop for SocatBridge {
-    fn drop(&mut self) {
-        if let Some(ref mut child) = self.child {
-            let _ = child.start_kill();
-        }
 
-        if self.socket_path.exists() {
-            let _ = std::fs::remove_file(&self.socket_path);
-        }
+    /// Create a bridge from a Unix socket to a TCP port listening in the sandbox.
+    pub fn unix_to_tcp_command(socket_path: &str, tcp_port: u16) -> String {
+        format!(
+            "socat UNIX-LISTEN:{},fork TCP:127.0.0.1:{}",
+            socket_path, tcp_port
+        )
     }
 }
 
@@ -103,29 +124,15 @@ pub fn check_socat() -> bool {
         .unwrap_or(false)
 }
 
-/// Generate a unique socket path in /tmp.
-pub fn generate_socket_path(prefix: &str) -> PathBuf {
-    use rand::Rng;
-    let mut rng = rand::thread_rng();
-    let suffix: u32 = rng.gen();
-    PathBuf::from(format!("/tmp/{}-{}-{:08x}.sock", prefix, std::process::id(), suffix))
+/// Get the socket for an exposed host port in the sandbox's expose directory.
+pub fn expose_socket_path(dir: &Path, host_port: u16) -> PathBuf {
+    dir.join(format!("{}.sock", host_port))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    #[test]
-    fn test_generate_socket_path() {
-        let path1 = generate_socket_path("srt-http");
-        let path2 = generate_socket_path("srt-http");
-
-        assert!(path1.to_string_lossy().starts_with("/tmp/srt-http-"));
-        assert!(path1.to_string_lossy().ends_with(".sock"));
-        // Paths should be different due to random suffix
-        assert_ne!(path1, path2);
-    }
-
     #[test]
     fn test_tcp_to_unix_command() {
         let cmd = SocatBridge::tcp_to_unix_command(3128, "/tmp/http.sock");
@@ -134,4 +141,67 @@ mod tests {
             "socat TCP-LISTEN:3128,fork,reuseaddr UNIX-CONNECT:/tmp/http.sock"
         );
     }
+
+    #[test]
+    fn test_udp_to_unix_command() {
+        let cmd = SocatBridge::udp_to_unix_command(1080, "/tmp/socks-udp.sock");
+        assert_eq!(
+            cmd,
+            "socat -T 30 UDP4-RECVFROM:1080,fork,reuseaddr UNIX-CONNECT:/tmp/socks-udp.sock"
+        );
+    }
+
+    #[test]
+    fn test_expose_bridge() {
+        let socket = expose_socket_path(Path::new("/run/srt/expose"), 8080);
+        assert_eq!(socket, Path::new("/run/srt/expose/8080.sock"));
+
+        let cmd = SocatBridge::unix_to_tcp_command(socket.to_str().unwrap(), 3000);
+        assert_eq!(
+            cmd,
+            "socat UNIX-LISTEN:/run/srt/expose/8080.sock,fork TCP:127.0.0.1:3000"
+        );
+    }
+
+    #[test]
+    fn test_bridge_spec() {
+        let specs = [
+            BridgeSpec::Tcp {
+                port: 3128,
+                socket: "/run/srt:1/http.sock".to_string(),
+            },
+            BridgeSpec::Udp {
+                port: 1080,
+                socket: "/tmp/socks-udp.sock".to_string(),
+            },
+            BridgeSpec::Dns {
+                socket: "/tmp/dns.sock".to_string(),
+            },
+            BridgeSpec::Expose {
+                port: 3000,
+                socket: "/tmp/expose/8080.sock".to_string(),
+            },
+        ];
+        assert_eq!(specs[0].to_arg(), "tcp:3128:/run/srt:1/http.sock");
+        assert_eq!(specs[2].to_arg(), "dns:/tmp/dns.sock");
+        for spec in &specs {
+            assert_eq!(BridgeSpec::parse(&spec.to_arg()).as_ref(), Some(spec));
+        }
+        assert_eq!(BridgeSpec::parse("tcp:http:/tmp/http.sock"), None);
+        assert_eq!(BridgeSpec::parse("sctp:80:/tmp/x.sock"), None);
+
+        assert_eq!(
+            specs[3].socat_command(),
+            "socat UNIX-LISTEN:/tmp/expose/8080.sock,fork TCP:127.0.0.1:3000"
+        );
+    }
+
+    #[test]
+    fn test_dns_to_unix_command() {
+        let cmd = SocatBridge::dns_to_unix_command("/tmp/dns.sock");
+        assert_eq!(
+            cmd,
+            "socat -T 5 UDP4-RECVFROM:53,bind=127.0.0.1,fork,reuseaddr UNIX-CONNECT:/tmp/dns.sock"
+        );
+    }
 }
@@ -2,13 +2,19 @@
 
 use std::path::Path;
 
-use crate::config::SandboxRuntimeConfig;
+use crate::config::{ExposedPort, SandboxRuntimeConfig};
 use crate::error::SandboxError;
-use crate::sandbox::linux::bridge::SocatBridge;
-use crate::sandbox::linux::filesystem::{generate_bind_mounts, BindMount};
+use crate::sandbox::linux::bridge::{expose_socket_path, BridgeSpec};
+use crate::sandbox::linux::filesystem::generate_bind_mounts;
+use crate::sandbox::linux::helper::{bridge_helper_command, bridge_helper_path};
 use crate::sandbox::linux::seccomp::{get_apply_seccomp_path, get_bpf_path};
+use crate::sandbox::linux::tun::{tun_bwrap_args, TunSpec};
+use crate::sandbox::proxy_env::CaTrust;
 use crate::utils::quote;
 
+/// Host resolver config, replaced inside the sandbox.
+const RESOLV_CONF_PATH: &str = "/etc/resolv.conf";
+
 /// Check if bubblewrap is available.
 pub fn check_bwrap() -> bool {
     std::process::Command::new("bwrap")
@@ -19,12 +25,18 @@ pub fn check_bwrap() -> bool {
 }
 
 /// Generate the bubblewrap command for sandboxed execution.
+#[allow(clippy::too_many_arguments)]
 pub fn generate_bwrap_command(
     command: &str,
     config: &SandboxRuntimeConfig,
     cwd: &Path,
     http_socket_path: Option<&str>,
     socks_socket_path: Option<&str>,
+    socks_udp_socket_path: Option<&str>,
+    dns_socket_path: Option<&str>,
+    resolv_conf_path: Option<&str>,
+    expose_dir: Option<&str>,
+    ca: Option<&CaTrust>,
     http_proxy_port: u16,
     socks_proxy_port: u16,
     shell: Option<&str>,
@@ -33,7 +45,7 @@ pub fn generate_bwrap_command(
 
     // Generate filesystem mounts
     let (mounts, warnings) = generate_bind_mounts(
-        &config.filesystem,
+        &config.sandbox_filesystem(),
         cwd,
         config.ripgrep.as_ref(),
         config.mandatory_deny_search_depth,
@@ -53,6 +65,12 @@ pub fn generate_bwrap_command(
         "/run".to_string(),
     ];
 
+    // Secrets injected by the proxy stay on the host
+    for name in config.network.credential_env_vars() {
+        bwrap_args.push("--unsetenv".to_string());
+        bwrap_args.push(name.to_string());
+    }
+
     // Start with read-only root filesystem
     bwrap_args.push("--ro-bind".to_string());
     bwrap_args.push("/".to_string());
@@ -72,19 +90,90 @@ pub fn generate_bwrap_command(
         }
     }
 
+    // The interception CA lives in the host's /tmp and may be under a denied path
+    if let Some(ca) = ca {
+        for path in ca.paths() {
+            bwrap_args.push("--ro-bind".to_string());
+            bwrap_args.push(path.display().to_string());
+            bwrap_args.push(path.display().to_string());
+        }
+    }
+
+    // The srt binary runs the sandbox side of the bridges, if it is the caller
+    let bridge_helper = bridge_helper_path().filter(|_| http_socket_path.is_some());
+
+    // The host side of the SOCKS5 UDP bridge expects each peer's port first,
+    // which socat doesn't send
+    let socks_udp_socket_path = match socks_udp_socket_path {
+        Some(_) if bridge_helper.is_none() => {
+            tracing::warn!("The SOCKS5 UDP bridge needs the srt bridge helper and is disabled");
+            None
+        }
+        path => path,
+    };
+
+    // Make the DNS bridge the only resolver. It binds port 53 in the sandbox,
+    // so only the helper, which drops the capability for the command, runs it
+    let dns_socket_path = match dns_socket_path {
+        Some(_) if bridge_helper.is_none() => {
+            tracing::warn!(
+                "The DNS bridge needs the srt bridge helper; the sandbox has no resolver"
+            );
+            None
+        }
+        path => path,
+    };
+    if let (Some(_), Some(resolv_conf)) = (dns_socket_path, resolv_conf_path) {
+        // Mount over the symlink target, as for systemd-resolved's stub file
+        if let Ok(target) = std::fs::canonicalize(RESOLV_CONF_PATH) {
+            bwrap_args.push("--ro-bind".to_string());
+            bwrap_args.push(resolv_conf.to_string());
+            bwrap_args.push(target.display().to_string());
+        }
+        bwrap_args.push("--cap-add".to_string());
+        bwrap_args.push("CAP_NET_BIND_SERVICE".to_string());
+    }
+
+    // TUN capture routes other traffic to the SOCKS5 bridge; the helper sets it up
+    if config.network.tun_capture.is_some() && socks_socket_path.is_some() {
+        if bridge_helper.is_some() {
+            bwrap_args.extend(tun_bwrap_args());
+        } else {
+            tracing::warn!("tunCapture needs the srt bridge helper and is disabled");
+        }
+    }
+
+    // Sockets the host's exposed-port bridges connect to
+    if let Some(dir) = expose_dir {
+        bwrap_args.push("--bind".to_string());
+        bwrap_args.push(dir.to_string());
+        bwrap_args.push(dir.to_string());
+    }
+
+    // The helper stays runnable even if its path is denied
+    if let Some(helper) = &bridge_helper {
+        bwrap_args.push("--ro-bind".to_string());
+        bwrap_args.push(helper.display().to_string());
+        bwrap_args.push(helper.display().to_string());
+    }
+
     // Set working directory
     bwrap_args.push("--chdir".to_string());
     bwrap_args.push(cwd.display().to_string());
 
-    // Build the inner command with socat bridges and seccomp
+    // Build the inner command with the bridges and seccomp
     let inner_command = build_inner_command(
         command,
         config,
         http_socket_path,
         socks_socket_path,
+        socks_udp_socket_path,
+        dns_socket_path,
+        expose_dir,
         http_proxy_port,
         socks_proxy_port,
         shell,
+        bridge_helper.as_deref(),
     )?;
 
     // Add the command
@@ -104,34 +193,96 @@ pub fn generate_bwrap_command(
 }
 
 /// Build the inner command to run inside bubblewrap.
-/// This sets up socat bridges and applies seccomp before running the user command.
+/// This sets up the bridges and applies seccomp before running the user command.
+/// With `bridge_helper`, the helper starts the rest once its bridges listen;
+/// otherwise socat runs them in the background.
+#[allow(clippy::too_many_arguments)]
 fn build_inner_command(
     command: &str,
     config: &SandboxRuntimeConfig,
     http_socket_path: Option<&str>,
     socks_socket_path: Option<&str>,
+    socks_udp_socket_path: Option<&str>,
+    dns_socket_path: Option<&str>,
+    expose_dir: Option<&str>,
     http_proxy_port: u16,
     socks_proxy_port: u16,
     shell: &str,
+    bridge_helper: Option<&Path>,
 ) -> Result<String, SandboxError> {
-    let mut parts = Vec::new();
+    let mut bridges = Vec::new();
 
-    // Set up socat bridges for proxy access
+    // Bridges for proxy access
     if let Some(http_sock) = http_socket_path {
-        let bridge_cmd = SocatBridge::tcp_to_unix_command(http_proxy_port, http_sock);
-        parts.push(format!("{} &", bridge_cmd));
+        bridges.push(BridgeSpec::Tcp {
+            port: http_proxy_port,
+            socket: http_sock.to_string(),
+        });
     }
 
     if let Some(socks_sock) = socks_socket_path {
-        let bridge_cmd = SocatBridge::tcp_to_unix_command(socks_proxy_port, socks_sock);
-        parts.push(format!("{} &", bridge_cmd));
+        bridges.push(BridgeSpec::Tcp {
+            port: socks_proxy_port,
+            socket: socks_sock.to_string(),
+        });
+    }
+
+    // SOCKS5 UDP relay listens on the same port number as the TCP proxy
+    if let Some(udp_sock) = socks_udp_socket_path {
+        bridges.push(BridgeSpec::Udp {
+            port: socks_proxy_port,
+            socket: udp_sock.to_string(),
+        });
+    }
+
+    // DNS forwarder, on the standard port for the resolver
+    if let Some(dns_sock) = dns_socket_path {
+        bridges.push(BridgeSpec::Dns {
+            socket: dns_sock.to_string(),
+        });
+    }
+
+    // Exposed ports, reached from the host through a socket per host port
+    if let Some(dir) = expose_dir {
+        for spec in &config.network.expose_ports {
+            if let Some(port) = ExposedPort::parse(spec) {
+                let socket = expose_socket_path(Path::new(dir), port.host_port);
+                bridges.push(BridgeSpec::Expose {
+                    port: port.sandbox_port,
+                    socket: socket.to_string_lossy().into_owned(),
+                });
+            }
+        }
+    }
+
+    // TUN device for traffic that doesn't use the proxies, through the SOCKS5 bridge
+    let tun = match (&config.network.tun_capture, socks_socket_path) {
+        (Some(tun), Some(_)) => Some(TunSpec::new(tun)),
+        _ => None,
+    };
+
+    // Servers in the sandbox need allowLocalBinding, as on macOS
+    let deny_listen = !config.network.allow_local_binding.unwrap_or(false);
+
+    let bridge_helper = bridge_helper.filter(|_| !bridges.is_empty());
+    let mut parts = Vec::new();
+    if bridge_helper.is_none() {
+        for bridge in &bridges {
+            parts.push(format!("{} &", bridge.socat_command()));
+        }
+        if deny_listen && !bridges.is_empty() {
+            tracing::warn!("allowLocalBinding needs the srt bridge helper and is not enforced");
+        }
     }
 
     // Small delay to let socat bridges start
-    if http_socket_path.is_some() || socks_socket_path.is_some() {
+    if bridge_helper.is_none() && (http_socket_path.is_some() || socks_socket_path.is_some()) {
         parts.push("sleep 0.1".to_string());
     }
 
+    // The proxy variables come from the environment bwrap passes on: they
+    // carry the session credentials, which must stay off the command line
+
     // Apply seccomp filter and execute command
     if !config.network.allow_all_unix_sockets.unwrap_or(false) {
         // Try to use seccomp to block Unix socket creation
@@ -139,10 +290,6 @@ fn build_inner_command(
             get_bpf_path(config.seccomp.as_ref()),
             get_apply_seccomp_path(config.seccomp.as_ref()),
         ) {
-            // Export proxy environment variables before applying seccomp
-            let env_vars = generate_proxy_env_string(http_proxy_port, socks_proxy_port);
-            parts.push(env_vars);
-
             // Use apply-seccomp to apply the filter and exec the command
             parts.push(format!(
                 "{} {} {} -c {}",
@@ -156,41 +303,34 @@ fn build_inner_command(
             tracing::warn!(
                 "Seccomp not available - Unix socket creation will not be blocked"
             );
-            let env_vars = generate_proxy_env_string(http_proxy_port, socks_proxy_port);
-            parts.push(format!("{} {} -c {}", env_vars, shell, quote(command)));
+            parts.push(format!("{} -c {}", shell, quote(command)));
         }
     } else {
         // Unix sockets allowed, just run the command
-        let env_vars = generate_proxy_env_string(http_proxy_port, socks_proxy_port);
-        parts.push(format!("{} {} -c {}", env_vars, shell, quote(command)));
+        parts.push(format!("{} -c {}", shell, quote(command)));
     }
 
-    Ok(parts.join(" ; "))
-}
-
-/// Generate proxy environment variable exports.
-fn generate_proxy_env_string(http_port: u16, socks_port: u16) -> String {
-    format!(
-        "export http_proxy='http://localhost:{}' https_proxy='http://localhost:{}' \
-         HTTP_PROXY='http://localhost:{}' HTTPS_PROXY='http://localhost:{}' \
-         ALL_PROXY='socks5://localhost:{}' all_proxy='socks5://localhost:{}' ;",
-        http_port, http_port, http_port, http_port, socks_port, socks_port
-    )
+    let inner = parts.join(" ; ");
+    match bridge_helper {
+        // The helper stays the parent, so the bridges live as long as the command
+        Some(helper) => Ok(format!(
+            "exec {} {} -c {}",
+            bridge_helper_command(helper, &bridges, tun.as_ref(), deny_listen),
+            shell,
+            quote(&inner)
+        )),
+        None => Ok(inner),
+    }
 }
 
-/// Generate proxy environment variables.
-pub fn generate_proxy_env(http_port: u16, socks_port: u16) -> Vec<(String, String)> {
-    let http_proxy = format!("http://localhost:{}", http_port);
-    let socks_proxy = format!("socks5://localhost:{}", socks_port);
-
-    vec![
-        ("http_proxy".to_string(), http_proxy.clone()),
-        ("HTTP_PROXY".to_string(), http_proxy.clone()),
-        ("https_proxy".to_string(), http_proxy.clone()),
-        ("HTTPS_PROXY".to_string(), http_proxy),
-        ("ALL_PROXY".to_string(), socks_proxy.clone()),
-        ("all_proxy".to_string(), socks_proxy),
-    ]
+/// Write the sandbox's resolver config, pointing at the DNS bridge.
+pub fn write_resolv_conf(path: &Path) -> Result<(), SandboxError> {
+    std::fs::write(
+        path,
+        "# Generated by srt: lookups go through the sandbox's DNS forwarder\n\
+         nameserver 127.0.0.1\n",
+    )?;
+    Ok(())
 }
 
 #[cfg(test)]
@@ -198,10 +338,130 @@ mod tests {
     use super::*;
 
     #[test]
-    fn test_generate_proxy_env_string() {
-        let env = generate_proxy_env_string(3128, 1080);
-        assert!(env.contains("http_proxy='http://localhost:3128'"));
-        assert!(env.contains("ALL_PROXY='socks5://localhost:1080'"));
+    fn test_command_has_no_proxy_credentials() {
+        let mut config = SandboxRuntimeConfig::default();
+        config.network.tun_capture = Some(Default::default());
+        config.network.proxy_env = Some(crate::config::ProxyEnvConfig {
+            presets: Some(vec![crate::config::ProxyEnvPreset::Java]),
+            no_proxy: Vec::new(),
+        });
+        let auth = crate::proxy::ProxyAuth::new("srt", "s3cr3t-session", None);
+
+        // The credentials are in the environment the command runs with
+        let env = crate::sandbox::sandbox_env(&config, 3128, 1080, Some(&auth), None);
+        let has_secret = |var: &str| {
+            env.iter().any(|(name, value)| name == var && value.contains("s3cr3t"))
+        };
+        assert!(has_secret("http_proxy"));
+        assert!(has_secret("SRT_TUN_PROXY"));
+
+        // And nowhere in the command line, which other users can read
+        let dir = tempfile::tempdir().unwrap();
+        let (command, _) = generate_bwrap_command(
+            "true",
+            &config,
+            dir.path(),
+            Some("/run/srt/http.sock"),
+            Some("/run/srt/socks.sock"),
+            None,
+            None,
+            None,
+            None,
+            None,
+            3128,
+            1080,
+            None,
+        )
+        .unwrap();
+        assert!(!command.contains("s3cr3t"));
+        assert!(!command.contains("http_proxy"));
+        let inner = build_inner_command(
+            "true",
+            &config,
+            Some("/run/srt/http.sock"),
+            Some("/run/srt/socks.sock"),
+            None,
+            None,
+            None,
+            3128,
+            1080,
+            "/bin/bash",
+            Some(Path::new("/usr/bin/srt")),
+        )
+        .unwrap();
+        assert!(inner.contains("--tun2socks tun2socks "));
+        assert!(!inner.contains("s3cr3t"));
+    }
+
+    #[test]
+    fn test_build_inner_command_bridges() {
+        let mut config = SandboxRuntimeConfig::default();
+        let build = |config: &SandboxRuntimeConfig, helper: Option<&Path>| {
+            build_inner_command(
+                "curl example.com",
+                config,
+                Some("/run/srt/http.sock"),
+                Some("/run/srt/socks.sock"),
+                None,
+                Some("/run/srt/dns.sock"),
+                None,
+                3128,
+                1080,
+                "/bin/bash",
+                helper,
+            )
+            .unwrap()
+        };
+
+        // socat runs the bridges in the background
+        let inner = build(&config, None);
+        assert!(inner.starts_with("socat TCP-LISTEN:3128,fork,reuseaddr "));
+        assert!(inner.contains(" & ; sleep 0.1 ; "));
+
+        // The helper starts the rest of the command once its bridges listen
+        let inner = build(&config, Some(Path::new("/usr/bin/srt")));
+        assert!(inner.starts_with(
+            "exec /usr/bin/srt __bridge --deny-listen tcp:3128:/run/srt/http.sock \
+             tcp:1080:/run/srt/socks.sock dns:/run/srt/dns.sock -- /bin/bash -c '"
+        ));
+        assert!(!inner.contains("socat"));
+        assert!(!inner.contains("sleep"));
+
+        // The helper sets up TUN capture before the command runs, which may listen here
+        config.network.tun_capture = Some(Default::default());
+        config.network.allow_local_binding = Some(true);
+        let inner = build(&config, Some(Path::new("/usr/bin/srt")));
+        assert!(inner.starts_with(
+            "exec /usr/bin/srt __bridge --tun2socks tun2socks tcp:3128:"
+        ));
+        assert!(!inner.contains("ip "));
+    }
+
+    #[test]
+    fn test_udp_bridges_need_helper() {
+        // Tests don't run as srt, so the bridges fall back to socat
+        let dir = tempfile::tempdir().unwrap();
+        let (command, _) = generate_bwrap_command(
+            "true",
+            &SandboxRuntimeConfig::default(),
+            dir.path(),
+            Some("/run/srt/http.sock"),
+            Some("/run/srt/socks.sock"),
+            Some("/run/srt/socks-udp.sock"),
+            Some("/run/srt/dns.sock"),
+            Some("/run/srt/resolv.conf"),
+            None,
+            None,
+            3128,
+            1080,
+            None,
+        )
+        .unwrap();
+        assert!(command.contains("TCP-LISTEN:3128"));
+        assert!(!command.contains("CAP_NET_BIND_SERVICE"));
+        assert!(!command.contains("dns.sock"));
+        assert!(!command.contains("socks-udp.sock"));
+        assert!(!command.contains("resolv.conf"));
     }
 
     #[test]
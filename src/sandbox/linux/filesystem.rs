@@ -7,7 +7,7 @@ use crate::config::{FilesystemConfig, RipgrepConfig, DANGEROUS_DIRECTORIES, DANG
 use crate::error::SandboxError;
 use crate::utils::{
     contains_glob_chars, find_dangerous_files, is_symlink_outside_boundary,
-    normalize_path_for_sandbox, remove_trailing_glob_suffix,
+    normalize_path_for_sandbox,
 };
 
 /// Bind mount specification.
@@ -21,6 +21,8 @@ pub struct BindMount {
     pub readonly: bool,
     /// Whether to create the path with dev-null if it doesn't exist.
     pub dev_null: bool,
+    /// Whether to cover the directory with an empty tmpfs.
+    pub tmpfs: bool,
 }
 
 impl BindMount {
@@ -32,6 +34,7 @@ impl BindMount {
             target: path,
             readonly: true,
             dev_null: false,
+            tmpfs: false,
         }
     }
 
@@ -43,6 +46,7 @@ impl BindMount {
             target: path,
             readonly: false,
             dev_null: false,
+            tmpfs: false,
         }
     }
 
@@ -54,12 +58,27 @@ impl BindMount {
             target: path,
             readonly: true,
             dev_null: true,
+            tmpfs: false,
+        }
+    }
+
+    /// Create an empty tmpfs mount to hide a directory.
+    pub fn hide(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        Self {
+            source: PathBuf::from("tmpfs"),
+            target: path,
+            readonly: true,
+            dev_null: false,
+            tmpfs: true,
         }
     }
 
     /// Convert to bwrap arguments.
     pub fn to_bwrap_args(&self) -> Vec<String> {
-        if self.dev_null {
+        if self.tmpfs {
+            vec!["--tmpfs".to_string(), self.target.display().to_string()]
+        } else if self.dev_null {
             vec![
                 "--ro-bind".to_string(),
                 "/dev/null".to_string(),
@@ -189,6 +208,24 @@ pub fn generate_bind_mounts(
         }
     }
 
+    // Hide paths denied for reading (these override everything above)
+    for path in &config.deny_read {
+        if contains_glob_chars(path) {
+            warnings.push(format!(
+                "Glob pattern '{}' is not supported on Linux; ignoring",
+                path
+            ));
+            continue;
+        }
+
+        let path = PathBuf::from(normalize_path_for_sandbox(path));
+        if path.is_dir() {
+            mounts.push(BindMount::hide(path));
+        } else if path.exists() {
+            mounts.push(BindMount::block(path));
+        }
+    }
+
     Ok((mounts, warnings))
 }
 
@@ -209,5 +246,32 @@ mod tests {
         let mount = BindMount::block("/path/to/blocked");
         let args = mount.to_bwrap_args();
         assert_eq!(args, vec!["--ro-bind", "/dev/null", "/path/to/blocked"]);
+
+        let mount = BindMount::hide("/path/to/hidden");
+        let args = mount.to_bwrap_args();
+        assert_eq!(args, vec!["--tmpfs", "/path/to/hidden"]);
+    }
+
+    #[test]
+    fn test_deny_read_mounts() {
+        let dir = tempfile::tempdir().unwrap();
+        let secret = dir.path().join("token");
+        std::fs::write(&secret, "s3cret").unwrap();
+        let config = FilesystemConfig {
+            deny_read: vec![
+                secret.display().to_string(),
+                dir.path().display().to_string(),
+                "/nonexistent/srt-test".to_string(),
+            ],
+            ..Default::default()
+        };
+
+        let (mounts, _) = generate_bind_mounts(&config, dir.path(), None, Some(0)).unwrap();
+        let args: Vec<Vec<String>> = mounts.iter().map(BindMount::to_bwrap_args).collect();
+        let secret = std::fs::canonicalize(&secret).unwrap().display().to_string();
+        let dir = std::fs::canonicalize(dir.path()).unwrap().display().to_string();
+        assert!(args.contains(&vec!["--ro-bind".to_string(), "/dev/null".to_string(), secret]));
+        assert!(args.contains(&vec!["--tmpfs".to_string(), dir]));
+        assert!(!args.concat().iter().any(|arg| arg.contains("srt-test")));
     }
 }
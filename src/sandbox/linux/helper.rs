@@ -0,0 +1,459 @@
+//! The `srt` bridge helper, run inside the sandbox in place of socat.
+//!
+//! bwrap starts `srt __bridge <bridge>... -- <command>`. The helper binds
+//! every bridge listener first, brings up TUN capture if asked to, and only
+//! then runs the command, so the command never races the bridges. It forwards
+//! termination signals to the command, exits with its status and takes the
+//! bridges and tun2socks down with it. bwrap grants capabilities for that
+//! setup only; the command and tun2socks run without them. Without
+//! `allowLocalBinding`, the command can't listen for connections.
+
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use nix::libc;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::error::SandboxError;
+use crate::sandbox::linux::bridge::{
+    BridgeSpec, DNS_BRIDGE_TIMEOUT_SECS, SANDBOX_DNS_PORT, UDP_BRIDGE_TIMEOUT_SECS,
+};
+use crate::sandbox::linux::host_bridge::HostBridge;
+use crate::sandbox::linux::tun::{start_tun, TunSpec, TUN_PROXY_ENV};
+use crate::utils::quote;
+
+/// Hidden subcommand of `srt` running the helper.
+pub const BRIDGE_HELPER_SUBCOMMAND: &str = "__bridge";
+
+/// Name of the binary providing the helper.
+const HELPER_BINARY: &str = "srt";
+
+/// `CAP_NET_BIND_SERVICE`, to bind the DNS port.
+const CAP_NET_BIND_SERVICE: u32 = 10;
+
+/// `CAP_NET_ADMIN`, to configure TUN capture.
+const CAP_NET_ADMIN: u32 = 12;
+
+/// Capabilities bwrap grants the helper for its setup.
+const SETUP_CAPABILITIES: &[u32] = &[CAP_NET_BIND_SERVICE, CAP_NET_ADMIN];
+
+/// `_LINUX_CAPABILITY_VERSION_3`, with 64-bit capability sets.
+const CAPABILITY_VERSION: u32 = 0x2008_0522;
+
+/// Helper option refusing `listen` to the command.
+const DENY_LISTEN_ARG: &str = "--deny-listen";
+
+/// Audit architecture of native syscalls, checked by the seccomp filter.
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: Option<u32> = Some(0xC000_003E);
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: Option<u32> = Some(0xC000_00B7);
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const AUDIT_ARCH: Option<u32> = None;
+
+/// Syscall number bit of the x32 ABI, which shares x86_64's audit architecture.
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+/// The 32-bit ABI a native process can also make syscalls through, with its
+/// own audit architecture and syscall numbers.
+struct CompatAbi {
+    arch: u32,
+    listen: u32,
+    /// `socketcall`, which multiplexes the socket calls, if the ABI has it.
+    socketcall: Option<u32>,
+}
+
+/// i386, reached with `int 0x80`.
+#[cfg(target_arch = "x86_64")]
+const COMPAT_ABI: Option<CompatAbi> = Some(CompatAbi {
+    arch: 0x4000_0003,
+    listen: 363,
+    socketcall: Some(102),
+});
+/// 32-bit ARM (EABI).
+#[cfg(target_arch = "aarch64")]
+const COMPAT_ABI: Option<CompatAbi> = Some(CompatAbi {
+    arch: 0x4000_0028,
+    listen: 284,
+    socketcall: None,
+});
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const COMPAT_ABI: Option<CompatAbi> = None;
+
+/// `socketcall` call number of `listen`.
+const SOCKETCALL_LISTEN: u32 = 4;
+
+/// Get the `srt` binary to run as the bridge helper: the current executable,
+/// when that is `srt` rather than another program embedding the library.
+pub fn bridge_helper_path() -> Option<PathBuf> {
+    std::env::current_exe()
+        .ok()
+        .filter(|exe| exe.file_name().is_some_and(|name| name == HELPER_BINARY))
+}
+
+/// Build the shell command prefix running `bridges` and `tun` with the
+/// helper, refusing `listen` to the command with `deny_listen`; the command
+/// to run follows it.
+pub fn bridge_helper_command(
+    helper: &Path,
+    bridges: &[BridgeSpec],
+    tun: Option<&TunSpec>,
+    deny_listen: bool,
+) -> String {
+    let mut parts = vec![quote(&helper.to_string_lossy()), BRIDGE_HELPER_SUBCOMMAND.to_string()];
+    if deny_listen {
+        parts.push(DENY_LISTEN_ARG.to_string());
+    }
+    if let Some(tun) = tun {
+        parts.extend(tun.to_args().iter().map(|arg| quote(arg)));
+    }
+    parts.extend(bridges.iter().map(|bridge| quote(&bridge.to_arg())));
+    parts.push("--".to_string());
+    parts.join(" ")
+}
+
+/// Start the bridges and TUN capture, run `command` once they are up and
+/// wait for it. With `deny_listen`, the command can't listen for connections.
+/// Returns the command's exit code, or 128 plus the signal that ended it.
+pub async fn run_bridge_helper(
+    bridges: &[BridgeSpec],
+    tun: Option<&TunSpec>,
+    deny_listen: bool,
+    command: &[String],
+) -> Result<i32, SandboxError> {
+    let (program, args) = command
+        .split_first()
+        .ok_or_else(|| SandboxError::ExecutionFailed("No command for the bridge helper".into()))?;
+
+    let mut running = Vec::with_capacity(bridges.len());
+    for bridge in bridges {
+        running.push(start_bridge(bridge).await?);
+    }
+    tracing::debug!("{} sandbox bridges listening", running.len());
+    let tun2socks = match tun {
+        Some(tun) => Some(start_tun(tun).await?),
+        None => None,
+    };
+
+    let mut child = tokio::process::Command::new(program);
+    child.args(args).env_remove(TUN_PROXY_ENV);
+    // SAFETY: only makes syscalls, as required between fork and exec
+    unsafe {
+        child.pre_exec(move || {
+            drop_setup_capabilities()?;
+            if deny_listen {
+                refuse_listen()?;
+            }
+            Ok(())
+        })
+    };
+    let mut child = child.spawn()?;
+    let pid = child.id().map(|id| Pid::from_raw(id as i32));
+
+    // Pass termination signals on, and outlive the command to keep its bridges up
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let mut sighup = signal(SignalKind::hangup())?;
+    let status = loop {
+        let forward = tokio::select! {
+            status = child.wait() => break status?,
+            _ = sigterm.recv() => Signal::SIGTERM,
+            _ = sigint.recv() => Signal::SIGINT,
+            _ = sighup.recv() => Signal::SIGHUP,
+        };
+        if let Some(pid) = pid {
+            let _ = kill(pid, forward);
+        }
+    };
+
+    if let Some(mut tun2socks) = tun2socks {
+        let _ = tun2socks.kill().await;
+    }
+    for mut bridge in running {
+        bridge.stop().await;
+    }
+
+    Ok(status.code().or_else(|| status.signal().map(|sig| 128 + sig)).unwrap_or(1))
+}
+
+/// Drop the capabilities granted for the helper's setup from the calling
+/// thread, for good: from its effective, permitted, inheritable and ambient
+/// sets, and from its bounding set where it may. Run in children between
+/// fork and exec, since capabilities are per thread.
+pub(crate) fn drop_setup_capabilities() -> std::io::Result<()> {
+    #[repr(C)]
+    struct Header {
+        version: u32,
+        pid: libc::c_int,
+    }
+    #[repr(C)]
+    #[derive(Clone, Copy, Default)]
+    struct Data {
+        effective: u32,
+        permitted: u32,
+        inheritable: u32,
+    }
+
+    let mut header = Header {
+        version: CAPABILITY_VERSION,
+        pid: 0,
+    };
+    let mut data = [Data::default(); 2];
+    // SAFETY: `header` and `data` have the kernel's layout for version 3
+    if unsafe { libc::syscall(libc::SYS_capget, &mut header, data.as_mut_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    for &cap in SETUP_CAPABILITIES {
+        let (index, bit) = ((cap / 32) as usize, 1 << (cap % 32));
+        data[index].effective &= !bit;
+        data[index].permitted &= !bit;
+        data[index].inheritable &= !bit;
+    }
+    // Lowering the permitted and inheritable sets clears the ambient set
+    // SAFETY: as above
+    if unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    // Without CAP_SETPCAP the bounding set stays, but with bwrap's
+    // no_new_privs nothing can regain them
+    for &cap in SETUP_CAPABILITIES {
+        // SAFETY: plain prctl call
+        unsafe { libc::prctl(libc::PR_CAPBSET_DROP, cap as libc::c_ulong, 0, 0, 0) };
+    }
+    Ok(())
+}
+
+/// Make `listen` fail with EACCES in the calling thread and everything it
+/// runs, with a seccomp filter, so no sockets accept connections: the
+/// sandbox's part of `allowLocalBinding`. Servers on Unix sockets are refused
+/// too. Run in children between fork and exec, since filters are per thread.
+pub(crate) fn refuse_listen() -> std::io::Result<()> {
+    let arch = AUDIT_ARCH.ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::Unsupported, "no seccomp support")
+    })?;
+    let mut filter = listen_filter(arch, COMPAT_ABI.as_ref());
+    let program = libc::sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_mut_ptr(),
+    };
+    // SAFETY: plain prctl calls; `program` outlives them
+    unsafe {
+        if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0
+            || libc::prctl(libc::PR_SET_SECCOMP, libc::SECCOMP_MODE_FILTER, &program) != 0
+        {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// The seccomp program refusing `listen` to native syscalls, and to those of
+/// the `compat` ABI, through `socketcall` too. Syscalls of any other
+/// architecture are refused, so they can't get around it.
+fn listen_filter(arch: u32, compat: Option<&CompatAbi>) -> Vec<libc::sock_filter> {
+    let statement = |code: u32, k: u32| libc::sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    };
+    let jump = |k: u32, jt: u8, jf: u8| libc::sock_filter {
+        code: (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16,
+        jt,
+        jf,
+        k,
+    };
+    // seccomp_data starts with the syscall number, then the architecture,
+    // and has the arguments from offset 16 (the low half first)
+    let load = |offset: u32| statement(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, offset);
+    let deny = statement(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ERRNO | libc::EACCES as u32);
+    let allow = statement(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ALLOW);
+
+    let native = [
+        load(0),
+        statement(libc::BPF_ALU | libc::BPF_AND | libc::BPF_K, !X32_SYSCALL_BIT),
+        jump(libc::SYS_listen as u32, 0, 1),
+        deny,
+        allow,
+    ];
+    let mut filter = vec![load(4), jump(arch, 0, native.len() as u8)];
+    filter.extend(native);
+
+    if let Some(compat) = compat {
+        let mut block = vec![load(0), jump(compat.listen, 0, 1), deny];
+        if let Some(socketcall) = compat.socketcall {
+            block.extend([jump(socketcall, 0, 3), load(16), jump(SOCKETCALL_LISTEN, 0, 1), deny]);
+        }
+        block.push(allow);
+        filter.push(jump(compat.arch, 0, block.len() as u8));
+        filter.extend(block);
+    }
+
+    filter.push(deny);
+    filter
+}
+
+/// Bind one bridge inside the sandbox.
+async fn start_bridge(bridge: &BridgeSpec) -> Result<HostBridge, SandboxError> {
+    match bridge {
+        BridgeSpec::Tcp { port, socket } => HostBridge::tcp_to_unix(*port, socket.into()).await,
+        BridgeSpec::Udp { port, socket } => {
+            let timeout = Duration::from_secs(UDP_BRIDGE_TIMEOUT_SECS);
+            HostBridge::udp_to_unix(*port, socket.into(), timeout).await
+        }
+        BridgeSpec::Dns { socket } => {
+            let timeout = Duration::from_secs(DNS_BRIDGE_TIMEOUT_SECS);
+            HostBridge::udp_to_unix(SANDBOX_DNS_PORT, socket.into(), timeout).await
+        }
+        BridgeSpec::Expose { port, socket } => {
+            HostBridge::unix_to_tcp(socket.into(), "127.0.0.1", *port)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::UnixListener;
+
+    #[test]
+    fn test_bridge_helper_command() {
+        let bridges = [
+            BridgeSpec::Tcp {
+                port: 3128,
+                socket: "/run/srt/http.sock".to_string(),
+            },
+            BridgeSpec::Dns {
+                socket: "/run/my dir/dns.sock".to_string(),
+            },
+        ];
+        assert_eq!(
+            bridge_helper_command(Path::new("/usr/bin/srt"), &bridges, None, false),
+            "/usr/bin/srt __bridge tcp:3128:/run/srt/http.sock 'dns:/run/my dir/dns.sock' --"
+        );
+
+        let tun = TunSpec {
+            tun2socks: "/opt/tun2socks".to_string(),
+        };
+        assert_eq!(
+            bridge_helper_command(Path::new("/usr/bin/srt"), &bridges[..1], Some(&tun), true),
+            "/usr/bin/srt __bridge --deny-listen --tun2socks /opt/tun2socks \
+             tcp:3128:/run/srt/http.sock --"
+        );
+    }
+
+    #[test]
+    fn test_refuse_listen() {
+        // The filter only applies to the thread installing it
+        std::thread::spawn(|| {
+            refuse_listen().unwrap();
+            let error = std::net::TcpListener::bind("127.0.0.1:0").unwrap_err();
+            assert_eq!(error.kind(), std::io::ErrorKind::PermissionDenied);
+            assert!(std::net::UdpSocket::bind("127.0.0.1:0").is_ok());
+            assert!(std::net::TcpStream::connect("127.0.0.1:9").is_err_and(|e| {
+                e.kind() != std::io::ErrorKind::PermissionDenied
+            }));
+        })
+        .join()
+        .unwrap();
+        assert!(std::net::TcpListener::bind("127.0.0.1:0").is_ok());
+    }
+
+    /// Run a seccomp program on a syscall, as the kernel would.
+    fn run_filter(filter: &[libc::sock_filter], arch: u32, nr: u32, arg0: u32) -> u32 {
+        let data = [nr, arch, 0, 0, arg0];
+        let (mut acc, mut pc) = (0u32, 0usize);
+        loop {
+            let op = filter[pc];
+            pc += 1;
+            match op.code as u32 {
+                code if code == libc::BPF_LD | libc::BPF_W | libc::BPF_ABS => {
+                    acc = data[op.k as usize / 4]
+                }
+                code if code == libc::BPF_ALU | libc::BPF_AND | libc::BPF_K => acc &= op.k,
+                code if code == libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K => {
+                    pc += if acc == op.k { op.jt } else { op.jf } as usize
+                }
+                code if code == libc::BPF_RET | libc::BPF_K => return op.k,
+                code => panic!("unexpected BPF instruction {:#x}", code),
+            }
+        }
+    }
+
+    #[test]
+    fn test_listen_filter() {
+        let i386 = CompatAbi {
+            arch: 0x4000_0003,
+            listen: 363,
+            socketcall: Some(102),
+        };
+        let filter = listen_filter(0xC000_003E, Some(&i386));
+        let denied = libc::SECCOMP_RET_ERRNO | libc::EACCES as u32;
+        let listen = libc::SYS_listen as u32;
+
+        assert_eq!(run_filter(&filter, 0xC000_003E, listen, 0), denied);
+        assert_eq!(run_filter(&filter, 0xC000_003E, listen | X32_SYSCALL_BIT, 0), denied);
+        assert_eq!(run_filter(&filter, 0xC000_003E, 0, 0), libc::SECCOMP_RET_ALLOW);
+
+        // The compat ABI can't listen directly or through socketcall
+        assert_eq!(run_filter(&filter, i386.arch, 363, 0), denied);
+        assert_eq!(run_filter(&filter, i386.arch, 102, SOCKETCALL_LISTEN), denied);
+        assert_eq!(run_filter(&filter, i386.arch, 102, 1), libc::SECCOMP_RET_ALLOW);
+        assert_eq!(run_filter(&filter, i386.arch, listen, 0), libc::SECCOMP_RET_ALLOW);
+
+        // Other architectures are refused everything
+        assert_eq!(run_filter(&filter, 0x4000_0028, 0, 0), denied);
+        let filter = listen_filter(0xC000_003E, None);
+        assert_eq!(run_filter(&filter, i386.arch, 363, 0), denied);
+        assert_eq!(run_filter(&filter, i386.arch, 3, 0), denied);
+    }
+
+    #[tokio::test]
+    async fn test_run_bridge_helper() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = dir.path().join("http.sock");
+        let server = UnixListener::bind(&socket_path).unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = server.accept().await.unwrap();
+            stream.write_all(b"proxied\n").await.unwrap();
+        });
+
+        // The command runs once the listener is up, and its status is returned
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let bridges = [BridgeSpec::Tcp {
+            port,
+            socket: socket_path.display().to_string(),
+        }];
+        let script = format!(
+            "exec 3<>/dev/tcp/127.0.0.1/{} && read -r line <&3 && [ \"$line\" = proxied ] \
+             && exit 7",
+            port
+        );
+        let command = ["bash".to_string(), "-c".to_string(), script];
+        assert_eq!(run_bridge_helper(&bridges, None, false, &command).await.unwrap(), 7);
+
+        // Its listeners are gone afterwards
+        assert!(tokio::net::TcpStream::connect(("127.0.0.1", port)).await.is_err());
+
+        // The command runs without the setup capabilities (bits 10 and 12)
+        let script = "while read -r set caps; do case $set in \
+                      CapEff:|CapPrm:|CapInh:|CapAmb:|CapBnd:) \
+                      [ $(( 0x$caps & 0x1400 )) = 0 ] || exit 1;; esac; \
+                      done < /proc/$$/status; exit 3";
+        let command = ["bash".to_string(), "-c".to_string(), script.to_string()];
+        assert_eq!(run_bridge_helper(&[], None, false, &command).await.unwrap(), 3);
+
+        // A listener that can't be bound fails before the command runs
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let bridges = [BridgeSpec::Tcp {
+            port: taken.local_addr().unwrap().port(),
+            socket: socket_path.display().to_string(),
+        }];
+        assert!(run_bridge_helper(&bridges, None, false, &command).await.is_err());
+    }
+}
@@ -0,0 +1,734 @@
+//! Host side of the Unix socket bridges, run in-process with tokio.
+//!
+//! The sandbox has its own network namespace, so it reaches the proxies and
+//! the DNS forwarder through Unix sockets in the session's runtime directory.
+//! A [`HostBridge`] listens on such a socket and relays each connection to a
+//! TCP or UDP port on the host; for `exposePorts` it listens on a host TCP
+//! port and relays into a socket the sandbox listens on. The listener is bound
+//! before the constructor returns, so the bridge is ready once created.
+//!
+//! The `srt` bridge helper ([`super::helper`]) runs the same bridges in the
+//! other direction inside the sandbox. UDP bridges carry each datagram as a
+//! frame with a 2-byte length, so datagrams stay apart on the stream.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use once_cell::sync::Lazy;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket, UnixListener, UnixStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+use crate::error::SandboxError;
+use crate::sandbox::linux::runtime::verify_peer;
+
+/// Largest datagram relayed by UDP bridges.
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+/// Datagrams queued for a UDP peer's connection before more are dropped.
+const UDP_PEER_QUEUE: usize = 64;
+
+/// The host's ephemeral port range, the only ports UDP bridges send from on
+/// behalf of the sandbox.
+static EPHEMERAL_PORTS: Lazy<RangeInclusive<u16>> = Lazy::new(|| {
+    std::fs::read_to_string("/proc/sys/net/ipv4/ip_local_port_range")
+        .ok()
+        .and_then(|range| parse_port_range(&range))
+        .unwrap_or(32768..=60999)
+});
+
+/// Parse a port range as in `ip_local_port_range` ("32768\t60999").
+fn parse_port_range(range: &str) -> Option<RangeInclusive<u16>> {
+    let mut ports = range.split_whitespace().map(|port| port.parse::<u16>());
+    match (ports.next(), ports.next()) {
+        (Some(Ok(start)), Some(Ok(end))) if start <= end => Some(start..=end),
+        _ => None,
+    }
+}
+
+/// Codec framing the datagrams of a UDP bridge connection.
+fn datagram_codec() -> LengthDelimitedCodec {
+    LengthDelimitedCodec::builder()
+        .length_field_length(2)
+        .max_frame_length(MAX_DATAGRAM_SIZE)
+        .new_codec()
+}
+
+/// Connection counts of a bridge.
+#[derive(Default)]
+struct BridgeStats {
+    active: AtomicUsize,
+    total: AtomicU64,
+}
+
+/// Counts a connection as active until dropped.
+struct ConnectionGuard(Arc<BridgeStats>);
+
+impl ConnectionGuard {
+    fn new(stats: &Arc<BridgeStats>) -> Self {
+        stats.active.fetch_add(1, Ordering::Relaxed);
+        stats.total.fetch_add(1, Ordering::Relaxed);
+        Self(stats.clone())
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Where a bridge accepts connections.
+enum Listener {
+    /// A Unix socket reached from the sandbox; peers must be the current user.
+    Unix(UnixListener),
+    /// A TCP port on the host.
+    Tcp(TcpListener),
+}
+
+/// An accepted connection.
+enum Client {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl Listener {
+    async fn accept(&self) -> io::Result<Client> {
+        match self {
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                verify_peer(&stream)?;
+                Ok(Client::Unix(stream))
+            }
+            Listener::Tcp(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok(Client::Tcp(stream))
+            }
+        }
+    }
+}
+
+/// Where a bridge relays the connections it accepts.
+#[derive(Clone)]
+enum Target {
+    /// A TCP port on the host.
+    Tcp(String),
+    /// A UDP port on the host; each connection gets its own source port and
+    /// ends after being idle for the timeout.
+    Udp(String, Duration),
+    /// A Unix socket the sandbox listens on.
+    Unix(PathBuf),
+}
+
+/// A running host-side bridge. Stopped by [`HostBridge::stop`] or on drop.
+pub struct HostBridge {
+    /// The Unix socket the bridge listens on or connects to.
+    socket_path: PathBuf,
+    /// Whether the bridge created the socket and must remove it.
+    owns_socket: bool,
+    stats: Arc<BridgeStats>,
+    shutdown_tx: Option<oneshot::Sender<()>>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl HostBridge {
+    /// Listen on a Unix socket and relay each connection to a TCP port.
+    pub fn unix_to_tcp(socket_path: PathBuf, host: &str, port: u16) -> Result<Self, SandboxError> {
+        let target = Target::Tcp(format!("{}:{}", host, port));
+        Self::listen_unix(socket_path, target)
+    }
+
+    /// Listen on a Unix socket and relay each connection to a UDP port.
+    /// A connection starts with the source port of the peer in the sandbox,
+    /// which its datagrams are sent from if that port is free on the host.
+    /// Each frame from the connection is sent as one datagram and each reply
+    /// is written back as a frame, until the connection has been idle for
+    /// `idle_timeout`.
+    pub fn unix_to_udp(
+        socket_path: PathBuf,
+        host: &str,
+        port: u16,
+        idle_timeout: Duration,
+    ) -> Result<Self, SandboxError> {
+        let target = Target::Udp(format!("{}:{}", host, port), idle_timeout);
+        Self::listen_unix(socket_path, target)
+    }
+
+    /// Listen on a TCP port on 127.0.0.1 and relay each connection to a Unix
+    /// socket the sandbox listens on. This exposes a port in the sandbox.
+    pub async fn tcp_to_unix(host_port: u16, socket_path: PathBuf) -> Result<Self, SandboxError> {
+        let listener = TcpListener::bind(("127.0.0.1", host_port)).await.map_err(|e| {
+            SandboxError::Proxy(format!("cannot listen on 127.0.0.1:{}: {}", host_port, e))
+        })?;
+        let target = Target::Unix(socket_path.clone());
+        Ok(Self::spawn(Listener::Tcp(listener), target, socket_path, false))
+    }
+
+    /// Listen on a UDP port on 127.0.0.1 and relay each peer's datagrams over
+    /// its own connection to a Unix socket, until idle for `idle_timeout`.
+    /// Each connection starts with the peer's port, for [`Self::unix_to_udp`].
+    pub async fn udp_to_unix(
+        port: u16,
+        socket_path: PathBuf,
+        idle_timeout: Duration,
+    ) -> Result<Self, SandboxError> {
+        let socket = UdpSocket::bind(("127.0.0.1", port)).await.map_err(|e| {
+            SandboxError::Proxy(format!("cannot listen on 127.0.0.1:{} (UDP): {}", port, e))
+        })?;
+        let path = socket_path.clone();
+        Ok(Self::start(socket_path, false, move |stats, shutdown_rx| {
+            run_udp(socket, path, idle_timeout, stats, shutdown_rx)
+        }))
+    }
+
+    /// Bind a Unix socket, replacing a stale one, and start relaying to `target`.
+    fn listen_unix(socket_path: PathBuf, target: Target) -> Result<Self, SandboxError> {
+        if socket_path.exists() {
+            std::fs::remove_file(&socket_path)?;
+        }
+        let listener = UnixListener::bind(&socket_path)?;
+        Ok(Self::spawn(Listener::Unix(listener), target, socket_path, true))
+    }
+
+    fn spawn(listener: Listener, target: Target, socket_path: PathBuf, owns_socket: bool) -> Self {
+        Self::start(socket_path, owns_socket, move |stats, shutdown_rx| {
+            run(listener, target, stats, shutdown_rx)
+        })
+    }
+
+    /// Run `serve` until the bridge is stopped.
+    fn start<F, Fut>(socket_path: PathBuf, owns_socket: bool, serve: F) -> Self
+    where
+        F: FnOnce(Arc<BridgeStats>, oneshot::Receiver<()>) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let stats = Arc::new(BridgeStats::default());
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let task = tokio::spawn(serve(stats.clone(), shutdown_rx));
+
+        Self {
+            socket_path,
+            owns_socket,
+            stats,
+            shutdown_tx: Some(shutdown_tx),
+            task: Some(task),
+        }
+    }
+
+    /// Get the socket path.
+    pub fn socket_path(&self) -> &Path {
+        &self.socket_path
+    }
+
+    /// Number of connections being relayed.
+    pub fn active_connections(&self) -> usize {
+        self.stats.active.load(Ordering::Relaxed)
+    }
+
+    /// Number of connections accepted since the bridge started.
+    pub fn total_connections(&self) -> u64 {
+        self.stats.total.load(Ordering::Relaxed)
+    }
+
+    /// Stop accepting, close every relayed connection and wait for them to end.
+    pub async fn stop(&mut self) {
+        if let Some(tx) = self.shutdown_tx.take() {
+            let _ = tx.send(());
+        }
+        if let Some(task) = self.task.take() {
+            let _ = task.await;
+        }
+        self.remove_socket();
+    }
+
+    fn remove_socket(&self) {
+        if self.owns_socket && self.socket_path.exists() {
+            let _ = std::fs::remove_file(&self.socket_path);
+        }
+    }
+}
+
+impl Drop for HostBridge {
+    fn drop(&mut self) {
+        // Aborting the accept loop drops its JoinSet, which aborts the connections
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+        self.remove_socket();
+    }
+}
+
+/// Accept connections until shut down, relaying each in its own task.
+async fn run(
+    listener: Listener,
+    target: Target,
+    stats: Arc<BridgeStats>,
+    mut shutdown_rx: oneshot::Receiver<()>,
+) {
+    let mut connections = JoinSet::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(client) => {
+                    let target = target.clone();
+                    let guard = ConnectionGuard::new(&stats);
+                    connections.spawn(async move {
+                        let _guard = guard;
+                        if let Err(e) = relay(client, &target).await {
+                            tracing::debug!("Bridge connection error: {}", e);
+                        }
+                    });
+                }
+                Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                    tracing::warn!("{}", e);
+                }
+                Err(e) => {
+                    tracing::error!("Bridge accept error: {}", e);
+                }
+            },
+            _ = &mut shutdown_rx => break,
+        }
+        // Reap finished connections
+        while connections.try_join_next().is_some() {}
+    }
+    connections.shutdown().await;
+}
+
+/// Receive datagrams until shut down, relaying each peer in its own task.
+async fn run_udp(
+    socket: UdpSocket,
+    socket_path: PathBuf,
+    idle_timeout: Duration,
+    stats: Arc<BridgeStats>,
+    mut shutdown_rx: oneshot::Receiver<()>,
+) {
+    let socket = Arc::new(socket);
+    let mut peers: HashMap<SocketAddr, mpsc::Sender<Vec<u8>>> = HashMap::new();
+    let mut connections = JoinSet::new();
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    loop {
+        tokio::select! {
+            received = socket.recv_from(&mut buf) => match received {
+                Ok((n, peer)) => {
+                    let datagram = match peers.get(&peer) {
+                        // A full queue drops the datagram, as the network would
+                        Some(tx) => match tx.try_send(buf[..n].to_vec()) {
+                            Err(mpsc::error::TrySendError::Closed(datagram)) => Some(datagram),
+                            _ => None,
+                        },
+                        None => Some(buf[..n].to_vec()),
+                    };
+                    if let Some(datagram) = datagram {
+                        let tx = spawn_peer(
+                            &mut connections,
+                            &socket,
+                            peer,
+                            datagram,
+                            &socket_path,
+                            idle_timeout,
+                            &stats,
+                        );
+                        peers.insert(peer, tx);
+                    }
+                }
+                Err(e) => {
+                    tracing::debug!("Bridge receive error: {}", e);
+                }
+            },
+            _ = &mut shutdown_rx => break,
+        }
+        // Forget peers whose connection ended, unless they already have a new one
+        while let Some(finished) = connections.try_join_next() {
+            if let Ok(peer) = finished {
+                if peers.get(&peer).is_some_and(|tx| tx.is_closed()) {
+                    peers.remove(&peer);
+                }
+            }
+        }
+    }
+    connections.shutdown().await;
+}
+
+/// Start relaying a new UDP peer, beginning with its first datagram.
+/// Returns the queue for its further datagrams.
+fn spawn_peer(
+    connections: &mut JoinSet<SocketAddr>,
+    socket: &Arc<UdpSocket>,
+    peer: SocketAddr,
+    first: Vec<u8>,
+    socket_path: &Path,
+    idle_timeout: Duration,
+    stats: &Arc<BridgeStats>,
+) -> mpsc::Sender<Vec<u8>> {
+    let (tx, rx) = mpsc::channel(UDP_PEER_QUEUE);
+    let _ = tx.try_send(first);
+
+    let socket = socket.clone();
+    let socket_path = socket_path.to_path_buf();
+    let guard = ConnectionGuard::new(stats);
+    connections.spawn(async move {
+        let _guard = guard;
+        if let Err(e) = relay_peer(&socket, peer, rx, &socket_path, idle_timeout).await {
+            tracing::debug!("Bridge connection error: {}", e);
+        }
+        peer
+    });
+    tx
+}
+
+/// Relay between one UDP peer and a connection to the Unix socket until the
+/// connection ends or is idle for `idle_timeout`.
+async fn relay_peer(
+    socket: &UdpSocket,
+    peer: SocketAddr,
+    mut rx: mpsc::Receiver<Vec<u8>>,
+    socket_path: &Path,
+    idle_timeout: Duration,
+) -> io::Result<()> {
+    let mut stream = UnixStream::connect(socket_path).await?;
+    stream.write_all(&peer.port().to_be_bytes()).await?;
+    let mut stream = Framed::new(stream, datagram_codec());
+    loop {
+        let step = async {
+            tokio::select! {
+                datagram = rx.recv() => match datagram {
+                    Some(datagram) => stream.send(Bytes::from(datagram)).await.map(|_| true),
+                    None => Ok(false),
+                },
+                frame = stream.next() => match frame {
+                    Some(datagram) => socket.send_to(&datagram?, peer).await.map(|_| true),
+                    None => Ok(false),
+                }
+            }
+        };
+        match tokio::time::timeout(idle_timeout, step).await {
+            Ok(Ok(true)) => {}
+            Ok(Ok(false)) | Err(_) => return Ok(()),
+            Ok(Err(e)) => return Err(e),
+        }
+    }
+}
+
+/// Relay one accepted connection to the target.
+async fn relay(client: Client, target: &Target) -> io::Result<()> {
+    match client {
+        Client::Unix(stream) => relay_from(stream, target).await,
+        Client::Tcp(stream) => relay_from(stream, target).await,
+    }
+}
+
+async fn relay_from<S>(mut client: S, target: &Target) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    match target {
+        Target::Tcp(addr) => {
+            let mut stream = TcpStream::connect(addr).await?;
+            tokio::io::copy_bidirectional(&mut client, &mut stream).await?;
+        }
+        Target::Unix(path) => {
+            let mut stream = UnixStream::connect(path).await?;
+            tokio::io::copy_bidirectional(&mut client, &mut stream).await?;
+        }
+        Target::Udp(addr, idle_timeout) => relay_udp(client, addr, *idle_timeout).await?,
+    }
+    Ok(())
+}
+
+/// Relay between the frames of a stream and a UDP peer until either side
+/// ends or the connection is idle for `idle_timeout`.
+async fn relay_udp<S>(mut client: S, addr: &str, idle_timeout: Duration) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // Send from the sandbox peer's port, so the SOCKS5 relay matches the
+    // port a client announces. The sandbox picks it, so only ports the host
+    // would hand out itself are taken, never a service's fixed port
+    let mut port = [0u8; 2];
+    match tokio::time::timeout(idle_timeout, client.read_exact(&mut port)).await {
+        Ok(read) => read?,
+        Err(_) => return Ok(()),
+    };
+    let port = u16::from_be_bytes(port);
+    let bound = match EPHEMERAL_PORTS.contains(&port) {
+        true => UdpSocket::bind(("127.0.0.1", port)).await,
+        false => Err(io::Error::new(io::ErrorKind::PermissionDenied, "not an ephemeral port")),
+    };
+    let socket = match bound {
+        Ok(socket) => socket,
+        Err(e) => {
+            tracing::debug!("Bridge cannot send from port {}: {}", port, e);
+            UdpSocket::bind("127.0.0.1:0").await?
+        }
+    };
+    socket.connect(addr).await?;
+
+    let mut client = Framed::new(client, datagram_codec());
+    let mut inbound = vec![0u8; MAX_DATAGRAM_SIZE];
+    loop {
+        let step = async {
+            tokio::select! {
+                frame = client.next() => match frame {
+                    Some(datagram) => socket.send(&datagram?).await.map(|_| true),
+                    None => Ok(false),
+                },
+                received = socket.recv(&mut inbound) => {
+                    let n = received?;
+                    let datagram = Bytes::copy_from_slice(&inbound[..n]);
+                    client.send(datagram).await.map(|_| true)
+                }
+            }
+        };
+        match tokio::time::timeout(idle_timeout, step).await {
+            Ok(Ok(true)) => {}
+            Ok(Ok(false)) | Err(_) => return Ok(()),
+            Ok(Err(e)) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn wait_for(condition: impl Fn() -> bool) {
+        for _ in 0..100 {
+            if condition() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("condition not reached");
+    }
+
+    #[tokio::test]
+    async fn test_unix_to_tcp() {
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = echo.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = echo.accept().await {
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = stream.split();
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                });
+            }
+        });
+
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = dir.path().join("http.sock");
+        let mut bridge = HostBridge::unix_to_tcp(socket_path.clone(), "127.0.0.1", port).unwrap();
+
+        // Ready as soon as it is created
+        let mut client = UnixStream::connect(&socket_path).await.unwrap();
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        assert_eq!(bridge.active_connections(), 1);
+        assert_eq!(bridge.total_connections(), 1);
+
+        // Stopping closes open connections and removes the socket
+        bridge.stop().await;
+        assert_eq!(bridge.active_connections(), 0);
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+        assert!(!socket_path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_unix_bridge_refuses_other_users() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Connecting as another user needs root
+        if !nix::unistd::geteuid().is_root() {
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::set_permissions(dir.path(), std::fs::Permissions::from_mode(0o711)).unwrap();
+        let socket_path = dir.path().join("http.sock");
+        let bridge = HostBridge::unix_to_tcp(socket_path.clone(), "127.0.0.1", 9).unwrap();
+        std::fs::set_permissions(&socket_path, std::fs::Permissions::from_mode(0o777)).unwrap();
+
+        // The raw syscall changes the user of this thread only
+        let path = socket_path.clone();
+        let client = std::thread::spawn(move || {
+            let nobody = 65534;
+            // SAFETY: setresuid takes no pointers
+            let ret = unsafe {
+                nix::libc::syscall(nix::libc::SYS_setresuid, nobody, nobody, nobody)
+            };
+            assert_eq!(ret, 0);
+            std::os::unix::net::UnixStream::connect(path).unwrap()
+        })
+        .join()
+        .unwrap();
+        client.set_nonblocking(true).unwrap();
+
+        // The connection is closed without being relayed
+        let mut client = UnixStream::from_std(client).unwrap();
+        let mut buf = [0u8; 1];
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+        assert_eq!(bridge.total_connections(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_unix_to_udp() {
+        let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = echo.local_addr().unwrap().port();
+        let (peer_tx, mut peer_rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            while let Ok((n, peer)) = echo.recv_from(&mut buf).await {
+                let _ = peer_tx.send(peer);
+                let _ = echo.send_to(&buf[..n], peer).await;
+            }
+        });
+
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = dir.path().join("dns.sock");
+        let timeout = Duration::from_millis(200);
+        let bridge = HostBridge::unix_to_udp(socket_path.clone(), "127.0.0.1", port, timeout)
+            .unwrap();
+
+        // Datagrams are sent from the port the connection starts with
+        let sandbox_port = std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mut client = UnixStream::connect(&socket_path).await.unwrap();
+        client.write_all(&sandbox_port.to_be_bytes()).await.unwrap();
+        let mut client = Framed::new(client, datagram_codec());
+        client.send(Bytes::from_static(b"query")).await.unwrap();
+        assert_eq!(&client.next().await.unwrap().unwrap()[..], b"query");
+        assert_eq!(peer_rx.recv().await.unwrap().port(), sandbox_port);
+
+        // Ports outside the ephemeral range are not sent from
+        let fixed_port = EPHEMERAL_PORTS.start() - 1;
+        let mut other = UnixStream::connect(&socket_path).await.unwrap();
+        other.write_all(&fixed_port.to_be_bytes()).await.unwrap();
+        let mut other = Framed::new(other, datagram_codec());
+        other.send(Bytes::from_static(b"query")).await.unwrap();
+        assert_eq!(&other.next().await.unwrap().unwrap()[..], b"query");
+        let port = peer_rx.recv().await.unwrap().port();
+        assert_ne!(port, fixed_port);
+        assert!(EPHEMERAL_PORTS.contains(&port));
+
+        // Idle connections are closed
+        assert!(client.next().await.is_none());
+        assert!(other.next().await.is_none());
+        wait_for(|| bridge.active_connections() == 0).await;
+    }
+
+    #[test]
+    fn test_parse_port_range() {
+        assert_eq!(parse_port_range("32768\t60999\n"), Some(32768..=60999));
+        assert_eq!(parse_port_range("1024 65535"), Some(1024..=65535));
+        assert_eq!(parse_port_range("60999 32768"), None);
+        assert_eq!(parse_port_range("32768"), None);
+        assert_eq!(parse_port_range(""), None);
+    }
+
+    #[tokio::test]
+    async fn test_udp_to_unix() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = dir.path().join("dns.sock");
+        let server = UnixListener::bind(&socket_path).unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = server.accept().await {
+                tokio::spawn(async move {
+                    // The connection starts with the peer's port
+                    let mut port = [0u8; 2];
+                    stream.read_exact(&mut port).await.unwrap();
+                    let mut frames = Framed::new(stream, datagram_codec());
+                    while let Some(Ok(datagram)) = frames.next().await {
+                        let _ = frames.send(datagram.freeze()).await;
+                    }
+                });
+            }
+        });
+
+        let port = std::net::UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let timeout = Duration::from_millis(200);
+        let bridge = HostBridge::udp_to_unix(port, socket_path, timeout).await.unwrap();
+
+        // Each peer gets its own connection
+        let mut buf = [0u8; 16];
+        for payload in [b"first", b"other"] {
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            client.send_to(payload, ("127.0.0.1", port)).await.unwrap();
+            let n = client.recv(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], payload);
+        }
+        assert_eq!(bridge.total_connections(), 2);
+        wait_for(|| bridge.active_connections() == 0).await;
+    }
+
+    #[tokio::test]
+    async fn test_udp_bridges_keep_datagrams_apart() {
+        let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let echo_port = echo.local_addr().unwrap().port();
+        let (received_tx, mut received_rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            while let Ok((n, peer)) = echo.recv_from(&mut buf).await {
+                let _ = received_tx.send(buf[..n].to_vec());
+                let _ = echo.send_to(&buf[..n], peer).await;
+            }
+        });
+
+        // Both sides of a bridge, as between the sandbox and the host
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = dir.path().join("socks-udp.sock");
+        let timeout = Duration::from_millis(500);
+        let _host = HostBridge::unix_to_udp(socket_path.clone(), "127.0.0.1", echo_port, timeout)
+            .unwrap();
+        let port = std::net::UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let _sandbox = HostBridge::udp_to_unix(port, socket_path, timeout).await.unwrap();
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(b"first", ("127.0.0.1", port)).await.unwrap();
+        client.send_to(b"second", ("127.0.0.1", port)).await.unwrap();
+
+        let mut buf = [0u8; 64];
+        for payload in [&b"first"[..], b"second"] {
+            assert_eq!(received_rx.recv().await.unwrap(), payload);
+            let n = client.recv(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], payload);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tcp_to_unix() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = dir.path().join("8080.sock");
+        let server = UnixListener::bind(&socket_path).unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = server.accept().await.unwrap();
+            stream.write_all(b"hello").await.unwrap();
+        });
+
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let mut bridge = HostBridge::tcp_to_unix(port, socket_path.clone()).await.unwrap();
+        assert!(HostBridge::tcp_to_unix(port, socket_path.clone()).await.is_err());
+
+        let mut client = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let mut buf = [0u8; 5];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        // The sandbox's socket is left alone
+        bridge.stop().await;
+        assert!(socket_path.exists());
+    }
+}
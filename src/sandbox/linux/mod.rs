@@ -3,9 +3,17 @@
 pub mod bridge;
 pub mod bwrap;
 pub mod filesystem;
+pub mod helper;
+pub mod host_bridge;
+pub mod runtime;
 pub mod seccomp;
+pub mod tun;
 
-pub use bridge::{check_socat, generate_socket_path, SocatBridge};
-pub use bwrap::{check_bwrap, generate_bwrap_command, generate_proxy_env};
+pub use bridge::{check_socat, expose_socket_path, BridgeSpec, SocatBridge};
+pub use bwrap::{check_bwrap, generate_bwrap_command, write_resolv_conf};
 pub use filesystem::{generate_bind_mounts, BindMount};
+pub use helper::{bridge_helper_path, run_bridge_helper};
+pub use host_bridge::HostBridge;
+pub use runtime::{reap_stale_sessions, verify_peer, RuntimeDir};
 pub use seccomp::{get_apply_seccomp_path, get_bpf_path, is_seccomp_available};
+pub use tun::{check_tun_capture, tun_bwrap_args, tun_proxy_env, TunSpec, TUN_PROXY_ENV};
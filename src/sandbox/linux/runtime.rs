@@ -0,0 +1,245 @@
+//! Private per-session directory for bridge sockets.
+//!
+//! Each manager session keeps its sockets and generated files in a 0700
+//! directory named `srt-<pid>-<rand>` under `$XDG_RUNTIME_DIR`, or `/tmp`
+//! when that isn't set. Other users can't reach the sockets, bridges refuse
+//! connections from them ([`verify_peer`]), and sessions whose process is
+//! gone are reaped at startup. A session holds an `flock` on its directory's
+//! lock file while it runs, which the kernel releases when the process
+//! exits, so liveness doesn't depend on PIDs, which differ between PID
+//! namespaces and get reused.
+
+use std::fs::File;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+use nix::fcntl::{flock, FlockArg};
+use nix::unistd::geteuid;
+use tokio::net::UnixStream;
+
+/// Prefix of session directories.
+const SESSION_PREFIX: &str = "srt-";
+
+/// Lock file held by a running session.
+const LOCK_FILE: &str = "lock";
+
+/// Fallback when `$XDG_RUNTIME_DIR` is not usable.
+const FALLBACK_BASE: &str = "/tmp";
+
+/// A private directory for one session, removed on drop.
+pub struct RuntimeDir {
+    path: PathBuf,
+    /// Locked for as long as the session runs.
+    _lock: File,
+}
+
+impl RuntimeDir {
+    /// Create a session directory under the runtime base directory.
+    pub fn create() -> io::Result<Self> {
+        Self::create_in(&base_dir())
+    }
+
+    /// Create a session directory in `base`.
+    pub fn create_in(base: &Path) -> io::Result<Self> {
+        use rand::Rng;
+        let suffix: u32 = rand::thread_rng().gen();
+        let name = format!("{}{}-{:08x}", SESSION_PREFIX, std::process::id(), suffix);
+        let path = base.join(name);
+        std::fs::DirBuilder::new().mode(0o700).create(&path)?;
+        let lock = match lock_session(&path) {
+            Ok(lock) => lock,
+            Err(e) => {
+                let _ = std::fs::remove_dir_all(&path);
+                return Err(e);
+            }
+        };
+        Ok(Self { path, _lock: lock })
+    }
+
+    /// Path of the directory.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Path of a socket in the directory.
+    pub fn socket_path(&self, name: &str) -> PathBuf {
+        self.path.join(format!("{}.sock", name))
+    }
+
+    /// Create a private subdirectory, e.g. one to bind into the sandbox.
+    pub fn create_subdir(&self, name: &str) -> io::Result<PathBuf> {
+        let path = self.path.join(name);
+        std::fs::DirBuilder::new().mode(0o700).create(&path)?;
+        Ok(path)
+    }
+}
+
+impl Drop for RuntimeDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Get the directory session directories are created in.
+pub fn base_dir() -> PathBuf {
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute() && dir.is_dir())
+        .unwrap_or_else(|| PathBuf::from(FALLBACK_BASE))
+}
+
+/// Remove session directories left behind by srt processes that are no
+/// longer running, i.e. whose lock file isn't locked. Directories without
+/// one and those not owned by the current user are left alone.
+/// Returns the number of directories removed.
+pub fn reap_stale_sessions() -> usize {
+    reap_stale_sessions_in(&base_dir())
+}
+
+/// Remove stale session directories in `base`.
+pub fn reap_stale_sessions_in(base: &Path) -> usize {
+    let Ok(entries) = std::fs::read_dir(base) else {
+        return 0;
+    };
+    let uid = geteuid().as_raw();
+
+    let mut reaped = 0;
+    for entry in entries.flatten() {
+        if !entry.file_name().to_str().is_some_and(is_session_name) {
+            continue;
+        }
+        // Don't follow symlinks, and leave other users' entries alone
+        let Ok(metadata) = entry.path().symlink_metadata() else {
+            continue;
+        };
+        if !metadata.is_dir() || metadata.uid() != uid {
+            continue;
+        }
+        // Holding the lock keeps a new session from taking the directory
+        let Some(_lock) = take_stale_lock(&entry.path()) else {
+            continue;
+        };
+
+        match std::fs::remove_dir_all(entry.path()) {
+            Ok(()) => {
+                let path = entry.path();
+                tracing::debug!("Removed stale sandbox runtime directory {}", path.display());
+                reaped += 1;
+            }
+            Err(e) => tracing::debug!("Failed to remove {}: {}", entry.path().display(), e),
+        }
+    }
+    reaped
+}
+
+/// Check whether a name is a session directory's, `srt-<pid>-<rand>` as
+/// made by [`RuntimeDir::create_in`].
+fn is_session_name(name: &str) -> bool {
+    let Some((pid, suffix)) = name.strip_prefix(SESSION_PREFIX).and_then(|n| n.split_once('-'))
+    else {
+        return false;
+    };
+    suffix.len() == 8
+        && suffix.bytes().all(|b| b.is_ascii_hexdigit())
+        && pid.bytes().all(|b| b.is_ascii_digit())
+        && pid.parse::<u32>().is_ok_and(|pid| pid > 0)
+}
+
+/// Create and lock a new session directory's lock file.
+fn lock_session(dir: &Path) -> io::Result<File> {
+    let lock = File::options()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(dir.join(LOCK_FILE))?;
+    flock(lock.as_raw_fd(), FlockArg::LockExclusiveNonblock)?;
+    Ok(lock)
+}
+
+/// Lock the lock file of a session directory whose process is gone.
+/// Returns None if the session is running or has no lock file.
+fn take_stale_lock(dir: &Path) -> Option<File> {
+    let lock = File::options()
+        .read(true)
+        .custom_flags(nix::libc::O_NOFOLLOW)
+        .open(dir.join(LOCK_FILE))
+        .ok()?;
+    flock(lock.as_raw_fd(), FlockArg::LockExclusiveNonblock).ok()?;
+    Some(lock)
+}
+
+/// Check that the peer of an accepted bridge connection runs as the current
+/// user, as the sandbox's processes do.
+pub fn verify_peer(stream: &UnixStream) -> io::Result<()> {
+    let uid = stream.peer_cred()?.uid();
+    if uid != geteuid().as_raw() {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("bridge connection from uid {} refused", uid),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_session_name() {
+        assert!(is_session_name("srt-1234-0badf00d"));
+        assert!(!is_session_name("srt-http-1234-0badf00d.sock"));
+        assert!(!is_session_name("srt-profile-1234.sb"));
+        assert!(!is_session_name("srt-ca-1234-0badf00d.pem"));
+        assert!(!is_session_name("srt-ca-bundle-1234-0badf00d.pem"));
+        assert!(!is_session_name("srt-1234-0badf00d.pem"));
+        assert!(!is_session_name("srt-+1234-0badf00d"));
+        assert!(!is_session_name("other-1234-0badf00d"));
+        assert!(!is_session_name("srt-0-0badf00d"));
+    }
+
+    #[test]
+    fn test_runtime_dir_and_reaper() {
+        let base = tempfile::tempdir().unwrap();
+        let dir = RuntimeDir::create_in(base.path()).unwrap();
+        let mode = std::fs::metadata(dir.path()).unwrap().mode();
+        assert_eq!(mode & 0o777, 0o700);
+        assert_eq!(dir.socket_path("http"), dir.path().join("http.sock"));
+
+        // A session whose process is gone left its lock file unlocked
+        let stale = base.path().join("srt-1-0badf00d");
+        std::fs::create_dir(&stale).unwrap();
+        std::fs::write(stale.join(LOCK_FILE), "").unwrap();
+        // A session whose PID this process can't see, e.g. from another PID
+        // namespace, is running as long as its lock is held
+        let live = base.path().join("srt-99999999-0badf00d");
+        std::fs::create_dir(&live).unwrap();
+        let _held = lock_session(&live).unwrap();
+        // Directories without a lock file aren't known to be stale
+        let unlocked = base.path().join("srt-99999997-0badf00d");
+        std::fs::create_dir(&unlocked).unwrap();
+        // Files, such as other srt files in /tmp, are left alone
+        let file = base.path().join("srt-99999998-0badf00d");
+        std::fs::write(&file, "").unwrap();
+        std::fs::write(base.path().join("srt-ca-99999999-0badf00d.pem"), "").unwrap();
+
+        assert_eq!(reap_stale_sessions_in(base.path()), 1);
+        assert!(!stale.exists());
+        assert!(live.exists());
+        assert!(unlocked.exists());
+        assert!(file.exists());
+        assert!(dir.path().exists());
+
+        let path = dir.path().to_path_buf();
+        drop(dir);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_verify_peer() {
+        let (a, _b) = UnixStream::pair().unwrap();
+        verify_peer(&a).unwrap();
+    }
+}
@@ -7,4 +7,4 @@ pub mod wrapper;
 
 pub use monitor::LogMonitor;
 pub use profile::{generate_log_tag, generate_profile};
-pub use wrapper::{cleanup_temp_profiles, generate_proxy_env, wrap_command};
+pub use wrapper::{cleanup_temp_profiles, wrap_command};
@@ -3,9 +3,11 @@
 use std::collections::HashSet;
 
 use crate::config::{
-    FilesystemConfig, NetworkConfig, SandboxRuntimeConfig, DANGEROUS_DIRECTORIES, DANGEROUS_FILES,
+    ExposedPort, FilesystemConfig, NetworkConfig, SandboxRuntimeConfig, DANGEROUS_DIRECTORIES,
+    DANGEROUS_FILES,
 };
 use crate::sandbox::macos::glob::glob_to_seatbelt_regex;
+use crate::sandbox::proxy_env::CaTrust;
 use crate::utils::{normalize_path_for_sandbox, contains_glob_chars};
 
 /// Session suffix for log tagging (generated once per session).
@@ -27,6 +29,8 @@ pub fn generate_profile(
     config: &SandboxRuntimeConfig,
     http_proxy_port: Option<u16>,
     socks_proxy_port: Option<u16>,
+    dns_port: Option<u16>,
+    ca: Option<&CaTrust>,
     log_tag: Option<&str>,
 ) -> String {
     let mut profile = String::new();
@@ -75,12 +79,30 @@ pub fn generate_profile(
 
     // Network rules
     profile.push_str("; Network\n");
-    generate_network_rules(&mut profile, &config.network, http_proxy_port, socks_proxy_port);
+    generate_network_rules(
+        &mut profile,
+        &config.network,
+        http_proxy_port,
+        socks_proxy_port,
+        dns_port,
+    );
     profile.push('\n');
 
     // Filesystem rules
     profile.push_str("; Filesystem\n");
-    generate_filesystem_rules(&mut profile, &config.filesystem);
+    generate_filesystem_rules(&mut profile, &config.sandbox_filesystem());
+
+    // The interception CA stays readable even under a denied path
+    if let Some(ca) = ca {
+        profile.push_str("\n; TLS interception CA\n");
+        for path in ca.paths() {
+            let normalized = normalize_path_for_sandbox(&path.display().to_string());
+            profile.push_str(&format!(
+                "(allow file-read* (literal \"{}\"))\n",
+                escape_seatbelt_string(&normalized)
+            ));
+        }
+    }
 
     profile
 }
@@ -91,9 +113,10 @@ fn generate_network_rules(
     config: &NetworkConfig,
     http_proxy_port: Option<u16>,
     socks_proxy_port: Option<u16>,
+    dns_port: Option<u16>,
 ) {
-    // If no network restrictions (empty allowed domains), allow all network
-    if config.allowed_domains.is_empty() && config.denied_domains.is_empty() {
+    // If nothing is enforced by the proxies, allow all network
+    if !config.has_restrictions() {
         profile.push_str("(allow network*)\n");
         return;
     }
@@ -115,6 +138,14 @@ fn generate_network_rules(
     // Allow local binding if configured
     if config.allow_local_binding.unwrap_or(false) {
         profile.push_str("(allow network-bind (local ip \"localhost:*\"))\n");
+
+        // Exposed ports accept connections from the host
+        for port in config.expose_ports.iter().filter_map(|spec| ExposedPort::parse(spec)) {
+            profile.push_str(&format!(
+                "(allow network-inbound (local ip \"localhost:{}\"))\n",
+                port.sandbox_port
+            ));
+        }
     }
 
     // Allow specific Unix sockets
@@ -128,9 +159,16 @@ fn generate_network_rules(
         }
     }
 
-    // Allow DNS lookups
+    // Allow DNS lookups. The system resolver can't be pointed at the
+    // filtering forwarder, so it is only there for tools that ask it
     profile.push_str("(allow network-outbound (remote ip \"*:53\"))\n");
     profile.push_str("(allow network-outbound (remote ip \"*:853\"))\n");
+    if let Some(port) = dns_port {
+        profile.push_str(&format!(
+            "(allow network-outbound (remote ip \"localhost:{}\"))\n",
+            port
+        ));
+    }
 }
 
 /// Generate filesystem rules for the Seatbelt profile.
@@ -254,6 +292,9 @@ fn escape_seatbelt_string(s: &str) -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::{
+        AskConfig, HttpRuleConfig, RateLimitsConfig, TlsInterceptConfig, TlsSniConfig,
+    };
 
     #[test]
     fn test_generate_log_tag() {
@@ -272,7 +313,7 @@ mod tests {
     #[test]
     fn test_generate_profile_minimal() {
         let config = SandboxRuntimeConfig::default();
-        let profile = generate_profile(&config, None, None, None);
+        let profile = generate_profile(&config, None, None, None, None, None);
 
         assert!(profile.contains("(version 1)"));
         assert!(profile.contains("(deny default)"));
@@ -285,14 +326,68 @@ mod tests {
         let config = SandboxRuntimeConfig {
             network: NetworkConfig {
                 allowed_domains: vec!["github.com".to_string()],
+                allow_local_binding: Some(true),
+                expose_ports: vec!["8080:3000".to_string()],
                 ..Default::default()
             },
             ..Default::default()
         };
-        let profile = generate_profile(&config, Some(3128), Some(1080), None);
+        let profile = generate_profile(&config, Some(3128), Some(1080), Some(5353), None, None);
 
         assert!(profile.contains("localhost:3128"));
         assert!(profile.contains("localhost:1080"));
+        assert!(profile.contains("localhost:5353"));
+        assert!(profile.contains("(allow network-outbound (remote ip \"*:53\"))"));
+        assert!(profile.contains("(allow network-outbound (remote ip \"*:853\"))"));
+        assert!(profile.contains("(allow network-inbound (local ip \"localhost:3000\"))"));
+    }
+
+    #[test]
+    fn test_generate_profile_proxy_enforced_settings() {
+        let settings = [
+            NetworkConfig {
+                block_private_addresses: Some(true),
+                ..Default::default()
+            },
+            NetworkConfig {
+                http_rules: vec![HttpRuleConfig::default()],
+                ..Default::default()
+            },
+            NetworkConfig {
+                rate_limits: Some(RateLimitsConfig::default()),
+                ..Default::default()
+            },
+            NetworkConfig {
+                tls_sni: Some(TlsSniConfig {
+                    enabled: Some(true),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            NetworkConfig {
+                tls_intercept: Some(TlsInterceptConfig::default()),
+                ..Default::default()
+            },
+            NetworkConfig {
+                ask_unknown_domains: Some(AskConfig::default()),
+                ..Default::default()
+            },
+            NetworkConfig {
+                audit_log_path: Some("/tmp/audit.jsonl".to_string()),
+                ..Default::default()
+            },
+        ];
+        for network in settings {
+            let config = SandboxRuntimeConfig {
+                network,
+                ..Default::default()
+            };
+            let profile = generate_profile(&config, Some(3128), Some(1080), None, None, None);
+
+            // Traffic has to go through the proxies enforcing the setting
+            assert!(!profile.contains("(allow network*)"), "{:?}", config.network);
+            assert!(profile.contains("localhost:3128"));
+        }
     }
 
     #[test]
@@ -301,8 +396,29 @@ mod tests {
             allow_pty: Some(true),
             ..Default::default()
         };
-        let profile = generate_profile(&config, None, None, None);
+        let profile = generate_profile(&config, None, None, None, None, None);
 
         assert!(profile.contains("(allow pseudo-tty)"));
     }
+
+    #[test]
+    fn test_generate_profile_ca_readable() {
+        let config = SandboxRuntimeConfig {
+            filesystem: FilesystemConfig {
+                deny_read: vec!["/srt-test/ca".to_string()],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let ca = CaTrust {
+            cert: "/srt-test/ca/ca.pem".into(),
+            bundle: "/srt-test/bundle.pem".into(),
+        };
+        let profile = generate_profile(&config, None, None, None, Some(&ca), None);
+
+        let deny = profile.find("(deny file-read* (subpath \"/srt-test/ca\"))").unwrap();
+        let allow = profile.find("(allow file-read* (literal \"/srt-test/ca/ca.pem\"))").unwrap();
+        assert!(allow > deny);
+        assert!(profile.contains("(allow file-read* (literal \"/srt-test/bundle.pem\"))"));
+    }
 }
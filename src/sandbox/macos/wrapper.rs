@@ -4,14 +4,18 @@
 use crate::config::SandboxRuntimeConfig;
 use crate::error::SandboxError;
 use crate::sandbox::macos::profile::{generate_log_tag, generate_profile};
+use crate::sandbox::proxy_env::CaTrust;
 use crate::utils::quote;
 
-/// Wrap a command with sandbox-exec.
+/// Wrap a command with sandbox-exec. The proxy variables are not part of it:
+/// the command runs with them in its environment ([`crate::sandbox::sandbox_env`]).
 pub fn wrap_command(
     command: &str,
     config: &SandboxRuntimeConfig,
     http_proxy_port: Option<u16>,
     socks_proxy_port: Option<u16>,
+    dns_port: Option<u16>,
+    ca: Option<&CaTrust>,
     shell: Option<&str>,
     enable_log_monitor: bool,
 ) -> Result<(String, Option<String>), SandboxError> {
@@ -25,14 +29,34 @@ pub fn wrap_command(
     };
 
     // Generate the Seatbelt profile
-    let profile = generate_profile(config, http_proxy_port, socks_proxy_port, log_tag.as_deref());
+    let profile = generate_profile(
+        config,
+        http_proxy_port,
+        socks_proxy_port,
+        dns_port,
+        ca,
+        log_tag.as_deref(),
+    );
 
     // Write profile to a temporary file
     let profile_path = write_profile_to_temp(&profile)?;
 
+    // Keep the secrets the proxy injects on the host
+    let unset: Vec<String> = config
+        .network
+        .credential_env_vars()
+        .iter()
+        .map(|name| format!("-u {} ", quote(name)))
+        .collect();
+    let env = match unset.is_empty() {
+        true => String::new(),
+        false => format!("env {}", unset.concat()),
+    };
+
     // Build the wrapped command
     let wrapped = format!(
-        "sandbox-exec -f {} {} -c {}",
+        "{}sandbox-exec -f {} {} -c {}",
+        env,
         quote(&profile_path),
         shell,
         quote(command)
@@ -66,40 +90,28 @@ pub fn cleanup_temp_profiles() {
     }
 }
 
-/// Generate proxy environment variables.
-pub fn generate_proxy_env(
-    http_proxy_port: u16,
-    socks_proxy_port: u16,
-) -> Vec<(String, String)> {
-    let http_proxy = format!("http://localhost:{}", http_proxy_port);
-    let socks_proxy = format!("socks5://localhost:{}", socks_proxy_port);
-
-    vec![
-        ("http_proxy".to_string(), http_proxy.clone()),
-        ("HTTP_PROXY".to_string(), http_proxy.clone()),
-        ("https_proxy".to_string(), http_proxy.clone()),
-        ("HTTPS_PROXY".to_string(), http_proxy),
-        ("ALL_PROXY".to_string(), socks_proxy.clone()),
-        ("all_proxy".to_string(), socks_proxy),
-        // For git SSH
-        (
-            "GIT_SSH_COMMAND".to_string(),
-            format!(
-                "ssh -o ProxyCommand='nc -X 5 -x localhost:{} %h %p'",
-                socks_proxy_port
-            ),
-        ),
-    ]
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
-    fn test_generate_proxy_env() {
-        let env = generate_proxy_env(3128, 1080);
-        assert!(env.iter().any(|(k, v)| k == "http_proxy" && v.contains("3128")));
-        assert!(env.iter().any(|(k, v)| k == "ALL_PROXY" && v.contains("1080")));
+    fn test_wrap_command_has_no_proxy_variables() {
+        let mut config = SandboxRuntimeConfig::default();
+        config.network.credentials = vec![crate::config::CredentialConfig {
+            domain: "api.example.com".to_string(),
+            header: "Authorization".to_string(),
+            prefix: None,
+            env: Some("EXAMPLE_TOKEN".to_string()),
+            file: None,
+            allow_plain_http: None,
+        }];
+        let (wrapped, _) =
+            wrap_command("true", &config, Some(3128), Some(1080), None, None, None, false).unwrap();
+        cleanup_temp_profiles();
+
+        // The proxy variables, with the session credentials, go in the environment
+        assert!(!wrapped.contains("_proxy="));
+        assert!(!wrapped.contains("_PROXY="));
+        assert!(wrapped.starts_with("env -u EXAMPLE_TOKEN sandbox-exec -f "));
     }
 }
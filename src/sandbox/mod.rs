@@ -6,8 +6,13 @@ pub mod macos;
 #[cfg(target_os = "linux")]
 pub mod linux;
 
+pub mod proxy_env;
+
+pub use proxy_env::{generate_proxy_env, CaTrust};
+
 use crate::config::SandboxRuntimeConfig;
 use crate::error::SandboxError;
+use crate::proxy::ProxyAuth;
 use crate::utils::Platform;
 
 /// Detailed status of sandbox dependencies.
@@ -15,6 +20,7 @@ use crate::utils::Platform;
 pub struct LinuxDependencyStatus {
     pub has_bwrap: bool,
     pub has_socat: bool,
+    pub has_bridge_helper: bool,
     pub has_seccomp_bpf: bool,
     pub has_seccomp_apply: bool,
 }
@@ -52,6 +58,7 @@ pub fn get_linux_dependency_status(
     LinuxDependencyStatus {
         has_bwrap: linux::check_bwrap(),
         has_socat: linux::check_socat(),
+        has_bridge_helper: linux::bridge_helper_path().is_some(),
         has_seccomp_bpf: linux::get_bpf_path(seccomp_config).is_ok(),
         has_seccomp_apply: linux::get_apply_seccomp_path(seccomp_config).is_ok(),
     }
@@ -67,7 +74,8 @@ pub fn check_linux_dependencies(
     if !linux::check_bwrap() {
         result.errors.push("bubblewrap (bwrap) not installed".to_string());
     }
-    if !linux::check_socat() {
+    // The srt binary runs the bridges inside the sandbox; socat is the fallback
+    if linux::bridge_helper_path().is_none() && !linux::check_socat() {
         result.errors.push("socat not installed".to_string());
     }
 
@@ -110,6 +118,28 @@ pub fn check_dependencies_detailed(
     }
 }
 
+/// Environment variables a wrapped command must run with: the proxy
+/// variables, with the proxies' session credentials, and on Linux the proxy
+/// for TUN capture. They carry the secret, so they are passed in the
+/// environment of the process running the command rather than in the
+/// command, where any local user could read them.
+pub fn sandbox_env(
+    config: &SandboxRuntimeConfig,
+    http_proxy_port: u16,
+    socks_proxy_port: u16,
+    proxy_auth: Option<&ProxyAuth>,
+    ca: Option<&CaTrust>,
+) -> Vec<(String, String)> {
+    let proxy_env = config.network.proxy_env.as_ref();
+    #[cfg_attr(not(target_os = "linux"), allow(unused_mut))]
+    let mut env = generate_proxy_env(http_proxy_port, socks_proxy_port, proxy_auth, proxy_env, ca);
+    #[cfg(target_os = "linux")]
+    if config.network.tun_capture.is_some() {
+        env.push(linux::tun_proxy_env(socks_proxy_port, proxy_auth));
+    }
+    env
+}
+
 /// Check if sandboxing dependencies are available for the current platform.
 /// Legacy function that returns Result for backward compatibility.
 pub fn check_dependencies(platform: Platform) -> Result<(), SandboxError> {
@@ -117,15 +147,24 @@ pub fn check_dependencies(platform: Platform) -> Result<(), SandboxError> {
 }
 
 /// Wrap a command with platform-specific sandboxing.
+#[allow(clippy::too_many_arguments)]
 pub async fn wrap_command(
     command: &str,
     config: &SandboxRuntimeConfig,
     platform: Platform,
     http_proxy_port: Option<u16>,
     socks_proxy_port: Option<u16>,
+    #[cfg_attr(not(target_os = "macos"), allow(unused_variables))]
+    dns_port: Option<u16>,
     #[cfg(target_os = "linux")] http_socket_path: Option<&str>,
     #[cfg(target_os = "linux")] socks_socket_path: Option<&str>,
+    #[cfg(target_os = "linux")] socks_udp_socket_path: Option<&str>,
+    #[cfg(target_os = "linux")] dns_socket_path: Option<&str>,
+    #[cfg(target_os = "linux")] resolv_conf_path: Option<&str>,
+    #[cfg(target_os = "linux")] expose_dir: Option<&str>,
+    ca: Option<&CaTrust>,
     shell: Option<&str>,
+    #[cfg_attr(not(target_os = "macos"), allow(unused_variables))]
     enable_log_monitor: bool,
 ) -> Result<WrapResult, SandboxError> {
     match platform {
@@ -137,6 +176,8 @@ pub async fn wrap_command(
                     config,
                     http_proxy_port,
                     socks_proxy_port,
+                    dns_port,
+                    ca,
                     shell,
                     enable_log_monitor,
                 )?;
@@ -163,6 +204,11 @@ pub async fn wrap_command(
                     &cwd,
                     http_socket_path,
                     socks_socket_path,
+                    socks_udp_socket_path,
+                    dns_socket_path,
+                    resolv_conf_path,
+                    expose_dir,
+                    ca,
                     http_proxy_port.unwrap_or(3128),
                     socks_proxy_port.unwrap_or(1080),
                     shell,
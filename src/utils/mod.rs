@@ -1,12 +1,14 @@
 //! Utility modules.
 
 pub mod debug;
+pub mod net;
 pub mod path;
 pub mod platform;
 pub mod ripgrep;
 pub mod shell;
 
 pub use debug::{init_debug_logging, is_debug_enabled, SRT_DEBUG_ENV};
+pub use net::{canonical_ip, format_host_port, is_private_address, parse_ip_host, IpCidr};
 pub use path::{
     contains_glob_chars, expand_home, is_symlink_outside_boundary, normalize_case_for_comparison,
     normalize_path_for_sandbox, remove_trailing_glob_suffix,
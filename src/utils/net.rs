@@ -0,0 +1,235 @@
+//! Network address utilities.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// An IP network in CIDR notation (e.g., "10.0.0.0/8", "2001:db8::/32").
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpCidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpCidr {
+    /// Parse CIDR notation. A bare address is treated as a single-host network.
+    pub fn parse(s: &str) -> Option<Self> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+
+        let addr: IpAddr = addr.parse().ok()?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix {
+            Some(prefix) => prefix.parse::<u8>().ok().filter(|len| *len <= max_len)?,
+            None => max_len,
+        };
+
+        Some(Self {
+            addr: mask(addr, prefix_len),
+            prefix_len,
+        })
+    }
+
+    /// Get the network address.
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    /// Get the prefix length.
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    /// Check if an address is within the network.
+    /// IPv4-mapped IPv6 addresses are matched as IPv4.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = canonical_ip(ip);
+        ip.is_ipv4() == self.addr.is_ipv4() && mask(ip, self.prefix_len) == self.addr
+    }
+}
+
+impl std::fmt::Display for IpCidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// Zero the host bits of an address.
+fn mask(ip: IpAddr, prefix_len: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(v4) => {
+            let bits = u32::from(v4) & u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+            IpAddr::V4(bits.into())
+        }
+        IpAddr::V6(v6) => {
+            let bits =
+                u128::from(v6) & u128::MAX.checked_shl(128 - prefix_len as u32).unwrap_or(0);
+            IpAddr::V6(bits.into())
+        }
+    }
+}
+
+/// Convert IPv4-mapped IPv6 addresses (::ffff:a.b.c.d) to IPv4.
+pub fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+/// Check if an address is loopback, private, link-local, unspecified,
+/// carrier-grade NAT, benchmarking or otherwise special-purpose space, i.e.
+/// not reachable on the public internet. IPv6 addresses embedding an IPv4
+/// address (NAT64, IPv4-compatible, 6to4) are checked by that address.
+pub fn is_private_address(ip: IpAddr) -> bool {
+    match canonical_ip(ip) {
+        IpAddr::V4(v4) => {
+            let octets = v4.octets();
+            v4.is_loopback()
+                || v4.is_private()
+                || v4.is_link_local()
+                || v4.is_unspecified()
+                || v4.is_broadcast()
+                // 0.0.0.0/8 ("this network")
+                || octets[0] == 0
+                // 100.64.0.0/10 (carrier-grade NAT)
+                || (octets[0] == 100 && (octets[1] & 0xc0) == 64)
+                // 192.0.0.0/24 (IETF protocol assignments)
+                || (octets[0] == 192 && octets[1] == 0 && octets[2] == 0)
+                // 198.18.0.0/15 (benchmarking)
+                || (octets[0] == 198 && (octets[1] & 0xfe) == 18)
+        }
+        IpAddr::V6(v6) => {
+            let first = v6.segments()[0];
+            v6.is_loopback()
+                || v6.is_unspecified()
+                || embedded_ipv4(v6).is_some_and(|v4| is_private_address(IpAddr::V4(v4)))
+                // fc00::/7 (unique local)
+                || (first & 0xfe00) == 0xfc00
+                // fe80::/10 (link-local)
+                || (first & 0xffc0) == 0xfe80
+                // fec0::/10 (site-local, deprecated)
+                || (first & 0xffc0) == 0xfec0
+        }
+    }
+}
+
+/// The IPv4 address a NAT64 (64:ff9b::/96), IPv4-compatible (::/96) or
+/// 6to4 (2002::/16) address leads to.
+fn embedded_ipv4(v6: Ipv6Addr) -> Option<Ipv4Addr> {
+    let octets = v6.octets();
+    let segments = v6.segments();
+    let start = match segments {
+        [0x64, 0xff9b, 0, 0, 0, 0, _, _] | [0, 0, 0, 0, 0, 0, _, _] => 12,
+        [0x2002, ..] => 2,
+        _ => return None,
+    };
+    let v4: [u8; 4] = octets[start..start + 4].try_into().ok()?;
+    Some(Ipv4Addr::from(v4))
+}
+
+/// Parse a host that is an IP literal, with or without IPv6 brackets.
+/// Returns the canonical address, or None for hostnames.
+pub fn parse_ip_host(host: &str) -> Option<IpAddr> {
+    let host = host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host);
+    host.parse().ok().map(canonical_ip)
+}
+
+/// Format a host and port for connecting, bracketing IPv6 literals.
+pub fn format_host_port(host: &str, port: u16) -> String {
+    if host.contains(':') && !host.starts_with('[') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cidr_parse_and_contains() {
+        let net = IpCidr::parse("10.0.0.0/8").unwrap();
+        assert!(net.contains("10.1.2.3".parse().unwrap()));
+        assert!(!net.contains("11.0.0.1".parse().unwrap()));
+        assert!(net.contains("::ffff:10.0.0.1".parse().unwrap()));
+
+        let net = IpCidr::parse("2001:db8::/32").unwrap();
+        assert!(net.contains("2001:db8:0:0:0:0:0:1".parse().unwrap()));
+        assert!(!net.contains("2001:db9::1".parse().unwrap()));
+        assert!(!net.contains("10.0.0.1".parse().unwrap()));
+
+        // Host bits are masked, bare addresses are single hosts
+        assert_eq!(IpCidr::parse("192.168.1.77/24").unwrap().to_string(), "192.168.1.0/24");
+        assert_eq!(IpCidr::parse("1.2.3.4").unwrap().to_string(), "1.2.3.4/32");
+        assert!(IpCidr::parse("0.0.0.0/0").unwrap().contains("8.8.8.8".parse().unwrap()));
+
+        assert!(IpCidr::parse("10.0.0.0/33").is_none());
+        assert!(IpCidr::parse("example.com/8").is_none());
+        assert!(IpCidr::parse("10.0.0.0/").is_none());
+    }
+
+    #[test]
+    fn test_is_private_address() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "0.0.0.0",
+            "100.64.0.1",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "192.0.0.8",
+            "198.18.0.1",
+            "198.19.255.255",
+            "fec0::1",
+            // NAT64, IPv4-compatible and 6to4 forms of private addresses
+            "64:ff9b::10.0.0.1",
+            "64:ff9b::a9fe:a9fe",
+            "::127.0.0.1",
+            "::10.0.0.1",
+            "2002:a00:1::1",
+            "2002:7f00:1::",
+        ] {
+            assert!(is_private_address(ip.parse().unwrap()), "{}", ip);
+        }
+
+        for ip in [
+            "8.8.8.8",
+            "140.82.112.3",
+            "100.128.0.1",
+            "192.0.1.1",
+            "198.20.0.1",
+            "2606:4700::1111",
+            "64:ff9b::8.8.8.8",
+            "::8.8.8.8",
+            "2002:808:808::1",
+        ] {
+            assert!(!is_private_address(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn test_parse_ip_host() {
+        assert_eq!(parse_ip_host("1.2.3.4"), Some("1.2.3.4".parse().unwrap()));
+        assert_eq!(parse_ip_host("[::1]"), Some("::1".parse().unwrap()));
+        assert_eq!(parse_ip_host("::ffff:1.2.3.4"), Some("1.2.3.4".parse().unwrap()));
+        assert_eq!(parse_ip_host("example.com"), None);
+    }
+
+    #[test]
+    fn test_format_host_port() {
+        assert_eq!(format_host_port("example.com", 443), "example.com:443");
+        assert_eq!(format_host_port("::1", 443), "[::1]:443");
+        assert_eq!(format_host_port("[::1]", 443), "[::1]:443");
+    }
+}